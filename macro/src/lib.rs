@@ -45,7 +45,31 @@ pub fn ffi_gen_native_64(input: TokenStream, _: TokenStream) -> TokenStream {
 
 fn inner_ffi_gen(input: TokenStream, abi: Abi) -> TokenStream {
     let input = syn::parse_macro_input!(input as syn::LitStr);
-    let ffigen = FfiGen::new(input.value()).unwrap();
-    let rust = ffigen.generate_rust(abi).unwrap();
-    rust.parse().unwrap()
+    generate(&input, abi)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Generates the rust api, reporting any errors at the span of the path literal.
+fn generate(input: &syn::LitStr, abi: Abi) -> syn::Result<TokenStream2> {
+    let error = |msg: String| syn::Error::new(input.span(), msg);
+    // Relative paths are resolved against the working directory like `FfiGen::new` does,
+    // while `include_bytes!` would resolve them relative to the invoking source file.
+    let path = std::env::current_dir()
+        .map_err(|err| error(err.to_string()))?
+        .join(input.value());
+    let mut ffigen = FfiGen::new(&path).map_err(|err| error(format!("{:#}", err)))?;
+    if cfg!(feature = "tokio") {
+        ffigen = ffigen.executor("tokio");
+    }
+    let rust = ffigen
+        .generate_rust(abi)
+        .map_err(|err| error(format!("{:#}", err)))?;
+    let rust: TokenStream2 = rust.parse().map_err(|err| error(format!("{:?}", err)))?;
+    let path = path.display().to_string();
+    Ok(quote! {
+        // Makes cargo rebuild the api when the interface file changes.
+        const _: &[u8] = include_bytes!(#path);
+        #rust
+    })
 }
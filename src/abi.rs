@@ -1,4 +1,5 @@
-use crate::parser::{Interface, Type};
+use crate::parser::{Interface, Type, Variant};
+use std::str::FromStr;
 
 pub mod export;
 pub mod import;
@@ -27,17 +28,28 @@ pub enum AbiType {
     String,
     RefSlice(NumType),
     Vec(NumType),
+    RefList(Box<AbiType>),
+    List(Box<AbiType>),
+    Map(Box<AbiType>, Box<AbiType>),
+    Set(Box<AbiType>),
     RefObject(String),
     Object(String),
     Option(Box<AbiType>),
-    Result(Box<AbiType>),
+    Result(Box<AbiType>, Box<AbiType>),
+    Error(AbiError),
     RefIter(Box<AbiType>),
-    Iter(Box<AbiType>),
+    /// An iterator, returning its items in lists of up to `batch` items if set.
+    Iter(Box<AbiType>, Option<usize>),
     RefFuture(Box<AbiType>),
     Future(Box<AbiType>),
     RefStream(Box<AbiType>),
-    Stream(Box<AbiType>),
+    /// A stream, returning its ready items in lists of up to `batch` items if set.
+    Stream(Box<AbiType>, Option<usize>),
     Tuple(Vec<AbiType>),
+    Struct(AbiStruct),
+    Enum(AbiEnum),
+    Callback(Vec<AbiType>, Option<Box<AbiType>>),
+    Trait(AbiTrait),
 }
 
 impl AbiType {
@@ -48,6 +60,24 @@ impl AbiType {
             panic!()
         }
     }
+
+    /// Returns the type of the items returned by each call to the next or poll
+    /// function of an iterator or stream, a list if it is batched.
+    pub fn batched(ty: &AbiType, batch: Option<usize>) -> AbiType {
+        match batch {
+            Some(_) => Self::List(Box::new(ty.clone())),
+            None => ty.clone(),
+        }
+    }
+
+    /// Returns the types making up a single entry of a list, map or set.
+    pub fn elements(&self) -> Vec<AbiType> {
+        match self {
+            Self::RefList(ty) | Self::List(ty) | Self::Set(ty) => vec![(**ty).clone()],
+            Self::Map(key, value) => vec![(**key).clone(), (**value).clone()],
+            _ => panic!(),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -82,6 +112,10 @@ impl AbiFunction {
         }
     }
 
+    pub fn callback_symbol(&self, arg: &str) -> String {
+        format!("{}_callback_{}", self.symbol(), arg)
+    }
+
     pub fn ret(&self, rets: Vec<Var>) -> Return {
         match rets.len() {
             0 => Return::Void,
@@ -89,6 +123,20 @@ impl AbiFunction {
             _ => Return::Struct(rets, format!("{}Return", self.symbol())),
         }
     }
+
+    /// Returns true if the function returns a future, so the bindings can
+    /// take a handle to cancel it.
+    pub fn returns_future(&self) -> bool {
+        fn is_future(ty: &AbiType) -> bool {
+            match ty {
+                AbiType::Future(_) => true,
+                AbiType::Option(ty) | AbiType::Result(ty, _) => is_future(ty),
+                AbiType::Tuple(tys) => tys.iter().any(is_future),
+                _ => false,
+            }
+        }
+        self.ret.as_ref().map(is_future).unwrap_or_default()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -96,11 +144,72 @@ pub struct AbiObject {
     pub doc: Vec<String>,
     pub name: String,
     pub methods: Vec<AbiFunction>,
+    /// Whether the object is stored in an `Arc` instead of a `Box`.
+    pub shared: bool,
     pub destructor: String,
 }
 
+#[derive(Clone, Debug)]
+pub struct AbiTrait {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub methods: Vec<AbiTraitMethod>,
+}
+
+impl AbiTrait {
+    pub fn method_symbol(&self, method: &AbiTraitMethod) -> String {
+        format!("__{}_{}_callback", self.name, method.name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AbiTraitMethod {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub args: Vec<(String, AbiType)>,
+    pub ret: Option<AbiType>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AbiStruct {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub fields: Vec<(String, AbiType)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AbiEnum {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub variants: Vec<AbiVariant>,
+}
+
+impl AbiEnum {
+    /// Returns true if none of the variants carry data.
+    pub fn is_c_like(&self) -> bool {
+        self.variants
+            .iter()
+            .all(|variant| variant.fields.is_empty())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AbiError {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub variants: Vec<AbiVariant>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AbiVariant {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub fields: Vec<(String, AbiType)>,
+}
+
 #[derive(Clone, Debug)]
 pub struct AbiIter {
+    /// Type returned by `next`, a list of items if the iterator is batched.
     pub ty: AbiType,
     pub symbol: String,
 }
@@ -140,6 +249,7 @@ impl AbiFuture {
 
 #[derive(Clone, Debug)]
 pub struct AbiStream {
+    /// Type returned by `poll`, a list of items if the stream is batched.
     pub ty: AbiType,
     pub symbol: String,
 }
@@ -229,6 +339,15 @@ impl Abi {
         }
     }
 
+    /// Returns the pointer to the payload of the active variant of an enum or
+    /// error, unless none of its variants carry data.
+    fn payload(self, variants: &[AbiVariant], gen: &mut VarGen) -> Option<Var> {
+        if variants.iter().all(|variant| variant.fields.is_empty()) {
+            return None;
+        }
+        Some(gen.gen_num(self.iptr()))
+    }
+
     /// Returns the size and alignment of a primitive type.
     pub(crate) fn layout(self, ty: NumType) -> (usize, usize) {
         let size = match ty {
@@ -245,6 +364,21 @@ impl Abi {
     }
 }
 
+impl FromStr for Abi {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "native" => Self::native(),
+            "native32" => Self::Native32,
+            "native64" => Self::Native64,
+            "wasm32" => Self::Wasm32,
+            "wasm64" => Self::Wasm64,
+            _ => anyhow::bail!("unknown abi `{}`", s),
+        })
+    }
+}
+
 impl Interface {
     pub fn objects(&self) -> Vec<AbiObject> {
         let mut objs = vec![];
@@ -273,12 +407,109 @@ impl Interface {
                 doc: object.doc.clone(),
                 name: object.ident.clone(),
                 methods,
+                shared: object.is_shared,
                 destructor: format!("drop_box_{}", &object.ident),
             });
         }
         objs
     }
 
+    pub fn traits(&self) -> Vec<AbiTrait> {
+        self.traits
+            .iter()
+            .map(|tr| self.to_trait(&tr.ident))
+            .collect()
+    }
+
+    fn to_trait(&self, ident: &str) -> AbiTrait {
+        let tr = self.traits.iter().find(|tr| tr.ident == ident).unwrap();
+        AbiTrait {
+            doc: tr.doc.clone(),
+            name: tr.ident.clone(),
+            methods: tr
+                .methods
+                .iter()
+                .map(|method| AbiTraitMethod {
+                    doc: method.doc.clone(),
+                    name: method.ident.clone(),
+                    args: method
+                        .args
+                        .iter()
+                        .map(|(n, ty)| (n.clone(), self.to_callback_arg(ty)))
+                        .collect(),
+                    ret: method.ret.as_ref().map(|ty| self.to_callback_ret(ty)),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn structs(&self) -> Vec<AbiStruct> {
+        self.structs
+            .iter()
+            .map(|st| self.to_struct(&st.ident))
+            .collect()
+    }
+
+    fn to_struct(&self, ident: &str) -> AbiStruct {
+        let st = self.structs.iter().find(|st| st.ident == ident).unwrap();
+        AbiStruct {
+            doc: st.doc.clone(),
+            name: st.ident.clone(),
+            fields: st
+                .fields
+                .iter()
+                .map(|field| (field.ident.clone(), self.to_type(&field.ty)))
+                .collect(),
+        }
+    }
+
+    pub fn errors(&self) -> Vec<AbiError> {
+        self.errors
+            .iter()
+            .map(|err| self.to_error(&err.ident))
+            .collect()
+    }
+
+    fn to_error(&self, ident: &str) -> AbiError {
+        let err = self.errors.iter().find(|err| err.ident == ident).unwrap();
+        AbiError {
+            doc: err.doc.clone(),
+            name: err.ident.clone(),
+            variants: self.to_variants(&err.variants),
+        }
+    }
+
+    pub fn enums(&self) -> Vec<AbiEnum> {
+        self.enums
+            .iter()
+            .map(|en| self.to_enum(&en.ident))
+            .collect()
+    }
+
+    fn to_enum(&self, ident: &str) -> AbiEnum {
+        let en = self.enums.iter().find(|en| en.ident == ident).unwrap();
+        AbiEnum {
+            doc: en.doc.clone(),
+            name: en.ident.clone(),
+            variants: self.to_variants(&en.variants),
+        }
+    }
+
+    fn to_variants(&self, variants: &[Variant]) -> Vec<AbiVariant> {
+        variants
+            .iter()
+            .map(|variant| AbiVariant {
+                doc: variant.doc.clone(),
+                name: variant.ident.clone(),
+                fields: variant
+                    .fields
+                    .iter()
+                    .map(|field| (field.ident.clone(), self.to_type(&field.ty)))
+                    .collect(),
+            })
+            .collect()
+    }
+
     pub fn functions(&self) -> Vec<AbiFunction> {
         let mut funcs = vec![];
         for func in &self.functions {
@@ -313,18 +544,18 @@ impl Interface {
                 let mut symbol = func.symbol();
                 loop {
                     match p {
-                        AbiType::Option(ty) | AbiType::Result(ty) => p = &**ty,
+                        AbiType::Option(ty) | AbiType::Result(ty, _) => p = &**ty,
                         AbiType::Future(ty) => {
                             symbol.push_str("_future_poll");
                             p = &**ty
                         }
-                        AbiType::Stream(ty) => {
+                        AbiType::Stream(ty, _) => {
                             symbol.push_str("_stream_poll");
                             p = &**ty
                         }
-                        AbiType::Iter(ty) => {
+                        AbiType::Iter(ty, batch) => {
                             iterators.push(AbiIter {
-                                ty: (&**ty).clone(),
+                                ty: AbiType::batched(ty, *batch),
                                 symbol,
                             });
                             break;
@@ -348,7 +579,7 @@ impl Interface {
                 let mut p = ty;
                 loop {
                     match p {
-                        AbiType::Option(ty) | AbiType::Result(ty) => p = &**ty,
+                        AbiType::Option(ty) | AbiType::Result(ty, _) => p = &**ty,
                         AbiType::Future(ty) => {
                             let symbol = func.symbol();
                             futures.push(AbiFuture {
@@ -376,11 +607,11 @@ impl Interface {
                 let mut p = ty;
                 loop {
                     match p {
-                        AbiType::Option(ty) | AbiType::Result(ty) => p = &**ty,
-                        AbiType::Stream(ty) => {
+                        AbiType::Option(ty) | AbiType::Result(ty, _) => p = &**ty,
+                        AbiType::Stream(ty, batch) => {
                             let symbol = func.symbol();
                             streams.push(AbiStream {
-                                ty: (&**ty).clone(),
+                                ty: AbiType::batched(ty, *batch),
                                 symbol,
                             });
                             break;
@@ -393,6 +624,27 @@ impl Interface {
         streams
     }
 
+    pub fn callbacks(&self) -> Vec<String> {
+        let mut callbacks = vec![];
+        let mut functions = self.functions();
+        for obj in self.objects() {
+            functions.extend(obj.methods);
+        }
+        for func in functions {
+            for (name, ty) in &func.args {
+                if let AbiType::Callback(_, _) = ty {
+                    callbacks.push(func.callback_symbol(name));
+                }
+            }
+        }
+        for tr in self.traits() {
+            for method in &tr.methods {
+                callbacks.push(tr.method_symbol(method));
+            }
+        }
+        callbacks
+    }
+
     pub fn imports(&self, abi: &Abi) -> Vec<import::Import> {
         let mut imports = vec![];
         for function in self.functions() {
@@ -434,10 +686,13 @@ impl Interface {
                 Type::String => AbiType::RefStr,
                 Type::Slice(inner) => match self.to_type(inner) {
                     AbiType::Num(ty) => AbiType::RefSlice(ty),
-                    ty => unimplemented!("&{:?}", ty),
+                    ty => AbiType::RefList(Box::new(self.to_list_element(ty))),
                 },
                 Type::Ident(ident) => {
                     if !self.is_object(ident) {
+                        if self.is_struct(ident) || self.is_enum(ident) || self.is_trait(ident) {
+                            panic!("{} needs to be passed by value", ident);
+                        }
                         panic!("unknown identifier {}", ident);
                     }
                     AbiType::RefObject(ident.clone())
@@ -448,10 +703,30 @@ impl Interface {
             Type::Slice(_) => panic!("slice needs to be passed by reference"),
             Type::Vec(inner) => match self.to_type(inner) {
                 AbiType::Num(ty) => AbiType::Vec(ty),
-                ty => unimplemented!("Vec<{:?}>", ty),
+                ty => AbiType::List(Box::new(self.to_list_element(ty))),
             },
+            Type::Map(key, value) => AbiType::Map(
+                Box::new(self.to_key(self.to_type(key))),
+                Box::new(self.to_list_element(self.to_type(value))),
+            ),
+            Type::Set(inner) => AbiType::Set(Box::new(self.to_key(self.to_type(inner)))),
             Type::Ident(ident) => {
+                if self.is_struct(ident) {
+                    return AbiType::Struct(self.to_struct(ident));
+                }
+                if self.is_enum(ident) {
+                    return AbiType::Enum(self.to_enum(ident));
+                }
+                if self.is_trait(ident) {
+                    return AbiType::Trait(self.to_trait(ident));
+                }
                 if !self.is_object(ident) {
+                    if self.is_error(ident) {
+                        panic!(
+                            "error {} can only be returned as `Result<_, {}>`",
+                            ident, ident
+                        );
+                    }
                     panic!("unknown identifier {}", ident);
                 }
                 AbiType::Object(ident.clone())
@@ -463,11 +738,105 @@ impl Interface {
                 }
                 AbiType::Option(Box::new(inner))
             }
-            Type::Result(ty) => AbiType::Result(Box::new(self.to_type(ty))),
-            Type::Iter(ty) => AbiType::Iter(Box::new(self.to_type(ty))),
+            Type::Result(ty, err) => {
+                let err = match &**err {
+                    Type::String => AbiType::String,
+                    Type::Ident(ident) if self.is_error(ident) => {
+                        AbiType::Error(self.to_error(ident))
+                    }
+                    ty => panic!("{:?} is not an error type", ty),
+                };
+                AbiType::Result(Box::new(self.to_type(ty)), Box::new(err))
+            }
+            Type::Iter(ty, batch) => AbiType::Iter(Box::new(self.to_item(ty, *batch)), *batch),
             Type::Future(ty) => AbiType::Future(Box::new(self.to_type(ty))),
-            Type::Stream(ty) => AbiType::Stream(Box::new(self.to_type(ty))),
+            Type::Stream(ty, batch) => AbiType::Stream(Box::new(self.to_item(ty, *batch)), *batch),
             Type::Tuple(ty) => AbiType::Tuple(ty.iter().map(|ty| self.to_type(ty)).collect()),
+            Type::Callback(args, ret) => {
+                let args = args.iter().map(|ty| self.to_callback_arg(ty)).collect();
+                let ret = ret.as_ref().map(|ty| Box::new(self.to_callback_ret(ty)));
+                AbiType::Callback(args, ret)
+            }
+        }
+    }
+
+    fn to_item(&self, ty: &Type, batch: Option<usize>) -> AbiType {
+        let ty = self.to_type(ty);
+        match batch {
+            Some(_) => self.to_list_element(ty),
+            None => ty,
+        }
+    }
+
+    fn to_list_element(&self, ty: AbiType) -> AbiType {
+        match &ty {
+            AbiType::Option(inner) => {
+                self.to_list_element((**inner).clone());
+            }
+            AbiType::Tuple(tys) => {
+                for ty in tys {
+                    self.to_list_element(ty.clone());
+                }
+            }
+            AbiType::Num(_)
+            | AbiType::Isize
+            | AbiType::Usize
+            | AbiType::Bool
+            | AbiType::String
+            | AbiType::Vec(_)
+            | AbiType::List(_)
+            | AbiType::Map(_, _)
+            | AbiType::Set(_)
+            | AbiType::Object(_)
+            | AbiType::Struct(_)
+            | AbiType::Enum(_) => {}
+            _ => panic!("{:?} can't be stored in a list", ty),
+        }
+        ty
+    }
+
+    fn to_key(&self, ty: AbiType) -> AbiType {
+        match &ty {
+            AbiType::Num(NumType::F32 | NumType::F64) => panic!("floats can't be used as keys"),
+            AbiType::Num(_)
+            | AbiType::Isize
+            | AbiType::Usize
+            | AbiType::Bool
+            | AbiType::String
+            | AbiType::Enum(_) => {}
+            _ => panic!("{:?} can't be used as a key", ty),
+        }
+        ty
+    }
+
+    fn to_callback_arg(&self, ty: &Type) -> AbiType {
+        if let Type::Ident(ident) = ty {
+            if self.is_trait(ident) {
+                panic!("interface {} can't be passed to a callback", ident);
+            }
+        }
+        match self.to_type(ty) {
+            AbiType::Option(_)
+            | AbiType::Result(_, _)
+            | AbiType::RefObject(_)
+            | AbiType::RefIter(_)
+            | AbiType::Iter(_, _)
+            | AbiType::RefFuture(_)
+            | AbiType::Future(_)
+            | AbiType::RefStream(_)
+            | AbiType::Stream(_, _)
+            | AbiType::Tuple(_)
+            | AbiType::Callback(_, _) => {
+                panic!("{:?} can't be passed to a callback", ty)
+            }
+            ty => ty,
+        }
+    }
+
+    fn to_callback_ret(&self, ty: &Type) -> AbiType {
+        match self.to_type(ty) {
+            ty @ (AbiType::Num(_) | AbiType::Isize | AbiType::Usize | AbiType::Bool) => ty,
+            _ => panic!("callbacks can only return primitive types"),
         }
     }
 }
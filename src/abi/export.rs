@@ -1,5 +1,7 @@
 use super::VarGen;
-use crate::{Abi, AbiFunction, AbiType, FunctionType, NumType, Return, Var};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiTrait, AbiType, FunctionType, NumType, Return, Var,
+};
 
 #[derive(Clone, Debug)]
 pub struct Export {
@@ -75,6 +77,25 @@ impl Abi {
                 let ty = *ty;
                 instr.push(Instr::LiftVec(ptr, len, cap, out, ty));
             }
+            AbiType::RefList(_) | AbiType::List(_) | AbiType::Map(_, _) | AbiType::Set(_) => {
+                let ptr = gen.gen_num(self.iptr());
+                let len = gen.gen_num(self.uptr());
+                ffi_args.extend_from_slice(&[ptr.clone(), len.clone()]);
+                let mut elems = vec![];
+                let mut elem_ffi = vec![];
+                let mut elem_instr = vec![];
+                for ty in out.ty.elements() {
+                    let elem = gen.gen(ty);
+                    elems.push(elem.clone());
+                    self.export_arg(elem, gen, &mut elem_instr, &mut elem_ffi);
+                }
+                assert!(
+                    !elem_ffi.is_empty(),
+                    "{:?} can't be stored in a list",
+                    out.ty
+                );
+                instr.push(Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr));
+            }
             AbiType::RefObject(object) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_args.push(ptr.clone());
@@ -95,14 +116,21 @@ impl Abi {
                 self.export_arg(some.clone(), gen, &mut some_instr, ffi_args);
                 instr.push(Instr::LiftOption(opt, out, some, some_instr));
             }
-            AbiType::Result(_) => todo!(),
+            AbiType::Result(_, _) => unreachable!("the validator only allows returning results"),
+            AbiType::Error(_) => unreachable!("the validator only allows errors in results"),
+            AbiType::Callback(_, _) => {
+                unreachable!("the validator only allows callbacks as function arguments")
+            }
+            AbiType::Trait(_) => {
+                unreachable!("the validator only allows interfaces as function arguments")
+            }
             AbiType::RefIter(ty) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_args.push(ptr.clone());
                 let ty = (&**ty).clone();
                 instr.push(Instr::LiftRefIter(ptr, out, ty));
             }
-            AbiType::Iter(ty) => {
+            AbiType::Iter(ty, _) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_args.push(ptr.clone());
                 let ty = (&**ty).clone();
@@ -126,7 +154,7 @@ impl Abi {
                 let ty = (&**ty).clone();
                 instr.push(Instr::LiftRefStream(ptr, out, ty));
             }
-            AbiType::Stream(ty) => {
+            AbiType::Stream(ty, _) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_args.push(ptr.clone());
                 let ty = (&**ty).clone();
@@ -141,6 +169,36 @@ impl Abi {
                 }
                 instr.push(Instr::LiftTuple(vars, out));
             }
+            AbiType::Struct(st) => {
+                let name = st.name.clone();
+                let mut vars = vec![];
+                for (field, ty) in st.fields.clone() {
+                    let arg = gen.gen(ty);
+                    vars.push((field, arg.clone()));
+                    self.export_arg(arg, gen, instr, ffi_args);
+                }
+                instr.push(Instr::LiftStruct(vars, out, name));
+            }
+            AbiType::Enum(en) => {
+                let en = en.clone();
+                let tag = gen.gen_num(NumType::U32);
+                ffi_args.push(tag.clone());
+                let payload = self.payload(&en.variants, gen);
+                ffi_args.extend(payload.clone());
+                let mut variants = vec![];
+                for variant in &en.variants {
+                    let mut fields = vec![];
+                    let mut variant_ffi = vec![];
+                    let mut variant_instr = vec![];
+                    for (name, ty) in &variant.fields {
+                        let field = gen.gen(ty.clone());
+                        fields.push((name.clone(), field.clone()));
+                        self.export_arg(field, gen, &mut variant_instr, &mut variant_ffi);
+                    }
+                    variants.push((variant.name.clone(), fields, variant_ffi, variant_instr));
+                }
+                instr.push(Instr::LiftEnum(tag, payload, out, en.name, variants));
+            }
         }
     }
 
@@ -209,6 +267,28 @@ impl Abi {
                 ffi_rets.extend_from_slice(&[ptr.clone(), len.clone(), cap.clone()]);
                 instr.push(Instr::LowerVec(ret, ptr, len, cap, ty));
             }
+            AbiType::RefList(_) => {
+                unreachable!("the validator rejects returning slices of non primitive types")
+            }
+            AbiType::List(_) | AbiType::Map(_, _) | AbiType::Set(_) => {
+                let ptr = gen.gen_num(self.iptr());
+                let len = gen.gen_num(self.uptr());
+                ffi_rets.extend_from_slice(&[ptr.clone(), len.clone()]);
+                let mut elems = vec![];
+                let mut elem_ffi = vec![];
+                let mut elem_instr = vec![];
+                for ty in ret.ty.elements() {
+                    let elem = gen.gen(ty);
+                    elems.push(elem.clone());
+                    self.export_return(elem, gen, &mut elem_instr, &mut elem_ffi);
+                }
+                assert!(
+                    !elem_ffi.is_empty(),
+                    "{:?} can't be stored in a list",
+                    ret.ty
+                );
+                instr.push(Instr::LowerList(ret, ptr, len, elems, elem_ffi, elem_instr));
+            }
             AbiType::RefObject(_object) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
@@ -227,10 +307,10 @@ impl Abi {
                 self.export_return(some.clone(), gen, &mut some_instr, ffi_rets);
                 instr.push(Instr::LowerOption(ret, var, some, some_instr));
             }
-            AbiType::Result(ty) => {
+            AbiType::Result(ty, err) => {
                 let var = gen.gen_num(NumType::U8);
                 let ok = gen.gen((&**ty).clone());
-                let err = gen.gen(AbiType::String);
+                let err = gen.gen((**err).clone());
                 ffi_rets.push(var.clone());
                 let mut err_instr = vec![];
                 self.export_return(err.clone(), gen, &mut err_instr, ffi_rets);
@@ -238,16 +318,40 @@ impl Abi {
                 self.export_return(ok.clone(), gen, &mut ok_instr, ffi_rets);
                 instr.push(Instr::LowerResult(ret, var, ok, ok_instr, err, err_instr));
             }
+            AbiType::Error(AbiError { name, variants, .. })
+            | AbiType::Enum(AbiEnum { name, variants, .. }) => {
+                let name = name.clone();
+                let abi_variants = variants.clone();
+                let tag = gen.gen_num(NumType::U32);
+                ffi_rets.push(tag.clone());
+                let payload = self.payload(&abi_variants, gen);
+                ffi_rets.extend(payload.clone());
+                let mut variants = vec![];
+                for variant in abi_variants {
+                    let mut fields = vec![];
+                    let mut variant_ffi = vec![];
+                    let mut variant_instr = vec![];
+                    for (name, ty) in variant.fields {
+                        let field = gen.gen(ty);
+                        fields.push((name, field.clone()));
+                        self.export_return(field, gen, &mut variant_instr, &mut variant_ffi);
+                    }
+                    variants.push((variant.name, fields, variant_ffi, variant_instr));
+                }
+                instr.push(Instr::LowerEnum(ret, tag, payload, name, variants));
+            }
+            AbiType::Callback(_, _) => unreachable!("the validator rejects returning callbacks"),
+            AbiType::Trait(_) => unreachable!("the validator rejects returning interfaces"),
             AbiType::RefIter(ty) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
                 let ty = (&**ty).clone();
                 instr.push(Instr::LowerRefIter(ret, ptr, ty));
             }
-            AbiType::Iter(ty) => {
+            AbiType::Iter(ty, batch) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
-                let ty = (&**ty).clone();
+                let ty = AbiType::batched(ty, *batch);
                 instr.push(Instr::LowerIter(ret, ptr, ty));
             }
             AbiType::RefFuture(ty) => {
@@ -268,10 +372,10 @@ impl Abi {
                 let ty = (&**ty).clone();
                 instr.push(Instr::LowerRefStream(ret, ptr, ty));
             }
-            AbiType::Stream(ty) => {
+            AbiType::Stream(ty, batch) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
-                let ty = (&**ty).clone();
+                let ty = AbiType::batched(ty, *batch);
                 instr.push(Instr::LowerStream(ret, ptr, ty));
             }
             AbiType::Tuple(tys) => {
@@ -285,9 +389,127 @@ impl Abi {
                 instr.push(Instr::LowerTuple(ret, vars));
                 instr.extend(instr_inner);
             }
+            AbiType::Struct(st) => {
+                let mut vars = vec![];
+                let mut instr_inner = vec![];
+                for (field, ty) in st.fields.clone() {
+                    let ret = gen.gen(ty);
+                    vars.push((field, ret.clone()));
+                    self.export_return(ret, gen, &mut instr_inner, ffi_rets);
+                }
+                instr.push(Instr::LowerStruct(ret, vars));
+                instr.extend(instr_inner);
+            }
         }
     }
 
+    fn export_callback(
+        self,
+        symbol: String,
+        out: Var,
+        gen: &mut VarGen,
+        instr: &mut Vec<Instr>,
+        ffi_args: &mut Vec<Var>,
+    ) {
+        let (args, ret) = match &out.ty {
+            AbiType::Callback(args, ret) => (args.clone(), ret.as_deref().cloned()),
+            _ => unreachable!(),
+        };
+        let ptr = gen.gen_num(self.iptr());
+        let post_cobject = gen.gen_num(self.iptr());
+        let port = gen.gen_num(NumType::I64);
+        ffi_args.extend_from_slice(&[ptr.clone(), post_cobject.clone(), port.clone()]);
+        let callback = self.export_callback_fn(symbol, args, ret, gen);
+        instr.push(Instr::LiftCallback(
+            ptr,
+            post_cobject,
+            port,
+            out,
+            Box::new(callback),
+        ));
+    }
+
+    fn export_trait(
+        self,
+        out: Var,
+        gen: &mut VarGen,
+        instr: &mut Vec<Instr>,
+        ffi_args: &mut Vec<Var>,
+    ) {
+        let tr = match &out.ty {
+            AbiType::Trait(tr) => tr.clone(),
+            _ => unreachable!(),
+        };
+        let vtable: Vec<_> = tr
+            .methods
+            .iter()
+            .map(|_| gen.gen_num(self.iptr()))
+            .collect();
+        let post_cobject = gen.gen_num(self.iptr());
+        let port = gen.gen_num(NumType::I64);
+        ffi_args.extend(vtable.iter().cloned());
+        ffi_args.extend_from_slice(&[post_cobject.clone(), port.clone()]);
+        instr.push(Instr::LiftTrait(vtable, post_cobject, port, out, tr.name));
+    }
+
+    fn export_callback_fn(
+        self,
+        symbol: String,
+        args: Vec<AbiType>,
+        ret: Option<AbiType>,
+        gen: &mut VarGen,
+    ) -> Callback {
+        let mut cb_args = vec![];
+        let mut cb_ffi_args = vec![];
+        let mut lower = vec![];
+        for ty in args {
+            let arg = gen.gen(ty);
+            cb_args.push(arg.clone());
+            self.export_return(arg, gen, &mut lower, &mut cb_ffi_args);
+        }
+        if !cb_ffi_args.is_empty() {
+            lower.insert(0, Instr::DefineRets(cb_ffi_args.clone()));
+        }
+        let mut cb_ffi_rets = vec![];
+        let mut lift = vec![];
+        let ret = ret.map(|ty| {
+            let ret = gen.gen(ty);
+            self.export_arg(ret.clone(), gen, &mut lift, &mut cb_ffi_rets);
+            ret
+        });
+        assert!(
+            cb_ffi_rets.len() <= 1,
+            "the validator only allows callbacks returning a single ffi value"
+        );
+        Callback {
+            symbol,
+            args: cb_args,
+            ffi_args: cb_ffi_args,
+            lower,
+            ffi_ret: cb_ffi_rets.pop(),
+            lift,
+            ret,
+        }
+    }
+
+    /// Returns the callbacks backing the proxy of a host implemented interface.
+    pub(crate) fn export_trait_methods(self, tr: &AbiTrait) -> Vec<(String, Callback)> {
+        tr.methods
+            .iter()
+            .map(|method| {
+                let mut gen = VarGen::new();
+                let args = method.args.iter().map(|(_, ty)| ty.clone()).collect();
+                let callback = self.export_callback_fn(
+                    tr.method_symbol(method),
+                    args,
+                    method.ret.clone(),
+                    &mut gen,
+                );
+                (method.name.clone(), callback)
+            })
+            .collect()
+    }
+
     pub(crate) fn export(self, func: &AbiFunction) -> Export {
         let mut gen = VarGen::new();
         let mut ffi_args = vec![];
@@ -325,10 +547,17 @@ impl Abi {
             }
             _ => None,
         };
-        for (_, ty) in func.args.iter() {
+        for (name, ty) in func.args.iter() {
             let out = gen.gen(ty.clone());
             abi_args.push(out.clone());
-            self.export_arg(out, &mut gen, &mut instr, &mut ffi_args);
+            if let AbiType::Callback(_, _) = ty {
+                let symbol = func.callback_symbol(name);
+                self.export_callback(symbol, out, &mut gen, &mut instr, &mut ffi_args);
+            } else if let AbiType::Trait(_) = ty {
+                self.export_trait(out, &mut gen, &mut instr, &mut ffi_args);
+            } else {
+                self.export_arg(out, &mut gen, &mut instr, &mut ffi_args);
+            }
         }
         let abi_ret = func.ret.as_ref().map(|ret| gen.gen(ret.clone()));
         instr.push(Instr::CallAbi(
@@ -355,6 +584,28 @@ impl Abi {
     }
 }
 
+/// A host callback called from rust.
+///
+/// The arguments are lowered with `lower` before calling into the host and
+/// the return value is lifted with `lift` afterwards.
+#[derive(Clone, Debug)]
+pub struct Callback {
+    pub symbol: String,
+    pub args: Vec<Var>,
+    pub ffi_args: Vec<Var>,
+    pub lower: Vec<Instr>,
+    pub ffi_ret: Option<Var>,
+    pub lift: Vec<Instr>,
+    pub ret: Option<Var>,
+}
+
+/// Variant name, lifted/lowered fields, the ffi values of the fields and the
+/// instructions operating on them.
+///
+/// Only the active variant is passed, its ffi values are stored in a payload
+/// buffer like an element of a list and the buffer is freed by the receiver.
+pub type Variants = Vec<(String, Vec<(String, Var)>, Vec<Var>, Vec<Instr>)>;
+
 #[derive(Clone, Debug)]
 pub enum Instr {
     LiftNum(Var, Var),
@@ -375,6 +626,8 @@ pub enum Instr {
     LowerSlice(Var, Var, Var, NumType),
     LiftVec(Var, Var, Var, Var, NumType),
     LowerVec(Var, Var, Var, Var, NumType),
+    LiftList(Var, Var, Var, Vec<Var>, Vec<Var>, Vec<Instr>),
+    LowerList(Var, Var, Var, Vec<Var>, Vec<Var>, Vec<Instr>),
     LiftRefObject(Var, Var, String),
     LowerRefObject(Var, Var),
     LiftObject(Var, Var, String),
@@ -382,6 +635,8 @@ pub enum Instr {
     LiftOption(Var, Var, Var, Vec<Instr>),
     LowerOption(Var, Var, Var, Vec<Instr>),
     LowerResult(Var, Var, Var, Vec<Instr>, Var, Vec<Instr>),
+    LiftEnum(Var, Option<Var>, Var, String, Variants),
+    LowerEnum(Var, Var, Option<Var>, String, Variants),
     LiftIter(Var, Var, AbiType),
     LowerIter(Var, Var, AbiType),
     LiftRefIter(Var, Var, AbiType),
@@ -396,6 +651,10 @@ pub enum Instr {
     LowerRefStream(Var, Var, AbiType),
     LiftTuple(Vec<Var>, Var),
     LowerTuple(Var, Vec<Var>),
+    LiftStruct(Vec<(String, Var)>, Var, String),
+    LowerStruct(Var, Vec<(String, Var)>),
+    LiftCallback(Var, Var, Var, Var, Box<Callback>),
+    LiftTrait(Vec<Var>, Var, Var, Var, String),
     CallAbi(FunctionType, Option<Var>, String, Option<Var>, Vec<Var>),
     DefineRets(Vec<Var>),
 }
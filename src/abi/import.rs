@@ -1,5 +1,5 @@
 use super::VarGen;
-use crate::{Abi, AbiFunction, AbiType, FunctionType, NumType, Return, Var};
+use crate::{Abi, AbiFunction, AbiType, AbiVariant, FunctionType, NumType, Return, Var};
 
 #[derive(Clone, Debug)]
 pub struct Import {
@@ -122,6 +122,27 @@ impl Abi {
                 ));
                 ffi_args.extend_from_slice(&[ptr, len, cap]);
             }
+            AbiType::RefList(_) | AbiType::List(_) | AbiType::Map(_, _) | AbiType::Set(_) => {
+                let ptr = gen.gen_num(self.iptr());
+                let len = gen.gen_num(self.uptr());
+                ffi_args.extend_from_slice(&[ptr.clone(), len.clone()]);
+                let mut elems = vec![];
+                let mut elem_ffi = vec![];
+                let mut elem_instr = vec![];
+                let mut elem_cleanup = vec![];
+                for ty in arg.ty.elements() {
+                    let elem = gen.gen(ty);
+                    elems.push(elem.clone());
+                    self.import_arg(elem, gen, &mut elem_ffi, &mut elem_instr, &mut elem_cleanup);
+                }
+                assert!(
+                    !elem_ffi.is_empty(),
+                    "{:?} can't be stored in a list",
+                    arg.ty
+                );
+                assert!(elem_cleanup.is_empty());
+                instr.push(Instr::LowerList(arg, ptr, len, elems, elem_ffi, elem_instr));
+            }
             AbiType::RefObject(_) => {
                 let ptr = gen.gen_num(self.iptr());
                 instr.push(Instr::BorrowObject(arg.clone(), ptr.clone()));
@@ -137,7 +158,7 @@ impl Abi {
                 instr.push(Instr::BorrowIter(arg.clone(), ptr.clone()));
                 ffi_args.push(ptr);
             }
-            AbiType::Iter(_) => {
+            AbiType::Iter(_, _) => {
                 let ptr = gen.gen_num(self.iptr());
                 instr.push(Instr::MoveIter(arg.clone(), ptr.clone()));
                 ffi_args.push(ptr);
@@ -157,7 +178,7 @@ impl Abi {
                 instr.push(Instr::BorrowStream(arg.clone(), ptr.clone()));
                 ffi_args.push(ptr);
             }
-            AbiType::Stream(_) => {
+            AbiType::Stream(_, _) => {
                 let ptr = gen.gen_num(self.iptr());
                 instr.push(Instr::MoveStream(arg.clone(), ptr.clone()));
                 ffi_args.push(ptr);
@@ -170,8 +191,59 @@ impl Abi {
                 self.import_arg(some.clone(), gen, ffi_args, &mut some_instr, instr_cleanup);
                 instr.push(Instr::LowerOption(arg, var, some, some_instr));
             }
-            AbiType::Tuple(_) => unreachable!(),
-            AbiType::Result(_) => todo!(),
+            AbiType::Tuple(tys) => {
+                let vars: Vec<_> = tys.iter().map(|ty| gen.gen(ty.clone())).collect();
+                instr.push(Instr::LowerTuple(arg, vars.clone()));
+                for var in vars {
+                    self.import_arg(var, gen, ffi_args, instr, instr_cleanup);
+                }
+            }
+            AbiType::Struct(st) => {
+                let mut vars = vec![];
+                for (field, ty) in &st.fields {
+                    vars.push((field.clone(), gen.gen(ty.clone())));
+                }
+                instr.push(Instr::LowerStruct(arg, vars.clone()));
+                for (_, var) in vars {
+                    self.import_arg(var, gen, ffi_args, instr, instr_cleanup);
+                }
+            }
+            AbiType::Enum(en) => {
+                let name = en.name.clone();
+                let tag = gen.gen_num(NumType::U32);
+                ffi_args.push(tag.clone());
+                let payload = self.payload(&en.variants, gen);
+                ffi_args.extend(payload.clone());
+                let mut variants = vec![];
+                for variant in &en.variants {
+                    let mut fields = vec![];
+                    let mut variant_ffi = vec![];
+                    let mut variant_instr = vec![];
+                    let mut variant_cleanup = vec![];
+                    for (name, ty) in &variant.fields {
+                        let field = gen.gen(ty.clone());
+                        fields.push((name.clone(), field.clone()));
+                        self.import_arg(
+                            field,
+                            gen,
+                            &mut variant_ffi,
+                            &mut variant_instr,
+                            &mut variant_cleanup,
+                        );
+                    }
+                    assert!(variant_cleanup.is_empty());
+                    variants.push((variant.name.clone(), fields, variant_ffi, variant_instr));
+                }
+                instr.push(Instr::LowerEnum(arg, tag, payload, name, variants));
+            }
+            AbiType::Result(_, _) => unreachable!("the validator only allows returning results"),
+            AbiType::Error(_) => unreachable!("the validator only allows errors in results"),
+            AbiType::Callback(_, _) => {
+                unreachable!("the validator only allows callbacks as function arguments")
+            }
+            AbiType::Trait(_) => {
+                unreachable!("the validator only allows interfaces as function arguments")
+            }
         }
     }
 
@@ -242,7 +314,38 @@ impl Abi {
                 instr.push(Instr::LiftVec(ptr.clone(), len, out, *ty));
                 instr.push(Instr::Deallocate(ptr, cap, size, align));
             }
-            AbiType::RefObject(_) => todo!(),
+            AbiType::RefList(_) => {
+                unreachable!(
+                    "the validator rejects slices of non primitive types passed to the host"
+                )
+            }
+            AbiType::List(_) | AbiType::Map(_, _) | AbiType::Set(_) => {
+                let ptr = gen.gen_num(self.iptr());
+                let len = gen.gen_num(self.uptr());
+                ffi_rets.extend_from_slice(&[ptr.clone(), len.clone()]);
+                let mut elems = vec![];
+                let mut elem_ffi = vec![];
+                let mut elem_instr = vec![];
+                for ty in ty.elements() {
+                    let elem = gen.gen(ty.clone());
+                    elems.push(elem.clone());
+                    self.import_field(symbol, &ty, elem, gen, &mut elem_ffi, &mut elem_instr);
+                }
+                assert!(!elem_ffi.is_empty(), "{:?} can't be stored in a list", ty);
+                let size = elem_ffi.len() * 8;
+                instr.push(Instr::LiftList(
+                    ptr.clone(),
+                    len.clone(),
+                    out,
+                    elems,
+                    elem_ffi,
+                    elem_instr,
+                ));
+                instr.push(Instr::Deallocate(ptr, len, size, 8));
+            }
+            AbiType::RefObject(_) => {
+                unreachable!("the validator only allows borrowing objects in arguments")
+            }
             AbiType::Object(obj) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
@@ -255,24 +358,43 @@ impl Abi {
                 instr.push(Instr::HandleNull(var));
                 self.import_return(symbol, &**ty, out, gen, ffi_rets, instr);
             }
-            AbiType::Result(ty) => {
+            AbiType::Result(ty, err) => {
                 let var = gen.gen_num(NumType::U8);
-                let ptr = gen.gen_num(self.iptr());
-                let len = gen.gen_num(self.uptr());
-                let cap = gen.gen_num(self.uptr());
-                ffi_rets.extend_from_slice(&[var.clone(), ptr.clone(), len.clone(), cap.clone()]);
-                instr.push(Instr::HandleError(var, ptr, len, cap));
+                ffi_rets.push(var.clone());
+                if let AbiType::Error(err) = &**err {
+                    let tag = gen.gen_num(NumType::U32);
+                    ffi_rets.push(tag.clone());
+                    let payload = self.payload(&err.variants, gen);
+                    ffi_rets.extend(payload.clone());
+                    let variants = self.import_variants(symbol, &err.variants, gen);
+                    instr.push(Instr::HandleTypedError(
+                        var,
+                        tag,
+                        payload,
+                        err.name.clone(),
+                        variants,
+                    ));
+                } else {
+                    let ptr = gen.gen_num(self.iptr());
+                    let len = gen.gen_num(self.uptr());
+                    let cap = gen.gen_num(self.uptr());
+                    ffi_rets.extend_from_slice(&[ptr.clone(), len.clone(), cap.clone()]);
+                    instr.push(Instr::HandleError(var, ptr, len, cap));
+                }
                 self.import_return(symbol, &**ty, out, gen, ffi_rets, instr);
             }
-            AbiType::RefIter(_) => todo!(),
-            AbiType::Iter(_) => {
+            AbiType::Error(_) => unreachable!(),
+            AbiType::Callback(_, _) => unreachable!("the validator rejects returning callbacks"),
+            AbiType::Trait(_) => unreachable!("the validator rejects returning interfaces"),
+            AbiType::RefIter(_) => unreachable!("iterators are never borrowed"),
+            AbiType::Iter(_, batch) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
                 let next = format!("{}_iter_next", symbol);
                 let destructor = format!("{}_iter_drop", symbol);
-                instr.push(Instr::LiftIter(ptr, next, destructor, out));
+                instr.push(Instr::LiftIter(ptr, next, destructor, out, batch.is_some()));
             }
-            AbiType::RefFuture(_) => todo!(),
+            AbiType::RefFuture(_) => unreachable!("futures are never borrowed"),
             AbiType::Future(_) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
@@ -280,23 +402,192 @@ impl Abi {
                 let destructor = format!("{}_future_drop", symbol);
                 instr.push(Instr::LiftFuture(ptr, poll, destructor, out));
             }
-            AbiType::RefStream(_) => todo!(),
-            AbiType::Stream(_) => {
+            AbiType::RefStream(_) => unreachable!("streams are never borrowed"),
+            AbiType::Stream(_, batch) => {
                 let ptr = gen.gen_num(self.iptr());
                 ffi_rets.push(ptr.clone());
                 let poll = format!("{}_stream_poll", symbol);
                 let destructor = format!("{}_stream_drop", symbol);
-                instr.push(Instr::LiftStream(ptr, poll, destructor, out));
+                instr.push(Instr::LiftStream(
+                    ptr,
+                    poll,
+                    destructor,
+                    out,
+                    batch.is_some(),
+                ));
             }
             AbiType::Tuple(tys) => {
                 let mut vars = vec![];
                 for ty in tys {
                     let out = gen.gen(ty.clone());
                     vars.push(out.clone());
-                    self.import_return(symbol, ty, out, gen, ffi_rets, instr);
+                    self.import_field(symbol, ty, out, gen, ffi_rets, instr);
                 }
                 instr.push(Instr::LiftTuple(vars, out));
             }
+            AbiType::Struct(st) => {
+                let mut vars = vec![];
+                for (field, ty) in &st.fields {
+                    let out = gen.gen(ty.clone());
+                    vars.push((field.clone(), out.clone()));
+                    self.import_field(symbol, ty, out, gen, ffi_rets, instr);
+                }
+                instr.push(Instr::LiftStruct(st.name.clone(), vars, out));
+            }
+            AbiType::Enum(en) => {
+                let tag = gen.gen_num(NumType::U32);
+                ffi_rets.push(tag.clone());
+                let payload = self.payload(&en.variants, gen);
+                ffi_rets.extend(payload.clone());
+                let variants = self.import_variants(symbol, &en.variants, gen);
+                instr.push(Instr::LiftEnum(
+                    tag,
+                    payload,
+                    en.name.clone(),
+                    variants,
+                    out,
+                ));
+            }
+        }
+    }
+
+    /// Lifts the fields of each variant from the ffi values in its payload.
+    fn import_variants(self, symbol: &str, variants: &[AbiVariant], gen: &mut VarGen) -> Variants {
+        let mut lifted = vec![];
+        for variant in variants {
+            let mut fields = vec![];
+            let mut variant_ffi = vec![];
+            let mut variant_instr = vec![];
+            for (name, ty) in &variant.fields {
+                let field = gen.gen(ty.clone());
+                fields.push((name.clone(), field.clone()));
+                self.import_field(symbol, ty, field, gen, &mut variant_ffi, &mut variant_instr);
+            }
+            lifted.push((variant.name.clone(), fields, variant_ffi, variant_instr));
+        }
+        lifted
+    }
+
+    /// Lifts a value nested inside of another value.
+    ///
+    /// Unlike a returned option, a nested option can't return early.
+    fn import_field(
+        self,
+        symbol: &str,
+        ty: &AbiType,
+        out: Var,
+        gen: &mut VarGen,
+        ffi_rets: &mut Vec<Var>,
+        instr: &mut Vec<Instr>,
+    ) {
+        if let AbiType::Option(ty) = ty {
+            let var = gen.gen_num(NumType::U8);
+            ffi_rets.push(var.clone());
+            let some = gen.gen((**ty).clone());
+            let mut some_instr = vec![];
+            self.import_field(symbol, ty, some.clone(), gen, ffi_rets, &mut some_instr);
+            instr.push(Instr::LiftOption(var, some, some_instr, out));
+        } else {
+            self.import_return(symbol, ty, out, gen, ffi_rets, instr);
+        }
+    }
+
+    fn import_callback(
+        self,
+        symbol: &str,
+        arg: Var,
+        gen: &mut VarGen,
+        ffi_args: &mut Vec<Var>,
+        instr: &mut Vec<Instr>,
+    ) {
+        let (args, ret) = match &arg.ty {
+            AbiType::Callback(args, ret) => (args.clone(), ret.as_deref().cloned()),
+            _ => unreachable!(),
+        };
+        let ptr = gen.gen_num(self.iptr());
+        let post_cobject = gen.gen_num(self.iptr());
+        let port = gen.gen_num(NumType::I64);
+        ffi_args.extend_from_slice(&[ptr.clone(), post_cobject.clone(), port.clone()]);
+        let callback = self.import_callback_fn(symbol, args, ret, gen);
+        instr.push(Instr::LowerCallback(
+            arg,
+            ptr,
+            post_cobject,
+            port,
+            Box::new(callback),
+        ));
+    }
+
+    fn import_trait(
+        self,
+        arg: Var,
+        gen: &mut VarGen,
+        ffi_args: &mut Vec<Var>,
+        instr: &mut Vec<Instr>,
+    ) {
+        let tr = match &arg.ty {
+            AbiType::Trait(tr) => tr.clone(),
+            _ => unreachable!(),
+        };
+        let vtable: Vec<_> = tr
+            .methods
+            .iter()
+            .map(|_| gen.gen_num(self.iptr()))
+            .collect();
+        let post_cobject = gen.gen_num(self.iptr());
+        let port = gen.gen_num(NumType::I64);
+        ffi_args.extend(vtable.iter().cloned());
+        ffi_args.extend_from_slice(&[post_cobject.clone(), port.clone()]);
+        let methods = tr
+            .methods
+            .iter()
+            .map(|method| {
+                let symbol = tr.method_symbol(method);
+                let args = method.args.iter().map(|(_, ty)| ty.clone()).collect();
+                let callback = self.import_callback_fn(&symbol, args, method.ret.clone(), gen);
+                (method.name.clone(), callback)
+            })
+            .collect();
+        instr.push(Instr::LowerTrait(arg, vtable, post_cobject, port, methods));
+    }
+
+    fn import_callback_fn(
+        self,
+        symbol: &str,
+        args: Vec<AbiType>,
+        ret: Option<AbiType>,
+        gen: &mut VarGen,
+    ) -> Callback {
+        let mut cb_args = vec![];
+        let mut cb_ffi_args = vec![];
+        if let Abi::Native32 | Abi::Native64 = self {
+            cb_ffi_args.push(gen.gen_num(NumType::I64));
+        }
+        let mut lift = vec![];
+        for ty in args {
+            let out = gen.gen(ty.clone());
+            cb_args.push(out.clone());
+            self.import_return(symbol, &ty, out, gen, &mut cb_ffi_args, &mut lift);
+        }
+        let mut cb_ffi_rets = vec![];
+        let mut lower = vec![];
+        let ret = ret.map(|ty| {
+            let ret = gen.gen(ty);
+            let mut cleanup = vec![];
+            self.import_arg(ret.clone(), gen, &mut cb_ffi_rets, &mut lower, &mut cleanup);
+            ret
+        });
+        if !cb_ffi_rets.is_empty() {
+            lower.insert(0, Instr::DefineArgs(cb_ffi_rets.clone()));
+        }
+        Callback {
+            symbol: symbol.to_string(),
+            ffi_args: cb_ffi_args,
+            lift,
+            args: cb_args,
+            ret,
+            lower,
+            ffi_ret: cb_ffi_rets.pop(),
         }
     }
 
@@ -335,20 +626,27 @@ impl Abi {
         for (name, ty) in abi_args.iter() {
             let arg = gen.gen(ty.clone());
             instr.push(Instr::BindArg(name.clone(), arg.clone()));
-            self.import_arg(
-                arg,
-                &mut gen,
-                &mut ffi_args,
-                &mut instr_arg,
-                &mut instr_cleanup,
-            );
+            if let AbiType::Callback(_, _) = ty {
+                let symbol = func.callback_symbol(name);
+                self.import_callback(&symbol, arg, &mut gen, &mut ffi_args, &mut instr_arg);
+            } else if let AbiType::Trait(_) = ty {
+                self.import_trait(arg, &mut gen, &mut ffi_args, &mut instr_arg);
+            } else {
+                self.import_arg(
+                    arg,
+                    &mut gen,
+                    &mut ffi_args,
+                    &mut instr_arg,
+                    &mut instr_cleanup,
+                );
+            }
         }
         if !ffi_args.is_empty() {
             instr.push(Instr::DefineArgs(ffi_args.clone()));
         }
         instr.extend(instr_arg);
         let abi_ret = match func.ret.as_ref() {
-            Some(AbiType::Option(inner)) | Some(AbiType::Result(inner)) => match &**inner {
+            Some(AbiType::Option(inner)) | Some(AbiType::Result(inner, _)) => match &**inner {
                 AbiType::Tuple(tuple) if tuple.is_empty() => None,
                 _ => func.ret.clone(),
             },
@@ -391,6 +689,27 @@ impl Abi {
     }
 }
 
+/// A host callback called from rust.
+///
+/// The arguments passed by rust are lifted with `lift` and the return value
+/// is lowered with `lower`.
+#[derive(Clone, Debug)]
+pub struct Callback {
+    pub symbol: String,
+    /// On native targets the first argument is the port the callback was passed with, so
+    /// hosts that can't pass closures as function pointers can look up the callback.
+    pub ffi_args: Vec<Var>,
+    pub lift: Vec<Instr>,
+    pub args: Vec<Var>,
+    pub ret: Option<Var>,
+    pub lower: Vec<Instr>,
+    pub ffi_ret: Option<Var>,
+}
+
+/// Variant name, lifted/lowered fields, the ffi values in the payload of the
+/// variant and the instructions operating on them.
+pub type Variants = Vec<(String, Vec<(String, Var)>, Vec<Var>, Vec<Instr>)>;
+
 #[derive(Clone, Debug)]
 pub enum Instr {
     BindArg(String, Var),
@@ -405,23 +724,36 @@ pub enum Instr {
     LowerString(Var, Var, Var, Var, usize, usize),
     LiftVec(Var, Var, Var, NumType),
     LowerVec(Var, Var, Var, Var, NumType, usize, usize),
+    LiftList(Var, Var, Var, Vec<Var>, Vec<Var>, Vec<Instr>),
+    LowerList(Var, Var, Var, Vec<Var>, Vec<Var>, Vec<Instr>),
     HandleNull(Var),
+    LiftOption(Var, Var, Vec<Instr>, Var),
     LowerOption(Var, Var, Var, Vec<Instr>),
     HandleError(Var, Var, Var, Var),
+    HandleTypedError(Var, Var, Option<Var>, String, Variants),
     BorrowSelf(Var),
     BorrowObject(Var, Var),
     MoveObject(Var, Var),
     LiftObject(String, Var, String, Var),
     BorrowIter(Var, Var),
     MoveIter(Var, Var),
-    LiftIter(Var, String, String, Var),
+    /// Lifts an iterator, the last field is set if `next` returns a list of items.
+    LiftIter(Var, String, String, Var, bool),
     BorrowFuture(Var, Var),
     MoveFuture(Var, Var),
     LiftFuture(Var, String, String, Var),
     BorrowStream(Var, Var),
     MoveStream(Var, Var),
-    LiftStream(Var, String, String, Var),
+    /// Lifts a stream, the last field is set if `poll` returns a list of items.
+    LiftStream(Var, String, String, Var, bool),
+    LowerTuple(Var, Vec<Var>),
     LiftTuple(Vec<Var>, Var),
+    LowerStruct(Var, Vec<(String, Var)>),
+    LiftStruct(String, Vec<(String, Var)>, Var),
+    LowerEnum(Var, Var, Option<Var>, String, Variants),
+    LiftEnum(Var, Option<Var>, String, Variants, Var),
+    LowerCallback(Var, Var, Var, Var, Box<Callback>),
+    LowerTrait(Var, Vec<Var>, Var, Var, Vec<(String, Callback)>),
     DefineArgs(Vec<Var>),
     Call(String, Option<Var>, Vec<Var>),
     BindRets(Var, Vec<Var>),
@@ -0,0 +1,267 @@
+use crate::export::Export;
+use crate::{Abi, AbiFunction, AbiObject, AbiType, Interface, NumType, Return};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+
+/// Generates a C header describing the symbols exported by the rust api.
+pub struct CHeaderGenerator {
+    abi: Abi,
+}
+
+impl Default for CHeaderGenerator {
+    fn default() -> Self {
+        Self { abi: Abi::native() }
+    }
+}
+
+impl CHeaderGenerator {
+    pub fn generate(&self, iface: Interface) -> Tokens {
+        quote! {
+            #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("//"))
+            #(static_literal("//")) Generated by "ffi-gen".
+
+            #(self.generate_doc(&iface.doc))
+            #(static_literal("#pragma")) once
+
+            #(static_literal("#include")) <stddef.h>
+            #(static_literal("#include")) <stdint.h>
+
+            #(static_literal("#ifdef")) __cplusplus
+            extern "C" {
+            #(static_literal("#endif"))
+
+            #(for func in self.functions(&iface) join (#<line>) => #(self.generate_return_struct(&self.abi.export(&func))))
+
+            #(self.generate_doc(&[
+                "Allocates `size` bytes aligned to `align`, which are owned by rust once",
+                "they are passed to a function.",
+            ]))
+            uint8_t *allocate(size_t size, size_t align);
+
+            #(self.generate_doc(&[
+                "Frees a buffer returned by rust, `size` and `align` need to match the",
+                "capacity and alignment of the buffer.",
+            ]))
+            void deallocate(uint8_t *ptr, size_t size, size_t align);
+
+            #(self.generate_doc(&[
+                "Runs the calls queued for the callback or interface passed with `port` on",
+                "the calling thread. Returns 1 once rust dropped it.",
+            ]))
+            uint8_t __ffi_dispatch(int64_t port);
+
+            #(for func in iface.functions() join (#<push>) => #(self.generate_function(&func, &[])))
+
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for iter in iface.iterators() join (#<line>) =>
+                #(self.generate_function(&iter.next(), &[
+                    format!("Returns the next item of an iterator returned by `{}`.", iter.symbol),
+                    "".to_string(),
+                    "The first return value is zero once the iterator is exhausted.".to_string(),
+                ]))
+                #(self.generate_destructor(&format!("{}_iter_drop", iter.symbol), &format!("an iterator returned by `{}`", iter.symbol)))
+            )
+
+            #(for fut in iface.futures() join (#<line>) =>
+                #(self.generate_function(&fut.poll(), &[
+                    format!("Polls a future returned by `{}`.", fut.symbol),
+                    "".to_string(),
+                    "The first return value is zero while the future is pending, `post_cobject`".to_string(),
+                    "is called with `port` when it should be polled again.".to_string(),
+                ]))
+                #(self.generate_destructor(&format!("{}_future_drop", fut.symbol), &format!("a future returned by `{}`", fut.symbol)))
+            )
+
+            #(for stream in iface.streams() join (#<line>) =>
+                #(self.generate_function(&stream.poll(), &[
+                    format!("Polls a stream returned by `{}`.", stream.symbol),
+                    "".to_string(),
+                    "The first return value is zero when no item is ready, `post_cobject` is".to_string(),
+                    "called with `port` when it should be polled again and with `done` when".to_string(),
+                    "the stream is exhausted.".to_string(),
+                ]))
+                #(self.generate_destructor(&format!("{}_stream_drop", stream.symbol), &format!("a stream returned by `{}`", stream.symbol)))
+            )
+
+            #(static_literal("#ifdef")) __cplusplus
+            }
+            #(static_literal("#endif"))
+        }
+    }
+
+    /// All functions exported by the rust api.
+    fn functions(&self, iface: &Interface) -> Vec<AbiFunction> {
+        let mut functions = iface.functions();
+        for obj in iface.objects() {
+            functions.extend(obj.methods);
+        }
+        functions.extend(iface.iterators().iter().map(|iter| iter.next()));
+        functions.extend(iface.futures().iter().map(|fut| fut.poll()));
+        functions.extend(iface.streams().iter().map(|stream| stream.poll()));
+        functions
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> Tokens {
+        quote! {
+            #(for method in &obj.methods join (#<push>) => #(self.generate_function(method, &[])))
+            #(self.generate_destructor(&obj.destructor, &format!("a `{}`", obj.name)))
+        }
+    }
+
+    fn generate_destructor(&self, symbol: &str, what: &str) -> Tokens {
+        let iptr = self.generate_num_type(self.abi.iptr());
+        quote! {
+            #(self.generate_doc(&[format!("Drops {}, the first argument is unused.", what)]))
+            void #symbol(#(&iptr) _, #(&iptr) boxed);
+        }
+    }
+
+    fn generate_function(&self, func: &AbiFunction, doc: &[String]) -> Tokens {
+        let ffi = self.abi.export(func);
+        let ret = match &ffi.ffi_ret {
+            Return::Void => quote!(void),
+            Return::Num(var) => self.generate_num_type(var.ty.num()),
+            Return::Struct(_, name) => quote!(#name),
+        };
+        let names = self.arg_names(func, ffi.ffi_args.len());
+        let args = if ffi.ffi_args.is_empty() {
+            quote!(void)
+        } else {
+            quote!(#(for (name, var) in names.iter().zip(&ffi.ffi_args) join (, ) =>
+                #(self.generate_num_type(var.ty.num())) #name))
+        };
+        let doc = if doc.is_empty() { &func.doc } else { doc };
+        quote! {
+            #(self.generate_doc(doc))
+            #ret #(&ffi.symbol)(#args);
+        }
+    }
+
+    /// Names the arguments after the function arguments when each of them is
+    /// passed as a single number, otherwise they are numbered.
+    fn arg_names(&self, func: &AbiFunction, len: usize) -> Vec<String> {
+        let is_num = |ty: &AbiType| {
+            matches!(
+                ty,
+                AbiType::Num(_) | AbiType::Isize | AbiType::Usize | AbiType::Bool
+            )
+        };
+        let mut names = vec![];
+        if len == func.args.len() + 1 {
+            names.push("boxed".to_string());
+        }
+        if len == names.len() + func.args.len() && func.args.iter().all(|(_, ty)| is_num(ty)) {
+            names.extend(func.args.iter().map(|(name, _)| name.clone()));
+            names
+        } else {
+            (0..len).map(|i| format!("arg{}", i)).collect()
+        }
+    }
+
+    fn generate_return_struct(&self, ffi: &Export) -> Tokens {
+        if let Return::Struct(vars, name) = &ffi.ffi_ret {
+            quote! {
+                #(self.generate_doc(&[format!("Return values of `{}`.", ffi.symbol)]))
+                typedef struct #name {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) =>
+                        #(self.generate_num_type(var.ty.num())) #(format!("ret{}", i));)
+                } #name;
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    fn generate_num_type(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 => quote!(int8_t),
+            NumType::I16 => quote!(int16_t),
+            NumType::I32 => quote!(int32_t),
+            NumType::I64 => quote!(int64_t),
+            NumType::U8 => quote!(uint8_t),
+            NumType::U16 => quote!(uint16_t),
+            NumType::U32 => quote!(uint32_t),
+            NumType::U64 => quote!(uint64_t),
+            NumType::F32 => quote!(float),
+            NumType::F64 => quote!(double),
+        }
+    }
+
+    fn generate_doc<S: AsRef<str>>(&self, doc: &[S]) -> Tokens {
+        quote!(#(for line in doc => #(static_literal("///")) #(line.as_ref()) #<push>))
+    }
+}
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, c: Tokens) -> Result<()> {
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native());
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let header_tokens = CHeaderGenerator::default().generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let bin_tokens: Tokens = quote! {
+            #(static_literal("#include")) <assert.h>
+            #(static_literal("#include")) <string.h>
+            #(static_literal("#include")) "compile_pass.h"
+
+            int main(void) {
+                #c
+                return 0;
+            }
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        let header_file = dir.as_ref().join("compile_pass.h");
+        std::fs::write(&header_file, header_tokens.to_file_string()?)?;
+        let c_file = dir.as_ref().join("main.c");
+        std::fs::write(&c_file, bin_tokens.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let bin_file = dir.as_ref().join("main");
+        let ret = Command::new("cc")
+            .arg("-Wall")
+            .arg("-Werror")
+            .arg("-o")
+            .arg(&bin_file)
+            .arg(&c_file)
+            .arg("-L")
+            .arg(dir.as_ref())
+            .arg("-lcompile_pass")
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile c program");
+        let ret = Command::new(&bin_file)
+            .env("LD_LIBRARY_PATH", dir.as_ref())
+            .status()?;
+        anyhow::ensure!(ret.success(), "c test failed");
+        Ok(())
+    }
+}
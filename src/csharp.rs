@@ -0,0 +1,1324 @@
+use crate::import::{Callback, Import, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Return, Var,
+};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+use heck::*;
+use std::collections::BTreeMap;
+
+/// Generates c# bindings, which call the cdylib through p/invoke.
+pub struct CSharpGenerator {
+    abi: Abi,
+    namespace: String,
+    cdylib_name: String,
+}
+
+impl CSharpGenerator {
+    pub fn new(namespace: String, cdylib_name: String) -> Self {
+        Self {
+            abi: Abi::native(),
+            namespace,
+            cdylib_name,
+        }
+    }
+
+    pub fn generate(&self, iface: Interface) -> Tokens {
+        let imports = iface.imports(&self.abi);
+        quote! {
+            #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("//"))
+            #(static_literal("//")) Generated by "ffi-gen".
+            #(static_literal("#nullable enable"))
+
+            using System;
+            using System.Collections;
+            using System.Collections.Concurrent;
+            using System.Collections.Generic;
+            using System.Runtime.CompilerServices;
+            using System.Runtime.InteropServices;
+            using System.Text;
+            using System.Threading;
+            using System.Threading.Tasks;
+
+            namespace #(&self.namespace);
+
+            #(self.generate_doc(&iface.doc))
+            public static class Api
+            {
+                #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+
+                #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+
+                #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+
+                #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+            }
+
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
+
+            #(self.generate_doc(&["Exception thrown by a rust function returning an error string."]))
+            public sealed class FfiException : Exception
+            {
+                public FfiException(string message) : base(message) { }
+            }
+
+            #(self.generate_doc(&["Owns a value boxed by rust, which is dropped once the handle is disposed or finalized."]))
+            public abstract class FfiHandle : SafeHandle
+            {
+                internal FfiHandle(long ptr) : base(IntPtr.Zero, true)
+                {
+                    SetHandle((IntPtr)ptr);
+                }
+
+                public override bool IsInvalid => handle == IntPtr.Zero;
+
+                internal long Borrow()
+                {
+                    if (IsClosed)
+                    {
+                        throw new ObjectDisposedException(GetType().Name);
+                    }
+                    return (long)handle;
+                }
+
+                #(self.generate_doc(&["Transfers ownership to rust, the handle is closed without dropping the value."]))
+                internal long Move()
+                {
+                    var ptr = Borrow();
+                    SetHandleAsInvalid();
+                    return ptr;
+                }
+
+                protected override bool ReleaseHandle()
+                {
+                    Drop((long)handle);
+                    return true;
+                }
+
+                private protected abstract void Drop(long ptr);
+            }
+
+            internal sealed class FfiBox : FfiHandle
+            {
+                private readonly Action<long> drop;
+
+                internal FfiBox(long ptr, Action<long> drop) : base(ptr)
+                {
+                    this.drop = drop;
+                }
+
+                private protected override void Drop(long ptr) => drop(ptr);
+            }
+
+            #(self.generate_doc(&["Returned by polling functions when no value is ready."]))
+            internal sealed class FfiPending
+            {
+                internal static readonly FfiPending Instance = new();
+
+                private FfiPending() { }
+            }
+
+            #(self.generate_doc(&["Iterates over the items of a rust iterator, disposing it drops the iterator."]))
+            public sealed class Iter<T> : IEnumerable<T>, IEnumerator<T>
+            {
+                internal readonly FfiBox Box;
+                private readonly Func<long, object?> next;
+                private readonly bool batched;
+                private IEnumerator<T>? items;
+                private T current = default!;
+
+                internal Iter(FfiBox box, Func<long, object?> next, bool batched = false)
+                {
+                    Box = box;
+                    this.next = next;
+                    this.batched = batched;
+                }
+
+                public T Current => current;
+
+                object? IEnumerator.Current => current;
+
+                public bool MoveNext()
+                {
+                    if (items != null && items.MoveNext())
+                    {
+                        current = items.Current;
+                        return true;
+                    }
+                    var ret = next(Box.Borrow());
+                    if (ret is FfiPending)
+                    {
+                        return false;
+                    }
+                    if (batched)
+                    {
+                        items = ((List<T>)ret!).GetEnumerator();
+                        return MoveNext();
+                    }
+                    current = (T)ret!;
+                    return true;
+                }
+
+                public void Reset() => throw new NotSupportedException();
+
+                public void Dispose() => Box.Dispose();
+
+                public IEnumerator<T> GetEnumerator() => this;
+
+                IEnumerator IEnumerable.GetEnumerator() => this;
+            }
+
+            #(self.generate_doc(&["Signals the task polling a future or stream."]))
+            internal sealed class FfiWakes
+            {
+                private readonly SemaphoreSlim signal = new(0, 1);
+                private volatile bool done;
+
+                internal bool Done => done;
+
+                internal void Wake()
+                {
+                    try
+                    {
+                        signal.Release();
+                    }
+                    catch (SemaphoreFullException)
+                    {
+                    }
+                }
+
+                internal void Finish()
+                {
+                    done = true;
+                    Wake();
+                }
+
+                internal Task WaitAsync(CancellationToken cancellationToken) => signal.WaitAsync(cancellationToken);
+            }
+
+            #(self.generate_doc(&[
+                "Maps the ports passed to rust to the actions run when they are notified.",
+                "",
+                "`PostCObjectAddress` is the function pointer of a delegate held by this class, so the",
+                "garbage collector keeps it alive. The action registered for the port runs on the",
+                "thread rust notifies it from. Delegates passed to rust are stored until their port is",
+                "notified.",
+            ]))
+            internal static class FfiPorts
+            {
+                [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
+                private delegate void FfiPostCObject(long port, IntPtr message);
+
+                private static long nextPort;
+                private static readonly ConcurrentDictionary<long, Action> callbacks = new();
+                private static readonly ConcurrentDictionary<long, object[]> alive = new();
+                private static readonly FfiPostCObject postCObject = (port, message) =>
+                {
+                    if (callbacks.TryGetValue(port, out var callback))
+                    {
+                        callback();
+                    }
+                };
+
+                internal static readonly long PostCObjectAddress = (long)Marshal.GetFunctionPointerForDelegate(postCObject);
+
+                internal static long Open(Action callback)
+                {
+                    var port = Interlocked.Increment(ref nextPort);
+                    callbacks[port] = callback;
+                    return port;
+                }
+
+                internal static void Close(long port)
+                {
+                    callbacks.TryRemove(port, out _);
+                    alive.TryRemove(port, out _);
+                }
+
+                #(self.generate_doc(&[
+                    "Keeps the delegates passed to rust alive until rust drops them.",
+                    "",
+                    "Notifying the returned port runs the calls rust queued for them.",
+                ]))
+                internal static long KeepAlive(params object[] objects)
+                {
+                    var port = Interlocked.Increment(ref nextPort);
+                    alive[port] = objects;
+                    callbacks[port] = () =>
+                    {
+                        if (Native.__ffi_dispatch(port) != 0)
+                        {
+                            Close(port);
+                        }
+                    };
+                    return port;
+                }
+            }
+
+            internal static class Ffi
+            {
+                internal static long Allocate(ulong size, ulong align) => (long)Native.allocate((nuint)size, (nuint)align);
+
+                internal static void Deallocate(long ptr, ulong size, ulong align) => Native.deallocate((IntPtr)ptr, (nuint)size, (nuint)align);
+
+                internal static string ReadString(long ptr, int len) => len == 0 ? "" : Marshal.PtrToStringUTF8((IntPtr)ptr, len)!;
+
+                internal static List<T> ReadVec<T>(long ptr, int len) where T : unmanaged
+                {
+                    var bytes = new byte[len * Unsafe.SizeOf<T>()];
+                    if (bytes.Length > 0)
+                    {
+                        Marshal.Copy((IntPtr)ptr, bytes, 0, bytes.Length);
+                    }
+                    return new List<T>(MemoryMarshal.Cast<byte, T>(bytes).ToArray());
+                }
+
+                internal static void Write<T>(long ptr, T[] values) where T : unmanaged
+                {
+                    var bytes = MemoryMarshal.AsBytes(values.AsSpan()).ToArray();
+                    if (bytes.Length > 0)
+                    {
+                        Marshal.Copy(bytes, 0, (IntPtr)ptr, bytes.Length);
+                    }
+                }
+
+                #(self.generate_doc(&["Reads the 8 byte slot `idx` of a list."]))
+                internal static T Load<T>(long ptr, long idx) where T : unmanaged
+                {
+                    var bytes = new byte[Unsafe.SizeOf<T>()];
+                    Marshal.Copy((IntPtr)(ptr + idx * 8), bytes, 0, bytes.Length);
+                    return MemoryMarshal.Read<T>(bytes);
+                }
+
+                #(self.generate_doc(&["Writes the 8 byte slot `idx` of a list."]))
+                internal static void Store<T>(long ptr, long idx, T value) where T : unmanaged
+                {
+                    var bytes = MemoryMarshal.AsBytes(MemoryMarshal.CreateSpan(ref value, 1)).ToArray();
+                    Marshal.Copy(bytes, 0, (IntPtr)(ptr + idx * 8), bytes.Length);
+                }
+
+                #(self.generate_doc(&["Polls a rust future until it is ready."]))
+                internal static async Task<T> Future<T>(FfiBox box, Func<long, long, long, object?> poll)
+                {
+                    var wakes = new FfiWakes();
+                    var port = FfiPorts.Open(wakes.Wake);
+                    try
+                    {
+                        while (true)
+                        {
+                            var ret = poll(box.Borrow(), FfiPorts.PostCObjectAddress, port);
+                            if (ret is not FfiPending)
+                            {
+                                return (T)ret!;
+                            }
+                            await wakes.WaitAsync(CancellationToken.None);
+                        }
+                    }
+                    finally
+                    {
+                        FfiPorts.Close(port);
+                        box.Dispose();
+                    }
+                }
+
+                #(self.generate_doc(&["Emits the items of a rust stream, it can only be enumerated once."]))
+                internal static async IAsyncEnumerable<T> Stream<T>(
+                    FfiBox box,
+                    Func<long, long, long, long, object?> poll,
+                    bool batched = false,
+                    [EnumeratorCancellation] CancellationToken cancellationToken = default)
+                {
+                    var wakes = new FfiWakes();
+                    var port = FfiPorts.Open(wakes.Wake);
+                    var done = FfiPorts.Open(wakes.Finish);
+                    try
+                    {
+                        while (!wakes.Done)
+                        {
+                            var ret = poll(box.Borrow(), FfiPorts.PostCObjectAddress, port, done);
+                            if (ret is not FfiPending && batched)
+                            {
+                                foreach (var item in (List<T>)ret!)
+                                {
+                                    yield return item;
+                                }
+                            }
+                            else if (ret is not FfiPending)
+                            {
+                                yield return (T)ret!;
+                            }
+                            else if (!wakes.Done)
+                            {
+                                await wakes.WaitAsync(cancellationToken);
+                            }
+                        }
+                    }
+                    finally
+                    {
+                        FfiPorts.Close(port);
+                        FfiPorts.Close(done);
+                        box.Dispose();
+                    }
+                }
+            }
+
+            internal static class Native
+            {
+                private const string Lib = #(quoted(&self.cdylib_name));
+
+                [DllImport(Lib)]
+                internal static extern IntPtr allocate(nuint size, nuint align);
+
+                [DllImport(Lib)]
+                internal static extern void deallocate(IntPtr ptr, nuint size, nuint align);
+
+                [DllImport(Lib)]
+                internal static extern byte __ffi_dispatch(long port);
+
+                #(for func in &imports join (#<line>) => #(self.generate_native_function(func)))
+
+                #(for drop in self.destructors(&iface) join (#<line>) =>
+                    [DllImport(Lib)]
+                    internal static extern void #drop(long unused, long boxed);
+                )
+            }
+
+            #(for (name, cb) in self.callbacks(&imports) join (#<line>) =>
+                [UnmanagedFunctionPointer(CallingConvention.Cdecl)]
+                internal delegate #(self.generate_native_return(&cb.ffi_ret)) #name(#(for (i, var) in cb.ffi_args.iter().enumerate() join (, ) => #(self.generate_num_type(var.ty.num())) #(format!("arg{}", i))));
+            )
+
+            #(for func in &imports => #(self.generate_return_struct(&func.ffi_ret)))
+        }
+    }
+
+    /// Symbols dropping the boxes returned by rust.
+    fn destructors(&self, iface: &Interface) -> Vec<String> {
+        let mut destructors = vec![];
+        destructors.extend(iface.objects().into_iter().map(|obj| obj.destructor));
+        destructors.extend(
+            iface
+                .iterators()
+                .iter()
+                .map(|iter| format!("{}_iter_drop", iter.symbol)),
+        );
+        destructors.extend(
+            iface
+                .futures()
+                .iter()
+                .map(|fut| format!("{}_future_drop", fut.symbol)),
+        );
+        destructors.extend(
+            iface
+                .streams()
+                .iter()
+                .map(|stream| format!("{}_stream_drop", stream.symbol)),
+        );
+        destructors
+    }
+
+    /// Unmanaged delegates by name, one for each callback signature.
+    fn callbacks<'a>(&self, imports: &'a [Import]) -> BTreeMap<String, &'a Callback> {
+        let mut callbacks = BTreeMap::new();
+        for func in imports {
+            for instr in &func.instr {
+                match instr {
+                    Instr::LowerCallback(_, _, _, _, cb) => {
+                        callbacks.insert(self.callback_ident(cb), &**cb);
+                    }
+                    Instr::LowerTrait(_, _, _, _, methods) => {
+                        for (_, cb) in methods {
+                            callbacks.insert(self.callback_ident(cb), cb);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        callbacks
+    }
+
+    fn callback_ident(&self, cb: &Callback) -> String {
+        let mut name = "FfiCallback".to_string();
+        for var in &cb.ffi_args {
+            name.push_str(&self.num_name(var.ty.num()));
+        }
+        name.push_str("To");
+        match &cb.ffi_ret {
+            Some(ret) => name.push_str(&self.num_name(ret.ty.num())),
+            None => name.push_str("Void"),
+        }
+        name
+    }
+
+    fn generate_native_return(&self, ret: &Option<Var>) -> Tokens {
+        match ret {
+            Some(var) => self.generate_num_type(var.ty.num()),
+            None => quote!(void),
+        }
+    }
+
+    fn generate_native_function(&self, func: &Import) -> Tokens {
+        let ret = match &func.ffi_ret {
+            Return::Void => quote!(void),
+            Return::Num(var) => self.generate_num_type(var.ty.num()),
+            Return::Struct(_, name) => quote!(#name),
+        };
+        quote! {
+            [DllImport(Lib)]
+            internal static extern #ret #(&func.symbol)(#(for (i, var) in func.ffi_args.iter().enumerate() join (, ) => #(self.generate_num_type(var.ty.num())) #(format!("arg{}", i))));
+        }
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> Tokens {
+        let name = self.type_ident(&obj.name);
+        quote! {
+            #(self.generate_doc(&obj.doc))
+            public sealed class #(&name) : FfiHandle
+            {
+                internal #(&name)(long ptr) : base(ptr) { }
+
+                #(for func in &obj.methods join (#<line>) => #(self.generate_function(func)))
+
+                private protected override void Drop(long ptr) => Native.#(&obj.destructor)(0, ptr);
+            }
+        }
+    }
+
+    fn generate_trait(&self, tr: AbiTrait) -> Tokens {
+        quote! {
+            #(self.generate_doc(&tr.doc))
+            public interface #(self.trait_ident(&tr.name))
+            {
+                #(for method in &tr.methods join (#<line>) =>
+                    #(self.generate_doc(&method.doc))
+                    #(match &method.ret {
+                        Some(ret) => #(self.generate_type(ret)),
+                        None => void,
+                    }) #(self.member_ident(&method.name))(#(for (name, ty) in &method.args join (, ) => #(self.generate_type(ty)) #(self.ident(name))));
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> Tokens {
+        quote! {
+            #(self.generate_doc(&st.doc))
+            public readonly record struct #(self.type_ident(&st.name))(#(self.generate_fields(&st.fields)));
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> Tokens {
+        if en.is_c_like() {
+            return quote! {
+                #(self.generate_doc(&en.doc))
+                public enum #(self.type_ident(&en.name)) : uint
+                {
+                    #(for variant in &en.variants join (#<push>) =>
+                        #(self.generate_doc(&variant.doc))
+                        #(self.type_ident(&variant.name)),
+                    )
+                }
+            };
+        }
+        let name = self.type_ident(&en.name);
+        quote! {
+            #(self.generate_doc(&en.doc))
+            public abstract record #(&name)
+            {
+                private #(&name)() { }
+
+                #(for variant in &en.variants join (#<line>) =>
+                    #(self.generate_doc(&variant.doc))
+                    public sealed record #(self.type_ident(&variant.name))(#(self.generate_fields(&variant.fields))) : #(&name);
+                )
+            }
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> Tokens {
+        let name = self.type_ident(&err.name);
+        quote! {
+            #(self.generate_doc(&err.doc))
+            public abstract class #(&name) : Exception
+            {
+                private #(&name)() { }
+
+                #(for variant in &err.variants join (#<line>) =>
+                    #(self.generate_doc(&variant.doc))
+                    #(if variant.fields.is_empty() {
+                        public sealed class #(self.type_ident(&variant.name)) : #(&name) { }
+                    } else {
+                        public sealed class #(self.type_ident(&variant.name)) : #(&name)
+                        {
+                            public #(self.type_ident(&variant.name))(#(for (name, ty) in &variant.fields join (, ) => #(self.generate_type(ty)) #(self.ident(name))))
+                            {
+                                #(for (name, _) in &variant.fields join (#<push>) => #(self.member_ident(name)) = #(self.ident(name));)
+                            }
+                            #(for (name, ty) in &variant.fields =>
+                                #<line>
+                                public #(self.generate_type(ty)) #(self.member_ident(name)) { get; }
+                            )
+                        }
+                    })
+                )
+            }
+        }
+    }
+
+    fn generate_fields(&self, fields: &[(String, AbiType)]) -> Tokens {
+        quote!(#(for (name, ty) in fields join (, ) => #(self.generate_type(ty)) #(self.member_ident(name))))
+    }
+
+    fn generate_function(&self, func: &AbiFunction) -> Tokens {
+        let ffi = self.abi.import(func);
+        let args = quote!(#(for (name, ty) in &ffi.abi_args join (, ) => #(self.generate_type(ty)) #(self.ident(name))));
+        let doc = self.generate_doc(&func.doc);
+        let is_poll = matches!(
+            &func.ty,
+            FunctionType::NextIter(_, _)
+                | FunctionType::PollFuture(_, _)
+                | FunctionType::PollStream(_, _)
+        );
+        if is_poll {
+            // Polling returns `FfiPending` when no value is ready, so that
+            // `null` can be used for an optional value.
+            let pending = ffi
+                .instr
+                .iter()
+                .position(|instr| matches!(instr, Instr::HandleNull(_)));
+            let body = ffi.instr.iter().enumerate().map(|(i, instr)| match instr {
+                Instr::HandleNull(var) if Some(i) == pending => {
+                    self.generate_handle_null(var, quote!(FfiPending.Instance))
+                }
+                Instr::ReturnVoid => quote!(return null;),
+                _ => self.generate_instr(instr),
+            });
+            return quote! {
+                internal static object? #(self.poll_ident(&ffi.symbol))(#args)
+                {
+                    #(for instr in body join (#<push>) => #instr)
+                }
+            };
+        }
+        // Rust only borrows the handles, so they must not be finalized
+        // before the call returns.
+        let kept: Vec<Tokens> = ffi
+            .instr
+            .iter()
+            .filter_map(|instr| match instr {
+                Instr::BorrowSelf(_) => Some(quote!(GC.KeepAlive(this);)),
+                Instr::BorrowObject(in_, _) | Instr::BorrowIter(in_, _) => {
+                    Some(quote!(GC.KeepAlive(#(self.var(in_)));))
+                }
+                _ => None,
+            })
+            .collect();
+        let body = ffi.instr.iter().map(|instr| match instr {
+            Instr::Call(_, _, _) => quote! {
+                #(self.generate_instr(instr))
+                #(for keep in &kept join (#<push>) => #keep)
+            },
+            Instr::ReturnVoid => quote!(return;),
+            _ => self.generate_instr(instr),
+        });
+        let modifiers = match &func.ty {
+            FunctionType::Method(_) => quote!(public),
+            _ => quote!(public static),
+        };
+        quote! {
+            #doc
+            #modifiers #(self.generate_return_type(ffi.abi_ret.as_ref())) #(self.member_ident(&func.name))(#args)
+            {
+                #(for instr in body join (#<push>) => #instr)
+            }
+        }
+    }
+
+    fn generate_return_type(&self, ret: Option<&AbiType>) -> Tokens {
+        match ret {
+            None => quote!(void),
+            Some(AbiType::Result(ty, _)) => self.generate_return_type(Some(ty)),
+            Some(AbiType::Tuple(tys)) if tys.is_empty() => quote!(void),
+            Some(ty) => self.generate_type(ty),
+        }
+    }
+
+    fn generate_handle_null(&self, var: &Var, null: Tokens) -> Tokens {
+        quote! {
+            if (#(self.var(var)) == 0)
+            {
+                return #null;
+            }
+        }
+    }
+
+    fn generate_instr(&self, instr: &Instr) -> Tokens {
+        match instr {
+            Instr::BorrowSelf(out) => quote!(#(self.var(out)) = Borrow();),
+            Instr::BorrowObject(in_, out) => quote!(#(self.var(out)) = #(self.var(in_)).Borrow();),
+            Instr::BorrowIter(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).Box.Borrow();)
+            }
+            Instr::MoveObject(in_, out) => quote!(#(self.var(out)) = #(self.var(in_)).Move();),
+            Instr::MoveIter(in_, out) => quote!(#(self.var(out)) = #(self.var(in_)).Box.Move();),
+            Instr::BorrowFuture(_, _) | Instr::MoveFuture(_, _) => {
+                quote!(throw new NotSupportedException("futures can't be passed to rust");)
+            }
+            Instr::BorrowStream(_, _) | Instr::MoveStream(_, _) => {
+                quote!(throw new NotSupportedException("streams can't be passed to rust");)
+            }
+            Instr::LiftObject(obj, box_, _, out) => {
+                quote!(var #(self.var(out)) = new #(self.type_ident(obj))((long)#(self.var(box_)));)
+            }
+            Instr::BindArg(arg, out) => quote!(var #(self.var(out)) = #(self.ident(arg));),
+            Instr::BindRets(ret, vars) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(var #(self.var(&vars[0])) = #(self.var(ret));),
+                _ => quote! {
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
+                        var #(self.var(var)) = #(self.var(ret)).#(format!("ret{}", idx));)
+                },
+            },
+            Instr::LowerNum(in_, out, num) => {
+                quote!(#(self.var(out)) = (#(self.generate_num_type(*num)))#(self.var(in_));)
+            }
+            Instr::LiftNum(in_, out, num) => {
+                let ty = match &out.ty {
+                    AbiType::Num(_) | AbiType::Isize | AbiType::Usize => {
+                        self.generate_type(&out.ty)
+                    }
+                    _ => self.generate_num_type(*num),
+                };
+                quote!(var #(self.var(out)) = (#ty)#(self.var(in_));)
+            }
+            Instr::LowerBool(in_, out) => {
+                quote!(#(self.var(out)) = (#(self.generate_num_type(out.ty.num())))(#(self.var(in_)) ? 1 : 0);)
+            }
+            Instr::LiftBool(in_, out) => quote!(var #(self.var(out)) = #(self.var(in_)) > 0;),
+            Instr::Deallocate(ptr, len, size, align) => quote! {
+                if (#(self.var(len)) > 0)
+                {
+                    Ffi.Deallocate((long)#(self.var(ptr)), (ulong)#(self.var(len)) * #(*size), #(*align));
+                }
+            },
+            Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
+                var #(self.var(in_))_0 = Encoding.UTF8.GetBytes(#(self.var(in_)));
+                #(self.var(len)) = (#(self.generate_num_type(len.ty.num())))#(self.var(in_))_0.Length;
+                #(self.var(ptr)) = (#(self.generate_num_type(ptr.ty.num())))Ffi.Allocate((ulong)#(self.var(len)) * #(*size), #(*align));
+                Ffi.Write((long)#(self.var(ptr)), #(self.var(in_))_0);
+                #(self.var(cap)) = #(self.var(len));
+            },
+            Instr::LiftString(ptr, len, out) => quote! {
+                var #(self.var(out)) = Ffi.ReadString((long)#(self.var(ptr)), (int)#(self.var(len)));
+            },
+            Instr::LowerVec(in_, ptr, len, cap, _, size, align) => quote! {
+                var #(self.var(in_))_0 = #(self.var(in_)).ToArray();
+                #(self.var(len)) = (#(self.generate_num_type(len.ty.num())))#(self.var(in_))_0.Length;
+                #(self.var(ptr)) = (#(self.generate_num_type(ptr.ty.num())))Ffi.Allocate((ulong)#(self.var(len)) * #(*size), #(*align));
+                Ffi.Write((long)#(self.var(ptr)), #(self.var(in_))_0);
+                #(self.var(cap)) = #(self.var(len));
+            },
+            Instr::LiftVec(ptr, len, out, ty) => quote! {
+                var #(self.var(out)) = Ffi.ReadVec<#(self.generate_num_type(*ty))>((long)#(self.var(ptr)), (int)#(self.var(len)));
+            },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match self.collection_type(&out.ty) {
+                    AbiType::Map(key, value) => (
+                        quote!(Dictionary<#(self.generate_type(key)), #(self.generate_type(value))>),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1]));),
+                    ),
+                    AbiType::Set(ty) => (
+                        quote!(HashSet<#(self.generate_type(ty))>),
+                        quote!(#(self.var(out)).Add(#(self.var(&elems[0])));),
+                    ),
+                    _ => (
+                        quote!(List<#(self.generate_type(&elems[0].ty))>),
+                        quote!(#(self.var(out)).Add(#(self.var(&elems[0])));),
+                    ),
+                };
+                quote! {
+                    var #(self.var(out)) = new #collection();
+                    for (var #(self.var(out))_0 = 0; #(self.var(out))_0 < (int)#(self.var(len)); #(self.var(out))_0++)
+                    {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            var #(self.var(var)) = Ffi.Load<#(self.generate_num_type(var.ty.num()))>((long)#(self.var(ptr)), #(self.var(out))_0 * #n + #i);
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(inst)))
+                        #add
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let bind = if let AbiType::Map(_, _) = self.collection_type(&in_.ty) {
+                    quote!((#(self.var(&elems[0])), #(self.var(&elems[1]))))
+                } else {
+                    self.var(&elems[0])
+                };
+                quote! {
+                    #(self.var(len)) = (#(self.generate_num_type(len.ty.num())))#(self.var(in_)).Count;
+                    if (#(self.var(len)) > 0)
+                    {
+                        #(self.var(ptr)) = (#(self.generate_num_type(ptr.ty.num())))Ffi.Allocate((ulong)#(self.var(len)) * #(n * 8), 8);
+                    }
+                    var #(self.var(in_))_0 = 0;
+                    foreach (var #bind in #(self.var(in_)))
+                    {
+                        #(self.generate_instr(&Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            Ffi.Store((long)#(self.var(ptr)), #(self.var(in_))_0 * #n + #i, #(self.var(var)));
+                        )
+                        #(self.var(in_))_0++;
+                    }
+                }
+            }
+            Instr::Call(symbol, ret, args) => {
+                let invoke =
+                    quote!(Native.#(symbol)(#(for arg in args join (, ) => #(self.var(arg)))));
+                if let Some(ret) = ret {
+                    quote!(var #(self.var(ret)) = #invoke;)
+                } else {
+                    quote!(#invoke;)
+                }
+            }
+            Instr::DefineArgs(vars) => quote! {
+                #(for var in vars join (#<push>) => #(self.generate_num_type(var.ty.num())) #(self.var(var)) = 0;)
+            },
+            Instr::ReturnValue(ret) => quote!(return #(self.var(ret));),
+            Instr::ReturnVoid => quote!(return;),
+            Instr::HandleNull(var) => self.generate_handle_null(var, quote!(null)),
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                #(self.generate_type(&out.ty)) #(self.var(out));
+                if (#(self.var(var)) == 0)
+                {
+                    #(self.var(out)) = null;
+                }
+                else
+                {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(inst)))
+                    #(self.var(out)) = #(self.var(some));
+                }
+            },
+            Instr::LowerOption(arg, var, some, some_instr) => quote! {
+                if (#(self.var(arg)) is { } #(self.var(some)))
+                {
+                    #(self.var(var)) = 1;
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(inst)))
+                }
+                else
+                {
+                    #(self.var(var)) = 0;
+                }
+            },
+            Instr::HandleError(var, ptr, len, cap) => quote! {
+                if (#(self.var(var)) == 0)
+                {
+                    var #(self.var(var))_0 = Ffi.ReadString((long)#(self.var(ptr)), (int)#(self.var(len)));
+                    if (#(self.var(len)) > 0)
+                    {
+                        Ffi.Deallocate((long)#(self.var(ptr)), (ulong)#(self.var(cap)), 1);
+                    }
+                    throw new FfiException(#(self.var(var))_0);
+                }
+            },
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if (#(self.var(var)) == 0)
+                {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if (#(self.var(tag)) == #i)
+                        {
+                            #(self.read_payload(payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                            throw new #(self.type_ident(err)).#(self.type_ident(variant))(#(for (_, field) in fields join (, ) => #(self.var(field))));
+                        }
+                    )
+                    throw new InvalidOperationException(#(quoted(format!("unknown variant of {}", err))));
+                }
+            },
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                #(self.callback_ident(cb)) #(self.var(in_))_0 = #(self.generate_native_callable(cb, self.var(in_)));
+                #(self.var(ptr)) = (long)Marshal.GetFunctionPointerForDelegate(#(self.var(in_))_0);
+                #(self.var(post_cobject)) = FfiPorts.PostCObjectAddress;
+                #(self.var(port)) = FfiPorts.KeepAlive(#(self.var(in_))_0);
+            },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => quote! {
+                #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                    #(self.callback_ident(cb)) #(self.var(in_))#(format!("_{}", i)) = #(self.generate_native_callable(
+                        cb,
+                        quote!(#(self.var(in_)).#(self.member_ident(name))),
+                    ));
+                )
+                #(for (i, ptr) in vtable.iter().enumerate() join (#<push>) =>
+                    #(self.var(ptr)) = (long)Marshal.GetFunctionPointerForDelegate(#(self.var(in_))#(format!("_{}", i)));)
+                #(self.var(post_cobject)) = FfiPorts.PostCObjectAddress;
+                #(self.var(port)) = FfiPorts.KeepAlive(#(for i in 0..methods.len() join (, ) => #(self.var(in_))#(format!("_{}", i))));
+            },
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = (#(self.generate_num_type(tag.ty.num())))#(self.var(in_)););
+                }
+                quote! {
+                    switch (#(self.var(in_)))
+                    {
+                        #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                            case #(self.type_ident(name)).#(self.type_ident(variant)) #(self.var(in_))#(format!("_{}", i)):
+                            {
+                                #(self.var(tag)) = #i;
+                                #(for (field, var) in fields join (#<push>) => var #(self.var(var)) = #(self.var(in_))#(format!("_{}", i)).#(self.member_ident(field));)
+                                #(self.generate_instr(&Instr::DefineArgs(ffi.clone())))
+                                #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                                #(self.write_payload(payload, ffi))
+                                break;
+                            }
+                        )
+                    }
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(var #(self.var(out)) = (#(self.type_ident(name)))#(self.var(tag)););
+                }
+                quote! {
+                    #(self.type_ident(name)) #(self.var(out));
+                    switch (#(self.var(tag)))
+                    {
+                        #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                            case #i:
+                            {
+                                #(self.read_payload(payload, ffi))
+                                #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                                #(self.var(out)) = new #(self.type_ident(name)).#(self.type_ident(variant))(#(for (_, field) in fields join (, ) => #(self.var(field))));
+                                break;
+                            }
+                        )
+                        default:
+                            throw new InvalidOperationException(#(quoted(format!("unknown variant of {}", name))));
+                    }
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    var #(self.var(out)) = new #(self.generate_type(self.collection_type(&out.ty)))(#(self.generate_box(box_, drop)), Api.#(self.poll_ident(next))#batched);
+                }
+            }
+            Instr::LiftFuture(box_, poll, drop, out) => quote! {
+                var #(self.var(out)) = Ffi.Future<#(self.generate_item_type(&out.ty))>(#(self.generate_box(box_, drop)), Api.#(self.poll_ident(poll)));
+            },
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    var #(self.var(out)) = Ffi.Stream<#(self.generate_item_type(&out.ty))>(#(self.generate_box(box_, drop)), Api.#(self.poll_ident(poll))#batched);
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(var #(self.var(&vars[0])) = #(self.var(in_));),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => var #(self.var(var)) = #(self.var(in_)).#(format!("Item{}", i + 1));)
+                },
+            },
+            Instr::LiftTuple(vars, out) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(var #(self.var(out)) = #(self.var(&vars[0]));),
+                _ => {
+                    quote!(var #(self.var(out)) = (#(for var in vars join (, ) => #(self.var(var))));)
+                }
+            },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => var #(self.var(var)) = #(self.var(in_)).#(self.member_ident(field));)
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                var #(self.var(out)) = new #(self.type_ident(name))(#(for (_, var) in vars join (, ) => #(self.var(var))));
+            },
+            Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
+        }
+    }
+
+    /// Implements the unmanaged delegate of `cb` by calling `func`.
+    fn generate_native_callable(&self, cb: &Callback, func: Tokens) -> Tokens {
+        let args = quote!(#(for var in &cb.args join (, ) => #(self.var(var))));
+        quote! {
+            (#(for var in &cb.ffi_args join (, ) => #(self.generate_num_type(var.ty.num())) #(self.var(var)))) =>
+            {
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(inst)))
+                #(match &cb.ret {
+                    Some(ret) => var #(self.var(ret)) = #(func)(#args);,
+                    None => #(func)(#args);,
+                })
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret));)
+            }
+        }
+    }
+
+    fn generate_box(&self, box_: &Var, drop: &str) -> Tokens {
+        quote!(new FfiBox((long)#(self.var(box_)), ptr => Native.#drop(0, ptr)))
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                var #(self.var(var)) = Ffi.Load<#(self.generate_num_type(var.ty.num()))>((long)#(self.var(payload)), #i);
+            )
+            Ffi.Deallocate((long)#(self.var(payload)), #(ffi.len() * 8), 8);
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = (#(self.generate_num_type(payload.ty.num())))Ffi.Allocate(#(ffi.len() * 8), 8);
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                Ffi.Store((long)#(self.var(payload)), #i, #(self.var(var)));
+            )
+        }
+    }
+
+    fn var(&self, var: &Var) -> Tokens {
+        quote!(#(format!("tmp{}", var.binding)))
+    }
+
+    /// Lifted options and results are typed by their inner collection.
+    fn collection_type<'a>(&self, ty: &'a AbiType) -> &'a AbiType {
+        match ty {
+            AbiType::Option(ty) | AbiType::Result(ty, _) => self.collection_type(ty),
+            ty => ty,
+        }
+    }
+
+    /// Items of an iterator, future or stream.
+    fn generate_item_type(&self, ty: &AbiType) -> Tokens {
+        match self.collection_type(ty) {
+            AbiType::RefIter(ty)
+            | AbiType::Iter(ty, _)
+            | AbiType::RefFuture(ty)
+            | AbiType::Future(ty)
+            | AbiType::RefStream(ty)
+            | AbiType::Stream(ty, _) => self.generate_type(ty),
+            ty => self.generate_type(ty),
+        }
+    }
+
+    fn generate_type(&self, ty: &AbiType) -> Tokens {
+        match ty {
+            AbiType::Num(ty) => self.generate_num_type(*ty),
+            AbiType::Isize => quote!(long),
+            AbiType::Usize => quote!(ulong),
+            AbiType::Bool => quote!(bool),
+            AbiType::RefStr | AbiType::String => quote!(string),
+            AbiType::RefSlice(ty) | AbiType::Vec(ty) => {
+                quote!(List<#(self.generate_num_type(*ty))>)
+            }
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!(List<#(self.generate_type(ty))>),
+            AbiType::Map(key, value) => {
+                quote!(Dictionary<#(self.generate_type(key)), #(self.generate_type(value))>)
+            }
+            AbiType::Set(ty) => quote!(HashSet<#(self.generate_type(ty))>),
+            AbiType::Option(ty) => quote!(#(self.generate_type(ty))?),
+            AbiType::Result(ty, _) => self.generate_type(ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.trait_ident(&tr.name))),
+            AbiType::Tuple(tuple) => match tuple.len() {
+                0 => quote!(object?),
+                1 => self.generate_type(&tuple[0]),
+                _ => quote!((#(for ty in tuple join (, ) => #(self.generate_type(ty))))),
+            },
+            AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(#(self.type_ident(ty))),
+            AbiType::Callback(args, ret) => {
+                let mut tys: Vec<Tokens> = args.iter().map(|arg| self.generate_type(arg)).collect();
+                match ret {
+                    Some(ret) => {
+                        tys.push(self.generate_type(ret));
+                        quote!(Func<#(for ty in tys join (, ) => #ty)>)
+                    }
+                    None if tys.is_empty() => quote!(Action),
+                    None => quote!(Action<#(for ty in tys join (, ) => #ty)>),
+                }
+            }
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => quote!(Iter<#(self.generate_type(ty))>),
+            AbiType::RefFuture(ty) | AbiType::Future(ty) => match &**ty {
+                AbiType::Tuple(tuple) if tuple.is_empty() => quote!(Task),
+                ty => quote!(Task<#(self.generate_type(ty))>),
+            },
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
+                quote!(IAsyncEnumerable<#(self.generate_type(ty))>)
+            }
+        }
+    }
+
+    fn generate_num_type(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 => quote!(sbyte),
+            NumType::I16 => quote!(short),
+            NumType::I32 => quote!(int),
+            NumType::I64 => quote!(long),
+            NumType::U8 => quote!(byte),
+            NumType::U16 => quote!(ushort),
+            NumType::U32 => quote!(uint),
+            NumType::U64 => quote!(ulong),
+            NumType::F32 => quote!(float),
+            NumType::F64 => quote!(double),
+        }
+    }
+
+    fn num_name(&self, ty: NumType) -> String {
+        self.generate_num_type(ty)
+            .to_string()
+            .unwrap()
+            .to_upper_camel_case()
+    }
+
+    fn generate_return_struct(&self, ret: &Return) -> Tokens {
+        if let Return::Struct(vars, name) = ret {
+            quote! {
+                #<line>
+                [StructLayout(LayoutKind.Sequential)]
+                internal struct #name
+                {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) =>
+                        internal #(self.generate_num_type(var.ty.num())) #(format!("ret{}", i));
+                    )
+                }
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    fn generate_doc<S: AsRef<str>>(&self, doc: &[S]) -> Tokens {
+        let lines: Vec<String> = doc
+            .iter()
+            .map(|line| {
+                line.as_ref()
+                    .trim()
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+            })
+            .collect();
+        match lines.len() {
+            0 => quote!(),
+            1 => quote!(#(static_literal("///")) <summary>#(&lines[0])</summary>),
+            _ => quote! {
+                #(static_literal("///")) <summary>
+                #(for line in &lines join (#<push>) => #(static_literal("///")) #line)
+                #(static_literal("///")) </summary>
+            },
+        }
+    }
+
+    fn type_ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_upper_camel_case())
+    }
+
+    /// Interfaces are prefixed with `I` by convention.
+    fn trait_ident(&self, s: &str) -> String {
+        format!("I{}", s.to_upper_camel_case())
+    }
+
+    /// Methods, properties and fields are pascal case.
+    fn member_ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_upper_camel_case())
+    }
+
+    fn ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_lower_camel_case())
+    }
+
+    /// Method of `Api` polling the iterator, future or stream of `symbol`.
+    fn poll_ident(&self, symbol: &str) -> String {
+        format!("Ffi{}", symbol.to_upper_camel_case())
+    }
+}
+
+fn sanitize_identifier(id: &str) -> String {
+    if RESERVED_IDENTIFIERS.contains(&id) {
+        format!("@{}", id)
+    } else {
+        id.to_string()
+    }
+}
+
+// https://learn.microsoft.com/en-us/dotnet/csharp/language-reference/keywords/
+static RESERVED_IDENTIFIERS: [&str; 77] = [
+    "abstract",
+    "as",
+    "base",
+    "bool",
+    "break",
+    "byte",
+    "case",
+    "catch",
+    "char",
+    "checked",
+    "class",
+    "const",
+    "continue",
+    "decimal",
+    "default",
+    "delegate",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "event",
+    "explicit",
+    "extern",
+    "false",
+    "finally",
+    "fixed",
+    "float",
+    "for",
+    "foreach",
+    "goto",
+    "if",
+    "implicit",
+    "in",
+    "int",
+    "interface",
+    "internal",
+    "is",
+    "lock",
+    "long",
+    "namespace",
+    "new",
+    "null",
+    "object",
+    "operator",
+    "out",
+    "override",
+    "params",
+    "private",
+    "protected",
+    "public",
+    "readonly",
+    "ref",
+    "return",
+    "sbyte",
+    "sealed",
+    "short",
+    "sizeof",
+    "stackalloc",
+    "static",
+    "string",
+    "struct",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "uint",
+    "ulong",
+    "unchecked",
+    "unsafe",
+    "ushort",
+    "using",
+    "virtual",
+    "void",
+    "volatile",
+    "while",
+];
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, csharp: Tokens) -> Result<()> {
+        if crate::test_runner::skip_without("dotnet") {
+            return Ok(());
+        }
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native());
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let csharp_gen =
+            CSharpGenerator::new("CompilePass".to_string(), "compile_pass".to_string());
+        let csharp_tokens = csharp_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let bin_tokens: Tokens = quote! {
+            using System;
+            using System.Collections.Generic;
+            using System.Linq;
+            using CompilePass;
+
+            #csharp
+
+            static void Check(bool value)
+            {
+                if (!value)
+                {
+                    throw new Exception("check failed");
+                }
+            }
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        std::fs::write(
+            dir.as_ref().join("compile_pass.csproj"),
+            "<Project Sdk=\"Microsoft.NET.Sdk\">\n  <PropertyGroup>\n    <OutputType>Exe</OutputType>\n    <TargetFramework>net8.0</TargetFramework>\n    <Nullable>enable</Nullable>\n  </PropertyGroup>\n</Project>\n",
+        )?;
+        let bindings_file = dir.as_ref().join("Bindings.cs");
+        std::fs::write(&bindings_file, csharp_tokens.to_file_string()?)?;
+        let main_file = dir.as_ref().join("Program.cs");
+        std::fs::write(&main_file, bin_tokens.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let ret = Command::new("dotnet")
+            .current_dir(dir.as_ref())
+            .env("LD_LIBRARY_PATH", dir.as_ref())
+            .env("DYLD_LIBRARY_PATH", dir.as_ref())
+            .arg("run")
+            .status()?;
+        anyhow::ensure!(ret.success(), "csharp test failed");
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
-use crate::import::{Import, Instr};
-use crate::{Abi, AbiFunction, AbiObject, AbiType, FunctionType, Interface, NumType, Return, Var};
+use crate::import::{Callback, Import, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, AbiVariant,
+    FunctionType, Interface, NumType, Return, Var,
+};
 use genco::prelude::*;
 use genco::tokens::static_literal;
 use heck::*;
@@ -120,6 +123,51 @@ impl DartGenerator {
                 }
             }
 
+            #(static_literal("///")) Compares the fields of structs, lists, sets and maps by value.
+            bool _deepEquals(Object? a, Object? b) {
+                if (a is List && b is List) {
+                    if (a.length != b.length) {
+                        return false;
+                    }
+                    for (var i = 0; i < a.length; i++) {
+                        if (!_deepEquals(a[i], b[i])) {
+                            return false;
+                        }
+                    }
+                    return true;
+                }
+                if (a is Set && b is Set) {
+                    return a.length == b.length && a.containsAll(b);
+                }
+                if (a is Map && b is Map) {
+                    if (a.length != b.length) {
+                        return false;
+                    }
+                    for (final key in a.keys) {
+                        if (!b.containsKey(key) || !_deepEquals(a[key], b[key])) {
+                            return false;
+                        }
+                    }
+                    return true;
+                }
+                return a == b;
+            }
+
+            #(static_literal("///")) Hashes the fields of structs consistently with [_deepEquals].
+            int _deepHash(Object? value) {
+                if (value is List) {
+                    return Object.hashAll(value.map(_deepHash));
+                }
+                if (value is Set) {
+                    return Object.hashAllUnordered(value);
+                }
+                if (value is Map) {
+                    return Object.hashAllUnordered(
+                        value.entries.map((entry) => Object.hash(entry.key, _deepHash(entry.value))));
+                }
+                return value.hashCode;
+            }
+
             #(static_literal("///")) Implements Iterable and Iterator for a rust iterator.
             class Iter<T> extends Iterable<T> implements Iterator<T> {
                 final _Box _box;
@@ -151,9 +199,78 @@ impl DartGenerator {
                 }
             }
 
-            Future<T> _nativeFuture<T>(_Box box, T? Function(int, int, int) nativePoll) {
+            #(static_literal("///")) Iterates over a batched rust iterator, which returns a list of items at a time.
+            class _BatchIter<T> extends Iter<T> {
+                final List<T>? Function(int) _nextBatch;
+                List<T> _batch = [];
+                int _index = 0;
+
+                _BatchIter(_Box box, this._nextBatch) : super._(box, (int _box) => null);
+
+                @override
+                bool moveNext() {
+                    if (_index == _batch.length) {
+                        final batch = _nextBatch(_box.borrow());
+                        if (batch == null) {
+                            return false;
+                        }
+                        _batch = batch;
+                        _index = 0;
+                    }
+                    _current = _batch[_index++];
+                    return true;
+                }
+            }
+
+            #(static_literal("///")) Cancels the futures returned by the functions it is passed to.
+            #(static_literal("///"))
+            #(static_literal("///")) Cancelling drops the rust future, and the dart future completes with a
+            #(static_literal("///")) [CancelledException] unless it already completed.
+            class CancellationToken {
+                final _listeners = <void Function()>[];
+                bool _cancelled = false;
+
+                bool get isCancelled => _cancelled;
+
+                void cancel() {
+                    if (_cancelled) {
+                        return;
+                    }
+                    _cancelled = true;
+                    for (final listener in List.of(_listeners)) {
+                        listener();
+                    }
+                    _listeners.clear();
+                }
+
+                void _listen(void Function() listener) {
+                    _listeners.add(listener);
+                }
+
+                void _unlisten(void Function() listener) {
+                    _listeners.remove(listener);
+                }
+            }
+
+            #(static_literal("///")) Error of a future cancelled with a [CancellationToken].
+            class CancelledException implements Exception {
+                @override
+                String toString() => "CancelledException";
+            }
+
+            Future<T> _nativeFuture<T>(_Box box, T? Function(int, int, int) nativePoll, CancellationToken? token) {
                 final completer = Completer<T>();
+                if (token != null && token.isCancelled) {
+                    box.drop();
+                    completer.completeError(CancelledException());
+                    return completer.future;
+                }
                 final rx = ReceivePort();
+                void cancel() {
+                    rx.close();
+                    box.drop();
+                    completer.completeError(CancelledException());
+                }
                 void poll() {
                     try {
                         final ret = nativePoll(box.borrow(), ffi.NativeApi.postCObject.address, rx.sendPort.nativePort);
@@ -164,19 +281,42 @@ impl DartGenerator {
                     } catch(err) {
                         completer.completeError(err);
                     }
+                    token?._unlisten(cancel);
                     rx.close();
                     box.drop();
                 }
+                token?._listen(cancel);
                 rx.listen((dynamic _message) => poll());
                 poll();
                 return completer.future;
             }
 
+            #(static_literal("///")) Rust is only polled while the stream has a listener that is not paused.
             Stream<T> _nativeStream<T>(_Box box, T? Function(int, int, int, int) nativePoll) {
-                final controller = StreamController<T>();
+                late final StreamController<T> controller;
                 final rx = ReceivePort();
                 final done = ReceivePort();
+                var pending = false;
+                var finished = false;
+                var closed = false;
+                void close() {
+                    if (closed) {
+                        return;
+                    }
+                    closed = true;
+                    rx.close();
+                    done.close();
+                    box.drop();
+                }
                 void poll() {
+                    if (closed || pending || controller.isPaused) {
+                        return;
+                    }
+                    if (finished) {
+                        close();
+                        controller.close();
+                        return;
+                    }
                     try {
                         final ret = nativePoll(
                             box.borrow(),
@@ -184,22 +324,30 @@ impl DartGenerator {
                             rx.sendPort.nativePort,
                             done.sendPort.nativePort,
                         );
-                        if (ret != null) {
-                            controller.add(ret);
+                        if (ret == null) {
+                            pending = true;
+                            return;
                         }
+                        controller.add(ret);
                     } catch(err) {
                         controller.addError(err);
                     }
+                    Timer.run(poll);
                 }
-                void close() {
-                    rx.close();
-                    done.close();
-                    box.drop();
-                }
-                controller.onCancel = close;
-                rx.listen((dynamic _message) => poll());
-                done.listen((dynamic _message) => controller.close());
-                poll();
+                controller = StreamController<T>(
+                    onListen: poll,
+                    onResume: poll,
+                    onCancel: close,
+                );
+                rx.listen((dynamic _message) {
+                    pending = false;
+                    poll();
+                });
+                done.listen((dynamic _message) {
+                    finished = true;
+                    pending = false;
+                    poll();
+                });
                 return controller.stream;
             }
 
@@ -275,7 +423,7 @@ impl DartGenerator {
                     _deallocate(pointer.cast(), byteCount, alignment);
                 }
 
-                #(for func in iface.functions() => #(self.generate_function(&func)))
+                #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
 
                 late final _allocatePtr = _lookup<
                     ffi.NativeFunction<
@@ -291,16 +439,29 @@ impl DartGenerator {
                 late final _deallocate = _deallocatePtr.asFunction<
                     void Function(ffi.Pointer<ffi.Uint8>, int, int)>();
 
-                #(for iter in iface.iterators() => #(self.generate_function(&iter.next())))
-                #(for fut in iface.futures() => #(self.generate_function(&fut.poll())))
-                #(for stream in iface.streams() => #(self.generate_function(&stream.poll())))
+                late final _dispatchPtr = _lookup<
+                    ffi.NativeFunction<ffi.Uint8 Function(ffi.Int64)>>("__ffi_dispatch");
 
-                #(for func in iface.imports(&self.abi) => #(self.generate_wrapper(func)))
+                late final _dispatch = _dispatchPtr.asFunction<int Function(int)>();
+
+                #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+                #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+                #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+
+                #(for func in iface.imports(&self.abi) join (#<line>) => #(self.generate_wrapper(func)))
             }
 
-            #(for obj in iface.objects() => #(self.generate_object(obj)))
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
 
-            #(for func in iface.imports(&self.abi) => #(self.generate_return_struct(&func.ffi_ret)))
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
+
+            #(for func in iface.imports(&self.abi) join (#<line>) => #(self.generate_return_struct(&func.ffi_ret)))
         }
     }
 
@@ -313,7 +474,7 @@ impl DartGenerator {
 
                 #(&obj.name)._(this._api, this._box);
 
-                #(for func in &obj.methods => #(self.generate_function(func)))
+                #(for func in &obj.methods join (#<line>) => #(self.generate_function(func)))
 
                 #(static_literal("///")) Manually drops the object and unregisters the FinalizableHandle.
                 void drop() {
@@ -323,6 +484,103 @@ impl DartGenerator {
         }
     }
 
+    fn generate_trait(&self, tr: AbiTrait) -> dart::Tokens {
+        quote! {
+            #(self.generate_doc(&tr.doc))
+            abstract class #(self.type_ident(&tr.name)) {
+                #(for method in &tr.methods join (#<line>) =>
+                    #(self.generate_doc(&method.doc))
+                    #(match &method.ret {
+                        Some(ret) => #(self.generate_type(ret)),
+                        None => void,
+                    }) #(self.ident(&method.name))(#(for (name, ty) in &method.args join (, ) => #(self.generate_type(ty)) #(self.ident(name))));
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> dart::Tokens {
+        quote! {
+            #(self.generate_doc(&st.doc))
+            class #(self.type_ident(&st.name)) {
+                #(for (name, ty) in &st.fields join (#<push>) => final #(self.generate_type(ty)) #(self.ident(name));)
+
+                #(self.type_ident(&st.name))(#(for (name, _) in &st.fields join (, ) => this.#(self.ident(name))));
+
+                @override
+                bool operator ==(Object other) =>
+                    other is #(self.type_ident(&st.name))#(for (name, _) in &st.fields => #<space>&& _deepEquals(#(self.ident(name)), other.#(self.ident(name))));
+
+                @override
+                int get hashCode => Object.hashAll([#(for (name, _) in &st.fields join (, ) => _deepHash(#(self.ident(name))))]);
+            }
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> dart::Tokens {
+        if en.is_c_like() {
+            return quote! {
+                #(self.generate_doc(&en.doc))
+                enum #(self.type_ident(&en.name)) {
+                    #(for variant in &en.variants join (#<push>) =>
+                        #(self.generate_doc(&variant.doc))
+                        #(self.ident(&variant.name)),
+                    )
+                }
+            };
+        }
+        self.generate_variants(&en.doc, &en.name, &en.variants, None)
+    }
+
+    fn generate_error(&self, err: AbiError) -> dart::Tokens {
+        self.generate_variants(&err.doc, &err.name, &err.variants, Some("Exception"))
+    }
+
+    fn generate_variants(
+        &self,
+        doc: &[String],
+        name: &str,
+        variants: &[AbiVariant],
+        implements: Option<&str>,
+    ) -> dart::Tokens {
+        let implements = implements.map(|iface| quote!(#<space>implements #iface));
+        quote! {
+            #(self.generate_doc(doc))
+            sealed class #(self.type_ident(name))#implements {}
+
+            #(for variant in variants join (#<line>) =>
+                #(self.generate_doc(&variant.doc))
+                class #(self.variant_ident(name, &variant.name)) extends #(self.type_ident(name)) {
+                    #(for (field, ty) in &variant.fields join (#<push>) => final #(self.generate_type(ty)) #(self.ident(field));)
+
+                    #(self.variant_ident(name, &variant.name))(#(for (field, _) in &variant.fields join (, ) => this.#(self.ident(field))));
+
+                    @override
+                    String toString() {
+                        return #(self.generate_variant_to_string(name, variant));
+                    }
+                }
+            )
+        }
+    }
+
+    fn generate_variant_to_string(&self, name: &str, variant: &AbiVariant) -> dart::Tokens {
+        if variant.fields.is_empty() {
+            return quote!(#(quoted(format!("{}.{}", name, variant.name))));
+        }
+        let mut tokens = dart::Tokens::new();
+        for (i, (field, _)) in variant.fields.iter().enumerate() {
+            let prefix = if i == 0 {
+                format!("{}.{}({}: ", name, variant.name, field)
+            } else {
+                format!(", {}: ", field)
+            };
+            quote_in!(tokens => #(quoted(prefix)) + #(self.ident(field)).toString() +#<space>);
+        }
+        quote_in!(tokens => ")");
+        tokens
+    }
+
     fn generate_function(&self, func: &AbiFunction) -> dart::Tokens {
         let ffi = self.abi.import(func);
         let api = match &func.ty {
@@ -341,8 +599,16 @@ impl DartGenerator {
             }
             _ => self.ident(&func.name),
         };
-        let args = quote!(#(for (name, ty) in &ffi.abi_args => #(self.generate_type(ty)) #(self.ident(name)),));
-        let body = quote!(#(for instr in &ffi.instr => #(self.generate_instr(api, instr))));
+        let mut args = quote!(#(for (name, ty) in &ffi.abi_args join (, ) => #(self.generate_type(ty)) #(self.ident(name))));
+        if func.returns_future() {
+            if !ffi.abi_args.is_empty() {
+                args.append(quote!(,));
+                args.space();
+            }
+            args.append(quote!([CancellationToken? cancellationToken]));
+        }
+        let body =
+            quote!(#(for instr in &ffi.instr join (#<push>) => #(self.generate_instr(api, instr))));
         let ret = if let Some(ret) = ffi.abi_ret.as_ref() {
             self.generate_type(ret)
         } else {
@@ -393,7 +659,7 @@ impl DartGenerator {
                 0 => quote!(),
                 1 => quote!(final #(self.var(&vars[0])) = #(self.var(ret));),
                 _ => quote! {
-                    #(for (idx, var) in vars.iter().enumerate() =>
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
                         final #(self.var(var)) = #(self.var(ret)).#(format!("arg{}", idx));)
                 },
             },
@@ -444,13 +710,77 @@ impl DartGenerator {
                     ffi.Pointer.fromAddress(#(self.var(ptr)));
                 final #(self.var(out)) = #(self.var(ptr))_0.asTypedList(#(self.var(len))).toList();
             },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match &out.ty {
+                    AbiType::Map(key, value) => (
+                        quote!(<#(self.generate_type(key)), #(self.generate_type(value))>{}),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1]))),
+                    ),
+                    AbiType::Set(ty) => (
+                        quote!(<#(self.generate_type(ty))>{}),
+                        quote!(#(self.var(out)).add(#(self.var(&elems[0])))),
+                    ),
+                    _ => (
+                        quote!(<#(self.generate_type(&elems[0].ty))>[]),
+                        quote!(#(self.var(out)).add(#(self.var(&elems[0])))),
+                    ),
+                };
+                quote! {
+                    final #(self.var(out)) = #collection;
+                    for (var #(self.var(out))_0 = 0; #(self.var(out))_0 < #(self.var(len)); #(self.var(out))_0++) {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            final #(self.var(var)) = ffi.Pointer<#(self.generate_native_num_type(var.ty.num()))>.fromAddress(
+                                #(self.var(ptr)) + (#(self.var(out))_0 * #n + #i) * 8).value;
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #add;
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (iter, bind) = if let AbiType::Map(_, _) = &in_.ty {
+                    (
+                        quote!(#(self.var(in_)).entries),
+                        quote! {
+                            final #(self.var(&elems[0])) = #(self.var(in_))_1.key;
+                            final #(self.var(&elems[1])) = #(self.var(in_))_1.value;
+                        },
+                    )
+                } else {
+                    (
+                        quote!(#(self.var(in_))),
+                        quote!(final #(self.var(&elems[0])) = #(self.var(in_))_1;),
+                    )
+                };
+                quote! {
+                    #(self.var(len)) = #(self.var(in_)).length;
+                    if (#(self.var(len)) > 0) {
+                        final ffi.Pointer<ffi.Uint64> #(self.var(ptr))_0 =
+                            #api.__allocate(#(self.var(len)) * #(n * 8), 8);
+                        #(self.var(ptr)) = #(self.var(ptr))_0.address;
+                    }
+                    var #(self.var(in_))_0 = 0;
+                    for (final #(self.var(in_))_1 in #iter) {
+                        #bind
+                        #(self.generate_instr(api, &Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            ffi.Pointer<#(self.generate_native_num_type(var.ty.num()))>.fromAddress(
+                                #(self.var(ptr)) + (#(self.var(in_))_0 * #n + #i) * 8).value = #(self.var(var));
+                        )
+                        #(self.var(in_))_0++;
+                    }
+                }
+            }
             Instr::Call(symbol, ret, args) => {
                 let api = if api == "this" {
                     quote!()
                 } else {
                     quote!(#api.)
                 };
-                let invoke = quote!(#(api)#(format!("_{}", self.ident(symbol)))(#(for arg in args => #(self.var(arg)),)););
+                let invoke = quote!(#(api)#(format!("_{}", self.ident(symbol)))(#(for arg in args join (, ) => #(self.var(arg)))););
                 if let Some(ret) = ret {
                     quote!(final #(self.var(ret)) = #invoke)
                 } else {
@@ -458,7 +788,7 @@ impl DartGenerator {
                 }
             }
             Instr::DefineArgs(vars) => quote! {
-                #(for var in vars => var #(self.var(var)) = #(self.literal(var.ty.num()));)
+                #(for var in vars join (#<push>) => var #(self.var(var)) = #(self.literal(var.ty.num()));)
             },
             Instr::ReturnValue(ret) => quote!(return #(self.var(ret));),
             Instr::ReturnVoid => quote!(return;),
@@ -467,13 +797,22 @@ impl DartGenerator {
                     return null;
                 }
             },
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                final #(self.generate_type(&out.ty)) #(self.var(out));
+                if (#(self.var(var)) == 0) {
+                    #(self.var(out)) = null;
+                } else {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(self.var(out)) = #(self.var(some));
+                }
+            },
             Instr::LowerOption(arg, var, some, some_instr) => quote! {
                 if (#(self.var(arg)) == null) {
                     #(self.var(var)) = 0;
                 } else {
                     #(self.var(var)) = 1;
                     final #(self.var(some)) = #(self.var(arg));
-                    #(for inst in some_instr => #(self.generate_instr(api, inst)))
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
                 }
             },
             Instr::HandleError(var, ptr, len, cap) => quote! {
@@ -488,40 +827,202 @@ impl DartGenerator {
                     throw #(self.var(var))_0;
                 }
             },
-            Instr::LiftIter(box_, next, drop, out) => quote! {
-                final ffi.Pointer<ffi.Void> #(self.var(box_))_0 = ffi.Pointer.fromAddress(#(self.var(box_)));
-                final #(self.var(box_))_1 = _Box(#api, #(self.var(box_))_0, #_(#drop));
-                #(self.var(box_))_1._finalizer = #api._registerFinalizer(#(self.var(box_))_1);
-                final #(self.var(out)) = Iter._(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(next))));
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if (#(self.var(var)) == 0) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if (#(self.var(tag)) == #(i)) {
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            throw #(self.variant_ident(err, variant))(#(for (_, field) in fields join (, ) => #(self.var(field))));
+                        }
+                    )
+                    throw StateError(#_(unknown variant of #err));
+                }
+            },
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                final #(self.var(in_))_0 = #(self.generate_native_callable(api, cb, self.var(in_)));
+                final #(self.var(in_))_1 = ReceivePort();
+                #(self.var(in_))_1.listen((dynamic _message) {
+                    if (#api._dispatch(#(self.var(in_))_1.sendPort.nativePort) != 0) {
+                        #(self.var(in_))_0.close();
+                        #(self.var(in_))_1.close();
+                    }
+                });
+                #(self.var(ptr)) = #(self.var(in_))_0.nativeFunction.address;
+                #(self.var(post_cobject)) = ffi.NativeApi.postCObject.address;
+                #(self.var(port)) = #(self.var(in_))_1.sendPort.nativePort;
             },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => {
+                let receive_port = format!("_{}", methods.len());
+                quote! {
+                    #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                        final #(self.var(in_))#(format!("_{}", i)) = #(self.generate_native_callable(api, cb, quote!(#(self.var(in_)).#(self.ident(name)))));
+                    )
+                    final #(self.var(in_))#(&receive_port) = ReceivePort();
+                    #(self.var(in_))#(&receive_port).listen((dynamic _message) {
+                        if (#api._dispatch(#(self.var(in_))#(&receive_port).sendPort.nativePort) != 0) {
+                            #(for i in 0..methods.len() join (#<push>) => #(self.var(in_))#(format!("_{}", i)).close();)
+                            #(self.var(in_))#(&receive_port).close();
+                        }
+                    });
+                    #(for (i, ptr) in vtable.iter().enumerate() join (#<push>) => #(self.var(ptr)) = #(self.var(in_))#(format!("_{}", i)).nativeFunction.address;)
+                    #(self.var(post_cobject)) = ffi.NativeApi.postCObject.address;
+                    #(self.var(port)) = #(self.var(in_))#(&receive_port).sendPort.nativePort;
+                }
+            }
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = #(self.var(in_)).index;);
+                }
+                quote! {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join ( else ) =>
+                        if (#(self.var(in_)) is #(self.variant_ident(name, variant))) {
+                            #(self.var(tag)) = #(i);
+                            #(for (field, var) in fields join (#<push>) => final #(self.var(var)) = #(self.var(in_)).#(self.ident(field));)
+                            #(self.generate_instr(api, &Instr::DefineArgs(ffi.clone())))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(self.write_payload(api, payload, ffi))
+                        }
+                    )
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(final #(self.var(out)) = #(self.type_ident(name)).values[#(self.var(tag))];);
+                }
+                quote! {
+                    final #(self.type_ident(name)) #(self.var(out));
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join ( else ) =>
+                        if (#(self.var(tag)) == #(i)) {
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(self.var(out)) = #(self.variant_ident(name, variant))(#(for (_, field) in fields join (, ) => #(self.var(field))));
+                        }
+                    ) else {
+                        throw StateError(#_(unknown variant of #name));
+                    }
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let iter = if *batched {
+                    quote!(_BatchIter)
+                } else {
+                    quote!(Iter._)
+                };
+                quote! {
+                    final ffi.Pointer<ffi.Void> #(self.var(box_))_0 = ffi.Pointer.fromAddress(#(self.var(box_)));
+                    final #(self.var(box_))_1 = _Box(#api, #(self.var(box_))_0, #_(#drop));
+                    #(self.var(box_))_1._finalizer = #api._registerFinalizer(#(self.var(box_))_1);
+                    final #(self.var(out)) = #iter(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(next))));
+                }
+            }
             Instr::LiftFuture(box_, poll, drop, out) => quote! {
                 final ffi.Pointer<ffi.Void> #(self.var(box_))_0 = ffi.Pointer.fromAddress(#(self.var(box_)));
                 final #(self.var(box_))_1 = _Box(#api, #(self.var(box_))_0, #_(#drop));
                 #(self.var(box_))_1._finalizer = #api._registerFinalizer(#(self.var(box_))_1);
-                final #(self.var(out)) = _nativeFuture(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(poll))));
+                final #(self.var(out)) = _nativeFuture(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(poll))), cancellationToken);
             },
-            Instr::LiftStream(box_, poll, drop, out) => quote! {
-                final ffi.Pointer<ffi.Void> #(self.var(box_))_0 = ffi.Pointer.fromAddress(#(self.var(box_)));
-                final #(self.var(box_))_1 = _Box(#api, #(self.var(box_))_0, #_(#drop));
-                #(self.var(box_))_1._finalizer = #api._registerFinalizer(#(self.var(box_))_1);
-                final #(self.var(out)) = _nativeStream(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(poll))));
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let expand = if *batched {
+                    quote!(.expand((items) => items))
+                } else {
+                    quote!()
+                };
+                quote! {
+                    final ffi.Pointer<ffi.Void> #(self.var(box_))_0 = ffi.Pointer.fromAddress(#(self.var(box_)));
+                    final #(self.var(box_))_1 = _Box(#api, #(self.var(box_))_0, #_(#drop));
+                    #(self.var(box_))_1._finalizer = #api._registerFinalizer(#(self.var(box_))_1);
+                    final #(self.var(out)) = _nativeStream(#(self.var(box_))_1, #api.#(format!("__{}", self.ident(poll))))#expand;
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(final #(self.var(&vars[0])) = #(self.var(in_));),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => final #(self.var(var)) = #(self.var(in_))[#(i)];)
+                },
             },
             Instr::LiftTuple(vars, out) => match vars.len() {
                 0 => quote!(),
                 1 => quote!(final #(self.var(out)) = #(self.var(&vars[0]));),
                 _ => quote! {
                     final List #(self.var(out)) = [];
-                    #(for var in vars => #(self.var(out)).add(#(self.var(var)));)
+                    #(for var in vars join (#<push>) => #(self.var(out)).add(#(self.var(var)));)
                 },
             },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => final #(self.var(var)) = #(self.var(in_)).#(self.ident(field));)
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                final #(self.var(out)) = #(self.type_ident(name))(#(for (_, var) in vars join (, ) => #(self.var(var))));
+            },
             Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
         }
     }
 
+    fn generate_native_callable(
+        &self,
+        api: &str,
+        cb: &Callback,
+        func: dart::Tokens,
+    ) -> dart::Tokens {
+        let native_args = quote!(#(for var in &cb.ffi_args join (, ) => #(self.generate_native_num_type(var.ty.num()))));
+        let wrapped_args = quote!(#(for var in &cb.ffi_args join (, ) => #(self.generate_wrapped_num_type(var.ty.num())) #(self.var(var))));
+        let args = quote!(#(for var in &cb.args join (, ) => #(self.var(var))));
+        let (native_ret, exceptional_return) = match &cb.ffi_ret {
+            Some(var) => (
+                self.generate_native_num_type(var.ty.num()),
+                quote!(, exceptionalReturn: #(self.literal(var.ty.num()))),
+            ),
+            None => (quote!(ffi.Void), quote!()),
+        };
+        quote! {
+            ffi.NativeCallable<#native_ret Function(#native_args)>.isolateLocal((#wrapped_args) {
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(api, inst)))
+                #(match &cb.ret {
+                    Some(ret) => final #(self.var(ret)) = #(func)(#args);,
+                    None => #(func)(#args);,
+                })
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(api, inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret));)
+            }#exceptional_return)
+        }
+    }
+
     fn var(&self, var: &Var) -> dart::Tokens {
         quote!(#(format!("tmp{}", var.binding)))
     }
 
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, api: &str, payload: &Option<Var>, ffi: &[Var]) -> dart::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                final #(self.var(var)) = ffi.Pointer<#(self.generate_native_num_type(var.ty.num()))>.fromAddress(
+                    #(self.var(payload)) + #(i * 8)).value;
+            )
+            #api.__deallocate(ffi.Pointer<ffi.Void>.fromAddress(#(self.var(payload))), #(ffi.len() * 8), 8);
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, api: &str, payload: &Option<Var>, ffi: &[Var]) -> dart::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = #api.__allocate(#(ffi.len() * 8), 8).address;
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                ffi.Pointer<#(self.generate_native_num_type(var.ty.num()))>.fromAddress(
+                    #(self.var(payload)) + #(i * 8)).value = #(self.var(var));
+            )
+        }
+    }
+
     fn literal(&self, ty: NumType) -> dart::Tokens {
         match ty {
             NumType::F32 | NumType::F64 => quote!(0.0),
@@ -530,9 +1031,8 @@ impl DartGenerator {
     }
 
     fn generate_wrapper(&self, func: Import) -> dart::Tokens {
-        let native_args =
-            quote!(#(for var in &func.ffi_args => #(self.generate_native_num_type(var.ty.num())),));
-        let wrapped_args = quote!(#(for var in &func.ffi_args => #(self.generate_wrapped_num_type(var.ty.num())),));
+        let native_args = quote!(#(for var in &func.ffi_args join (, ) => #(self.generate_native_num_type(var.ty.num()))));
+        let wrapped_args = quote!(#(for var in &func.ffi_args join (, ) => #(self.generate_wrapped_num_type(var.ty.num()))));
         let native_ret = self.generate_native_return_type(&func.ffi_ret);
         let wrapped_ret = self.generate_wrapped_return_type(&func.ffi_ret);
         let symbol_ptr = format!("_{}Ptr", self.ident(&func.symbol));
@@ -554,19 +1054,35 @@ impl DartGenerator {
             AbiType::RefSlice(ty) | AbiType::Vec(ty) => {
                 quote!(List<#(self.generate_wrapped_num_type(*ty))>)
             }
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!(List<#(self.generate_type(ty))>),
+            AbiType::Map(key, value) => {
+                quote!(Map<#(self.generate_type(key)), #(self.generate_type(value))>)
+            }
+            AbiType::Set(ty) => quote!(Set<#(self.generate_type(ty))>),
             AbiType::Option(ty) => quote!(#(self.generate_type(&**ty))?),
-            AbiType::Result(ty) => self.generate_type(&**ty),
+            AbiType::Result(ty, _) => self.generate_type(&**ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
             AbiType::Tuple(tuple) => match tuple.len() {
                 0 => quote!(void),
                 1 => self.generate_type(&tuple[0]),
                 _ => quote!(List<dynamic>),
             },
             AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(#ty),
-            AbiType::RefIter(ty) | AbiType::Iter(ty) => quote!(Iter<#(self.generate_type(ty))>),
+            AbiType::Callback(args, ret) => {
+                let ret = match ret {
+                    Some(ret) => self.generate_type(ret),
+                    None => quote!(void),
+                };
+                quote!(#ret Function(#(for arg in args join (, ) => #(self.generate_type(arg)))))
+            }
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => quote!(Iter<#(self.generate_type(ty))>),
             AbiType::RefFuture(ty) | AbiType::Future(ty) => {
                 quote!(Future<#(self.generate_type(ty))>)
             }
-            AbiType::RefStream(ty) | AbiType::Stream(ty) => {
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
                 quote!(Stream<#(self.generate_type(ty))>)
             }
         }
@@ -614,7 +1130,7 @@ impl DartGenerator {
         if let Return::Struct(vars, name) = ret {
             quote! {
                 class #(format!("_{}", self.type_ident(name))) extends ffi.Struct {
-                    #(for (i, var) in vars.iter().enumerate() => #(self.generate_return_struct_field(i, var.ty.num())))
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(self.generate_return_struct_field(i, var.ty.num())))
                 }
             }
         } else {
@@ -640,6 +1156,10 @@ impl DartGenerator {
     fn ident(&self, s: &str) -> String {
         sanitize_identifier(&s.to_lower_camel_case())
     }
+
+    fn variant_ident(&self, err: &str, variant: &str) -> String {
+        self.type_ident(&format!("{}_{}", err, variant))
+    }
 }
 
 fn sanitize_identifier(id: &str) -> String {
@@ -728,6 +1248,17 @@ pub mod test_runner {
     use trybuild::TestCases;
 
     pub fn compile_pass(iface: &str, rust: rust::Tokens, dart: dart::Tokens) -> Result<()> {
+        compile_pass_with_declarations(iface, rust, quote!(), dart)
+    }
+
+    /// Like `compile_pass`, `declarations` are placed outside of `main`, for example to
+    /// declare a class implementing an interface.
+    pub fn compile_pass_with_declarations(
+        iface: &str,
+        rust: rust::Tokens,
+        declarations: dart::Tokens,
+        dart: dart::Tokens,
+    ) -> Result<()> {
         let iface = Interface::parse(iface)?;
         let mut rust_file = NamedTempFile::new()?;
         let rust_gen = RustGenerator::new(Abi::native());
@@ -756,6 +1287,8 @@ pub mod test_runner {
                 }
             }
 
+            #declarations
+
             void main() async {
                 final api = Api.load();
                 #dart
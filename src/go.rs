@@ -0,0 +1,1285 @@
+use crate::c::CHeaderGenerator;
+use crate::import::{Callback, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Var,
+};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+use heck::*;
+use std::collections::BTreeMap;
+
+/// Generates a cgo package calling the symbols declared by the c header.
+pub struct GoGenerator {
+    abi: Abi,
+    package: String,
+    cdylib_name: String,
+}
+
+/// How the instructions of a function return.
+struct Returns {
+    /// Returns a go value.
+    value: bool,
+    /// The returned value is a pointer.
+    option: bool,
+    /// Returns an error or a `Result` item.
+    result: bool,
+    /// Polls an iterator, future or stream, returning the item and whether
+    /// it is ready.
+    poll: Option<Tokens>,
+    /// The returned type.
+    ty: Option<AbiType>,
+}
+
+impl GoGenerator {
+    pub fn new(package: String, cdylib_name: String) -> Self {
+        Self {
+            abi: Abi::native(),
+            package,
+            cdylib_name,
+        }
+    }
+
+    pub fn generate(&self, iface: Interface) -> Tokens {
+        let header = CHeaderGenerator::default()
+            .generate(iface.clone())
+            .to_string()
+            .unwrap();
+        let callbacks = self.callbacks(&iface);
+        quote! {
+            #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("//"))
+            #(static_literal("//")) Generated by "ffi-gen".
+
+            #(self.generate_doc(&iface.doc))
+            package #(&self.package)
+
+            #(static_literal("/*"))
+            #(static_literal("#cgo")) LDFLAGS: #(format!("-l{}", self.cdylib_name))
+            #(for line in header.lines() join (#<push>) => #(line.trim_end()))
+
+            extern void ffiPostCObject(int64_t port, void *message);
+            #(for (symbol, (_, cb)) in &callbacks join (#<push>) =>
+                extern #(self.c_return_type(&cb.ffi_ret)) #(self.callback_ident(symbol))(#(for var in &cb.ffi_args join (, ) => #(self.c_num_type(var.ty.num()))));
+            )
+            #(static_literal("*/"))
+            import "C"
+
+            import (
+                "errors"
+                "iter"
+                "runtime"
+                "sync"
+                "sync/atomic"
+                "unsafe"
+            )
+
+            #(self.generate_doc(&["Result is the item of an iterator, future or stream of a fallible rust type."]))
+            type Result[T any] struct {
+                Value T
+                Err   error
+            }
+
+            var (
+                ffiPorts    sync.Map
+                ffiValues   sync.Map
+                ffiNextPort atomic.Int64
+            )
+
+            #(static_literal("//"))export ffiPostCObject
+            func ffiPostCObject(port C.int64_t, message unsafe.Pointer) {
+                if wake, ok := ffiPorts.Load(int64(port)); ok {
+                    wake.(func())()
+                }
+            }
+
+            #(static_literal("//")) Rust calls the function at this address with a port to wake a future or
+            #(static_literal("//")) stream.
+            var ffiPostCObjectAddress = int(uintptr(unsafe.Pointer(C.ffiPostCObject)))
+
+            func ffiOpenPort(wake func()) int64 {
+                port := ffiNextPort.Add(1)
+                ffiPorts.Store(port, wake)
+                return port
+            }
+
+            func ffiClosePort(port int64) {
+                ffiPorts.Delete(port)
+            }
+
+            #(static_literal("//")) ffiKeepAlive keeps value alive until rust drops it, notifying the returned
+            #(static_literal("//")) port runs the calls rust queued for it. C function pointers can not capture
+            #(static_literal("//")) go closures, so rust passes the port to the exported callback, which looks
+            #(static_literal("//")) up the value.
+            func ffiKeepAlive(value any) int64 {
+                port := ffiNextPort.Add(1)
+                ffiValues.Store(port, value)
+                ffiPorts.Store(port, func() {
+                    if C.__ffi_dispatch(C.int64_t(port)) != 0 {
+                        ffiPorts.Delete(port)
+                        ffiValues.Delete(port)
+                    }
+                })
+                return port
+            }
+
+            #(static_literal("//")) ffiWakes returns a channel receiving a value when the returned port is
+            #(static_literal("//")) notified, wakes are coalesced while nobody is waiting.
+            func ffiWakes() (chan struct{}, int64) {
+                wakes := make(chan struct{}, 1)
+                port := ffiOpenPort(func() {
+                    select {
+                    case wakes <- struct{}{}:
+                    default:
+                    }
+                })
+                return wakes, port
+            }
+
+            #(static_literal("//")) ffiBox owns a value boxed by rust, which is dropped once the box is
+            #(static_literal("//")) closed or garbage collected.
+            type ffiBox struct {
+                ptr     int
+                drop    func(int)
+                moved   bool
+                dropped bool
+            }
+
+            func newFfiBox(ptr int, drop func(int)) *ffiBox {
+                box := &ffiBox{ptr: ptr, drop: drop}
+                runtime.SetFinalizer(box, (*ffiBox).finalize)
+                return box
+            }
+
+            func (box *ffiBox) borrow() int {
+                if box.moved {
+                    panic("use after move")
+                }
+                if box.dropped {
+                    panic("use after free")
+                }
+                return box.ptr
+            }
+
+            func (box *ffiBox) move() int {
+                if box.moved {
+                    panic("can't move value twice")
+                }
+                if box.dropped {
+                    panic("use after free")
+                }
+                box.moved = true
+                runtime.SetFinalizer(box, nil)
+                return box.ptr
+            }
+
+            func (box *ffiBox) close() {
+                if box.moved {
+                    panic("can't drop moved value")
+                }
+                if box.dropped {
+                    panic("double free")
+                }
+                box.dropped = true
+                runtime.SetFinalizer(box, nil)
+                box.drop(box.ptr)
+            }
+
+            func (box *ffiBox) finalize() {
+                if !box.moved && !box.dropped {
+                    box.dropped = true
+                    box.drop(box.ptr)
+                }
+            }
+
+            #(self.generate_doc(&["Iter iterates over the items of a rust iterator."]))
+            type Iter[T any] struct {
+                box  *ffiBox
+                next func(int) (T, bool)
+            }
+
+            #(self.generate_doc(&["Next returns the next item, the second value is false once the iterator is exhausted."]))
+            func (it *Iter[T]) Next() (T, bool) {
+                defer runtime.KeepAlive(it)
+                return it.next(it.box.borrow())
+            }
+
+            #(self.generate_doc(&["All returns the remaining items, which can be ranged over."]))
+            func (it *Iter[T]) All() iter.Seq[T] {
+                return func(yield func(T) bool) {
+                    for {
+                        item, ok := it.Next()
+                        if !ok || !yield(item) {
+                            return
+                        }
+                    }
+                }
+            }
+
+            #(self.generate_doc(&["Close manually drops the iterator."]))
+            func (it *Iter[T]) Close() {
+                it.box.close()
+            }
+
+            #(static_literal("//")) ffiFuture polls a rust future on a goroutine and sends its output to
+            #(static_literal("//")) the returned channel.
+            func ffiFuture[T any](box *ffiBox, poll func(int, int, int64) (T, bool)) <-chan T {
+                out := make(chan T, 1)
+                go func() {
+                    wakes, port := ffiWakes()
+                    defer ffiClosePort(port)
+                    defer box.close()
+                    for {
+                        if item, ok := poll(box.borrow(), ffiPostCObjectAddress, port); ok {
+                            out <- item
+                            return
+                        }
+                        <-wakes
+                    }
+                }()
+                return out
+            }
+
+            #(static_literal("//")) ffiStream polls a rust stream on a goroutine and sends its items to the
+            #(static_literal("//")) returned channel, which is closed once the stream is exhausted.
+            func ffiStream[T any](box *ffiBox, poll func(int, int, int64, int64) (T, bool)) <-chan T {
+                out := make(chan T)
+                go func() {
+                    defer close(out)
+                    var done atomic.Bool
+                    wakes, port := ffiWakes()
+                    defer ffiClosePort(port)
+                    donePort := ffiOpenPort(func() {
+                        done.Store(true)
+                        select {
+                        case wakes <- struct{}{}:
+                        default:
+                        }
+                    })
+                    defer ffiClosePort(donePort)
+                    defer box.close()
+                    for !done.Load() {
+                        if item, ok := poll(box.borrow(), ffiPostCObjectAddress, port, donePort); ok {
+                            out <- item
+                        } else if !done.Load() {
+                            <-wakes
+                        }
+                    }
+                }()
+                return out
+            }
+
+            #(static_literal("//")) ffiBatchedStream polls a batched rust stream, which returns a slice of
+            #(static_literal("//")) items at a time, and sends the items one by one to the returned channel.
+            func ffiBatchedStream[T any](box *ffiBox, poll func(int, int, int64, int64) ([]T, bool)) <-chan T {
+                out := make(chan T)
+                go func() {
+                    defer close(out)
+                    for items := range ffiStream(box, poll) {
+                        for _, item := range items {
+                            out <- item
+                        }
+                    }
+                }()
+                return out
+            }
+
+            #(static_literal("//")) batchedNext adapts the next function of a batched rust iterator, which
+            #(static_literal("//")) returns a slice of items at a time, to return one item at a time.
+            func batchedNext[T any](next func(int) ([]T, bool)) func(int) (T, bool) {
+                var items []T
+                return func(ptr int) (T, bool) {
+                    for len(items) == 0 {
+                        batch, ok := next(ptr)
+                        if !ok {
+                            var zero T
+                            return zero, false
+                        }
+                        items = batch
+                    }
+                    item := items[0]
+                    items = items[1:]
+                    return item, true
+                }
+            }
+
+            func ffiAllocate(size, align int) int {
+                return int(uintptr(unsafe.Pointer(C.allocate(C.size_t(size), C.size_t(align)))))
+            }
+
+            func ffiDeallocate(ptr, size, align int) {
+                C.deallocate((*C.uint8_t)(unsafe.Pointer(uintptr(ptr))), C.size_t(size), C.size_t(align))
+            }
+
+            func ffiSlice[T any](ptr, len int) []T {
+                return unsafe.Slice((*T)(unsafe.Pointer(uintptr(ptr))), len)
+            }
+
+            func ffiString(ptr, len int) string {
+                return string(ffiSlice[byte](ptr, len))
+            }
+
+            func ffiError(ptr, len, cap int) error {
+                err := errors.New(ffiString(ptr, len))
+                if len > 0 {
+                    ffiDeallocate(ptr, cap, 1)
+                }
+                return err
+            }
+
+            #(static_literal("//")) ffiLoad reads the 8 byte slot idx of a list.
+            func ffiLoad[T any](ptr, idx int) T {
+                return *(*T)(unsafe.Pointer(uintptr(ptr) + uintptr(idx*8)))
+            }
+
+            #(static_literal("//")) ffiStore writes the 8 byte slot idx of a list.
+            func ffiStore[T any](ptr, idx int, value T) {
+                *(*T)(unsafe.Pointer(uintptr(ptr) + uintptr(idx*8))) = value
+            }
+
+            #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
+
+            #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+
+            #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+
+            #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+
+            #(for (symbol, (func, cb)) in &callbacks join (#<line>) => #(self.generate_callback(symbol, func, cb)))
+        }
+    }
+
+    /// The callbacks passed to rust by symbol, with the function calling the value they
+    /// were passed for.
+    fn callbacks(&self, iface: &Interface) -> BTreeMap<String, (Tokens, Callback)> {
+        let mut callbacks = BTreeMap::new();
+        for func in iface.imports(&self.abi) {
+            for instr in func.instr {
+                match instr {
+                    Instr::LowerCallback(in_, _, _, _, cb) => {
+                        let func = quote!(callback.(#(self.generate_type(&in_.ty))));
+                        callbacks.insert(cb.symbol.clone(), (func, *cb));
+                    }
+                    Instr::LowerTrait(in_, _, _, _, methods) => {
+                        let tr = match &in_.ty {
+                            AbiType::Trait(tr) => tr.name.clone(),
+                            _ => unreachable!(),
+                        };
+                        for (name, cb) in methods {
+                            let func = quote!(callback.(#(self.type_ident(&tr))).#(self.type_ident(&name)));
+                            callbacks.insert(cb.symbol.clone(), (func, cb));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        callbacks
+    }
+
+    /// Exported function rust calls with the port of the callback.
+    fn generate_callback(&self, symbol: &str, func: &Tokens, cb: &Callback) -> Tokens {
+        let returns = Returns {
+            value: false,
+            option: false,
+            result: false,
+            poll: None,
+            ty: None,
+        };
+        let name = self.callback_ident(symbol);
+        let call = quote!(#(func)(#(for var in &cb.args join (, ) => #(self.var(var)))));
+        let call = match &cb.ret {
+            Some(ret) => quote!(#(self.var(ret)) := #call),
+            None => call,
+        };
+        quote! {
+            #(static_literal("//"))export #(&name)
+            func #(&name)(#(for var in &cb.ffi_args join (, ) => #(self.var(var)) #(self.c_type(var))))#(if let Some(ret) = &cb.ffi_ret => #(" ")#(self.c_type(ret))) {
+                callback, _ := ffiValues.Load(int64(#(self.var(&cb.ffi_args[0]))))
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(&returns, inst)))
+                #call
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(&returns, inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret)))
+            }
+        }
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> Tokens {
+        let name = self.type_ident(&obj.name);
+        quote! {
+            #(self.generate_doc(&obj.doc))
+            type #(&name) struct {
+                box *ffiBox
+            }
+
+            #(for func in &obj.methods join (#<line>) => #(self.generate_function(func)))
+
+            #(self.generate_doc(&["Close manually drops the object."]))
+            func (self *#(&name)) Close() {
+                self.box.close()
+            }
+        }
+    }
+
+    fn generate_trait(&self, tr: AbiTrait) -> Tokens {
+        quote! {
+            #(self.generate_doc(&tr.doc))
+            type #(self.type_ident(&tr.name)) interface {
+                #(for method in &tr.methods join (#<push>) =>
+                    #(self.generate_doc(&method.doc))
+                    #(self.type_ident(&method.name))(#(for (name, ty) in &method.args join (, ) => #(self.ident(name)) #(self.generate_type(ty))))#(if let Some(ret) = &method.ret => #(" ")#(self.generate_type(ret)))
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> Tokens {
+        quote! {
+            #(self.generate_doc(&st.doc))
+            type #(self.type_ident(&st.name)) #(self.generate_fields(&st.fields))
+        }
+    }
+
+    fn generate_fields(&self, fields: &[(String, AbiType)]) -> Tokens {
+        if fields.is_empty() {
+            return quote!(struct{});
+        }
+        quote! {
+            struct {
+                #(for (name, ty) in fields join (#<push>) => #(self.type_ident(name)) #(self.generate_type(ty)))
+            }
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> Tokens {
+        let name = self.type_ident(&en.name);
+        if en.is_c_like() {
+            return quote! {
+                #(self.generate_doc(&en.doc))
+                type #(&name) uint32
+
+                const (
+                    #(for (i, variant) in en.variants.iter().enumerate() join (#<push>) =>
+                        #(self.generate_doc(&variant.doc))
+                        #(&name)#(self.type_ident(&variant.name))#(if i == 0 => #(" ")#(&name) = iota)
+                    )
+                )
+            };
+        }
+        let marker = format!("is{}", name);
+        quote! {
+            #(self.generate_doc(&en.doc))
+            type #(&name) interface {
+                #(&marker)()
+            }
+
+            #(for variant in &en.variants join (#<line>) =>
+                #(self.generate_doc(&variant.doc))
+                type #(&name)#(self.type_ident(&variant.name)) #(self.generate_fields(&variant.fields))
+
+                func (#(&name)#(self.type_ident(&variant.name))) #(&marker)() {}
+            )
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> Tokens {
+        let name = self.type_ident(&err.name);
+        let marker = format!("is{}", name);
+        quote! {
+            #(self.generate_doc(&err.doc))
+            type #(&name) interface {
+                error
+                #(&marker)()
+            }
+
+            #(for variant in &err.variants join (#<line>) =>
+                #(self.generate_doc(&variant.doc))
+                type #(&name)#(self.type_ident(&variant.name)) #(self.generate_fields(&variant.fields))
+
+                func (#(&name)#(self.type_ident(&variant.name))) #(&marker)() {}
+
+                func (#(&name)#(self.type_ident(&variant.name))) Error() string {
+                    return #(quoted(variant.name.to_snake_case().replace('_', " ")))
+                }
+            )
+        }
+    }
+
+    fn generate_function(&self, func: &AbiFunction) -> Tokens {
+        let ffi = self.abi.import(func);
+        let args = quote!(#(for (name, ty) in &ffi.abi_args join (, ) => #(self.ident(name)) #(self.generate_type(ty))));
+        let (name, returns) = match &func.ty {
+            FunctionType::Constructor(obj) if func.name == "new" => (
+                quote!(#(format!("New{}", self.type_ident(obj)))),
+                self.returns(func),
+            ),
+            FunctionType::Constructor(obj) => (
+                quote!(#(self.type_ident(obj))#(self.type_ident(&func.name))),
+                self.returns(func),
+            ),
+            FunctionType::Method(obj) => (
+                quote!((self *#(self.type_ident(obj))) #(self.type_ident(&func.name))),
+                self.returns(func),
+            ),
+            FunctionType::Function => (quote!(#(self.type_ident(&func.name))), self.returns(func)),
+            FunctionType::NextIter(_, ty)
+            | FunctionType::PollFuture(_, ty)
+            | FunctionType::PollStream(_, ty) => (
+                quote!(#(self.poll_ident(&ffi.symbol))),
+                self.poll_returns(ty),
+            ),
+        };
+        let ret = self.generate_return_type(&returns);
+        let doc = match &func.ty {
+            FunctionType::NextIter(_, _)
+            | FunctionType::PollFuture(_, _)
+            | FunctionType::PollStream(_, _) => quote!(),
+            _ => self.generate_doc(&func.doc),
+        };
+        if let Some(reason) = self.unsupported(&ffi.abi_args) {
+            let mut doc = func.doc.clone();
+            if !doc.is_empty() {
+                doc.push(String::new());
+            }
+            doc.push(format!("Deprecated: {}.", reason));
+            return quote! {
+                #(self.generate_doc(&doc))
+                func #name(#args)#ret {
+                    panic(#(quoted(reason)))
+                }
+            };
+        }
+        let mut nulls = 0;
+        let body = ffi.instr.iter().map(|instr| match instr {
+            Instr::HandleNull(var) => {
+                nulls += 1;
+                let ret = if returns.poll.is_some() && nulls == 1 {
+                    self.generate_pending(&returns)
+                } else {
+                    self.generate_null(&returns)
+                };
+                quote! {
+                    if #(self.var(var)) == 0 {
+                        #ret
+                    }
+                }
+            }
+            _ => self.generate_instr(&returns, instr),
+        });
+        let body: Vec<_> = body.collect();
+        quote! {
+            #doc
+            func #name(#args)#ret {
+                #(for instr in body join (#<push>) => #instr)
+            }
+        }
+    }
+
+    /// Futures and streams are channels, which don't own the rust value
+    /// anymore.
+    fn unsupported(&self, args: &[(String, AbiType)]) -> Option<&'static str> {
+        args.iter().find_map(|(_, ty)| match ty {
+            AbiType::RefFuture(_) | AbiType::Future(_) => {
+                Some("futures can't be passed to rust from go")
+            }
+            AbiType::RefStream(_) | AbiType::Stream(_, _) => {
+                Some("streams can't be passed to rust from go")
+            }
+            _ => None,
+        })
+    }
+
+    fn returns(&self, func: &AbiFunction) -> Returns {
+        let (ty, result) = match &func.ret {
+            Some(AbiType::Result(ty, _)) => (Some(&**ty), true),
+            ty => (ty.as_ref(), false),
+        };
+        let ty = ty.filter(|ty| !self.is_unit(ty));
+        Returns {
+            value: ty.is_some(),
+            option: matches!(ty, Some(AbiType::Option(_))),
+            result,
+            poll: None,
+            ty: ty.cloned(),
+        }
+    }
+
+    fn poll_returns(&self, ty: &AbiType) -> Returns {
+        let (inner, result) = match ty {
+            AbiType::Result(ty, _) => (&**ty, true),
+            ty => (ty, false),
+        };
+        Returns {
+            value: !self.is_unit(inner),
+            option: matches!(inner, AbiType::Option(_)),
+            result,
+            poll: Some(self.generate_item_type(ty)),
+            ty: Some(ty.clone()),
+        }
+    }
+
+    fn is_unit(&self, ty: &AbiType) -> bool {
+        matches!(ty, AbiType::Tuple(tys) if tys.is_empty())
+    }
+
+    fn generate_return_type(&self, returns: &Returns) -> Tokens {
+        if let Some(item) = &returns.poll {
+            return quote!(#(" ")(#item, bool));
+        }
+        match (returns.value, returns.result) {
+            (false, false) => quote!(),
+            (false, true) => quote!(#(" ")error),
+            (true, false) => quote!(#(" ")#(self.generate_type(returns.ty.as_ref().unwrap()))),
+            (true, true) => {
+                quote!(#(" ")(#(self.generate_type(returns.ty.as_ref().unwrap())), error))
+            }
+        }
+    }
+
+    /// Returns `value`, which is a pointer to `var` for optional values.
+    fn generate_return(&self, returns: &Returns, var: &Var) -> Tokens {
+        let value = if returns.option {
+            quote!(&#(self.var(var)))
+        } else {
+            self.var(var)
+        };
+        self.generate_return_value(returns, value)
+    }
+
+    fn generate_return_value(&self, returns: &Returns, value: Tokens) -> Tokens {
+        match (&returns.poll, returns.result) {
+            (Some(item), true) => quote!(return #item{Value: #value}, true),
+            (Some(_), false) => quote!(return #value, true),
+            (None, true) => quote!(return #value, nil),
+            (None, false) => quote!(return #value),
+        }
+    }
+
+    fn generate_void(&self, returns: &Returns) -> Tokens {
+        match (&returns.poll, returns.result) {
+            (Some(item), true) => quote!(return #item{}, true),
+            (Some(_), false) => quote!(return struct{}{}, true),
+            (None, true) => quote!(return nil),
+            (None, false) => quote!(return),
+        }
+    }
+
+    fn generate_null(&self, returns: &Returns) -> Tokens {
+        match (&returns.poll, returns.value) {
+            (Some(item), _) if returns.result => quote!(return #item{}, true),
+            (Some(_), _) => quote!(return nil, true),
+            (None, true) => self.generate_return_value(returns, quote!(nil)),
+            (None, false) => self.generate_void(returns),
+        }
+    }
+
+    fn generate_pending(&self, returns: &Returns) -> Tokens {
+        quote!(return #(self.zero_value(returns.ty.as_ref().unwrap())), false)
+    }
+
+    fn generate_error_return(&self, returns: &Returns, err: Tokens) -> Tokens {
+        match (&returns.poll, returns.value) {
+            (Some(item), _) => quote!(return #item{Err: #err}, true),
+            (None, true) => quote!(return #(self.zero_value(returns.ty.as_ref().unwrap())), #err),
+            (None, false) => quote!(return #err),
+        }
+    }
+
+    fn zero_value(&self, ty: &AbiType) -> Tokens {
+        match ty {
+            AbiType::Num(_) | AbiType::Isize | AbiType::Usize => quote!(0),
+            AbiType::Bool => quote!(false),
+            AbiType::RefStr | AbiType::String => quote!(""),
+            AbiType::Struct(_) | AbiType::Result(_, _) => {
+                quote!(#(self.generate_item_type(ty)){})
+            }
+            AbiType::Tuple(tuple) if tuple.len() == 1 => self.zero_value(&tuple[0]),
+            AbiType::Tuple(_) => quote!(#(self.generate_type(ty)){}),
+            _ => quote!(nil),
+        }
+    }
+
+    /// Items of fallible iterators, futures and streams are a `Result`.
+    fn generate_item_type(&self, ty: &AbiType) -> Tokens {
+        match ty {
+            AbiType::Result(ty, _) => quote!(Result[#(self.generate_type(ty))]),
+            ty => self.generate_type(ty),
+        }
+    }
+
+    fn generate_instr(&self, returns: &Returns, instr: &Instr) -> Tokens {
+        match instr {
+            Instr::BorrowSelf(out) => quote! {
+                #(self.var(out)) = #(self.c_type(out))(self.box.borrow())
+                defer runtime.KeepAlive(self)
+            },
+            Instr::BorrowObject(in_, out) | Instr::BorrowIter(in_, out) => quote! {
+                #(self.var(out)) = #(self.c_type(out))(#(self.var(in_)).box.borrow())
+                defer runtime.KeepAlive(#(self.var(in_)))
+            },
+            Instr::MoveObject(in_, out) | Instr::MoveIter(in_, out) => {
+                quote!(#(self.var(out)) = #(self.c_type(out))(#(self.var(in_)).box.move()))
+            }
+            Instr::BorrowFuture(_, _)
+            | Instr::MoveFuture(_, _)
+            | Instr::BorrowStream(_, _)
+            | Instr::MoveStream(_, _) => unreachable!(),
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                #(self.var(ptr)) = #(self.c_type(ptr))(uintptr(unsafe.Pointer(C.#(self.callback_ident(&cb.symbol)))))
+                #(self.var(post_cobject)) = #(self.c_type(post_cobject))(ffiPostCObjectAddress)
+                #(self.var(port)) = #(self.c_type(port))(ffiKeepAlive(#(self.var(in_))))
+            },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => quote! {
+                #(for (ptr, (_, cb)) in vtable.iter().zip(methods) join (#<push>) =>
+                    #(self.var(ptr)) = #(self.c_type(ptr))(uintptr(unsafe.Pointer(C.#(self.callback_ident(&cb.symbol)))))
+                )
+                #(self.var(post_cobject)) = #(self.c_type(post_cobject))(ffiPostCObjectAddress)
+                #(self.var(port)) = #(self.c_type(port))(ffiKeepAlive(#(self.var(in_))))
+            },
+            Instr::LiftObject(obj, box_, drop, out) => quote! {
+                #(self.var(out)) := &#(self.type_ident(obj)){box: newFfiBox(int(#(self.var(box_))), func(ptr int) {
+                    C.#drop(0, #(self.c_type(box_))(ptr))
+                })}
+            },
+            Instr::BindArg(arg, out) => quote!(#(self.var(out)) := #(self.ident(arg))),
+            Instr::BindRets(ret, vars) => match vars.len() {
+                0 => quote!(_ = #(self.var(ret))),
+                1 => quote!(#(self.var(&vars[0])) := #(self.var(ret))),
+                _ => quote! {
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
+                        #(self.var(var)) := #(self.var(ret)).#(format!("ret{}", idx)))
+                },
+            },
+            Instr::LowerNum(in_, out, _) => {
+                quote!(#(self.var(out)) = #(self.c_type(out))(#(self.var(in_))))
+            }
+            Instr::LiftNum(in_, out, num) => {
+                let ty = match &out.ty {
+                    AbiType::Num(num) => self.generate_num_type(*num),
+                    AbiType::Isize | AbiType::Usize => self.generate_type(&out.ty),
+                    _ => self.generate_num_type(*num),
+                };
+                quote!(#(self.var(out)) := #ty(#(self.var(in_))))
+            }
+            Instr::LowerBool(in_, out) => quote! {
+                if #(self.var(in_)) {
+                    #(self.var(out)) = 1
+                }
+            },
+            Instr::LiftBool(in_, out) => quote!(#(self.var(out)) := #(self.var(in_)) > 0),
+            Instr::Deallocate(ptr, len, size, align) => quote! {
+                if #(self.var(len)) > 0 {
+                    ffiDeallocate(int(#(self.var(ptr))), int(#(self.var(len)))*#(*size), #(*align))
+                }
+            },
+            Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
+                #(self.var(len)) = #(self.c_type(len))(len(#(self.var(in_))))
+                #(self.var(ptr)) = #(self.c_type(ptr))(ffiAllocate(int(#(self.var(len)))*#(*size), #(*align)))
+                copy(ffiSlice[byte](int(#(self.var(ptr))), int(#(self.var(len)))), #(self.var(in_)))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftString(ptr, len, out) => quote! {
+                #(self.var(out)) := ffiString(int(#(self.var(ptr))), int(#(self.var(len))))
+            },
+            Instr::LowerVec(in_, ptr, len, cap, ty, size, align) => quote! {
+                #(self.var(len)) = #(self.c_type(len))(len(#(self.var(in_))))
+                #(self.var(ptr)) = #(self.c_type(ptr))(ffiAllocate(int(#(self.var(len)))*#(*size), #(*align)))
+                copy(ffiSlice[#(self.generate_num_type(*ty))](int(#(self.var(ptr))), int(#(self.var(len)))), #(self.var(in_)))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftVec(ptr, len, out, ty) => quote! {
+                #(self.var(out)) := append([]#(self.generate_num_type(*ty)){}, ffiSlice[#(self.generate_num_type(*ty))](int(#(self.var(ptr))), int(#(self.var(len))))...)
+            },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match self.collection_type(&out.ty) {
+                    AbiType::Map(key, value) => (
+                        quote!(make(map[#(self.generate_type(key))]#(self.generate_type(value)), int(#(self.var(len))))),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1]))),
+                    ),
+                    AbiType::Set(ty) => (
+                        quote!(make(map[#(self.generate_type(ty))]struct{}, int(#(self.var(len))))),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = struct{}{}),
+                    ),
+                    _ => (
+                        quote!(make([]#(self.generate_type(&elems[0].ty)), 0, int(#(self.var(len))))),
+                        quote!(#(self.var(out)) = append(#(self.var(out)), #(self.var(&elems[0])))),
+                    ),
+                };
+                quote! {
+                    #(self.var(out)) := #collection
+                    for #(self.var(out))_0 := 0; #(self.var(out))_0 < int(#(self.var(len))); #(self.var(out))_0++ {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            #(self.var(var)) := ffiLoad[#(self.c_type(var))](int(#(self.var(ptr))), #(self.var(out))_0*#n+#i)
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                        #add
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let range = match self.collection_type(&in_.ty) {
+                    AbiType::Map(_, _) => {
+                        quote!(#(self.var(&elems[0])), #(self.var(&elems[1])) := range)
+                    }
+                    AbiType::Set(_) => quote!(#(self.var(&elems[0])) := range),
+                    _ => quote!(_, #(self.var(&elems[0])) := range),
+                };
+                quote! {
+                    #(self.var(len)) = #(self.c_type(len))(len(#(self.var(in_))))
+                    if #(self.var(len)) > 0 {
+                        #(self.var(ptr)) = #(self.c_type(ptr))(ffiAllocate(int(#(self.var(len)))*#(n * 8), 8))
+                    }
+                    #(self.var(in_))_0 := 0
+                    for #range #(self.var(in_)) {
+                        #(self.generate_instr(returns, &Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            ffiStore(int(#(self.var(ptr))), #(self.var(in_))_0*#n+#i, #(self.var(var)))
+                        )
+                        #(self.var(in_))_0++
+                    }
+                }
+            }
+            Instr::Call(symbol, ret, args) => {
+                let invoke = quote!(C.#(symbol)(#(for arg in args join (, ) => #(self.var(arg)))));
+                if let Some(ret) = ret {
+                    quote!(#(self.var(ret)) := #invoke)
+                } else {
+                    invoke
+                }
+            }
+            Instr::DefineArgs(vars) => quote! {
+                #(for var in vars join (#<push>) => var #(self.var(var)) #(self.c_type(var)))
+            },
+            Instr::ReturnValue(ret) => self.generate_return(returns, ret),
+            Instr::ReturnVoid => self.generate_void(returns),
+            Instr::HandleNull(var) => quote! {
+                if #(self.var(var)) == 0 {
+                    #(self.generate_null(returns))
+                }
+            },
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                var #(self.var(out)) #(self.generate_type(&out.ty))
+                if #(self.var(var)) != 0 {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                    #(self.var(out)) = &#(self.var(some))
+                }
+            },
+            Instr::LowerOption(arg, var, some, some_instr) => quote! {
+                if #(self.var(arg)) != nil {
+                    #(self.var(var)) = 1
+                    #(self.var(some)) := *#(self.var(arg))
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                }
+            },
+            Instr::HandleError(var, ptr, len, cap) => quote! {
+                if #(self.var(var)) == 0 {
+                    #(self.generate_error_return(
+                        returns,
+                        quote!(ffiError(int(#(self.var(ptr))), int(#(self.var(len))), int(#(self.var(cap))))),
+                    ))
+                }
+            },
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if #(self.var(var)) == 0 {
+                    switch #(self.var(tag)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        case #i:
+                            #(self.read_payload(payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                            #(self.generate_error_return(
+                                returns,
+                                quote!(#(self.type_ident(err))#(self.type_ident(variant)){#(for (name, field) in fields join (, ) => #(self.type_ident(name)): #(self.var(field)))}),
+                            ))
+                    )
+                    }
+                    panic(#(quoted(format!("unknown variant of {}", err))))
+                }
+            },
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = #(self.c_type(tag))(#(self.var(in_))));
+                }
+                quote! {
+                    switch #(self.var(in_))_0 := #(self.var(in_)).(type) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        case #(self.type_ident(name))#(self.type_ident(variant)):
+                            #(self.var(tag)) = #i
+                            #(for (field, var) in fields join (#<push>) => #(self.var(var)) := #(self.var(in_))_0.#(self.type_ident(field)))
+                            #(self.generate_instr(returns, &Instr::DefineArgs(ffi.clone())))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                            #(self.write_payload(payload, ffi))
+                    )
+                    }
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(out)) := #(self.type_ident(name))(#(self.var(tag))));
+                }
+                quote! {
+                    var #(self.var(out)) #(self.type_ident(name))
+                    switch #(self.var(tag)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        case #i:
+                            #(self.read_payload(payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(returns, inst)))
+                            #(self.var(out)) = #(self.type_ident(name))#(self.type_ident(variant)){#(for (field, var) in fields join (, ) => #(self.type_ident(field)): #(self.var(var)))}
+                    )
+                    default:
+                        panic(#(quoted(format!("unknown variant of {}", name))))
+                    }
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let next = if *batched {
+                    quote!(batchedNext(#(self.poll_ident(next))))
+                } else {
+                    quote!(#(self.poll_ident(next)))
+                };
+                quote! {
+                    #(self.var(out)) := &Iter[#(self.generate_item_type(self.element_type(&out.ty)))]{
+                        box: newFfiBox(int(#(self.var(box_))), func(ptr int) {
+                            C.#drop(0, #(self.c_type(box_))(ptr))
+                        }),
+                        next: #next,
+                    }
+                }
+            }
+            Instr::LiftFuture(box_, poll, drop, out) => quote! {
+                #(self.var(out)) := ffiFuture(newFfiBox(int(#(self.var(box_))), func(ptr int) {
+                    C.#drop(0, #(self.c_type(box_))(ptr))
+                }), #(self.poll_ident(poll)))
+            },
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let stream = if *batched {
+                    quote!(ffiBatchedStream)
+                } else {
+                    quote!(ffiStream)
+                };
+                quote! {
+                    #(self.var(out)) := #stream(newFfiBox(int(#(self.var(box_))), func(ptr int) {
+                        C.#drop(0, #(self.c_type(box_))(ptr))
+                    }), #(self.poll_ident(poll)))
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(#(self.var(&vars[0])) := #(self.var(in_))),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(self.var(var)) := #(self.var(in_)).#(format!("F{}", i)))
+                },
+            },
+            Instr::LiftTuple(vars, out) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(#(self.var(out)) := #(self.var(&vars[0]))),
+                _ => quote! {
+                    #(self.var(out)) := #(self.generate_type(self.collection_type(&out.ty))){#(for var in vars join (, ) => #(self.var(var)))}
+                },
+            },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => #(self.var(var)) := #(self.var(in_)).#(self.type_ident(field)))
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                #(self.var(out)) := #(self.type_ident(name)){#(for (field, var) in vars join (, ) => #(self.type_ident(field)): #(self.var(var)))}
+            },
+            Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
+        }
+    }
+
+    /// The value of a variable, without the option or result it is returned
+    /// in.
+    fn collection_type<'a>(&self, ty: &'a AbiType) -> &'a AbiType {
+        match ty {
+            AbiType::Option(ty) | AbiType::Result(ty, _) => self.collection_type(ty),
+            ty => ty,
+        }
+    }
+
+    /// Items of an iterator, future or stream.
+    fn element_type<'a>(&self, ty: &'a AbiType) -> &'a AbiType {
+        match self.collection_type(ty) {
+            AbiType::RefIter(ty)
+            | AbiType::Iter(ty, _)
+            | AbiType::RefFuture(ty)
+            | AbiType::Future(ty)
+            | AbiType::RefStream(ty)
+            | AbiType::Stream(ty, _) => ty,
+            ty => ty,
+        }
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                #(self.var(var)) := ffiLoad[#(self.c_type(var))](int(#(self.var(payload))), #i)
+            )
+            ffiDeallocate(int(#(self.var(payload))), #(ffi.len() * 8), 8)
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = #(self.c_type(payload))(ffiAllocate(#(ffi.len() * 8), 8))
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                ffiStore(int(#(self.var(payload))), #i, #(self.var(var)))
+            )
+        }
+    }
+
+    fn var(&self, var: &Var) -> Tokens {
+        quote!(#(format!("tmp{}", var.binding)))
+    }
+
+    fn c_type(&self, var: &Var) -> Tokens {
+        quote!(#(format!("C.{}", self.c_num_type(var.ty.num()))))
+    }
+
+    fn c_num_type(&self, ty: NumType) -> &'static str {
+        match ty {
+            NumType::I8 => "int8_t",
+            NumType::I16 => "int16_t",
+            NumType::I32 => "int32_t",
+            NumType::I64 => "int64_t",
+            NumType::U8 => "uint8_t",
+            NumType::U16 => "uint16_t",
+            NumType::U32 => "uint32_t",
+            NumType::U64 => "uint64_t",
+            NumType::F32 => "float",
+            NumType::F64 => "double",
+        }
+    }
+
+    fn c_return_type(&self, ret: &Option<Var>) -> &'static str {
+        match ret {
+            Some(ret) => self.c_num_type(ret.ty.num()),
+            None => "void",
+        }
+    }
+
+    fn generate_type(&self, ty: &AbiType) -> Tokens {
+        match ty {
+            AbiType::Num(ty) => self.generate_num_type(*ty),
+            AbiType::Isize => quote!(int),
+            AbiType::Usize => quote!(uint),
+            AbiType::Bool => quote!(bool),
+            AbiType::RefStr | AbiType::String => quote!(string),
+            AbiType::RefSlice(ty) | AbiType::Vec(ty) => quote!([]#(self.generate_num_type(*ty))),
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!([]#(self.generate_type(ty))),
+            AbiType::Map(key, value) => {
+                quote!(map[#(self.generate_type(key))]#(self.generate_type(value)))
+            }
+            AbiType::Set(ty) => quote!(map[#(self.generate_type(ty))]struct{}),
+            AbiType::Option(ty) => quote!(*#(self.generate_type(ty))),
+            AbiType::Result(ty, _) => self.generate_type(ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
+            AbiType::Tuple(tuple) => match tuple.len() {
+                0 => quote!(struct{}),
+                1 => self.generate_type(&tuple[0]),
+                _ => quote! {
+                    struct{ #(for (i, ty) in tuple.iter().enumerate() join (; ) => #(format!("F{}", i)) #(self.generate_type(ty))) }
+                },
+            },
+            AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(*#(self.type_ident(ty))),
+            AbiType::Callback(args, ret) => quote! {
+                func(#(for arg in args join (, ) => #(self.generate_type(arg))))#(if let Some(ret) = ret => #(" ")#(self.generate_type(ret)))
+            },
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => {
+                quote!(*Iter[#(self.generate_item_type(ty))])
+            }
+            AbiType::RefFuture(ty) | AbiType::Future(ty) => {
+                quote!(<-chan #(self.generate_item_type(ty)))
+            }
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
+                quote!(<-chan #(self.generate_item_type(ty)))
+            }
+        }
+    }
+
+    fn generate_num_type(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 => quote!(int8),
+            NumType::I16 => quote!(int16),
+            NumType::I32 => quote!(int32),
+            NumType::I64 => quote!(int64),
+            NumType::U8 => quote!(uint8),
+            NumType::U16 => quote!(uint16),
+            NumType::U32 => quote!(uint32),
+            NumType::U64 => quote!(uint64),
+            NumType::F32 => quote!(float32),
+            NumType::F64 => quote!(float64),
+        }
+    }
+
+    fn generate_doc<S: AsRef<str>>(&self, doc: &[S]) -> Tokens {
+        quote!(#(for line in doc => #(static_literal("//")) #(line.as_ref().trim()) #<push>))
+    }
+
+    /// Exported identifiers can't be `C`, which refers to the cgo package.
+    fn type_ident(&self, s: &str) -> String {
+        match s.to_upper_camel_case() {
+            id if id == "C" => "C_".to_string(),
+            id => id,
+        }
+    }
+
+    fn ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_lower_camel_case())
+    }
+
+    /// Exported function called by rust for the callback `symbol`.
+    fn callback_ident(&self, symbol: &str) -> String {
+        format!("ffi{}", symbol.to_upper_camel_case())
+    }
+
+    /// Function polling the iterator, future or stream of `symbol`.
+    fn poll_ident(&self, symbol: &str) -> String {
+        format!("ffi{}", symbol.to_upper_camel_case())
+    }
+}
+
+/// Formats the generated file, indenting with tabs like `gofmt`.
+pub fn to_file_string(tokens: &Tokens) -> genco::fmt::Result<String> {
+    let mut w = genco::fmt::FmtWriter::new(String::new());
+    let fmt = genco::fmt::Config::from_lang::<()>().with_indentation(genco::fmt::Indentation::Tab);
+    tokens.format_file(&mut w.as_formatter(&fmt), &())?;
+    Ok(w.into_inner())
+}
+
+fn sanitize_identifier(id: &str) -> String {
+    if RESERVED_IDENTIFIERS.contains(&id) {
+        format!("{}_", id)
+    } else {
+        id.to_string()
+    }
+}
+
+// https://go.dev/ref/spec#Keywords
+static RESERVED_IDENTIFIERS: [&str; 25] = [
+    "break",
+    "case",
+    "chan",
+    "const",
+    "continue",
+    "default",
+    "defer",
+    "else",
+    "fallthrough",
+    "for",
+    "func",
+    "go",
+    "goto",
+    "if",
+    "import",
+    "interface",
+    "map",
+    "package",
+    "range",
+    "return",
+    "select",
+    "struct",
+    "switch",
+    "type",
+    "var",
+];
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, go: Tokens) -> Result<()> {
+        if crate::test_runner::skip_without("go") {
+            return Ok(());
+        }
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native());
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let go_gen = GoGenerator::new("main".to_string(), "compile_pass".to_string());
+        let go_tokens = go_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let bin_tokens: Tokens = quote! {
+            package main
+
+            func main() {
+                #go
+            }
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        std::fs::write(
+            dir.as_ref().join("go.mod"),
+            "module compile_pass\n\ngo 1.23\n",
+        )?;
+        let bindings_file = dir.as_ref().join("bindings.go");
+        std::fs::write(&bindings_file, to_file_string(&go_tokens)?)?;
+        let main_file = dir.as_ref().join("main.go");
+        std::fs::write(&main_file, to_file_string(&bin_tokens)?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let bin_file = dir.as_ref().join("main");
+        let ret = Command::new("go")
+            .current_dir(dir.as_ref())
+            .env("CGO_ENABLED", "1")
+            .env("CGO_LDFLAGS", format!("-L{}", dir.as_ref().display()))
+            .arg("build")
+            .arg("-o")
+            .arg(&bin_file)
+            .arg(".")
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile go program");
+        let ret = Command::new(&bin_file)
+            .env("LD_LIBRARY_PATH", dir.as_ref())
+            .status()?;
+        anyhow::ensure!(ret.success(), "go test failed");
+        Ok(())
+    }
+}
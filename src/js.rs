@@ -1,29 +1,81 @@
-use crate::import::Instr;
-use crate::{Abi, AbiFunction, AbiObject, AbiType, FunctionType, Interface, NumType, Return, Var};
-use anyhow::Result;
+use crate::import::{Callback, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Return, Var,
+};
+use anyhow::{Context, Result};
 use genco::prelude::*;
 use genco::tokens::static_literal;
 use heck::*;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::path::Path;
-use std::process::Command;
+use wasm_encoder::reencode::{self, Reencode, RoundtripReencoder};
+use wasm_encoder::{
+    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, MemArg,
+    TypeSection, ValType,
+};
 
 pub struct JsGenerator {
     abi: Abi,
+    return_pointer: bool,
 }
 
 impl Default for JsGenerator {
     fn default() -> Self {
-        Self { abi: Abi::Wasm32 }
+        Self {
+            abi: Abi::Wasm32,
+            return_pointer: false,
+        }
+    }
+}
+
+impl JsGenerator {
+    /// Bindings loading the native cdylib with the Node-API module generated
+    /// by `NapiGenerator` instead of a wasm blob.
+    pub fn node() -> Self {
+        Self {
+            abi: Abi::Native64,
+            return_pointer: false,
+        }
+    }
+
+    /// Bindings for the rust api generated by
+    /// `RustGenerator::with_return_pointer`, reading returned structs from
+    /// the wasm memory.
+    pub fn with_return_pointer() -> Self {
+        Self {
+            abi: Abi::Wasm32,
+            return_pointer: true,
+        }
+    }
+
+    fn is_node(&self) -> bool {
+        matches!(self.abi, Abi::Native32 | Abi::Native64)
     }
 }
 
 pub struct TsGenerator {
     docs: bool,
+    node: bool,
 }
 
 impl Default for TsGenerator {
     fn default() -> Self {
-        Self { docs: true }
+        Self {
+            docs: true,
+            node: false,
+        }
+    }
+}
+
+impl TsGenerator {
+    /// Type definitions for the bindings generated by `JsGenerator::node`.
+    pub fn node() -> Self {
+        Self {
+            docs: true,
+            node: true,
+        }
     }
 }
 
@@ -136,19 +188,39 @@ impl TsGenerator {
               #(self.gen_doc(&["API constructor.","","@returns an `Api` instance."]))
               constructor();
 
-              #(self.gen_doc(&["Initialize the API.", "", "@returns a promise resolved when initialization is done."]))
-              fetch(url, imports): Promise<void>;
+              #(if self.node {
+                  #(self.gen_doc(&["Initialize the API by loading the native library at `path`."]))
+                  load(path: string): void;
+              } else {
+                  #(self.gen_doc(&["Initialize the API.", "", "@returns a promise resolved when initialization is done."]))
+                  fetch(url, imports): Promise<void>;
+              })
 
               #(for func in iface.functions() join (#<line>#<line>) => #(self.generate_function(func)))
             }
 
-            #(for obj in iface.objects() => #(self.generate_object(obj)))
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
         }
     }
 
     fn generate_function(&self, func: AbiFunction) -> js::Tokens {
         let ffi = Abi::Wasm32.import(&func);
-        let args = self.generate_args(&ffi.abi_args);
+        let mut args = self.generate_args(&ffi.abi_args);
+        if func.returns_future() {
+            if !ffi.abi_args.is_empty() {
+                args.append(quote!(,));
+                args.space();
+            }
+            args.append(quote!(signal?: AbortSignal));
+        }
         let ret = self.generate_return_type(ffi.abi_ret.as_ref());
         let name = self.ident(&func.name);
         let fun = match &func.ty {
@@ -197,6 +269,15 @@ impl TsGenerator {
                     let inner = self.generate_return_type(Some(&AbiType::Num(*prim)));
                     quote!(Array<#inner>)
                 }
+                AbiType::RefList(ty) | AbiType::List(ty) => {
+                    quote!(Array<#(self.generate_return_type(Some(ty)))>)
+                }
+                AbiType::Map(key, value) => {
+                    let key = self.generate_return_type(Some(key));
+                    let value = self.generate_return_type(Some(value));
+                    quote!(Map<#key, #value>)
+                }
+                AbiType::Set(ty) => quote!(Set<#(self.generate_return_type(Some(ty)))>),
                 AbiType::RefObject(i) | AbiType::Object(i) => {
                     quote!(#(self.type_ident(i)))
                 }
@@ -204,8 +285,16 @@ impl TsGenerator {
                     let inner = self.generate_return_type(Some(i));
                     quote!(#inner?)
                 }
-                AbiType::Result(i) => quote!(#(self.generate_return_type(Some(i)))),
-                AbiType::RefIter(i) | AbiType::Iter(i) => {
+                AbiType::Result(i, _) => quote!(#(self.generate_return_type(Some(i)))),
+                AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+                AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+                AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+                AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
+                AbiType::Callback(args, ret) => {
+                    let ret = self.generate_return_type(ret.as_deref());
+                    quote!((#(for (i, arg) in args.iter().enumerate() join (, ) => #(format!("arg{}", i)): #(self.generate_return_type(Some(arg))))) => #ret)
+                }
+                AbiType::RefIter(i) | AbiType::Iter(i, _) => {
                     let inner = self.generate_return_type(Some(i));
                     quote!(Iterable<#inner>)
                 }
@@ -213,7 +302,7 @@ impl TsGenerator {
                     let inner = self.generate_return_type(Some(i));
                     quote!(Promise<#inner>)
                 }
-                AbiType::RefStream(i) | AbiType::Stream(i) => {
+                AbiType::RefStream(i) | AbiType::Stream(i, _) => {
                     let inner = self.generate_return_type(Some(i));
                     quote!(ReadableStream<#inner>)
                 }
@@ -240,6 +329,63 @@ impl TsGenerator {
         }
     }
 
+    fn generate_trait(&self, tr: AbiTrait) -> js::Tokens {
+        quote! {
+            #(self.gen_doc(tr.doc))
+            export interface #(self.type_ident(&tr.name)) {
+                #(for method in tr.methods join (#<line>) =>
+                    #(self.gen_doc(method.doc))
+                    #(self.ident(&method.name))(#(self.generate_args(&method.args))): #(self.generate_return_type(method.ret.as_ref()));
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> js::Tokens {
+        quote! {
+            #(self.gen_doc(st.doc))
+            export interface #(self.type_ident(&st.name)) {
+                #(for (name, ty) in &st.fields join (#<push>) => #(self.ident(name)): #(self.generate_return_type(Some(ty)));)
+            }
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> js::Tokens {
+        let variants = quote!(#(for variant in &en.variants join ( | ) =>
+            #(if en.is_c_like() {
+                #(quoted(&variant.name))
+            } else {
+                { kind: #(quoted(&variant.name));#(for (name, ty) in &variant.fields => #<space>#(self.ident(name)): #(self.generate_return_type(Some(ty)));) }
+            })
+        ));
+        quote! {
+            #(self.gen_doc(en.doc))
+            export type #(self.type_ident(&en.name)) = #variants;
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> js::Tokens {
+        let name = self.type_ident(&err.name);
+        let variant_ident = |variant: &str| self.type_ident(&format!("{}_{}", err.name, variant));
+        quote! {
+            #(for variant in &err.variants join (#<line>) =>
+                #(self.gen_doc(variant.doc.clone()))
+                export interface #(variant_ident(&variant.name)) extends Error {
+                    kind: #(quoted(&variant.name));
+                    #(for (name, ty) in &variant.fields join (#<push>) => #(self.ident(name)): #(self.generate_return_type(Some(ty)));)
+                }
+            )
+
+            #(self.gen_doc(err.doc))
+            export type #(&name) = #(for variant in &err.variants join ( | ) => #(variant_ident(&variant.name)));
+
+            export const #(&name): {
+                new (kind: #(&name)["kind"], fields?: object): #(&name);
+                prototype: #(&name);
+            };
+        }
+    }
+
     fn type_ident(&self, s: &str) -> String {
         sanitize_identifier(&s.to_upper_camel_case())
     }
@@ -251,6 +397,7 @@ impl TsGenerator {
 
 impl JsGenerator {
     pub fn generate(&self, iface: Interface) -> js::Tokens {
+        let return_area = self.generate_return_area(&iface);
         quote! {
             #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
             #(static_literal("//"))
@@ -258,66 +405,11 @@ impl JsGenerator {
             #(static_literal("/* tslint:disable */"))
             #(static_literal("/* eslint:disable */"))
 
-            // a node fetch polyfill that won't trigger webpack or other bundlers
-            // idea borrowed from:
-            // https://github.com/dcodeIO/webassembly/blob/master/src/index.js#L223
-            let fs;
-            const fetch_polyfill = async (file) => {
-                const readFile = await eval("mport('fs')".replace(/^/, 'i'))
-                    .then(({ readFile }) => readFile);
-                return new Promise((resolve, reject) => {
-                    readFile(
-                        file,
-                        function(err, data) {
-                            return (err)
-                                ? reject(err)
-                                : resolve({
-                                    arrayBuffer: () => Promise.resolve(data),
-                                    ok: true,
-                                });
-                        }
-                        );
-                });
-            }
-
-            let ReadableStream;
-            if (typeof window == "object") {
-                ReadableStream = window.ReadableStream;
-                #(static_literal("// Workaround for combined use with `wasm-bindgen`, so we don't have to"))
-                #(static_literal("// patch the `importObject` while loading the WASM module."))
-                window.__notifier_callback = (idx) => notifierRegistry.callbacks[idx]();
+            #(if self.is_node() {
+                #(self.generate_node_loader())
             } else {
-                eval("mport('node:stream/web')".replace(/^/, 'i')).then(pkg => {
-                    ReadableStream = pkg.ReadableStream;
-                });
-                #(static_literal("// Workaround for combined use with `wasm-bindgen`, so we don't have to"))
-                #(static_literal("// patch the `importObject` while loading the WASM module."))
-                global.__notifier_callback = (idx) => notifierRegistry.callbacks[idx]();
-            };
-
-            const fetchFn = (typeof fetch === "function" && fetch) || fetch_polyfill;
-
-            // gets the wasm at a url and instantiates it.
-            // checks if streaming instantiation is available and uses that
-            function fetchAndInstantiate(url, imports) {
-                const env = imports.env || {};
-                env.__notifier_callback = (idx) => notifierRegistry.callbacks[idx]();
-                imports.env = env;
-                return fetchFn(url)
-                    .then((resp) => {
-                        if (!resp.ok) {
-                            throw new Error("Got a ${resp.status} fetching wasm @ ${url}");
-                        }
-
-                        const wasm = "application/wasm";
-                        const type = resp.headers && resp.headers.get("content-type");
-
-                        return (WebAssembly.instantiateStreaming && type === wasm)
-                            ? WebAssembly.instantiateStreaming(resp, imports)
-                            : resp.arrayBuffer().then(buf => WebAssembly.instantiate(buf, imports));
-                        })
-                        .then(result => result.instance);
-            }
+                #(self.generate_wasm_loader(&iface))
+            })
 
             const dropRegistry = new FinalizationRegistry(drop => drop());
 
@@ -379,84 +471,187 @@ impl JsGenerator {
 
                 registerNotifier(idx, notifier) {
                     this.callbacks[idx] = notifier;
+                    #(if self.is_node() {
+                        #(static_literal("// rust wakes pending futures from other threads, so node must not exit"))
+                        if (this.timer === undefined) {
+                            this.timer = setInterval(() => {}, 1 << 30);
+                        }
+                    })
+                }
+
+                #(static_literal("// Wakers can outlive a cancelled future, so unknown slots are ignored."))
+                notify(idx) {
+                    const notifier = this.callbacks[idx];
+                    if (notifier) {
+                        notifier();
+                    }
                 }
 
                 unregisterNotifier(idx) {
                     delete this.callbacks[idx];
+                    #(if self.is_node() {
+                        if (this.timer !== undefined && Object.keys(this.callbacks).length === 0) {
+                            clearInterval(this.timer);
+                            this.timer = undefined;
+                        }
+                    })
                 }
             }
 
             const notifierRegistry = new NotifierRegistry();
 
-            const nativeFuture = (box, nativePoll) => {
-                const poll = (resolve, reject, idx) => {
-                    try {
-                        const ret = nativePoll(box.borrow(), 0, BigInt(idx));
-                        if (ret == null) {
-                            return;
-                        }
-                        resolve(ret);
-                    } catch(err) {
-                        reject(err);
-                    }
-                    notifierRegistry.unregisterNotifier(idx);
-                    box.drop();
-                };
+            const nativeFuture = (box, nativePoll, signal) => {
                 return new Promise((resolve, reject) => {
+                    if (signal && signal.aborted) {
+                        box.drop();
+                        reject(signal.reason);
+                        return;
+                    }
                     const idx = notifierRegistry.reserveSlot();
-                    const notifier = () => poll(resolve, reject, idx);
-                    notifierRegistry.registerNotifier(idx, notifier);
-                    poll(resolve, reject, idx);
+                    const abort = () => {
+                        notifierRegistry.unregisterNotifier(idx);
+                        box.drop();
+                        reject(signal.reason);
+                    };
+                    const poll = () => {
+                        try {
+                            const ret = nativePoll(box.borrow(), 0, BigInt(idx));
+                            if (ret == null) {
+                                return;
+                            }
+                            resolve(ret);
+                        } catch(err) {
+                            reject(err);
+                        }
+                        if (signal) {
+                            signal.removeEventListener("abort", abort);
+                        }
+                        notifierRegistry.unregisterNotifier(idx);
+                        box.drop();
+                    };
+                    if (signal) {
+                        signal.addEventListener("abort", abort, { once: true });
+                    }
+                    notifierRegistry.registerNotifier(idx, poll);
+                    poll();
                 });
             };
 
-            function* nativeIter(box, nxt) {
+            function* nativeIter(box, nxt, batched) {
                 let el;
                 while(true) {
                     el = nxt(box.borrow());
                     if (el === null) {
                         break;
                     }
-                    yield el;
+                    if (batched) {
+                        yield* el;
+                    } else {
+                        yield el;
+                    }
                 }
                 box.drop();
             }
 
-            const nativeStream = (box, nativePoll) => {
-                const poll = (next, nextIdx, doneIdx) => {
-                    const ret = nativePoll(box.borrow(), 0, BigInt(nextIdx), BigInt(doneIdx));
-                    if (ret != null) {
-                        next(ret);
+            #(static_literal("// Rust is only polled when the stream is pulled, so the queuing strategy"))
+            #(static_literal("// of the stream applies backpressure to the rust stream."))
+            const nativeStream = (box, nativePoll, batched) => {
+                let nextIdx, doneIdx;
+                let waiting = null;
+                let woken = false;
+                let finished = false;
+                let closed = false;
+                const wake = () => {
+                    woken = true;
+                    if (waiting) {
+                        const poll = waiting;
+                        waiting = null;
+                        setTimeout(poll, 0);
                     }
                 };
+                const close = () => {
+                    if (closed) {
+                        return;
+                    }
+                    closed = true;
+                    notifierRegistry.unregisterNotifier(nextIdx);
+                    notifierRegistry.unregisterNotifier(doneIdx);
+                    box.drop();
+                };
                 return new ReadableStream({
-                    start(controller) {
-                        const nextIdx = notifierRegistry.reserveSlot();
-                        const doneIdx = notifierRegistry.reserveSlot();
-                        const nextNotifier = () => setTimeout(() =>
-                            poll(x => controller.enqueue(x), nextIdx, doneIdx),
-                            0);
-                        const doneNotifier = () => {
-                            notifierRegistry.unregisterNotifier(nextIdx);
-                            notifierRegistry.unregisterNotifier(doneIdx);
-                            controller.close();
-                            box.drop();
-                        };
-                        notifierRegistry.registerNotifier(nextIdx, nextNotifier);
-                        notifierRegistry.registerNotifier(doneIdx, doneNotifier);
-                        nextNotifier();
+                    start() {
+                        nextIdx = notifierRegistry.reserveSlot();
+                        doneIdx = notifierRegistry.reserveSlot();
+                        notifierRegistry.registerNotifier(nextIdx, wake);
+                        notifierRegistry.registerNotifier(doneIdx, () => {
+                            finished = true;
+                            wake();
+                        });
+                    },
+                    pull(controller) {
+                        return new Promise((resolve) => {
+                            const poll = () => {
+                                if (closed) {
+                                    resolve();
+                                    return;
+                                }
+                                if (finished) {
+                                    close();
+                                    controller.close();
+                                    resolve();
+                                    return;
+                                }
+                                woken = false;
+                                try {
+                                    const ret = nativePoll(box.borrow(), 0, BigInt(nextIdx), BigInt(doneIdx));
+                                    if (ret == null) {
+                                        if (woken) {
+                                            setTimeout(poll, 0);
+                                        } else {
+                                            waiting = poll;
+                                        }
+                                        return;
+                                    }
+                                    if (batched) {
+                                        for (const item of ret) {
+                                            controller.enqueue(item);
+                                        }
+                                    } else {
+                                        controller.enqueue(ret);
+                                    }
+                                } catch(err) {
+                                    close();
+                                    controller.error(err);
+                                }
+                                resolve();
+                            };
+                            poll();
+                        });
+                    },
+                    cancel() {
+                        close();
                     },
                 });
             };
 
             export class Api {
-                async fetch(url, imports) {
-                    this.instance = await fetchAndInstantiate(url, imports);
-                }
+                #(if self.is_node() {
+                    load(path) {
+                        const module = { exports: {} };
+                        process.dlopen(module, path);
+                        this.instance = { exports: module.exports };
+                    }
+                } else {
+                    async fetch(url, imports) {
+                        this.instance = await fetchAndInstantiate(url, imports);
+                        #(&return_area)
+                    }
 
-                initWithInstance(instance) {
-                    this.instance = instance;
-                }
+                    initWithInstance(instance) {
+                        this.instance = instance;
+                        #(&return_area)
+                    }
+                })
 
                 allocate(size, align) {
                     return this.instance.exports.allocate(size, align);
@@ -470,18 +665,101 @@ impl JsGenerator {
                     this.instance.exports[symbol](0, ptr);
                 }
 
-                #(for func in iface.functions() => #(self.generate_function(&func)))
-                #(for iter in iface.iterators() => #(self.generate_function(&iter.next())))
-                #(for fut in iface.futures() => #(self.generate_function(&fut.poll())))
-                #(for stream in iface.streams() => #(self.generate_function(&stream.poll())))
+                dispatch(port) {
+                    return this.instance.exports.__ffi_dispatch(BigInt(port));
+                }
+
+                #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+                #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+                #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+                #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
             }
 
-            #(for obj in iface.objects() => #(self.generate_object(obj)))
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
 
             export default Api;
         }
     }
 
+    fn generate_node_loader(&self) -> js::Tokens {
+        quote! {
+            import { ReadableStream } from "node:stream/web";
+
+            #(static_literal("// Called by the Node-API module when rust wakes a future or stream."))
+            global.__notifier_callback = (idx) => notifierRegistry.notify(idx);
+        }
+    }
+
+    fn generate_wasm_loader(&self, iface: &Interface) -> js::Tokens {
+        quote! {
+                // a node fetch polyfill that won't trigger webpack or other bundlers
+                // idea borrowed from:
+                // https://github.com/dcodeIO/webassembly/blob/master/src/index.js#L223
+                let fs;
+                const fetch_polyfill = async (file) => {
+                    const readFile = await eval("mport('fs')".replace(/^/, 'i'))
+                        .then(({ readFile }) => readFile);
+                    return new Promise((resolve, reject) => {
+                        readFile(
+                            file,
+                            function(err, data) {
+                                return (err)
+                                    ? reject(err)
+                                    : resolve({
+                                        arrayBuffer: () => Promise.resolve(data),
+                                        ok: true,
+                                    });
+                            }
+                            );
+                    });
+                }
+
+                let ReadableStream;
+                if (typeof window == "object") {
+                    ReadableStream = window.ReadableStream;
+                    #(static_literal("// Workaround for combined use with `wasm-bindgen`, so we don't have to"))
+                    #(static_literal("// patch the `importObject` while loading the WASM module."))
+                    window.__notifier_callback = (idx) => notifierRegistry.notify(idx);
+                    #(for cb in iface.callbacks() join (#<push>) => window.#(&cb) = (idx, ...args) => notifierRegistry.callbacks[idx](...args);)
+                } else {
+                    eval("mport('node:stream/web')".replace(/^/, 'i')).then(pkg => {
+                        ReadableStream = pkg.ReadableStream;
+                    });
+                    #(static_literal("// Workaround for combined use with `wasm-bindgen`, so we don't have to"))
+                    #(static_literal("// patch the `importObject` while loading the WASM module."))
+                    global.__notifier_callback = (idx) => notifierRegistry.notify(idx);
+                    #(for cb in iface.callbacks() join (#<push>) => global.#(&cb) = (idx, ...args) => notifierRegistry.callbacks[idx](...args);)
+                };
+
+                const fetchFn = (typeof fetch === "function" && fetch) || fetch_polyfill;
+
+                // gets the wasm at a url and instantiates it.
+                // checks if streaming instantiation is available and uses that
+                function fetchAndInstantiate(url, imports) {
+                    const env = imports.env || {};
+                    env.__notifier_callback = (idx) => notifierRegistry.notify(idx);
+                    #(for cb in iface.callbacks() join (#<push>) => env.#(&cb) = (idx, ...args) => notifierRegistry.callbacks[idx](...args);)
+                    imports.env = env;
+                    return fetchFn(url)
+                        .then((resp) => {
+                            if (!resp.ok) {
+                                throw new Error("Got a ${resp.status} fetching wasm @ ${url}");
+                            }
+
+                            const wasm = "application/wasm";
+                            const type = resp.headers && resp.headers.get("content-type");
+
+                            return (WebAssembly.instantiateStreaming && type === wasm)
+                                ? WebAssembly.instantiateStreaming(resp, imports)
+                                : resp.arrayBuffer().then(buf => WebAssembly.instantiate(buf, imports));
+                            })
+                            .then(result => result.instance);
+                }
+        }
+    }
+
     fn generate_object(&self, obj: AbiObject) -> js::Tokens {
         quote! {
             export class #(self.type_ident(&obj.name)) {
@@ -490,7 +768,7 @@ impl JsGenerator {
                     this.box = box;
                 }
 
-                #(for method in obj.methods => #(self.generate_function(&method)))
+                #(for method in obj.methods join (#<line>) => #(self.generate_function(&method)))
 
                 drop() {
                     this.box.drop();
@@ -499,6 +777,19 @@ impl JsGenerator {
         }
     }
 
+    fn generate_error(&self, err: AbiError) -> js::Tokens {
+        quote! {
+            export class #(self.type_ident(&err.name)) extends Error {
+                constructor(kind, fields) {
+                    super(#(quoted(format!("{}.", err.name))) + kind);
+                    this.name = #(quoted(&err.name));
+                    this.kind = kind;
+                    Object.assign(this, fields);
+                }
+            }
+        }
+    }
+
     fn generate_function(&self, func: &AbiFunction) -> js::Tokens {
         let ffi = self.abi.import(func);
         let api = match &func.ty {
@@ -515,8 +806,26 @@ impl JsGenerator {
             | &FunctionType::NextIter(_, _) => &ffi.symbol,
             _ => &func.name,
         });
-        let args = quote!(#(for (name, _) in &ffi.abi_args => #(self.ident(name)),));
-        let body = quote!(#(for instr in &ffi.instr => #(self.generate_instr(&api, instr))));
+        let mut args = quote!(#(for (name, _) in &ffi.abi_args join (, ) => #(self.ident(name))));
+        if func.returns_future() {
+            if !ffi.abi_args.is_empty() {
+                args.append(quote!(,));
+                args.space();
+            }
+            args.append(quote!(signal));
+        }
+        let mut body = js::Tokens::new();
+        for instr in &ffi.instr {
+            body.push();
+            body.append(match (instr, &ffi.ffi_ret) {
+                (Instr::Call(symbol, Some(ret), args), Return::Struct(fields, _))
+                    if self.return_pointer =>
+                {
+                    self.generate_return_pointer_call(&api, symbol, ret, args, fields)
+                }
+                _ => self.generate_instr(&api, instr),
+            });
+        }
         match &func.ty {
             FunctionType::Constructor(_) => quote! {
                 static #(self.ident(&func.name))(api, #args) {
@@ -531,6 +840,69 @@ impl JsGenerator {
         }
     }
 
+    /// Calls `symbol` with a pointer to a `#[repr(C)]` struct and reads its
+    /// fields into the array `ret`.
+    /// Offsets of the fields of a struct returned through the return area, and
+    /// its size and alignment.
+    fn return_layout(&self, fields: &[Var]) -> (Vec<usize>, usize, usize) {
+        let mut offsets = vec![];
+        let mut size = 0;
+        let mut align = 1;
+        for field in fields {
+            let (field_size, field_align) = self.abi.layout(field.ty.num());
+            let offset = (size + field_align - 1) & !(field_align - 1);
+            size = offset + field_size;
+            align = core::cmp::max(align, field_align);
+            offsets.push(offset);
+        }
+        let size = (size + align - 1) & !(align - 1);
+        (offsets, size, align)
+    }
+
+    /// Reserves the return area once per instance, large enough for the
+    /// biggest returned struct.
+    fn generate_return_area(&self, iface: &Interface) -> js::Tokens {
+        if !self.return_pointer {
+            return quote!();
+        }
+        let (size, align) = iface
+            .imports(&self.abi)
+            .iter()
+            .filter_map(|import| match &import.ffi_ret {
+                Return::Struct(fields, _) => Some(self.return_layout(fields)),
+                _ => None,
+            })
+            .fold((0, 1), |(size, align), (_, field_size, field_align)| {
+                (
+                    core::cmp::max(size, field_size),
+                    core::cmp::max(align, field_align),
+                )
+            });
+        if size == 0 {
+            return quote!();
+        }
+        quote!(this.returnArea = this.allocate(#size, #align);)
+    }
+
+    fn generate_return_pointer_call(
+        &self,
+        api: &js::Tokens,
+        symbol: &str,
+        ret: &Var,
+        args: &[Var],
+        fields: &[Var],
+    ) -> js::Tokens {
+        let (offsets, _, _) = self.return_layout(fields);
+        quote! {
+            #api.instance.exports.#symbol(#api.returnArea, #(for arg in args join (, ) => #(self.var(arg))));
+            const #(self.var(ret))_0 = new DataView(#api.instance.exports.memory.buffer);
+            const #(self.var(ret)) = [
+                #(for (field, offset) in fields.iter().zip(offsets) join (#<push>) =>
+                    #(self.var(ret))_0.#(format!("get{}", self.generate_data_view_type(field.ty.num())))(#api.returnArea + #offset, true),)
+            ];
+        }
+    }
+
     fn generate_instr(&self, api: &js::Tokens, instr: &Instr) -> js::Tokens {
         match instr {
             Instr::BorrowSelf(out) => quote!(#(self.var(out)) = this.box.borrow();),
@@ -556,7 +928,7 @@ impl JsGenerator {
                 0 => quote!(),
                 1 => quote!(const #(self.var(&vars[0])) = #(self.var(ret));),
                 _ => quote! {
-                    #(for (idx, var) in vars.iter().enumerate() =>
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
                         const #(self.var(var)) = #(self.var(ret))[#(idx)];)
                 },
             },
@@ -593,6 +965,11 @@ impl JsGenerator {
             Instr::LowerNum(in_, out, _num) => {
                 quote!(#(self.var(out)) = #(self.var(in_));)
             }
+            Instr::LiftNum(in_, out, _num)
+                if self.is_node() && matches!(out.ty, AbiType::Isize | AbiType::Usize) =>
+            {
+                quote!(const #(self.var(out)) = Number(#(self.var(in_)));)
+            }
             Instr::LiftNum(in_, out, _num) => {
                 quote!(const #(self.var(out)) = #(self.var(in_));)
             }
@@ -602,7 +979,7 @@ impl JsGenerator {
             Instr::LiftBool(in_, out) => quote!(const #(self.var(out)) = #(self.var(in_)) > 0;),
             Instr::Deallocate(ptr, len, size, align) => quote! {
                 if (#(self.var(len)) > 0) {
-                    #api.deallocate(#(self.var(ptr)), #(self.var(len)) * #(*size), #(*align));
+                    #api.deallocate(#(self.var(ptr)), #(self.len(len)) * #(*size), #(*align));
                 }
             },
             Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
@@ -611,13 +988,13 @@ impl JsGenerator {
                 #(self.var(len)) = #(self.var(in_))_1.length;
                 #(self.var(ptr)) = #api.allocate(#(self.var(len)) * #(*size), #(*align));
                 const #(self.var(ptr))_0 =
-                    new Uint8Array(#api.instance.exports.memory.buffer, #(self.var(ptr)), #(self.var(len)));
+                    new Uint8Array(#(self.memory(api, ptr, self.var(len))), #(self.var(len)));
                 #(self.var(ptr))_0.set(#(self.var(in_))_1, 0);
                 #(self.var(cap)) = #(self.var(len));
             },
             Instr::LiftString(ptr, len, out) => quote! {
                 const #(self.var(out))_0 =
-                    new Uint8Array(#api.instance.exports.memory.buffer, #(self.var(ptr)), #(self.var(len)));
+                    new Uint8Array(#(self.memory(api, ptr, self.len(len))), #(self.len(len)));
                 const #(self.var(out))_1 = new TextDecoder();
                 const #(self.var(out)) = #(self.var(out))_1.decode(#(self.var(out))_0);
             },
@@ -626,19 +1003,75 @@ impl JsGenerator {
                 #(self.var(ptr)) = #api.allocate(#(self.var(len)) * #(*size), #(*align));
                 const #(self.var(ptr))_0 =
                     new #(self.generate_array(*ty))(
-                        #api.instance.exports.memory.buffer, #(self.var(ptr)), #(self.var(len)));
+                        #(self.memory(api, ptr, quote!(#(self.var(len)) * #(*size)))), #(self.var(len)));
                 #(self.var(ptr))_0.set(#(self.var(in_)), 0);
                 #(self.var(cap)) = #(self.var(len));
             },
             Instr::LiftVec(ptr, len, out, ty) => quote! {
                 const #(self.var(out))_0 =
                     new #(self.generate_array(*ty))(
-                        #api.instance.exports.memory.buffer, #(self.var(ptr)), #(self.var(len)));
+                        #(self.memory(api, ptr, quote!(#(self.len(len)) * #(self.abi.layout(*ty).0)))), #(self.len(len)));
                 const #(self.var(out)) = Array.from(#(self.var(out))_0);
             },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match &out.ty {
+                    AbiType::Map(_, _) => (
+                        quote!(new Map()),
+                        quote!(set(#(self.var(&elems[0])), #(self.var(&elems[1])))),
+                    ),
+                    AbiType::Set(_) => (quote!(new Set()), quote!(add(#(self.var(&elems[0]))))),
+                    _ => (quote!([]), quote!(push(#(self.var(&elems[0]))))),
+                };
+                let idx = quote!(#(self.var(out))_0);
+                quote! {
+                    const #(self.var(out)) = #collection;
+                    #(if self.is_node() {
+                        const #(self.var(out))_1 = new DataView(#(self.memory(api, ptr, quote!(#(self.len(len)) * #(n * 8)))));
+                    })
+                    for (let #(&idx) = 0; #(&idx) < #(self.var(len)); #(&idx)++) {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            const #(self.var(var)) = #(self.list_view(api, out))
+                                .#(format!("get{}", self.generate_data_view_type(var.ty.num())))(#(self.list_offset(ptr, &idx, n, i)), true);
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #(self.var(out)).#add;
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (len_prop, elem) = match &in_.ty {
+                    AbiType::Map(_, _) => (
+                        "size",
+                        quote!([#(self.var(&elems[0])), #(self.var(&elems[1]))]),
+                    ),
+                    AbiType::Set(_) => ("size", self.var(&elems[0])),
+                    _ => ("length", self.var(&elems[0])),
+                };
+                let idx = quote!(#(self.var(in_))_0);
+                quote! {
+                    #(self.var(len)) = #(self.var(in_)).#len_prop;
+                    if (#(self.var(len)) > 0) {
+                        #(self.var(ptr)) = #api.allocate(#(self.var(len)) * #(n * 8), 8);
+                    }
+                    let #(&idx) = 0;
+                    #(if self.is_node() {
+                        const #(self.var(in_))_1 = new DataView(#(self.memory(api, ptr, quote!(#(self.var(len)) * #(n * 8)))));
+                    })
+                    for (const #elem of #(self.var(in_))) {
+                        #(self.generate_instr(api, &Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            #(self.list_view(api, in_))
+                                .#(format!("set{}", self.generate_data_view_type(var.ty.num())))(#(self.list_offset(ptr, &idx, n, i)), #(self.list_value(var)), true);
+                        )
+                        #(&idx)++;
+                    }
+                }
+            }
             Instr::Call(symbol, ret, args) => {
-                let invoke =
-                    quote!(#api.instance.exports.#symbol(#(for arg in args => #(self.var(arg)),)););
+                let invoke = quote!(#api.instance.exports.#symbol(#(for arg in args join (, ) => #(self.var(arg)))););
                 if let Some(ret) = ret {
                     quote!(const #(self.var(ret)) = #invoke)
                 } else {
@@ -646,28 +1079,35 @@ impl JsGenerator {
                 }
             }
             Instr::DefineArgs(vars) => quote! {
-                #(for var in vars => let #(self.var(var)) = 0;)
+                #(for var in vars join (#<push>) => let #(self.var(var)) = 0;)
             },
             Instr::ReturnValue(ret) => quote!(return #(self.var(ret));),
             Instr::ReturnVoid => quote!(return;),
             Instr::HandleNull(var) => quote! {
-                if (#(self.var(var)) === 0) {
+                if (#(self.var(var)) == 0) {
                     return null;
                 }
             },
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                let #(self.var(out)) = null;
+                if (#(self.var(var)) !== 0) {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(self.var(out)) = #(self.var(some));
+                }
+            },
             Instr::LowerOption(arg, var, some, some_instr) => quote! {
                 if (#(self.var(arg)) == null) {
                     #(self.var(var)) = 0;
                 } else {
                     #(self.var(var)) = 1;
                     const #(self.var(some)) = #(self.var(arg));
-                    #(for inst in some_instr => #(self.generate_instr(api, inst)))
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
                 }
             },
             Instr::HandleError(var, ptr, len, cap) => quote! {
                 if (#(self.var(var)) === 0) {
                     const #(self.var(var))_0 =
-                        new Uint8Array(#api.instance.exports.memory.buffer, #(self.var(ptr)), #(self.var(len)));
+                        new Uint8Array(#(self.memory(api, ptr, self.len(len))), #(self.len(len)));
                     const #(self.var(var))_1 = new TextDecoder();
                     const #(self.var(var))_2 = #(self.var(var))_1.decode(#(self.var(var))_0);
                     if (#(self.var(len)) > 0) {
@@ -676,35 +1116,157 @@ impl JsGenerator {
                     throw #(self.var(var))_2;
                 }
             },
-            Instr::LiftIter(box_, next, drop, out) => quote! {
-                const #(self.var(box_))_0 = () => { #api.drop(#_(#drop), #(self.var(box_))); };
-                const #(self.var(box_))_1 = new Box(#(self.var(box_)), #(self.var(box_))_0);
-                const #(self.var(out)) = nativeIter(#(self.var(box_))_1, (a) => {
-                    return #api.#(self.ident(next))(a);
-                });
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if (#(self.var(var)) === 0) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if (#(self.var(tag)) === #(i)) {
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            throw new #(self.type_ident(err))(#(quoted(variant)), {
+                                #(for (name, field) in fields join (#<push>) => #(self.ident(name)): #(self.var(field)),)
+                            });
+                        }
+                    )
+                    throw new Error(#(quoted(format!("unknown variant of {}", err))));
+                }
             },
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    const #(self.var(box_))_0 = () => { #api.drop(#_(#drop), #(self.var(box_))); };
+                    const #(self.var(box_))_1 = new Box(#(self.var(box_)), #(self.var(box_))_0);
+                    const #(self.var(out)) = nativeIter(#(self.var(box_))_1, (a) => {
+                        return #api.#(self.ident(next))(a);
+                    }#batched);
+                }
+            }
             Instr::LiftFuture(box_, poll, drop, out) => quote! {
                 const #(self.var(box_))_0 = () => { #api.drop(#_(#drop), #(self.var(box_))); };
                 const #(self.var(box_))_1 = new Box(#(self.var(box_)), #(self.var(box_))_0);
                 const #(self.var(out)) = nativeFuture(#(self.var(box_))_1, (a, b, c) => {
-                    return #api.#(self.ident(poll))(a, b, c);
-                });
+                    return #api.#(self.ident(poll))(a, #(self.post_cobject(api, quote!(b))), c);
+                }, signal);
             },
-            Instr::LiftStream(box_, poll, drop, out) => quote! {
-                const #(self.var(box_))_0 = () => { #api.drop(#_(#drop), #(self.var(box_))); };
-                const #(self.var(box_))_1 = new Box(#(self.var(box_)), #(self.var(box_))_0);
-                const #(self.var(out)) = nativeStream(#(self.var(box_))_1, (a, b, c, d) => {
-                    return #api.#(self.ident(poll))(a, b, c, d);
-                });
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    const #(self.var(box_))_0 = () => { #api.drop(#_(#drop), #(self.var(box_))); };
+                    const #(self.var(box_))_1 = new Box(#(self.var(box_)), #(self.var(box_))_0);
+                    const #(self.var(out)) = nativeStream(#(self.var(box_))_1, (a, b, c, d) => {
+                        return #api.#(self.ident(poll))(a, #(self.post_cobject(api, quote!(b))), c, d);
+                    }#batched);
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(const #(self.var(&vars[0])) = #(self.var(in_));),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => const #(self.var(var)) = #(self.var(in_))[#(i)];)
+                },
             },
             Instr::LiftTuple(vars, out) => match vars.len() {
                 0 => quote!(),
                 1 => quote!(const #(self.var(out)) = #(self.var(&vars[0]));),
                 _ => quote! {
                     const #(self.var(out)) = [];
-                    #(for var in vars => #(self.var(out)).push(#(self.var(var)));)
+                    #(for var in vars join (#<push>) => #(self.var(out)).push(#(self.var(var)));)
                 },
             },
+            Instr::LowerCallback(..) | Instr::LowerTrait(..) if self.is_node() => {
+                unreachable!("`FfiGen` rejects callbacks and interfaces for node")
+            }
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                const #(self.var(in_))_0 = notifierRegistry.reserveSlot();
+                const #(self.var(in_))_1 = notifierRegistry.reserveSlot();
+                notifierRegistry.registerNotifier(#(self.var(in_))_0, #(self.generate_callback(api, cb, self.var(in_))));
+                notifierRegistry.registerNotifier(#(self.var(in_))_1, () => {
+                    if (#api.dispatch(#(self.var(in_))_1)) {
+                        notifierRegistry.unregisterNotifier(#(self.var(in_))_0);
+                        notifierRegistry.unregisterNotifier(#(self.var(in_))_1);
+                    }
+                });
+                #(self.var(ptr)) = #(self.var(in_))_0;
+                #(self.var(post_cobject)) = 0;
+                #(self.var(port)) = BigInt(#(self.var(in_))_1);
+            },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => {
+                let slot = |i: usize| quote!(#(self.var(in_))#(format!("_{}", i)));
+                let drop_slot = slot(methods.len());
+                quote! {
+                    #(for i in 0..=methods.len() join (#<push>) => const #(slot(i)) = notifierRegistry.reserveSlot();)
+                    #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                        notifierRegistry.registerNotifier(#(slot(i)), #(self.generate_callback(api, cb, quote!(#(self.var(in_)).#(self.ident(name))))));
+                    )
+                    notifierRegistry.registerNotifier(#(&drop_slot), () => {
+                        if (#api.dispatch(#(&drop_slot))) {
+                            #(for i in 0..=methods.len() join (#<push>) => notifierRegistry.unregisterNotifier(#(slot(i)));)
+                        }
+                    });
+                    #(for (i, ptr) in vtable.iter().enumerate() join (#<push>) => #(self.var(ptr)) = #(slot(i));)
+                    #(self.var(post_cobject)) = 0;
+                    #(self.var(port)) = BigInt(#(&drop_slot));
+                }
+            }
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                let c_like = payload.is_none();
+                quote! {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join ( else ) =>
+                        if (#(self.var(in_))#(if !c_like { .kind }) === #(quoted(variant))) {
+                            #(self.var(tag)) = #(i);
+                            #(for (field, var) in fields join (#<push>) => const #(self.var(var)) = #(self.var(in_)).#(self.ident(field));)
+                            #(self.generate_instr(api, &Instr::DefineArgs(ffi.clone())))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(self.write_payload(api, payload, ffi))
+                        }
+                    ) else {
+                        throw new Error(#(quoted(format!("unknown variant of {}", name))));
+                    }
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                let c_like = payload.is_none();
+                quote! {
+                    let #(self.var(out));
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join ( else ) =>
+                        if (#(self.var(tag)) === #(i)) {
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(if c_like {
+                                #(self.var(out)) = #(quoted(variant));
+                            } else {
+                                #(self.var(out)) = {
+                                    kind: #(quoted(variant)),
+                                    #(for (field, var) in fields join (#<push>) => #(self.ident(field)): #(self.var(var)),)
+                                };
+                            })
+                        }
+                    ) else {
+                        throw new Error(#(quoted(format!("unknown variant of {}", name))));
+                    }
+                }
+            }
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => const #(self.var(var)) = #(self.var(in_)).#(self.ident(field));)
+            },
+            Instr::LiftStruct(_, vars, out) => quote! {
+                const #(self.var(out)) = {
+                    #(for (field, var) in vars join (#<push>) => #(self.ident(field)): #(self.var(var)),)
+                };
+            },
+        }
+    }
+
+    fn generate_callback(&self, api: &js::Tokens, cb: &Callback, func: js::Tokens) -> js::Tokens {
+        let args = quote!(#(for var in &cb.args join (, ) => #(self.var(var))));
+        quote! {
+            (#(for var in &cb.ffi_args join (, ) => #(self.var(var)))) => {
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(api, inst)))
+                #(match &cb.ret {
+                    Some(ret) => const #(self.var(ret)) = #(func)(#args);,
+                    None => #(func)(#args);,
+                })
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(api, inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret));)
+            }
         }
     }
 
@@ -712,6 +1274,112 @@ impl JsGenerator {
         quote!(#(format!("tmp{}", var.binding)))
     }
 
+    /// Returns the arguments of a typed array viewing `bytes` bytes of memory
+    /// at `ptr`. Node can't view the whole address space, so it gets a buffer
+    /// over just these bytes.
+    fn memory(&self, api: &js::Tokens, ptr: &Var, bytes: js::Tokens) -> js::Tokens {
+        if self.is_node() {
+            quote!(#api.instance.exports.__ffi_buffer(#(self.var(ptr)), #bytes), 0)
+        } else {
+            quote!(#api.instance.exports.memory.buffer, #(self.var(ptr)))
+        }
+    }
+
+    /// Returns the `DataView` over the slots of the list `list`.
+    fn list_view(&self, api: &js::Tokens, list: &Var) -> js::Tokens {
+        if self.is_node() {
+            quote!(#(self.var(list))_1)
+        } else {
+            quote!(new DataView(#api.instance.exports.memory.buffer))
+        }
+    }
+
+    /// Returns the offset of the `i`th value of the element `idx` in the
+    /// `list_view`.
+    fn list_offset(&self, ptr: &Var, idx: &js::Tokens, n: usize, i: usize) -> js::Tokens {
+        if self.is_node() {
+            quote!((#idx * #n + #i) * 8)
+        } else {
+            quote!(#(self.var(ptr)) + (#idx * #n + #i) * 8)
+        }
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, api: &js::Tokens, payload: &Option<Var>, ffi: &[Var]) -> js::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        let size = ffi.len() * 8;
+        quote! {
+            const #(self.var(payload))_0 = new DataView(#(self.memory(api, payload, quote!(#size))));
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                const #(self.var(var)) = #(self.var(payload))_0
+                    .#(format!("get{}", self.generate_data_view_type(var.ty.num())))(#(i * 8), true);
+            )
+            #api.deallocate(#(self.var(payload)), #size, 8);
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, api: &js::Tokens, payload: &Option<Var>, ffi: &[Var]) -> js::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        let size = ffi.len() * 8;
+        quote! {
+            #(self.var(payload)) = #api.allocate(#size, 8);
+            const #(self.var(payload))_0 = new DataView(#(self.memory(api, payload, quote!(#size))));
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                #(self.var(payload))_0
+                    .#(format!("set{}", self.generate_data_view_type(var.ty.num())))(#(i * 8), #(self.list_value(var)), true);
+            )
+        }
+    }
+
+    /// Lengths are js numbers, but native pointer sized slots need bigints.
+    fn list_value(&self, var: &Var) -> js::Tokens {
+        match var.ty.num() {
+            NumType::U64 | NumType::I64 if self.is_node() => quote!(BigInt(#(self.var(var)))),
+            _ => self.var(var),
+        }
+    }
+
+    /// Native lengths are returned as bigints.
+    fn len(&self, var: &Var) -> js::Tokens {
+        if self.is_node() {
+            quote!(Number(#(self.var(var))))
+        } else {
+            self.var(var)
+        }
+    }
+
+    fn post_cobject(&self, api: &js::Tokens, arg: js::Tokens) -> js::Tokens {
+        if self.is_node() {
+            quote!(#api.instance.exports.__ffi_post_cobject)
+        } else {
+            arg
+        }
+    }
+
+    /// Returns the `DataView` accessor suffix of a value stored in memory.
+    fn generate_data_view_type(&self, ty: NumType) -> &'static str {
+        match ty {
+            NumType::U8 if self.is_node() => "Uint8",
+            NumType::U16 if self.is_node() => "Uint16",
+            NumType::I8 if self.is_node() => "Int8",
+            NumType::I16 if self.is_node() => "Int16",
+            NumType::U8 | NumType::U16 | NumType::U32 => "Uint32",
+            NumType::I8 | NumType::I16 | NumType::I32 => "Int32",
+            NumType::U64 => "BigUint64",
+            NumType::I64 => "BigInt64",
+            NumType::F32 => "Float32",
+            NumType::F64 => "Float64",
+        }
+    }
+
     fn generate_array(&self, ty: NumType) -> js::Tokens {
         match ty {
             NumType::U8 => quote!(Uint8Array),
@@ -751,81 +1419,284 @@ impl WasmMultiValueShim {
         Self { abi: Abi::Wasm32 }
     }
 
-    #[cfg(feature = "test_runner")]
-    pub fn generate(&self, path: &str, iface: Interface) -> rust::Tokens {
-        let args = self.generate_args(iface);
-        if !args.is_empty() {
-            quote! {
-                let ret = Command::new("multi-value-reverse-polyfill")
-                    .arg(#_(#path))
-                    #(for arg in args => .arg(#(quoted(arg))))
-                    .status()
-                    .unwrap()
-                    .success();
-                assert!(ret);
-            }
-        } else {
-            quote! {
-                let ret = Command::new("cp")
-                    .arg(#_(#path))
-                    .arg(#_(#(path).multivalue.wasm))
-                    .status()
-                    .unwrap()
-                    .success();
-                assert!(ret);
-            }
-        }
+    /// Writes the wasm blob at `path` to `{path}.multivalue.wasm`, with the
+    /// ffi functions returning structs patched to use multi-value returns.
+    pub fn run<P: AsRef<Path>>(&self, path: P, iface: Interface) -> Result<()> {
+        let path = path.as_ref();
+        let wasm =
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let wasm = self.transform(&wasm, iface)?;
+        let mut out = path.as_os_str().to_owned();
+        out.push(".multivalue.wasm");
+        std::fs::write(&out, wasm)
+            .with_context(|| format!("failed to write {}", Path::new(&out).display()))?;
+        Ok(())
     }
 
-    pub fn run<P: AsRef<Path>>(&self, path: P, iface: Interface) -> Result<()> {
-        let args = self.generate_args(iface);
-        let path = path.as_ref().to_str().unwrap();
-        if !args.is_empty() {
-            let mut cmd = Command::new("multi-value-reverse-polyfill");
-            cmd.arg(path);
-            for arg in args {
-                cmd.arg(arg);
-            }
-            let status = cmd.status()?;
-            if !status.success() {
-                anyhow::bail!("multi-value-reverse-polyfill failed for: {:?}", cmd);
-            }
-        } else {
-            let status = Command::new("cp")
-                .arg(path)
-                .arg(format!("{}.multivalue.wasm", path))
-                .status()?;
-            if !status.success() {
-                anyhow::bail!("cp failed");
+    /// Rustc returns structs through a pointer passed as first argument. For
+    /// every function returning a struct, this adds a function returning the
+    /// fields as multiple values and exports it instead. The new functions
+    /// are appended, so the indices of the existing ones don't change.
+    fn transform(&self, wasm: &[u8], iface: Interface) -> Result<Vec<u8>> {
+        let returns = self.generate_args(iface);
+        if returns.is_empty() {
+            return Ok(wasm.to_vec());
+        }
+        let module = WasmModule::parse(wasm)?;
+        let stack_pointer = module.stack_pointer()?;
+        let mut shim = MultiValueShim::default();
+        for (symbol, results) in returns {
+            let func = *module
+                .exports
+                .get(&symbol)
+                .with_context(|| format!("`{}` isn't exported", symbol))?;
+            let ty = &module.types[module.funcs[func as usize] as usize];
+            if ty.params().first() != Some(&wasmparser::ValType::I32) || !ty.results().is_empty() {
+                anyhow::bail!("`{}` doesn't return a struct through a pointer", symbol);
             }
+            let params = ty.params()[1..]
+                .iter()
+                .map(|ty| RoundtripReencoder.val_type(*ty))
+                .collect::<Result<Vec<_>, _>>()?;
+            let body = self.generate_wrapper(func, &params, &results, stack_pointer);
+            let ty = module.types.len() + shim.types.len();
+            let index = module.funcs.len() + shim.funcs.len();
+            shim.types.push((params, results));
+            shim.funcs.push((ty as u32, body));
+            shim.exports.insert(symbol, index as u32);
         }
-        Ok(())
+        let mut out = wasm_encoder::Module::new();
+        shim.parse_core_module(&mut out, wasmparser::Parser::new(0), wasm)?;
+        Ok(out.finish())
     }
 
-    fn generate_args(&self, iface: Interface) -> Vec<String> {
+    /// Returns the symbols of the functions returning structs, with the wasm
+    /// types of the struct fields.
+    fn generate_args(&self, iface: Interface) -> Vec<(String, Vec<ValType>)> {
         iface
             .imports(&self.abi)
             .into_iter()
             .filter_map(|import| match &import.ffi_ret {
                 Return::Struct(fields, _) => {
-                    let mut ret = String::new();
+                    let mut ret = vec![];
                     for field in fields {
                         let (size, _) = self.abi.layout(field.ty.num());
                         let r = match field.ty.num() {
-                            NumType::F32 => "f32 ",
-                            NumType::F64 => "f64 ",
-                            _ if size > 4 => "i64 ",
-                            _ => "i32 ",
+                            NumType::F32 => ValType::F32,
+                            NumType::F64 => ValType::F64,
+                            _ if size > 4 => ValType::I64,
+                            _ => ValType::I32,
                         };
-                        ret.push_str(r);
+                        ret.push(r);
                     }
 
-                    Some(format!("{} {}", import.symbol, ret))
+                    Some((import.symbol, ret))
                 }
                 _ => None,
             })
             .collect()
     }
+
+    /// Reserves a `#[repr(C)]` struct on the shadow stack, calls `func` with
+    /// a pointer to it and returns its fields.
+    fn generate_wrapper(
+        &self,
+        func: u32,
+        params: &[ValType],
+        results: &[ValType],
+        stack_pointer: u32,
+    ) -> Function {
+        let mut fields = vec![];
+        let mut size = 0;
+        for ty in results {
+            let (load, align): (fn(MemArg) -> Instruction<'static>, u64) = match ty {
+                ValType::I64 => (Instruction::I64Load, 8),
+                ValType::F32 => (Instruction::F32Load, 4),
+                ValType::F64 => (Instruction::F64Load, 8),
+                _ => (Instruction::I32Load, 4),
+            };
+            let offset = (size + align - 1) & !(align - 1);
+            size = offset + align;
+            fields.push(load(MemArg {
+                offset,
+                align: align.trailing_zeros(),
+                memory_index: 0,
+            }));
+        }
+        // the shadow stack is 16 byte aligned
+        let frame = ((size + 15) & !15) as i32;
+        let ret = params.len() as u32;
+        let mut body = Function::new([(1, ValType::I32)]);
+        body.instruction(&Instruction::GlobalGet(stack_pointer))
+            .instruction(&Instruction::I32Const(frame))
+            .instruction(&Instruction::I32Sub)
+            .instruction(&Instruction::LocalTee(ret))
+            .instruction(&Instruction::GlobalSet(stack_pointer))
+            .instruction(&Instruction::LocalGet(ret));
+        for i in 0..ret {
+            body.instruction(&Instruction::LocalGet(i));
+        }
+        body.instruction(&Instruction::Call(func))
+            .instruction(&Instruction::LocalGet(ret))
+            .instruction(&Instruction::I32Const(frame))
+            .instruction(&Instruction::I32Add)
+            .instruction(&Instruction::GlobalSet(stack_pointer));
+        for load in &fields {
+            body.instruction(&Instruction::LocalGet(ret))
+                .instruction(load);
+        }
+        body.instruction(&Instruction::End);
+        body
+    }
+}
+
+/// The parts of a wasm module needed to patch it.
+#[derive(Default)]
+struct WasmModule {
+    types: Vec<wasmparser::FuncType>,
+    /// Type of every function, including the imported ones.
+    funcs: Vec<u32>,
+    /// Every global, including the imported ones.
+    globals: Vec<wasmparser::GlobalType>,
+    imported_globals: usize,
+    exports: HashMap<String, u32>,
+    global_names: HashMap<String, u32>,
+}
+
+impl WasmModule {
+    fn parse(wasm: &[u8]) -> Result<Self> {
+        let mut module = Self::default();
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            match payload? {
+                wasmparser::Payload::TypeSection(reader) => {
+                    for ty in reader.into_iter_err_on_gc_types() {
+                        module.types.push(ty?);
+                    }
+                }
+                wasmparser::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        match import?.ty {
+                            wasmparser::TypeRef::Func(ty) => module.funcs.push(ty),
+                            wasmparser::TypeRef::Global(ty) => {
+                                module.globals.push(ty);
+                                module.imported_globals += 1;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                wasmparser::Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        module.funcs.push(ty?);
+                    }
+                }
+                wasmparser::Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        module.globals.push(global?.ty);
+                    }
+                }
+                wasmparser::Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == wasmparser::ExternalKind::Func {
+                            module.exports.insert(export.name.to_string(), export.index);
+                        }
+                    }
+                }
+                wasmparser::Payload::CustomSection(reader) => {
+                    if let wasmparser::KnownCustom::Name(reader) = reader.as_known() {
+                        for name in reader {
+                            if let wasmparser::Name::Global(names) = name? {
+                                for naming in names {
+                                    let naming = naming?;
+                                    module
+                                        .global_names
+                                        .insert(naming.name.to_string(), naming.index);
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(module)
+    }
+
+    /// Returns the global holding the shadow stack pointer. Without a name
+    /// section it's the first mutable i32 global defined by the module.
+    fn stack_pointer(&self) -> Result<u32> {
+        if let Some(global) = self.global_names.get("__stack_pointer") {
+            return Ok(*global);
+        }
+        self.globals
+            .iter()
+            .enumerate()
+            .skip(self.imported_globals)
+            .find(|(_, ty)| ty.mutable && ty.content_type == wasmparser::ValType::I32)
+            .map(|(i, _)| i as u32)
+            .context("failed to find the stack pointer")
+    }
+}
+
+/// Reencodes a wasm module, appending the multi-value functions and
+/// exporting them instead of the functions they wrap.
+#[derive(Default)]
+struct MultiValueShim {
+    types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    funcs: Vec<(u32, Function)>,
+    exports: HashMap<String, u32>,
+}
+
+impl Reencode for MultiValueShim {
+    type Error = Infallible;
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        reencode::utils::parse_type_section(self, types, section)?;
+        for (params, results) in &self.types {
+            types
+                .ty()
+                .function(params.iter().copied(), results.iter().copied());
+        }
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        reencode::utils::parse_function_section(self, functions, section)?;
+        for (ty, _) in &self.funcs {
+            functions.function(*ty);
+        }
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Infallible>> {
+        reencode::utils::parse_code_section(self, code, section)?;
+        for (_, body) in &self.funcs {
+            code.function(body);
+        }
+        Ok(())
+    }
+
+    fn parse_export(&mut self, exports: &mut ExportSection, export: wasmparser::Export<'_>) {
+        match self.exports.get(export.name) {
+            Some(func) if export.kind == wasmparser::ExternalKind::Func => {
+                exports.export(export.name, ExportKind::Func, *func);
+            }
+            _ => reencode::utils::parse_export(self, exports, export),
+        }
+    }
 }
 
 #[cfg(feature = "test_runner")]
@@ -839,12 +1710,32 @@ pub mod test_runner {
     use trybuild::TestCases;
 
     pub fn compile_pass(iface: &str, rust: rust::Tokens, js: js::Tokens) -> Result<()> {
+        run(iface, rust, js, false)
+    }
+
+    pub fn compile_pass_return_pointer(
+        iface: &str,
+        rust: rust::Tokens,
+        js: js::Tokens,
+    ) -> Result<()> {
+        run(iface, rust, js, true)
+    }
+
+    fn run(iface: &str, rust: rust::Tokens, js: js::Tokens, return_pointer: bool) -> Result<()> {
+        let mut iface_file = NamedTempFile::new()?;
+        iface_file.write_all(iface.as_bytes())?;
         let iface = Interface::parse(iface)?;
         let mut rust_file = NamedTempFile::new()?;
-        let rust_gen = RustGenerator::new(Abi::Wasm32);
+        let (rust_gen, js_gen) = if return_pointer {
+            (
+                RustGenerator::with_return_pointer(Abi::Wasm32),
+                JsGenerator::with_return_pointer(),
+            )
+        } else {
+            (RustGenerator::new(Abi::Wasm32), JsGenerator::default())
+        };
         let rust_tokens = rust_gen.generate(iface.clone());
         let mut js_file = tempfile::Builder::new().suffix(".mjs").tempfile()?;
-        let js_gen = JsGenerator::default();
         let js_tokens = js_gen.generate(iface.clone());
 
         let library_tokens = quote! {
@@ -866,13 +1757,21 @@ pub mod test_runner {
         };
 
         let library_file = NamedTempFile::new()?;
+        let wasm = if return_pointer {
+            library_file.as_ref().to_str().unwrap().to_string()
+        } else {
+            format!(
+                "{}.multivalue.wasm",
+                library_file.as_ref().to_str().unwrap()
+            )
+        };
         let bin_tokens = quote! {
             import assert from "assert";
             #js_tokens
 
             async function main() {
                 const api = new Api();
-                await api.fetch(#_(#(library_file.as_ref().to_str().unwrap()).multivalue.wasm), {
+                await api.fetch(#(quoted(wasm)), {
                     env: {
                         __panic: (ptr, len) => {
                             const buf = new Uint8Array(api.instance.exports.memory.buffer, ptr, len);
@@ -896,9 +1795,6 @@ pub mod test_runner {
         let bin = bin_tokens.to_file_string()?;
         js_file.write_all(bin.as_bytes())?;
 
-        let wasm_multi_value =
-            WasmMultiValueShim::new().generate(library_file.as_ref().to_str().unwrap(), iface);
-
         let runner_tokens: rust::Tokens = quote! {
             fn main() {
                 use std::process::Command;
@@ -921,7 +1817,12 @@ pub mod test_runner {
                     .success();
                 assert!(ret);
                 //println!("{}", #_(#bin));
-                #wasm_multi_value
+                #(if !return_pointer {
+                    ffi_gen::FfiGen::new(#(quoted(iface_file.as_ref().to_str().unwrap())))
+                        .unwrap()
+                        .wasm_multi_value_shim(#(quoted(library_file.as_ref().to_str().unwrap())))
+                        .unwrap();
+                })
                 let ret = Command::new("node")
                     .arg("--expose-gc")
                     .arg("--unhandled-rejections=strict")
@@ -949,7 +1850,10 @@ pub mod test_runner {
 
     pub fn compile_pass_ts(iface: &str, ts_tokens: js::Tokens) -> Result<()> {
         let iface = Interface::parse(iface)?;
-        let ts_gen = TsGenerator { docs: false };
+        let ts_gen = TsGenerator {
+            docs: false,
+            node: false,
+        };
         let js_tokens = ts_gen.generate(iface);
         // remove static header to no bloat the tests
         let left = js_tokens.to_file_string().unwrap().replace(
@@ -0,0 +1,1186 @@
+use crate::import::{Callback, Import, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Return, Var,
+};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+use heck::*;
+use std::collections::BTreeMap;
+
+/// Generates kotlin bindings, which load the cdylib with jna.
+///
+/// Futures, streams and callbacks are woken through a jna callback passed to
+/// rust as `post_cobject`, see `FfiPorts` in the generated runtime.
+pub struct KotlinGenerator {
+    abi: Abi,
+    package: String,
+    cdylib_name: String,
+}
+
+impl KotlinGenerator {
+    pub fn new(package: String, cdylib_name: String) -> Self {
+        Self {
+            abi: Abi::native(),
+            package,
+            cdylib_name,
+        }
+    }
+
+    pub fn generate(&self, iface: Interface) -> Tokens {
+        let imports = iface.imports(&self.abi);
+        quote! {
+            #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("//"))
+            #(static_literal("//")) Generated by "ffi-gen".
+            #(self.generate_doc(&iface.doc))
+            package #(&self.package)
+
+            import com.sun.jna.Callback
+            import com.sun.jna.CallbackReference
+            import com.sun.jna.Library
+            import com.sun.jna.Native
+            import com.sun.jna.Pointer
+            import com.sun.jna.Structure
+            import java.lang.ref.Cleaner
+            import java.util.concurrent.ConcurrentHashMap
+            import java.util.concurrent.atomic.AtomicBoolean
+            import java.util.concurrent.atomic.AtomicLong
+            import kotlinx.coroutines.channels.Channel
+            import kotlinx.coroutines.flow.Flow
+            import kotlinx.coroutines.flow.flow
+
+            #(self.generate_doc(&["Returned by polling functions when no value is ready."]))
+            internal object FfiPending
+
+            #(self.generate_doc(&["Exception thrown by a rust function returning an error string."]))
+            class FfiException(message: String) : Exception(message)
+
+            #(self.generate_doc(&["The callback rust calls to notify a port."]))
+            internal interface FfiPostCObject : Callback {
+                fun invoke(port: Long, message: Pointer?)
+            }
+
+            #(self.generate_doc(&[
+                "Maps the ports passed to rust to what the jvm does when they are notified.",
+                "",
+                "The jvm has no native ports, so it reuses the `post_cobject` argument dart passes",
+                "`Dart_PostCObject` in as its waker: `postCObjectAddress` points to a jna callback,",
+                "which rust calls with the port to poll a future or stream again, or to release a",
+                "callback it dropped. The callback runs the function registered for the port on the",
+                "thread rust notifies it from. Objects kept alive for rust are stored until their",
+                "port is notified.",
+            ]))
+            internal object FfiPorts {
+                private val nextPort = AtomicLong(1)
+                private val callbacks = ConcurrentHashMap<Long, () -> Unit>()
+                private val alive = ConcurrentHashMap<Long, Array<out Any>>()
+
+                private val postCObject = object : FfiPostCObject {
+                    override fun invoke(port: Long, message: Pointer?) {
+                        callbacks[port]?.invoke()
+                    }
+                }
+
+                val postCObjectAddress: Long = Pointer.nativeValue(CallbackReference.getFunctionPointer(postCObject))
+
+                fun open(callback: () -> Unit): Long {
+                    val port = nextPort.getAndIncrement()
+                    callbacks[port] = callback
+                    return port
+                }
+
+                fun close(port: Long) {
+                    callbacks.remove(port)
+                    alive.remove(port)
+                }
+
+                #(self.generate_doc(&[
+                    "Keeps the callbacks in `objects` alive until rust drops them.",
+                    "",
+                    "Notifying the returned port runs the calls rust queued for them.",
+                ]))
+                fun keepAlive(lib: FfiLib, vararg objects: Any): Long {
+                    val port = openOnce {
+                        if (lib.__ffi_dispatch(it).toInt() != 0) {
+                            close(it)
+                        }
+                    }
+                    alive[port] = objects
+                    return port
+                }
+
+                private fun openOnce(callback: (Long) -> Unit): Long {
+                    val port = nextPort.getAndIncrement()
+                    callbacks[port] = { callback(port) }
+                    return port
+                }
+            }
+
+            private val ffiCleaner: Cleaner = Cleaner.create()
+
+            private class FfiDrop(private val ptr: Long, private val drop: (Long) -> Unit) : Runnable {
+                @Volatile
+                var moved = false
+
+                @Volatile
+                var dropped = false
+
+                override fun run() {
+                    if (!moved && !dropped) {
+                        dropped = true
+                        drop(ptr)
+                    }
+                }
+            }
+
+            #(self.generate_doc(&["Owns a value boxed by rust, which is dropped once the box is closed or unreachable."]))
+            internal class FfiBox(private val ptr: Long, drop: (Long) -> Unit) : AutoCloseable {
+                private val state = FfiDrop(ptr, drop)
+                private val cleanable = ffiCleaner.register(this, state)
+
+                fun borrow(): Long {
+                    check(!state.moved) { "use after move" }
+                    check(!state.dropped) { "use after free" }
+                    return ptr
+                }
+
+                fun move(): Long {
+                    check(!state.moved) { "can't move value twice" }
+                    check(!state.dropped) { "use after free" }
+                    state.moved = true
+                    return ptr
+                }
+
+                override fun close() {
+                    check(!state.moved) { "can't drop moved value" }
+                    check(!state.dropped) { "double free" }
+                    cleanable.clean()
+                }
+            }
+
+            #(self.generate_doc(&["Iterates over the items of a rust iterator."]))
+            class Iter<T> internal constructor(
+                internal val box: FfiBox,
+                private val nextFn: (Long) -> Any?,
+                private val batched: Boolean = false,
+            ) : Iterator<T>, AutoCloseable {
+                private var peeked: Any? = FfiPending
+                private var items: Iterator<Any?> = emptyList<Any?>().iterator()
+
+                override fun hasNext(): Boolean {
+                    if (peeked === FfiPending) {
+                        peeked = if (batched) nextItem() else nextFn(box.borrow())
+                    }
+                    return peeked !== FfiPending
+                }
+
+                #(static_literal("//")) A batched iterator returns a list of items at a time.
+                private fun nextItem(): Any? {
+                    while (!items.hasNext()) {
+                        val ret = nextFn(box.borrow())
+                        if (ret === FfiPending) {
+                            return FfiPending
+                        }
+                        items = (ret as List<*>).iterator()
+                    }
+                    return items.next()
+                }
+
+                override fun next(): T {
+                    if (!hasNext()) {
+                        throw NoSuchElementException()
+                    }
+                    val ret = peeked
+                    peeked = FfiPending
+                    @Suppress("UNCHECKED_CAST")
+                    return ret as T
+                }
+
+                #(self.generate_doc(&["Manually drops the iterator."]))
+                override fun close() {
+                    box.close()
+                }
+            }
+
+            #(self.generate_doc(&["Resolves to the output of a rust future."]))
+            class FfiFuture<T> internal constructor(
+                internal val box: FfiBox,
+                private val pollFn: (Long, Long, Long) -> Any?,
+            ) {
+                #(self.generate_doc(&["Polls the future until it is ready, it can only be awaited once."]))
+                suspend fun await(): T {
+                    val wakes = Channel<Unit>(Channel.CONFLATED)
+                    val port = FfiPorts.open { wakes.trySend(Unit) }
+                    try {
+                        while (true) {
+                            val ret = pollFn(box.borrow(), FfiPorts.postCObjectAddress, port)
+                            if (ret !== FfiPending) {
+                                @Suppress("UNCHECKED_CAST")
+                                return ret as T
+                            }
+                            wakes.receive()
+                        }
+                    } finally {
+                        FfiPorts.close(port)
+                        box.close()
+                    }
+                }
+            }
+
+            #(self.generate_doc(&["Emits the items of a rust stream, the flow can only be collected once."]))
+            internal fun <T> ffiStream(box: FfiBox, pollFn: (Long, Long, Long, Long) -> Any?, batched: Boolean = false): Flow<T> = flow {
+                val wakes = Channel<Unit>(Channel.CONFLATED)
+                val finished = AtomicBoolean(false)
+                val port = FfiPorts.open { wakes.trySend(Unit) }
+                val done = FfiPorts.open {
+                    finished.set(true)
+                    wakes.trySend(Unit)
+                }
+                try {
+                    while (!finished.get()) {
+                        val ret = pollFn(box.borrow(), FfiPorts.postCObjectAddress, port, done)
+                        if (ret !== FfiPending && batched) {
+                            @Suppress("UNCHECKED_CAST")
+                            (ret as List<T>).forEach { emit(it) }
+                        } else if (ret !== FfiPending) {
+                            @Suppress("UNCHECKED_CAST")
+                            emit(ret as T)
+                        } else if (!finished.get()) {
+                            wakes.receive()
+                        }
+                    }
+                } finally {
+                    FfiPorts.close(port)
+                    FfiPorts.close(done)
+                    box.close()
+                }
+            }
+
+            internal interface FfiLib : Library {
+                fun allocate(size: Long, align: Long): Long
+
+                fun deallocate(ptr: Long, size: Long, align: Long)
+
+                fun __ffi_dispatch(port: Long): Byte
+
+                #(for func in &imports join (#<line>) => #(self.generate_native_function(func)))
+
+                #(for drop in self.destructors(&iface) join (#<line>) => fun #drop(unused: Long, boxed: Long))
+            }
+
+            #(for (name, cb) in self.callbacks(&imports) join (#<line>) =>
+                internal interface #name : Callback {
+                    fun invoke(#(for (i, var) in cb.ffi_args.iter().enumerate() join (, ) => #(format!("arg{}", i)): #(self.generate_native_num_type(var.ty.num()))))#(if let Some(ret) = &cb.ffi_ret => : #(self.generate_native_num_type(ret.ty.num())))
+                }
+            )
+
+            #(self.generate_doc(&["Main entry point to library."]))
+            class Api internal constructor(internal val lib: FfiLib) {
+                companion object {
+                    #(self.generate_doc(&["The library is loaded based on platform conventions."]))
+                    fun load(): Api = Api(Native.load(#(quoted(&self.cdylib_name)), FfiLib::class.java))
+                }
+
+                #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+
+                #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+
+                #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+
+                #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+            }
+
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
+
+            #(for func in &imports => #(self.generate_return_struct(&func.ffi_ret)))
+        }
+    }
+
+    /// Symbols dropping the boxes returned by rust.
+    fn destructors(&self, iface: &Interface) -> Vec<String> {
+        let mut destructors = vec![];
+        destructors.extend(iface.objects().into_iter().map(|obj| obj.destructor));
+        destructors.extend(
+            iface
+                .iterators()
+                .iter()
+                .map(|iter| format!("{}_iter_drop", iter.symbol)),
+        );
+        destructors.extend(
+            iface
+                .futures()
+                .iter()
+                .map(|fut| format!("{}_future_drop", fut.symbol)),
+        );
+        destructors.extend(
+            iface
+                .streams()
+                .iter()
+                .map(|stream| format!("{}_stream_drop", stream.symbol)),
+        );
+        destructors
+    }
+
+    /// Jna callback interfaces by name, one for each callback signature.
+    fn callbacks<'a>(&self, imports: &'a [Import]) -> BTreeMap<String, &'a Callback> {
+        let mut callbacks = BTreeMap::new();
+        for func in imports {
+            for instr in &func.instr {
+                match instr {
+                    Instr::LowerCallback(_, _, _, _, cb) => {
+                        callbacks.insert(self.callback_ident(cb), &**cb);
+                    }
+                    Instr::LowerTrait(_, _, _, _, methods) => {
+                        for (_, cb) in methods {
+                            callbacks.insert(self.callback_ident(cb), cb);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        callbacks
+    }
+
+    fn callback_ident(&self, cb: &Callback) -> String {
+        let mut name = "FfiCallback".to_string();
+        for var in &cb.ffi_args {
+            name.push_str(&self.native_num_name(var.ty.num()));
+        }
+        name.push_str("To");
+        match &cb.ffi_ret {
+            Some(ret) => name.push_str(&self.native_num_name(ret.ty.num())),
+            None => name.push_str("Unit"),
+        }
+        name
+    }
+
+    fn generate_native_function(&self, func: &Import) -> Tokens {
+        let ret = match &func.ffi_ret {
+            Return::Void => quote!(),
+            Return::Num(var) => quote!(: #(self.generate_native_num_type(var.ty.num()))),
+            Return::Struct(_, name) => quote!(: #name),
+        };
+        quote! {
+            fun #(&func.symbol)(#(for (i, var) in func.ffi_args.iter().enumerate() join (, ) => #(format!("arg{}", i)): #(self.generate_native_num_type(var.ty.num()))))#ret
+        }
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> Tokens {
+        let constructors = obj
+            .methods
+            .iter()
+            .filter(|func| matches!(func.ty, FunctionType::Constructor(_)));
+        let methods = obj
+            .methods
+            .iter()
+            .filter(|func| !matches!(func.ty, FunctionType::Constructor(_)));
+        quote! {
+            #(self.generate_doc(&obj.doc))
+            class #(self.type_ident(&obj.name)) internal constructor(
+                private val api: Api,
+                internal val box: FfiBox,
+            ) : AutoCloseable {
+                companion object {
+                    #(for func in constructors join (#<line>) => #(self.generate_function(func)))
+                }
+
+                #(for func in methods join (#<line>) => #(self.generate_function(func)))
+
+                #(self.generate_doc(&["Manually drops the object."]))
+                override fun close() {
+                    box.close()
+                }
+            }
+        }
+    }
+
+    fn generate_trait(&self, tr: AbiTrait) -> Tokens {
+        quote! {
+            #(self.generate_doc(&tr.doc))
+            interface #(self.type_ident(&tr.name)) {
+                #(for method in &tr.methods join (#<line>) =>
+                    #(self.generate_doc(&method.doc))
+                    fun #(self.ident(&method.name))(#(for (name, ty) in &method.args join (, ) => #(self.ident(name)): #(self.generate_type(ty))))#(if let Some(ret) = &method.ret => : #(self.generate_type(ret)))
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> Tokens {
+        if st.fields.is_empty() {
+            return quote! {
+                #(self.generate_doc(&st.doc))
+                class #(self.type_ident(&st.name))
+            };
+        }
+        quote! {
+            #(self.generate_doc(&st.doc))
+            data class #(self.type_ident(&st.name))(
+                #(for (name, ty) in &st.fields join (#<push>) => val #(self.ident(name)): #(self.generate_type(ty)),)
+            )
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> Tokens {
+        if en.is_c_like() {
+            return quote! {
+                #(self.generate_doc(&en.doc))
+                enum class #(self.type_ident(&en.name)) {
+                    #(for variant in &en.variants join (#<push>) =>
+                        #(self.generate_doc(&variant.doc))
+                        #(self.variant_const(&variant.name)),
+                    )
+                }
+            };
+        }
+        let name = self.type_ident(&en.name);
+        quote! {
+            #(self.generate_doc(&en.doc))
+            sealed class #(&name) {
+                #(for variant in &en.variants join (#<line>) =>
+                    #(self.generate_doc(&variant.doc))
+                    #(if variant.fields.is_empty() {
+                        object #(self.type_ident(&variant.name)) : #(&name)()
+                    } else {
+                        data class #(self.type_ident(&variant.name))(
+                            #(for (name, ty) in &variant.fields join (#<push>) => val #(self.ident(name)): #(self.generate_type(ty)),)
+                        ) : #(&name)()
+                    })
+                )
+            }
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> Tokens {
+        let name = self.type_ident(&err.name);
+        quote! {
+            #(self.generate_doc(&err.doc))
+            sealed class #(&name) : Exception() {
+                #(for variant in &err.variants join (#<line>) =>
+                    #(self.generate_doc(&variant.doc))
+                    #(if variant.fields.is_empty() {
+                        class #(self.type_ident(&variant.name)) : #(&name)()
+                    } else {
+                        data class #(self.type_ident(&variant.name))(
+                            #(for (name, ty) in &variant.fields join (#<push>) => val #(self.ident(name)): #(self.generate_type(ty)),)
+                        ) : #(&name)()
+                    })
+                )
+            }
+        }
+    }
+
+    fn generate_function(&self, func: &AbiFunction) -> Tokens {
+        let ffi = self.abi.import(func);
+        let api = match &func.ty {
+            FunctionType::Constructor(_) | FunctionType::Method(_) => "api",
+            FunctionType::Function
+            | FunctionType::NextIter(_, _)
+            | FunctionType::PollFuture(_, _)
+            | FunctionType::PollStream(_, _) => "this",
+        };
+        let args = quote!(#(for (name, ty) in &ffi.abi_args join (, ) => #(self.ident(name)): #(self.generate_type(ty))));
+        let doc = self.generate_doc(&func.doc);
+        let is_poll = matches!(
+            &func.ty,
+            FunctionType::NextIter(_, _)
+                | FunctionType::PollFuture(_, _)
+                | FunctionType::PollStream(_, _)
+        );
+        if is_poll {
+            // Polling returns `FfiPending` when no value is ready, so that
+            // `null` can be used for an optional value.
+            let pending = ffi
+                .instr
+                .iter()
+                .position(|instr| matches!(instr, Instr::HandleNull(_)));
+            let body = ffi.instr.iter().enumerate().map(|(i, instr)| match instr {
+                Instr::HandleNull(var) if Some(i) == pending => {
+                    self.generate_handle_null(var, quote!(FfiPending))
+                }
+                Instr::ReturnVoid => quote!(return Unit),
+                _ => self.generate_instr(api, instr),
+            });
+            return quote! {
+                internal fun #(self.poll_ident(&ffi.symbol))(#args): Any? {
+                    #(for instr in body join (#<push>) => #instr)
+                }
+            };
+        }
+        let is_async = ffi.abi_ret.as_ref().map(|ret| self.is_async(ret)) == Some(true);
+        let ret = match &ffi.abi_ret {
+            Some(ret) => self.generate_return_type(ret),
+            None => quote!(),
+        };
+        let body = ffi.instr.iter().map(|instr| match instr {
+            Instr::ReturnValue(ret) if is_async => quote!(return #(self.var(ret)).await()),
+            Instr::ReturnVoid if ffi.abi_ret.is_some() => quote!(return Unit),
+            _ => self.generate_instr(api, instr),
+        });
+        let args = match &func.ty {
+            FunctionType::Constructor(_) if ffi.abi_args.is_empty() => quote!(api: Api),
+            FunctionType::Constructor(_) => quote!(api: Api, #args),
+            _ => args,
+        };
+        quote! {
+            #doc
+            #(if is_async { suspend fun } else { fun }) #(self.ident(&func.name))(#args)#ret {
+                #(for instr in body join (#<push>) => #instr)
+            }
+        }
+    }
+
+    /// Functions returning a future await it.
+    fn is_async(&self, ret: &AbiType) -> bool {
+        match ret {
+            AbiType::Result(ty, _) => self.is_async(ty),
+            AbiType::Future(_) => true,
+            _ => false,
+        }
+    }
+
+    fn generate_return_type(&self, ret: &AbiType) -> Tokens {
+        match ret {
+            AbiType::Result(ty, _) | AbiType::Future(ty) => self.generate_return_type(ty),
+            AbiType::Tuple(tys) if tys.is_empty() => quote!(),
+            ty => quote!(: #(self.generate_type(ty))),
+        }
+    }
+
+    fn generate_handle_null(&self, var: &Var, null: Tokens) -> Tokens {
+        quote! {
+            if (#(self.var(var)) == #(self.literal(var.ty.num()))) {
+                return #null
+            }
+        }
+    }
+
+    fn generate_instr(&self, api: &str, instr: &Instr) -> Tokens {
+        let lib = if api == "this" {
+            quote!(lib)
+        } else {
+            quote!(#api.lib)
+        };
+        match instr {
+            Instr::BorrowSelf(out) => quote!(#(self.var(out)) = box.borrow()),
+            Instr::BorrowObject(in_, out)
+            | Instr::BorrowIter(in_, out)
+            | Instr::BorrowFuture(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).box.borrow())
+            }
+            Instr::MoveObject(in_, out)
+            | Instr::MoveIter(in_, out)
+            | Instr::MoveFuture(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).box.move())
+            }
+            Instr::BorrowStream(_, out) | Instr::MoveStream(_, out) => {
+                quote!(#(self.var(out)) = throw UnsupportedOperationException("streams can't be passed to rust"))
+            }
+            Instr::LiftObject(obj, box_, drop, out) => quote! {
+                val #(self.var(box_))_0 = FfiBox(#(self.var(box_))) { #lib.#drop(0, it) }
+                val #(self.var(out)) = #(self.type_ident(obj))(#api, #(self.var(box_))_0)
+            },
+            Instr::BindArg(arg, out) => quote!(val #(self.var(out)) = #(self.ident(arg))),
+            Instr::BindRets(ret, vars) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(val #(self.var(&vars[0])) = #(self.var(ret))),
+                _ => quote! {
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
+                        val #(self.var(var)) = #(self.var(ret)).#(format!("ret{}", idx)))
+                },
+            },
+            Instr::LowerNum(in_, out, num) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).#(self.native_conversion(*num))())
+            }
+            Instr::LiftNum(in_, out, num) => {
+                quote!(val #(self.var(out)) = #(self.var(in_)).#(self.wrapped_conversion(&out.ty, *num))())
+            }
+            Instr::LowerBool(in_, out) => {
+                quote!(#(self.var(out)) = (if (#(self.var(in_))) 1 else 0).toByte())
+            }
+            Instr::LiftBool(in_, out) => {
+                quote!(val #(self.var(out)) = #(self.var(in_)) > 0)
+            }
+            Instr::Deallocate(ptr, len, size, align) => quote! {
+                if (#(self.var(len)) > 0) {
+                    #lib.deallocate(#(self.var(ptr)), #(self.var(len)) * #(*size), #(*align))
+                }
+            },
+            Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
+                val #(self.var(in_))_0 = #(self.var(in_)).toByteArray(Charsets.UTF_8)
+                #(self.var(len)) = #(self.var(in_))_0.size.#(self.native_conversion(len.ty.num()))()
+                #(self.var(ptr)) = #lib.allocate(#(self.var(len)) * #(*size), #(*align))
+                Pointer(#(self.var(ptr))).write(0, #(self.var(in_))_0, 0, #(self.var(in_))_0.size)
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftString(ptr, len, out) => quote! {
+                val #(self.var(out)) = String(Pointer(#(self.var(ptr))).getByteArray(0, #(self.var(len)).toInt()), Charsets.UTF_8)
+            },
+            Instr::LowerVec(in_, ptr, len, cap, ty, size, align) => {
+                let native = self.native_num_name(*ty);
+                quote! {
+                    val #(self.var(in_))_0 = #(self.var(in_)).map { it.#(self.native_conversion(*ty))() }.#(format!("to{}Array", native))()
+                    #(self.var(len)) = #(self.var(in_))_0.size.#(self.native_conversion(len.ty.num()))()
+                    #(self.var(ptr)) = #lib.allocate(#(self.var(len)) * #(*size), #(*align))
+                    Pointer(#(self.var(ptr))).write(0, #(self.var(in_))_0, 0, #(self.var(in_))_0.size)
+                    #(self.var(cap)) = #(self.var(len))
+                }
+            }
+            Instr::LiftVec(ptr, len, out, ty) => {
+                let native = self.native_num_name(*ty);
+                quote! {
+                    val #(self.var(out)) = Pointer(#(self.var(ptr))).#(format!("get{}Array", native))(0, #(self.var(len)).toInt()).map { it.#(self.wrapped_conversion(&AbiType::Num(*ty), *ty))() }
+                }
+            }
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match &out.ty {
+                    AbiType::Map(key, value) => (
+                        quote!(mutableMapOf<#(self.generate_type(key)), #(self.generate_type(value))>()),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1]))),
+                    ),
+                    AbiType::Set(ty) => (
+                        quote!(mutableSetOf<#(self.generate_type(ty))>()),
+                        quote!(#(self.var(out)).add(#(self.var(&elems[0])))),
+                    ),
+                    _ => (
+                        quote!(mutableListOf<#(self.generate_type(&elems[0].ty))>()),
+                        quote!(#(self.var(out)).add(#(self.var(&elems[0])))),
+                    ),
+                };
+                quote! {
+                    val #(self.var(out)) = #collection
+                    for (#(self.var(out))_0 in 0 until #(self.var(len)).toInt()) {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            val #(self.var(var)) = Pointer(#(self.var(ptr))).#(format!("get{}", self.native_num_name(var.ty.num())))((#(self.var(out))_0 * #n + #i) * 8L)
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #add
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (iter, bind) = if let AbiType::Map(_, _) = &in_.ty {
+                    (
+                        quote!(#(self.var(in_)).entries),
+                        quote!((#(self.var(&elems[0])), #(self.var(&elems[1])))),
+                    )
+                } else {
+                    (quote!(#(self.var(in_))), self.var(&elems[0]))
+                };
+                quote! {
+                    #(self.var(len)) = #(self.var(in_)).size.#(self.native_conversion(len.ty.num()))()
+                    if (#(self.var(len)) > 0) {
+                        #(self.var(ptr)) = #lib.allocate(#(self.var(len)) * #(n * 8), 8)
+                    }
+                    for ((#(self.var(in_))_0, #bind) in #iter.withIndex()) {
+                        #(self.generate_instr(api, &Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            Pointer(#(self.var(ptr))).#(format!("set{}", self.native_num_name(var.ty.num())))((#(self.var(in_))_0 * #n + #i) * 8L, #(self.var(var)))
+                        )
+                    }
+                }
+            }
+            Instr::Call(symbol, ret, args) => {
+                let invoke =
+                    quote!(#lib.#(symbol)(#(for arg in args join (, ) => #(self.var(arg)))));
+                if let Some(ret) = ret {
+                    quote!(val #(self.var(ret)) = #invoke)
+                } else {
+                    invoke
+                }
+            }
+            Instr::DefineArgs(vars) => quote! {
+                #(for var in vars join (#<push>) => var #(self.var(var)): #(self.generate_native_num_type(var.ty.num())) = #(self.literal(var.ty.num())))
+            },
+            Instr::ReturnValue(ret) => quote!(return #(self.var(ret))),
+            Instr::ReturnVoid => quote!(return),
+            Instr::HandleNull(var) => self.generate_handle_null(var, quote!(null)),
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                val #(self.var(out)): #(self.generate_type(&out.ty))
+                if (#(self.var(var)) == #(self.literal(var.ty.num()))) {
+                    #(self.var(out)) = null
+                } else {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(self.var(out)) = #(self.var(some))
+                }
+            },
+            Instr::LowerOption(arg, var, some, some_instr) => quote! {
+                if (#(self.var(arg)) == null) {
+                    #(self.var(var)) = 0
+                } else {
+                    #(self.var(var)) = 1
+                    val #(self.var(some)) = #(self.var(arg))
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                }
+            },
+            Instr::HandleError(var, ptr, len, cap) => quote! {
+                if (#(self.var(var)) == #(self.literal(var.ty.num()))) {
+                    val #(self.var(var))_0 = String(Pointer(#(self.var(ptr))).getByteArray(0, #(self.var(len)).toInt()), Charsets.UTF_8)
+                    if (#(self.var(len)) > 0) {
+                        #lib.deallocate(#(self.var(ptr)), #(self.var(cap)), 1)
+                    }
+                    throw FfiException(#(self.var(var))_0)
+                }
+            },
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if (#(self.var(var)) == #(self.literal(var.ty.num()))) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if (#(self.var(tag)) == #i) {
+                            #(self.read_payload(&lib, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            throw #(self.type_ident(err)).#(self.type_ident(variant))(#(for (_, field) in fields join (, ) => #(self.var(field))))
+                        }
+                    )
+                    throw IllegalStateException(#(quoted(format!("unknown variant of {}", err))))
+                }
+            },
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                val #(self.var(in_))_0 = #(self.generate_native_callable(api, cb, self.var(in_)))
+                #(self.var(ptr)) = Pointer.nativeValue(CallbackReference.getFunctionPointer(#(self.var(in_))_0))
+                #(self.var(post_cobject)) = FfiPorts.postCObjectAddress
+                #(self.var(port)) = FfiPorts.keepAlive(#(&lib), #(self.var(in_))_0)
+            },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => quote! {
+                #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                    val #(self.var(in_))#(format!("_{}", i)) = #(self.generate_native_callable(
+                        api,
+                        cb,
+                        quote!(#(self.var(in_)).#(self.ident(name))),
+                    ))
+                )
+                #(for (i, ptr) in vtable.iter().enumerate() join (#<push>) =>
+                    #(self.var(ptr)) = Pointer.nativeValue(CallbackReference.getFunctionPointer(#(self.var(in_))#(format!("_{}", i)))))
+                #(self.var(post_cobject)) = FfiPorts.postCObjectAddress
+                #(self.var(port)) = FfiPorts.keepAlive(#(&lib), #(for i in 0..methods.len() join (, ) => #(self.var(in_))#(format!("_{}", i))))
+            },
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = #(self.var(in_)).ordinal);
+                }
+                quote! {
+                    when (#(self.var(in_))) {
+                        #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                            is #(self.type_ident(name)).#(self.type_ident(variant)) -> {
+                                #(self.var(tag)) = #i
+                                #(for (field, var) in fields join (#<push>) => val #(self.var(var)) = #(self.var(in_)).#(self.ident(field)))
+                                #(self.generate_instr(api, &Instr::DefineArgs(ffi.clone())))
+                                #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                                #(self.write_payload(&lib, payload, ffi))
+                            }
+                        )
+                    }
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(val #(self.var(out)) = #(self.type_ident(name)).values()[#(self.var(tag))]);
+                }
+                quote! {
+                    val #(self.var(out)): #(self.type_ident(name))
+                    when (#(self.var(tag))) {
+                        #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                            #i -> {
+                                #(self.read_payload(&lib, payload, ffi))
+                                #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                                #(self.var(out)) = #(self.type_ident(name)).#(self.type_ident(variant))#(if !fields.is_empty() => (#(for (_, field) in fields join (, ) => #(self.var(field)))))
+                            }
+                        )
+                        else -> throw IllegalStateException(#(quoted(format!("unknown variant of {}", name))))
+                    }
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    val #(self.var(box_))_0 = FfiBox(#(self.var(box_))) { #lib.#drop(0, it) }
+                    val #(self.var(out)): #(self.generate_type(&out.ty)) = Iter(#(self.var(box_))_0, #api::#(self.poll_ident(next))#batched)
+                }
+            }
+            Instr::LiftFuture(box_, poll, drop, out) => quote! {
+                val #(self.var(box_))_0 = FfiBox(#(self.var(box_))) { #lib.#drop(0, it) }
+                val #(self.var(out)): #(self.generate_type(&out.ty)) = FfiFuture(#(self.var(box_))_0, #api::#(self.poll_ident(poll)))
+            },
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let batched = if *batched { quote!(, true) } else { quote!() };
+                quote! {
+                    val #(self.var(box_))_0 = FfiBox(#(self.var(box_))) { #lib.#drop(0, it) }
+                    val #(self.var(out)): #(self.generate_type(&out.ty)) = ffiStream(#(self.var(box_))_0, #api::#(self.poll_ident(poll))#batched)
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(val #(self.var(&vars[0])) = #(self.var(in_))),
+                2 | 3 => {
+                    let fields = ["first", "second", "third"];
+                    quote! {
+                        #(for (var, field) in vars.iter().zip(fields) join (#<push>) => val #(self.var(var)) = #(self.var(in_)).#(field))
+                    }
+                }
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => val #(self.var(var)) = #(self.var(in_))[#(i)] as #(self.generate_type(&var.ty)))
+                },
+            },
+            Instr::LiftTuple(vars, out) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(val #(self.var(out)) = #(self.var(&vars[0]))),
+                2 => {
+                    quote!(val #(self.var(out)) = Pair(#(self.var(&vars[0])), #(self.var(&vars[1]))))
+                }
+                3 => {
+                    quote!(val #(self.var(out)) = Triple(#(for var in vars join (, ) => #(self.var(var)))))
+                }
+                _ => {
+                    quote!(val #(self.var(out)) = listOf(#(for var in vars join (, ) => #(self.var(var)))))
+                }
+            },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => val #(self.var(var)) = #(self.var(in_)).#(self.ident(field)))
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                val #(self.var(out)) = #(self.type_ident(name))(#(for (_, var) in vars join (, ) => #(self.var(var))))
+            },
+            Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
+        }
+    }
+
+    /// Implements the jna callback interface of `cb` by calling `func`.
+    fn generate_native_callable(&self, api: &str, cb: &Callback, func: Tokens) -> Tokens {
+        let args = quote!(#(for var in &cb.args join (, ) => #(self.var(var))));
+        let ret = match &cb.ffi_ret {
+            Some(var) => quote!(: #(self.generate_native_num_type(var.ty.num()))),
+            None => quote!(),
+        };
+        quote! {
+            object : #(self.callback_ident(cb)) {
+                override fun invoke(#(for var in &cb.ffi_args join (, ) => #(self.var(var)): #(self.generate_native_num_type(var.ty.num()))))#ret {
+                    #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(match &cb.ret {
+                        Some(ret) => val #(self.var(ret)) = #(func)(#args),
+                        None => #(func)(#args),
+                    })
+                    #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret)))
+                }
+            }
+        }
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, lib: &Tokens, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                val #(self.var(var)) = Pointer(#(self.var(payload))).#(format!("get{}", self.native_num_name(var.ty.num())))(#(i * 8)L)
+            )
+            #lib.deallocate(#(self.var(payload)), #(ffi.len() * 8)L, 8L)
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, lib: &Tokens, payload: &Option<Var>, ffi: &[Var]) -> Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = #lib.allocate(#(ffi.len() * 8)L, 8L)
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                Pointer(#(self.var(payload))).#(format!("set{}", self.native_num_name(var.ty.num())))(#(i * 8)L, #(self.var(var)))
+            )
+        }
+    }
+
+    fn var(&self, var: &Var) -> Tokens {
+        quote!(#(format!("tmp{}", var.binding)))
+    }
+
+    fn literal(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 | NumType::U8 => quote!(0.toByte()),
+            NumType::I16 | NumType::U16 => quote!(0.toShort()),
+            NumType::I64 | NumType::U64 => quote!(0L),
+            NumType::F32 => quote!(0f),
+            NumType::F64 => quote!(0.0),
+            NumType::I32 | NumType::U32 => quote!(0),
+        }
+    }
+
+    /// Converts a kotlin number to the jna type of `ty`.
+    fn native_conversion(&self, ty: NumType) -> Tokens {
+        quote!(#(format!("to{}", self.native_num_name(ty))))
+    }
+
+    /// Converts a jna number to the kotlin type `ty`.
+    fn wrapped_conversion(&self, ty: &AbiType, num: NumType) -> Tokens {
+        let ty = match ty {
+            AbiType::Num(num) => self.generate_num_type(*num),
+            AbiType::Isize | AbiType::Usize => self.generate_type(ty),
+            _ => self.generate_num_type(num),
+        };
+        quote!(#(format!("to{}", ty.to_string().unwrap())))
+    }
+
+    fn generate_type(&self, ty: &AbiType) -> Tokens {
+        match ty {
+            AbiType::Num(ty) => self.generate_num_type(*ty),
+            AbiType::Isize => quote!(Long),
+            AbiType::Usize => quote!(ULong),
+            AbiType::Bool => quote!(Boolean),
+            AbiType::RefStr | AbiType::String => quote!(String),
+            AbiType::RefSlice(ty) | AbiType::Vec(ty) => {
+                quote!(List<#(self.generate_num_type(*ty))>)
+            }
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!(List<#(self.generate_type(ty))>),
+            AbiType::Map(key, value) => {
+                quote!(Map<#(self.generate_type(key)), #(self.generate_type(value))>)
+            }
+            AbiType::Set(ty) => quote!(Set<#(self.generate_type(ty))>),
+            AbiType::Option(ty) => quote!(#(self.generate_type(ty))?),
+            AbiType::Result(ty, _) => self.generate_type(ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
+            AbiType::Tuple(tuple) => match tuple.len() {
+                0 => quote!(Unit),
+                1 => self.generate_type(&tuple[0]),
+                2 => quote!(Pair<#(for ty in tuple join (, ) => #(self.generate_type(ty)))>),
+                3 => quote!(Triple<#(for ty in tuple join (, ) => #(self.generate_type(ty)))>),
+                _ => quote!(List<Any?>),
+            },
+            AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(#(self.type_ident(ty))),
+            AbiType::Callback(args, ret) => {
+                let ret = match ret {
+                    Some(ret) => self.generate_type(ret),
+                    None => quote!(Unit),
+                };
+                quote!((#(for arg in args join (, ) => #(self.generate_type(arg)))) -> #ret)
+            }
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => quote!(Iter<#(self.generate_type(ty))>),
+            AbiType::RefFuture(ty) | AbiType::Future(ty) => {
+                quote!(FfiFuture<#(self.generate_type(ty))>)
+            }
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
+                quote!(Flow<#(self.generate_type(ty))>)
+            }
+        }
+    }
+
+    fn generate_num_type(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 => quote!(Byte),
+            NumType::I16 => quote!(Short),
+            NumType::I32 => quote!(Int),
+            NumType::I64 => quote!(Long),
+            NumType::U8 => quote!(UByte),
+            NumType::U16 => quote!(UShort),
+            NumType::U32 => quote!(UInt),
+            NumType::U64 => quote!(ULong),
+            NumType::F32 => quote!(Float),
+            NumType::F64 => quote!(Double),
+        }
+    }
+
+    fn native_num_name(&self, ty: NumType) -> String {
+        self.generate_native_num_type(ty).to_string().unwrap()
+    }
+
+    /// Jna maps unsigned numbers to the signed types of the same size.
+    fn generate_native_num_type(&self, ty: NumType) -> Tokens {
+        match ty {
+            NumType::I8 | NumType::U8 => quote!(Byte),
+            NumType::I16 | NumType::U16 => quote!(Short),
+            NumType::I32 | NumType::U32 => quote!(Int),
+            NumType::I64 | NumType::U64 => quote!(Long),
+            NumType::F32 => quote!(Float),
+            NumType::F64 => quote!(Double),
+        }
+    }
+
+    fn generate_return_struct(&self, ret: &Return) -> Tokens {
+        if let Return::Struct(vars, name) = ret {
+            let fields: Vec<String> = (0..vars.len()).map(|i| format!("ret{}", i)).collect();
+            quote! {
+                #<line>
+                @Structure.FieldOrder(#(for field in &fields join (, ) => #(quoted(field))))
+                internal class #name : Structure(), Structure.ByValue {
+                    #(for (field, var) in fields.iter().zip(vars) join (#<push>) =>
+                        @JvmField
+                        var #field: #(self.generate_native_num_type(var.ty.num())) = #(self.literal(var.ty.num()))
+                    )
+                }
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    fn generate_doc<S: AsRef<str>>(&self, doc: &[S]) -> Tokens {
+        match doc.len() {
+            0 => quote!(),
+            1 => quote!(#(static_literal("/**")) #(doc[0].as_ref().trim()) #(static_literal("*/"))),
+            _ => quote! {
+                #(static_literal("/**"))
+                #(for line in doc join (#<push>) => #(static_literal(" *")) #(line.as_ref().trim()))
+                #(static_literal(" */"))
+            },
+        }
+    }
+
+    fn type_ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_upper_camel_case())
+    }
+
+    fn ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_lower_camel_case())
+    }
+
+    fn variant_const(&self, variant: &str) -> String {
+        sanitize_identifier(&variant.to_shouty_snake_case())
+    }
+
+    /// Method of `Api` polling the iterator, future or stream of `symbol`.
+    fn poll_ident(&self, symbol: &str) -> String {
+        format!("ffi{}", symbol.to_upper_camel_case())
+    }
+}
+
+fn sanitize_identifier(id: &str) -> String {
+    if RESERVED_IDENTIFIERS.contains(&id) {
+        format!("`{}`", id)
+    } else {
+        id.to_string()
+    }
+}
+
+// https://kotlinlang.org/docs/keyword-reference.html#hard-keywords
+static RESERVED_IDENTIFIERS: [&str; 28] = [
+    "as",
+    "break",
+    "class",
+    "continue",
+    "do",
+    "else",
+    "false",
+    "for",
+    "fun",
+    "if",
+    "in",
+    "interface",
+    "is",
+    "null",
+    "object",
+    "package",
+    "return",
+    "super",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typealias",
+    "typeof",
+    "val",
+    "var",
+    "when",
+    "while",
+];
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    /// Jna and kotlinx-coroutines are looked up in the `CLASSPATH`.
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, kotlin: Tokens) -> Result<()> {
+        if crate::test_runner::skip_without("kotlinc") {
+            return Ok(());
+        }
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native());
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let kotlin_gen =
+            KotlinGenerator::new("compile_pass".to_string(), "compile_pass".to_string());
+        let kotlin_tokens = kotlin_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let bin_tokens: Tokens = quote! {
+            package compile_pass
+
+            import kotlinx.coroutines.flow.toList
+            import kotlinx.coroutines.runBlocking
+
+            fun main() {
+                runBlocking {
+                    val api = Api.load()
+                    #kotlin
+                }
+            }
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        let bindings_file = dir.as_ref().join("Bindings.kt");
+        std::fs::write(&bindings_file, kotlin_tokens.to_file_string()?)?;
+        let main_file = dir.as_ref().join("Main.kt");
+        std::fs::write(&main_file, bin_tokens.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let classpath = std::env::var("CLASSPATH").unwrap_or_default();
+        let jar_file = dir.as_ref().join("main.jar");
+        let ret = Command::new("kotlinc")
+            .arg("-classpath")
+            .arg(&classpath)
+            .arg("-include-runtime")
+            .arg("-d")
+            .arg(&jar_file)
+            .arg(&main_file)
+            .arg(&bindings_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile kotlin program");
+        let ret = Command::new("java")
+            .arg(format!("-Djna.library.path={}", dir.as_ref().display()))
+            .arg("-classpath")
+            .arg(format!("{}:{}", jar_file.display(), classpath))
+            .arg("compile_pass.MainKt")
+            .status()?;
+        anyhow::ensure!(ret.success(), "kotlin test failed");
+        Ok(())
+    }
+}
@@ -4,19 +4,33 @@
 #![deny(missing_docs)]
 
 mod abi;
+mod c;
+mod csharp;
 mod dart;
+mod go;
 mod js;
+mod kotlin;
+mod napi;
 mod parser;
+mod python;
 mod rust;
+mod swift;
 
 use crate::abi::{
-    export, import, AbiFunction, AbiFuture, AbiIter, AbiObject, AbiStream, AbiType, FunctionType,
-    NumType, Return, Var,
+    export, import, AbiEnum, AbiError, AbiFunction, AbiFuture, AbiIter, AbiObject, AbiStream,
+    AbiStruct, AbiTrait, AbiType, AbiVariant, FunctionType, NumType, Return, Var,
 };
+use crate::c::CHeaderGenerator;
+use crate::csharp::CSharpGenerator;
 use crate::dart::DartGenerator;
+use crate::go::GoGenerator;
 use crate::js::{JsGenerator, TsGenerator, WasmMultiValueShim};
+use crate::kotlin::KotlinGenerator;
+use crate::napi::NapiGenerator;
 use crate::parser::Interface;
+use crate::python::PythonGenerator;
 use crate::rust::RustGenerator;
+use crate::swift::SwiftGenerator;
 use anyhow::{Context, Result};
 use std::path::Path;
 use std::process::Command;
@@ -26,20 +40,70 @@ pub use crate::abi::Abi;
 /// Main entry point to `ffi-gen`.
 pub struct FfiGen {
     iface: Interface,
+    format: bool,
+    executor: Option<String>,
 }
 
 impl FfiGen {
     /// Takes a path to an ffi-gen interface description file and constructs
     /// a new `FfiGen` instance.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let s = std::fs::read_to_string(path)?;
-        let iface = Interface::parse(&s)?;
-        Ok(Self { iface })
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let iface =
+            Interface::parse(&s).map_err(|err| err.with_path(&path.display().to_string()))?;
+        Ok(Self {
+            iface,
+            format: true,
+            executor: None,
+        })
+    }
+
+    /// Whether to run `dart format` and `prettier` on the generated dart, js
+    /// and ts files. Defaults to `true`, without them the files are written
+    /// as emitted by the generators.
+    pub fn format(mut self, format: bool) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Spawns the futures returned by the rust api onto an executor, so they
+    /// can rely on its timers and io. The host is notified once they complete.
+    /// `tokio` creates a multi-thread runtime on first use, any other value is
+    /// the path of a function spawning an `impl Future<Output = ()> + Send +
+    /// 'static`, like `async_std::task::spawn`. By default futures are only
+    /// polled by the host.
+    pub fn executor(mut self, executor: &str) -> Self {
+        self.executor = Some(executor.to_string());
+        self
+    }
+
+    /// Lowers the functions and objects of the interface to the ffi abi of
+    /// every target, like the generators do, without writing any bindings.
+    /// The validator rejects everything lowering can't handle when parsing,
+    /// so this only panics on a bug in ffi-gen.
+    pub fn check(&self) -> Result<()> {
+        for abi in [Abi::Native32, Abi::Native64, Abi::Wasm32, Abi::Wasm64] {
+            self.iface.imports(&abi);
+            RustGenerator::new(abi).generate(self.iface.clone());
+        }
+        Ok(())
     }
 
     /// Generates the rust api.
     pub fn generate_rust(&self, abi: Abi) -> Result<String> {
-        let rust = RustGenerator::new(abi);
+        let rust = RustGenerator::new(abi).executor(self.executor.clone());
+        let rust = rust.generate(self.iface.clone()).to_file_string()?;
+        Ok(rust)
+    }
+
+    /// Generates the rust api, with the functions returning structs writing
+    /// them to a pointer passed as first argument. Used together with
+    /// `generate_js_return_pointer`, the wasm blob doesn't need to be patched
+    /// with `wasm_multi_value_shim`.
+    pub fn generate_rust_return_pointer(&self, abi: Abi) -> Result<String> {
+        let rust = RustGenerator::with_return_pointer(abi).executor(self.executor.clone());
         let rust = rust.generate(self.iface.clone()).to_file_string()?;
         Ok(rust)
     }
@@ -49,6 +113,14 @@ impl FfiGen {
         WasmMultiValueShim::new().run(path, self.iface.clone())
     }
 
+    /// Generates a c header declaring the symbols exported by the rust api.
+    pub fn generate_c_header<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let header = CHeaderGenerator::default();
+        let header = header.generate(self.iface.clone()).to_file_string()?;
+        std::fs::write(path.as_ref(), &header)?;
+        Ok(())
+    }
+
     /// Generates dart bindings for the rust api.
     pub fn generate_dart<P: AsRef<Path>>(
         &self,
@@ -59,6 +131,9 @@ impl FfiGen {
         let dart = DartGenerator::new(library.to_string(), cdylib.to_string());
         let dart = dart.generate(self.iface.clone()).to_file_string()?;
         std::fs::write(path.as_ref(), &dart)?;
+        if !self.format {
+            return Ok(());
+        }
         let status = Command::new("dart")
             .arg("format")
             .arg(path.as_ref())
@@ -70,30 +145,136 @@ impl FfiGen {
         Ok(())
     }
 
+    /// Generates python bindings for the rust api.
+    pub fn generate_python<P: AsRef<Path>>(&self, path: P, cdylib: &str) -> Result<()> {
+        let python = PythonGenerator::new(cdylib.to_string());
+        let python = python.generate(self.iface.clone()).to_file_string()?;
+        std::fs::write(path.as_ref(), &python)?;
+        Ok(())
+    }
+
+    /// Generates swift bindings for the rust api, which call the symbols
+    /// declared by the c header imported as the clang module `module`.
+    pub fn generate_swift<P: AsRef<Path>>(&self, path: P, module: &str) -> Result<()> {
+        let swift = SwiftGenerator::new(module.to_string());
+        let swift = swift.generate(self.iface.clone()).to_file_string()?;
+        std::fs::write(path.as_ref(), &swift)?;
+        Ok(())
+    }
+
+    /// Generates kotlin bindings for the rust api in `package`, which load the
+    /// cdylib with jna.
+    pub fn generate_kotlin<P: AsRef<Path>>(
+        &self,
+        path: P,
+        package: &str,
+        cdylib: &str,
+    ) -> Result<()> {
+        let kotlin = KotlinGenerator::new(package.to_string(), cdylib.to_string());
+        let kotlin = kotlin.generate(self.iface.clone()).to_file_string()?;
+        std::fs::write(path.as_ref(), &kotlin)?;
+        Ok(())
+    }
+
+    /// Generates a cgo package for the rust api, which links against the
+    /// cdylib.
+    pub fn generate_go<P: AsRef<Path>>(&self, path: P, package: &str, cdylib: &str) -> Result<()> {
+        let go = GoGenerator::new(package.to_string(), cdylib.to_string());
+        let go = go::to_file_string(&go.generate(self.iface.clone()))?;
+        std::fs::write(path.as_ref(), &go)?;
+        Ok(())
+    }
+
+    /// Generates c# bindings for the rust api in `namespace`, which call the
+    /// cdylib through p/invoke.
+    pub fn generate_csharp<P: AsRef<Path>>(
+        &self,
+        path: P,
+        namespace: &str,
+        cdylib: &str,
+    ) -> Result<()> {
+        let csharp = CSharpGenerator::new(namespace.to_string(), cdylib.to_string());
+        let csharp = csharp.generate(self.iface.clone()).to_file_string()?;
+        std::fs::write(path.as_ref(), &csharp)?;
+        Ok(())
+    }
+
     /// Generates js bindings for the rust api.
     pub fn generate_js<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let js = JsGenerator::default();
         let js = js.generate(self.iface.clone()).to_file_string()?;
-        std::fs::write(path.as_ref(), &js)?;
-        let status = Command::new("prettier")
-            .arg("--write")
-            .arg(path.as_ref())
-            .status()
-            .context("prettier not installed")?;
-        if !status.success() {
-            anyhow::bail!("prettier failed");
-        }
-        Ok(())
+        self.write_js(path.as_ref(), &js)
+    }
+
+    /// Generates js bindings for the rust api generated by
+    /// `generate_rust_return_pointer`.
+    pub fn generate_js_return_pointer<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let js = JsGenerator::with_return_pointer();
+        let js = js.generate(self.iface.clone()).to_file_string()?;
+        self.write_js(path.as_ref(), &js)
     }
 
     /// Generates typescript type definitions for the js bindings.
     pub fn generate_ts<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let ts = TsGenerator::default();
         let ts = ts.generate(self.iface.clone()).to_file_string()?;
-        std::fs::write(path.as_ref(), &ts)?;
+        self.write_js(path.as_ref(), &ts)
+    }
+
+    /// Generates js bindings for the rust api, which load the native cdylib
+    /// into node. The cdylib needs to include the module generated by
+    /// `generate_napi`.
+    pub fn generate_node_js<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.check_node()?;
+        let js = JsGenerator::node();
+        let js = js.generate(self.iface.clone()).to_file_string()?;
+        self.write_js(path.as_ref(), &js)
+    }
+
+    /// Generates typescript type definitions for the node js bindings.
+    pub fn generate_node_ts<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.check_node()?;
+        let ts = TsGenerator::node();
+        let ts = ts.generate(self.iface.clone()).to_file_string()?;
+        self.write_js(path.as_ref(), &ts)
+    }
+
+    /// Generates the Node-API module used by the node js bindings.
+    pub fn generate_napi(&self) -> Result<String> {
+        self.check_node()?;
+        let napi = NapiGenerator::default();
+        let napi = napi.generate(self.iface.clone()).to_file_string()?;
+        Ok(napi)
+    }
+
+    /// Callbacks and interfaces can't be passed to rust from node yet.
+    fn check_node(&self) -> Result<()> {
+        let mut functions = self.iface.functions();
+        for obj in self.iface.objects() {
+            functions.extend(obj.methods);
+        }
+        for func in functions {
+            for (name, ty) in &func.args {
+                if matches!(ty, AbiType::Callback(_, _) | AbiType::Trait(_)) {
+                    anyhow::bail!(
+                        "argument `{}` of `{}` can't be passed to rust from node, callbacks and interfaces are only supported with wasm",
+                        name,
+                        func.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_js(&self, path: &Path, js: &str) -> Result<()> {
+        std::fs::write(path, js)?;
+        if !self.format {
+            return Ok(());
+        }
         let status = Command::new("prettier")
             .arg("--write")
-            .arg(path.as_ref())
+            .arg(path)
             .status()
             .context("prettier not installed")?;
         if !status.success() {
@@ -106,14 +287,40 @@ impl FfiGen {
 #[cfg(feature = "test_runner")]
 #[doc(hidden)]
 pub mod test_runner {
+    pub use crate::c::test_runner::compile_pass as compile_pass_c;
+    pub use crate::csharp::test_runner::compile_pass as compile_pass_csharp;
     pub use crate::dart::test_runner::compile_pass as compile_pass_dart;
+    pub use crate::dart::test_runner::compile_pass_with_declarations as compile_pass_dart_with_declarations;
+    pub use crate::go::test_runner::compile_pass as compile_pass_go;
     pub use crate::js::test_runner::compile_pass as compile_pass_js;
+    pub use crate::js::test_runner::compile_pass_return_pointer as compile_pass_js_return_pointer;
     pub use crate::js::test_runner::compile_pass_ts;
+    pub use crate::kotlin::test_runner::compile_pass as compile_pass_kotlin;
+    pub use crate::napi::test_runner::compile_pass as compile_pass_node;
+    pub use crate::python::test_runner::compile_pass as compile_pass_python;
+    pub use crate::python::test_runner::compile_pass_executor as compile_pass_python_executor;
     pub use crate::rust::test_runner::compile_pass as compile_pass_rust;
+    pub use crate::swift::test_runner::compile_pass as compile_pass_swift;
 
+    /// Returns whether a test needing `tool` is skipped because it isn't installed.
+    /// CI installs every tool, so there a missing tool fails the test instead.
+    pub(crate) fn skip_without(tool: &str) -> bool {
+        let installed = std::process::Command::new(tool)
+            .arg("--version")
+            .output()
+            .is_ok();
+        if installed || std::env::var_os("CI").is_some() {
+            return false;
+        }
+        eprintln!("skipping test, `{}` is not installed", tool);
+        true
+    }
+
+    /// The dart test can start with a block of declarations placed outside of `main`,
+    /// `({ class A {} } final a = A();)`.
     #[macro_export]
     macro_rules! compile_pass {
-        ($ident:ident, $iface:expr, ($($api:tt)*), ($($rust:tt)*), ($($dart:tt)*), ($($js:tt)*), ($($ts:tt)*)) => {
+        ($ident:ident, $iface:expr, ($($api:tt)*), ($($rust:tt)*), ({ $($decls:tt)* } $($dart:tt)*), ($($js:tt)*), ($($ts:tt)*)) => {
             mod $ident {
                 #[test]
                 fn rust() {
@@ -122,7 +329,7 @@ pub mod test_runner {
 
                 #[test]
                 fn dart() {
-                    $crate::test_runner::compile_pass_dart($iface, genco::quote!($($api)*), genco::quote!($($dart)*)).unwrap();
+                    $crate::test_runner::compile_pass_dart_with_declarations($iface, genco::quote!($($api)*), genco::quote!($($decls)*), genco::quote!($($dart)*)).unwrap();
                 }
 
                 #[test]
@@ -130,11 +337,19 @@ pub mod test_runner {
                     $crate::test_runner::compile_pass_js($iface, genco::quote!($($api)*), genco::quote!($($js)*)).unwrap();
                 }
 
+                #[test]
+                fn js_return_pointer() {
+                    $crate::test_runner::compile_pass_js_return_pointer($iface, genco::quote!($($api)*), genco::quote!($($js)*)).unwrap();
+                }
+
                 #[test]
                 fn ts() {
                     $crate::test_runner::compile_pass_ts($iface, genco::quote!($($ts)*)).unwrap();
                 }
             }
-        }
+        };
+        ($ident:ident, $iface:expr, ($($api:tt)*), ($($rust:tt)*), ($($dart:tt)*), ($($js:tt)*), ($($ts:tt)*)) => {
+            $crate::compile_pass!($ident, $iface, ($($api)*), ($($rust)*), ({} $($dart)*), ($($js)*), ($($ts)*));
+        };
     }
 }
@@ -0,0 +1,219 @@
+//! Command line interface to `ffi-gen`.
+use anyhow::{Context, Result};
+use ffi_gen::{Abi, FfiGen};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Call rust from any language.
+
+USAGE:
+    ffi-gen check <INTERFACE>
+    ffi-gen generate <rust|napi|c|dart|js|ts|python|swift|kotlin|go|csharp> <INTERFACE> [OPTIONS]
+    ffi-gen wasm-shim <INTERFACE> <WASM>
+
+OPTIONS:
+    --abi <ABI>          Abi of the rust api: native, native32, native64, wasm32
+                         or wasm64 [default: native]
+    --target <TARGET>    Runtime of the js and ts bindings: wasm or node, which
+                         loads the cdylib with the napi module [default: wasm]
+    --return-pointer     Return structs from the rust api through a pointer, so
+                         the wasm blob doesn't need to be patched with wasm-shim
+    --library <NAME>     Name of the generated dart library or of the c module
+                         imported by the swift bindings [default: interface file stem]
+    --cdylib <NAME>      Name of the cdylib loaded by the dart, python, kotlin,
+                         go and csharp bindings [default: interface file stem]
+    --package <NAME>     Package of the generated kotlin or go bindings, or
+                         namespace of the csharp bindings
+                         [default: interface file stem]
+    --executor <PATH>    Spawn the futures of the rust api onto an executor: tokio,
+                         or the path of a spawn function like async_std::task::spawn
+    --no-format          Don't run dart format or prettier on the generated files
+    --out-dir <DIR>      Directory to write the generated file to [default: .]
+    -o, --out <FILE>     Path of the generated file, overrides `--out-dir`
+    -h, --help           Print this message
+";
+
+#[derive(Default)]
+struct Options {
+    args: Vec<String>,
+    abi: Option<String>,
+    target: Option<String>,
+    library: Option<String>,
+    cdylib: Option<String>,
+    package: Option<String>,
+    executor: Option<String>,
+    out_dir: Option<PathBuf>,
+    out: Option<PathBuf>,
+    return_pointer: bool,
+    no_format: bool,
+    help: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut opts = Self::default();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with('-') => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                opts.help = true;
+                continue;
+            }
+            if flag == "--return-pointer" {
+                opts.return_pointer = true;
+                continue;
+            }
+            if flag == "--no-format" {
+                opts.no_format = true;
+                continue;
+            }
+            if !flag.starts_with('-') {
+                opts.args.push(flag);
+                continue;
+            }
+            if !matches!(
+                flag.as_str(),
+                "--abi"
+                    | "--target"
+                    | "--library"
+                    | "--cdylib"
+                    | "--package"
+                    | "--executor"
+                    | "--out-dir"
+                    | "-o"
+                    | "--out"
+            ) {
+                anyhow::bail!("unknown option `{}`", flag);
+            }
+            let value = match value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => anyhow::bail!("missing value for `{}`", flag),
+            };
+            match flag.as_str() {
+                "--abi" => opts.abi = Some(value),
+                "--target" => opts.target = Some(value),
+                "--library" => opts.library = Some(value),
+                "--cdylib" => opts.cdylib = Some(value),
+                "--package" => opts.package = Some(value),
+                "--executor" => opts.executor = Some(value),
+                "--out-dir" => opts.out_dir = Some(value.into()),
+                _ => opts.out = Some(value.into()),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Returns the path of the generated file, creating its directory if needed.
+    fn out(&self, file_name: &str) -> Result<PathBuf> {
+        let path = match &self.out {
+            Some(out) => out.clone(),
+            None => {
+                let dir = self.out_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+                dir.join(file_name)
+            }
+        };
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+            }
+        }
+        Ok(path)
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let opts = Options::parse(std::env::args().skip(1))?;
+    let args: Vec<&str> = opts.args.iter().map(|arg| arg.as_str()).collect();
+    if opts.help || args.is_empty() {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    match args.as_slice() {
+        ["check", iface] => FfiGen::new(iface)?.check()?,
+        ["generate", lang, iface] => {
+            let mut ffigen = FfiGen::new(iface)?.format(!opts.no_format);
+            if let Some(executor) = &opts.executor {
+                ffigen = ffigen.executor(executor);
+            }
+            let stem = Path::new(iface)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .context("invalid interface file name")?;
+            match *lang {
+                "rust" => {
+                    let abi: Abi = opts.abi.as_deref().unwrap_or("native").parse()?;
+                    let rust = if opts.return_pointer {
+                        ffigen.generate_rust_return_pointer(abi)?
+                    } else {
+                        ffigen.generate_rust(abi)?
+                    };
+                    std::fs::write(opts.out("bindings.rs")?, rust)?;
+                }
+                "napi" => std::fs::write(opts.out("napi.rs")?, ffigen.generate_napi()?)?,
+                "c" => ffigen.generate_c_header(opts.out("bindings.h")?)?,
+                "dart" => {
+                    let library = opts.library.as_deref().unwrap_or(stem);
+                    let cdylib = opts.cdylib.as_deref().unwrap_or(stem);
+                    ffigen.generate_dart(opts.out("bindings.dart")?, library, cdylib)?;
+                }
+                "js" | "ts" => {
+                    let path = match *lang {
+                        "js" => opts.out("bindings.mjs")?,
+                        _ => opts.out("bindings.d.ts")?,
+                    };
+                    match (*lang, opts.target.as_deref().unwrap_or("wasm")) {
+                        ("js", "wasm") if opts.return_pointer => {
+                            ffigen.generate_js_return_pointer(path)?
+                        }
+                        ("js", "wasm") => ffigen.generate_js(path)?,
+                        ("ts", "wasm") => ffigen.generate_ts(path)?,
+                        ("js", "node") => ffigen.generate_node_js(path)?,
+                        ("ts", "node") => ffigen.generate_node_ts(path)?,
+                        (_, target) => anyhow::bail!("unknown target `{}`", target),
+                    }
+                }
+                "python" => {
+                    let cdylib = opts.cdylib.as_deref().unwrap_or(stem);
+                    ffigen.generate_python(opts.out("bindings.py")?, cdylib)?;
+                }
+                "swift" => {
+                    let module = opts.library.as_deref().unwrap_or(stem);
+                    ffigen.generate_swift(opts.out("bindings.swift")?, module)?;
+                }
+                "kotlin" => {
+                    let package = opts.package.as_deref().unwrap_or(stem);
+                    let cdylib = opts.cdylib.as_deref().unwrap_or(stem);
+                    ffigen.generate_kotlin(opts.out("Bindings.kt")?, package, cdylib)?;
+                }
+                "go" => {
+                    let package = opts.package.as_deref().unwrap_or(stem);
+                    let cdylib = opts.cdylib.as_deref().unwrap_or(stem);
+                    ffigen.generate_go(opts.out("bindings.go")?, package, cdylib)?;
+                }
+                "csharp" => {
+                    let namespace = opts.package.as_deref().unwrap_or(stem);
+                    let cdylib = opts.cdylib.as_deref().unwrap_or(stem);
+                    ffigen.generate_csharp(opts.out("Bindings.cs")?, namespace, cdylib)?;
+                }
+                _ => anyhow::bail!("unknown language `{}`\n\n{}", lang, USAGE),
+            }
+        }
+        ["wasm-shim", iface, wasm] => {
+            FfiGen::new(iface)?.wasm_multi_value_shim(wasm)?;
+        }
+        _ => anyhow::bail!("invalid arguments\n\n{}", USAGE),
+    }
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use crate::import::Import;
+use crate::{Abi, Interface, NumType, Return, Var};
+use genco::prelude::*;
+
+/// Generates a Node-API module exporting the symbols of the rust api to the
+/// node bindings of `JsGenerator::node`.
+///
+/// The module only declares the Node-API functions it uses, so it doesn't
+/// need any crates. They are resolved by node when loading the cdylib.
+pub struct NapiGenerator {
+    abi: Abi,
+}
+
+impl Default for NapiGenerator {
+    fn default() -> Self {
+        Self { abi: Abi::Native64 }
+    }
+}
+
+impl NapiGenerator {
+    pub fn generate(&self, iface: Interface) -> rust::Tokens {
+        let imports = iface.imports(&self.abi);
+        let destructors = self.destructors(&iface);
+        quote! {
+            #[allow(non_camel_case_types, non_snake_case, unused)]
+            mod napi {
+                use core::ffi::{c_char, c_void};
+                use core::ptr::null_mut;
+                use core::sync::atomic::{AtomicPtr, Ordering};
+
+                type napi_env = *mut c_void;
+                type napi_value = *mut c_void;
+                type napi_callback_info = *mut c_void;
+                type napi_threadsafe_function = *mut c_void;
+                type napi_status = i32;
+                type napi_callback = unsafe extern "C" fn(napi_env, napi_callback_info) -> napi_value;
+                type napi_threadsafe_function_call_js =
+                    unsafe extern "C" fn(napi_env, napi_value, *mut c_void, *mut c_void);
+
+                const NAPI_BIGINT: i32 = 9;
+                const NAPI_TSFN_NONBLOCKING: i32 = 0;
+
+                extern "C" {
+                    fn napi_get_cb_info(
+                        env: napi_env,
+                        info: napi_callback_info,
+                        argc: *mut usize,
+                        argv: *mut napi_value,
+                        this_arg: *mut napi_value,
+                        data: *mut *mut c_void,
+                    ) -> napi_status;
+                    fn napi_typeof(env: napi_env, value: napi_value, result: *mut i32) -> napi_status;
+                    fn napi_get_value_double(env: napi_env, value: napi_value, result: *mut f64) -> napi_status;
+                    fn napi_get_value_bigint_int64(
+                        env: napi_env,
+                        value: napi_value,
+                        result: *mut i64,
+                        lossless: *mut bool,
+                    ) -> napi_status;
+                    fn napi_create_double(env: napi_env, value: f64, result: *mut napi_value) -> napi_status;
+                    fn napi_create_bigint_int64(env: napi_env, value: i64, result: *mut napi_value) -> napi_status;
+                    fn napi_create_bigint_uint64(env: napi_env, value: u64, result: *mut napi_value) -> napi_status;
+                    fn napi_create_array_with_length(env: napi_env, length: usize, result: *mut napi_value) -> napi_status;
+                    fn napi_set_element(env: napi_env, object: napi_value, index: u32, value: napi_value) -> napi_status;
+                    fn napi_get_undefined(env: napi_env, result: *mut napi_value) -> napi_status;
+                    fn napi_get_global(env: napi_env, result: *mut napi_value) -> napi_status;
+                    fn napi_create_string_utf8(
+                        env: napi_env,
+                        str: *const c_char,
+                        length: usize,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_create_function(
+                        env: napi_env,
+                        name: *const c_char,
+                        length: usize,
+                        cb: napi_callback,
+                        data: *mut c_void,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_set_named_property(
+                        env: napi_env,
+                        object: napi_value,
+                        name: *const c_char,
+                        value: napi_value,
+                    ) -> napi_status;
+                    fn napi_get_named_property(
+                        env: napi_env,
+                        object: napi_value,
+                        name: *const c_char,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_call_function(
+                        env: napi_env,
+                        recv: napi_value,
+                        func: napi_value,
+                        argc: usize,
+                        argv: *const napi_value,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_create_arraybuffer(
+                        env: napi_env,
+                        byte_length: usize,
+                        data: *mut *mut c_void,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_create_external_arraybuffer(
+                        env: napi_env,
+                        data: *mut c_void,
+                        byte_length: usize,
+                        finalize_cb: *mut c_void,
+                        finalize_hint: *mut c_void,
+                        result: *mut napi_value,
+                    ) -> napi_status;
+                    fn napi_create_threadsafe_function(
+                        env: napi_env,
+                        func: napi_value,
+                        async_resource: napi_value,
+                        async_resource_name: napi_value,
+                        max_queue_size: usize,
+                        initial_thread_count: usize,
+                        thread_finalize_data: *mut c_void,
+                        thread_finalize_cb: *mut c_void,
+                        context: *mut c_void,
+                        call_js_cb: napi_threadsafe_function_call_js,
+                        result: *mut napi_threadsafe_function,
+                    ) -> napi_status;
+                    fn napi_call_threadsafe_function(
+                        func: napi_threadsafe_function,
+                        data: *mut c_void,
+                        is_blocking: i32,
+                    ) -> napi_status;
+                    fn napi_unref_threadsafe_function(env: napi_env, func: napi_threadsafe_function) -> napi_status;
+                }
+
+                mod ffi {
+                    extern "C" {
+                        pub fn allocate(size: usize, align: usize) -> *mut u8;
+                        pub fn deallocate(ptr: *mut u8, size: usize, align: usize);
+                        #(for func in &imports join (#<push>) => #(self.generate_extern(func)))
+                        #(for drop in &destructors join (#<push>) => pub fn #drop(_: i64, boxed: i64);)
+                    }
+
+                    #(for func in &imports join (#<line>) => #(self.generate_return_struct(&func.ffi_ret)))
+                }
+
+                /// Converts a js number or bigint.
+                unsafe fn get_i64(env: napi_env, value: napi_value) -> i64 {
+                    let mut ty = 0;
+                    napi_typeof(env, value, &mut ty);
+                    if ty == NAPI_BIGINT {
+                        let mut int = 0;
+                        let mut lossless = false;
+                        napi_get_value_bigint_int64(env, value, &mut int, &mut lossless);
+                        int
+                    } else {
+                        get_f64(env, value) as i64
+                    }
+                }
+
+                unsafe fn get_f64(env: napi_env, value: napi_value) -> f64 {
+                    let mut num = 0.0;
+                    napi_get_value_double(env, value, &mut num);
+                    num
+                }
+
+                unsafe fn create_f64(env: napi_env, value: f64) -> napi_value {
+                    let mut ret = null_mut();
+                    napi_create_double(env, value, &mut ret);
+                    ret
+                }
+
+                unsafe fn create_i64(env: napi_env, value: i64) -> napi_value {
+                    let mut ret = null_mut();
+                    napi_create_bigint_int64(env, value, &mut ret);
+                    ret
+                }
+
+                unsafe fn create_u64(env: napi_env, value: u64) -> napi_value {
+                    let mut ret = null_mut();
+                    napi_create_bigint_uint64(env, value, &mut ret);
+                    ret
+                }
+
+                unsafe fn undefined(env: napi_env) -> napi_value {
+                    let mut ret = null_mut();
+                    napi_get_undefined(env, &mut ret);
+                    ret
+                }
+
+                unsafe fn args<const N: usize>(env: napi_env, info: napi_callback_info) -> [napi_value; N] {
+                    let mut argc = N;
+                    let mut argv = [null_mut(); N];
+                    napi_get_cb_info(env, info, &mut argc, argv.as_mut_ptr(), null_mut(), null_mut());
+                    argv
+                }
+
+                /// Returns an array buffer viewing `len` bytes of native memory at `ptr`.
+                unsafe extern "C" fn buffer(env: napi_env, info: napi_callback_info) -> napi_value {
+                    let [ptr, len] = args::<2>(env, info);
+                    let ptr = get_i64(env, ptr) as usize as *mut c_void;
+                    let len = get_i64(env, len) as usize;
+                    let mut ret = null_mut();
+                    if len == 0 {
+                        napi_create_arraybuffer(env, 0, null_mut(), &mut ret);
+                    } else {
+                        napi_create_external_arraybuffer(env, ptr, len, null_mut(), null_mut(), &mut ret);
+                    }
+                    ret
+                }
+
+                unsafe extern "C" fn allocate(env: napi_env, info: napi_callback_info) -> napi_value {
+                    let [size, align] = args::<2>(env, info);
+                    let ptr = ffi::allocate(get_i64(env, size) as usize, get_i64(env, align) as usize);
+                    create_i64(env, ptr as i64)
+                }
+
+                unsafe extern "C" fn deallocate(env: napi_env, info: napi_callback_info) -> napi_value {
+                    let [ptr, size, align] = args::<3>(env, info);
+                    ffi::deallocate(
+                        get_i64(env, ptr) as usize as *mut u8,
+                        get_i64(env, size) as usize,
+                        get_i64(env, align) as usize,
+                    );
+                    undefined(env)
+                }
+
+                static NOTIFIER: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+
+                /// Passed to rust as `post_cobject`, it can be called from any thread.
+                unsafe extern "C" fn post_cobject(port: i64, _message: *const c_void) {
+                    let notifier = NOTIFIER.load(Ordering::SeqCst);
+                    if !notifier.is_null() {
+                        napi_call_threadsafe_function(notifier, port as usize as *mut c_void, NAPI_TSFN_NONBLOCKING);
+                    }
+                }
+
+                /// Calls `__notifier_callback` with the port on the js thread.
+                unsafe extern "C" fn notify(env: napi_env, _: napi_value, _: *mut c_void, port: *mut c_void) {
+                    if env.is_null() {
+                        return;
+                    }
+                    let mut global = null_mut();
+                    napi_get_global(env, &mut global);
+                    let mut callback = null_mut();
+                    napi_get_named_property(env, global, "__notifier_callback\0".as_ptr() as _, &mut callback);
+                    let port = create_f64(env, port as usize as f64);
+                    let mut ret = null_mut();
+                    napi_call_function(env, global, callback, 1, &port, &mut ret);
+                }
+
+                unsafe fn export(env: napi_env, exports: napi_value, name: &str, cb: napi_callback) {
+                    let mut func = null_mut();
+                    napi_create_function(env, name.as_ptr() as _, name.len(), cb, null_mut(), &mut func);
+                    let name = format!("{}\0", name);
+                    napi_set_named_property(env, exports, name.as_ptr() as _, func);
+                }
+
+                #(for func in &imports join (#<line>) => #(self.generate_function(func)))
+
+                #(for drop in &destructors join (#<line>) =>
+                    unsafe extern "C" fn #drop(env: napi_env, info: napi_callback_info) -> napi_value {
+                        let [_, boxed] = args::<2>(env, info);
+                        ffi::#drop(0, get_i64(env, boxed));
+                        undefined(env)
+                    }
+                )
+
+                #[no_mangle]
+                pub unsafe extern "C" fn napi_register_module_v1(env: napi_env, exports: napi_value) -> napi_value {
+                    let mut name = null_mut();
+                    napi_create_string_utf8(env, "ffi-gen\0".as_ptr() as _, usize::MAX, &mut name);
+                    let mut notifier = null_mut();
+                    napi_create_threadsafe_function(
+                        env,
+                        null_mut(),
+                        null_mut(),
+                        name,
+                        0,
+                        1,
+                        null_mut(),
+                        null_mut(),
+                        null_mut(),
+                        notify,
+                        &mut notifier,
+                    );
+                    // pending futures and streams keep node alive, not the notifier
+                    napi_unref_threadsafe_function(env, notifier);
+                    NOTIFIER.store(notifier, Ordering::SeqCst);
+                    let post_cobject = create_i64(env, post_cobject as *const () as usize as i64);
+                    napi_set_named_property(env, exports, "__ffi_post_cobject\0".as_ptr() as _, post_cobject);
+                    export(env, exports, "__ffi_buffer", buffer);
+                    export(env, exports, "allocate", allocate);
+                    export(env, exports, "deallocate", deallocate);
+                    #(for func in &imports join (#<push>) => export(env, exports, #(quoted(&func.symbol)), #(&func.symbol));)
+                    #(for drop in &destructors join (#<push>) => export(env, exports, #(quoted(drop)), #drop);)
+                    exports
+                }
+            }
+        }
+    }
+
+    /// Symbols dropping the boxes returned by rust.
+    fn destructors(&self, iface: &Interface) -> Vec<String> {
+        let mut destructors = vec![];
+        destructors.extend(iface.objects().into_iter().map(|obj| obj.destructor));
+        destructors.extend(
+            iface
+                .iterators()
+                .iter()
+                .map(|iter| format!("{}_iter_drop", iter.symbol)),
+        );
+        destructors.extend(
+            iface
+                .futures()
+                .iter()
+                .map(|fut| format!("{}_future_drop", fut.symbol)),
+        );
+        destructors.extend(
+            iface
+                .streams()
+                .iter()
+                .map(|stream| format!("{}_stream_drop", stream.symbol)),
+        );
+        destructors
+    }
+
+    fn generate_extern(&self, func: &Import) -> rust::Tokens {
+        let args = quote!(#(for (i, var) in func.ffi_args.iter().enumerate() join (, ) => #(format!("arg{}", i)): #(self.num_type(var.ty.num()))));
+        match &func.ffi_ret {
+            Return::Void => quote!(pub fn #(&func.symbol)(#args);),
+            Return::Num(var) => {
+                quote!(pub fn #(&func.symbol)(#args) -> #(self.num_type(var.ty.num()));)
+            }
+            Return::Struct(_, name) => quote!(pub fn #(&func.symbol)(#args) -> #name;),
+        }
+    }
+
+    fn generate_return_struct(&self, ret: &Return) -> rust::Tokens {
+        if let Return::Struct(vars, name) = ret {
+            quote! {
+                #[repr(C)]
+                pub struct #name {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(format!("pub ret{}", i)): #(self.num_type(var.ty.num())),)
+                }
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    /// Wraps `func` in a js function, multiple return values are returned
+    /// as an array like the multi value wasm functions.
+    fn generate_function(&self, func: &Import) -> rust::Tokens {
+        let n = func.ffi_args.len();
+        let call = quote!(ffi::#(&func.symbol)(#(for (i, var) in func.ffi_args.iter().enumerate() join (, ) => #(self.lift_arg(var, quote!(argv[#i]))))));
+        let ret = match &func.ffi_ret {
+            Return::Void => quote! {
+                #call;
+                undefined(env)
+            },
+            Return::Num(var) => quote! {
+                let ret = #call;
+                #(self.lower_ret(var, quote!(ret)))
+            },
+            Return::Struct(vars, _) => quote! {
+                let ret = #call;
+                let mut array = null_mut();
+                napi_create_array_with_length(env, #(vars.len()), &mut array);
+                #(for (i, var) in vars.iter().enumerate() join (#<push>) =>
+                    napi_set_element(env, array, #(i as u32), #(self.lower_ret(var, quote!(ret.#(format!("ret{}", i))))));
+                )
+                array
+            },
+        };
+        quote! {
+            unsafe extern "C" fn #(&func.symbol)(env: napi_env, info: napi_callback_info) -> napi_value {
+                #(if n > 0 => let argv = args::<#n>(env, info);)
+                #ret
+            }
+        }
+    }
+
+    fn lift_arg(&self, var: &Var, value: rust::Tokens) -> rust::Tokens {
+        match var.ty.num() {
+            NumType::F32 | NumType::F64 => {
+                quote!(get_f64(env, #value) as #(self.num_type(var.ty.num())))
+            }
+            num => quote!(get_i64(env, #value) as #(self.num_type(num))),
+        }
+    }
+
+    /// 64 bit integers are converted to bigints, like the wasm bindings.
+    fn lower_ret(&self, var: &Var, value: rust::Tokens) -> rust::Tokens {
+        match var.ty.num() {
+            NumType::I64 => quote!(create_i64(env, #value)),
+            NumType::U64 => quote!(create_u64(env, #value)),
+            _ => quote!(create_f64(env, #value as f64)),
+        }
+    }
+
+    fn num_type(&self, ty: NumType) -> rust::Tokens {
+        match ty {
+            NumType::U8 => quote!(u8),
+            NumType::U16 => quote!(u16),
+            NumType::U32 => quote!(u32),
+            NumType::U64 => quote!(u64),
+            NumType::I8 => quote!(i8),
+            NumType::I16 => quote!(i16),
+            NumType::I32 => quote!(i32),
+            NumType::I64 => quote!(i64),
+            NumType::F32 => quote!(f32),
+            NumType::F64 => quote!(f64),
+        }
+    }
+}
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{JsGenerator, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, js: js::Tokens) -> Result<()> {
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::Native64);
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let napi_gen = NapiGenerator::default();
+        let napi_tokens = napi_gen.generate(iface.clone());
+        let js_gen = JsGenerator::node();
+        let js_tokens = js_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #napi_tokens
+            #rust
+        };
+
+        let dir = tempfile::tempdir()?;
+        let library_file = dir.as_ref().join("libcompile_pass.so");
+        let bin_tokens = quote! {
+            import assert from "assert";
+            #js_tokens
+
+            async function main() {
+                const api = new Api();
+                api.load(#(quoted(library_file.to_str().unwrap())));
+                #js
+            }
+            main();
+        };
+
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        let js_file = dir.as_ref().join("compile_pass.mjs");
+        std::fs::write(&js_file, bin_tokens.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("-o")
+            .arg(&library_file)
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let ret = Command::new("node")
+            .arg("--expose-gc")
+            .arg("--unhandled-rejections=strict")
+            .arg(&js_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "node test failed");
+        Ok(())
+    }
+}
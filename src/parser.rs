@@ -1,28 +1,107 @@
-use anyhow::Result;
-use pest::iterators::Pair;
-use pest::Parser;
+mod validate;
+
+use self::validate::Validator;
+use pest::error::{Error, ErrorVariant};
+use pest::iterators::{Pair, Pairs};
+use pest::{Parser, Span};
 use pest_derive::Parser;
 use std::collections::HashSet;
+use std::fmt;
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 struct GrammarParser;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// All syntax or semantic errors found in an interface description.
+#[derive(Debug)]
+pub struct ParseError {
+    errors: Vec<pest::error::Error<Rule>>,
+}
+
+impl ParseError {
+    /// Prefixes the location of every error with the path of the interface description.
+    pub fn with_path(self, path: &str) -> Self {
+        Self {
+            errors: self
+                .errors
+                .into_iter()
+                .map(|err| err.with_path(path))
+                .collect(),
+        }
+    }
+
+    fn new(span: Span, message: impl Into<String>) -> Self {
+        let variant = ErrorVariant::CustomError {
+            message: message.into(),
+        };
+        Error::new_from_span(variant, span).into()
+    }
+}
+
+/// Takes the next type of a pair, which the grammar guarantees.
+fn next_pair<'a>(pairs: &mut Pairs<'a, Rule>, span: Span) -> Result<Pair<'a, Rule>, ParseError> {
+    pairs
+        .next()
+        .ok_or_else(|| ParseError::new(span, "expected a type"))
+}
+
+/// Unwraps a part of an item, which the grammar guarantees.
+fn required<T>(value: Option<T>, span: Span, what: &str) -> Result<T, ParseError> {
+    value.ok_or_else(|| ParseError::new(span, format!("expected {}", what)))
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        Self { errors: vec![err] }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, err) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Interface {
     pub doc: Vec<String>,
     pub functions: Vec<Function>,
     pub objects: Vec<Object>,
+    pub traits: Vec<Trait>,
+    pub errors: Vec<ErrorType>,
+    pub structs: Vec<Struct>,
+    pub enums: Vec<Enum>,
     idents: HashSet<String>,
+    trait_idents: HashSet<String>,
+    error_idents: HashSet<String>,
+    struct_idents: HashSet<String>,
+    enum_idents: HashSet<String>,
 }
 
 impl Interface {
-    pub fn parse(input: &str) -> Result<Self> {
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
         let pairs = GrammarParser::parse(Rule::root, input)?;
+        Validator::default().validate(pairs.clone())?;
         let mut doc = vec![];
         let mut functions = vec![];
         let mut objects = vec![];
+        let mut traits = vec![];
+        let mut errors = vec![];
+        let mut structs = vec![];
+        let mut enums = vec![];
         let mut idents = HashSet::new();
+        let mut trait_idents = HashSet::new();
+        let mut error_idents = HashSet::new();
+        let mut struct_idents = HashSet::new();
+        let mut enum_idents = HashSet::new();
         for pair in pairs {
             for pair in pair.into_inner() {
                 match pair.as_rule() {
@@ -31,12 +110,29 @@ impl Interface {
                     }
                     Rule::object => {
                         let obj = Object::parse(pair)?;
-                        if idents.contains(&obj.ident) {
-                            anyhow::bail!("duplicate object identifier");
-                        }
                         idents.insert(obj.ident.clone());
                         objects.push(obj);
                     }
+                    Rule::interface => {
+                        let tr = Trait::parse(pair)?;
+                        trait_idents.insert(tr.ident.clone());
+                        traits.push(tr);
+                    }
+                    Rule::error => {
+                        let err = ErrorType::parse(pair)?;
+                        error_idents.insert(err.ident.clone());
+                        errors.push(err);
+                    }
+                    Rule::struct_ => {
+                        let st = Struct::parse(pair)?;
+                        struct_idents.insert(st.ident.clone());
+                        structs.push(st);
+                    }
+                    Rule::enum_ => {
+                        let en = Enum::parse(pair)?;
+                        enum_idents.insert(en.ident.clone());
+                        enums.push(en);
+                    }
                     Rule::function => {
                         let fun = Function::parse(pair)?;
                         functions.push(fun);
@@ -49,24 +145,92 @@ impl Interface {
             doc,
             functions,
             objects,
+            traits,
+            errors,
+            structs,
+            enums,
             idents,
+            trait_idents,
+            error_idents,
+            struct_idents,
+            enum_idents,
         })
     }
 
     pub fn is_object(&self, name: &str) -> bool {
         self.idents.contains(name)
     }
+
+    pub fn is_trait(&self, name: &str) -> bool {
+        self.trait_idents.contains(name)
+    }
+
+    pub fn is_error(&self, name: &str) -> bool {
+        self.error_idents.contains(name)
+    }
+
+    pub fn is_struct(&self, name: &str) -> bool {
+        self.struct_idents.contains(name)
+    }
+
+    pub fn is_enum(&self, name: &str) -> bool {
+        self.enum_idents.contains(name)
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Object {
     pub doc: Vec<String>,
+    /// Whether the object is stored in an `Arc`, so async methods can hold on to it.
+    pub is_shared: bool,
     pub ident: String,
     pub methods: Vec<Function>,
 }
 
 impl Object {
-    pub fn parse(pair: Pair<Rule>) -> Result<Self> {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut is_shared = false;
+        let mut ident = None;
+        let mut methods = vec![];
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::shared => {
+                    is_shared = true;
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::function => {
+                    let method = Function::parse(pair)?;
+                    methods.push(method);
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            is_shared,
+            ident: required(ident, span, "an identifier")?,
+            methods,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trait {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub methods: Vec<Function>,
+}
+
+impl Trait {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
         let mut doc = vec![];
         let mut ident = None;
         let mut methods = vec![];
@@ -87,12 +251,203 @@ impl Object {
         }
         Ok(Self {
             doc,
-            ident: ident.unwrap(),
+            ident: required(ident, span, "an identifier")?,
             methods,
         })
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Struct {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub fields: Vec<Field>,
+}
+
+impl Struct {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut ident = None;
+        let mut fields = vec![];
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::fields => {
+                    for pair in pair.into_inner() {
+                        if pair.as_rule() == Rule::field {
+                            fields.push(Field::parse(pair)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            ident: required(ident, span, "an identifier")?,
+            fields,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Enum {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub variants: Vec<Variant>,
+}
+
+impl Enum {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut ident = None;
+        let mut variants = vec![];
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::variants => {
+                    for pair in pair.into_inner() {
+                        if pair.as_rule() == Rule::variant {
+                            variants.push(Variant::parse(pair)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            ident: required(ident, span, "an identifier")?,
+            variants,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorType {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub variants: Vec<Variant>,
+}
+
+impl ErrorType {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut ident = None;
+        let mut variants = vec![];
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::variants => {
+                    for pair in pair.into_inner() {
+                        if pair.as_rule() == Rule::variant {
+                            variants.push(Variant::parse(pair)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            ident: required(ident, span, "an identifier")?,
+            variants,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Variant {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub fields: Vec<Field>,
+}
+
+impl Variant {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut ident = None;
+        let mut fields = vec![];
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::fields => {
+                    for pair in pair.into_inner() {
+                        if pair.as_rule() == Rule::field {
+                            fields.push(Field::parse(pair)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            ident: required(ident, span, "an identifier")?,
+            fields,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Field {
+    pub doc: Vec<String>,
+    pub ident: String,
+    pub ty: Type,
+}
+
+impl Field {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let mut doc = vec![];
+        let mut ident = None;
+        let mut ty = None;
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::item_docs => {
+                    doc.push(pair.as_str()[3..].trim().to_string());
+                }
+                Rule::ident => {
+                    ident = Some(pair.as_str().to_string());
+                }
+                Rule::type_ => {
+                    ty = Some(Type::parse(pair)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            doc,
+            ident: required(ident, span, "an identifier")?,
+            ty: required(ty, span, "a type")?,
+        })
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Function {
     pub doc: Vec<String>,
@@ -103,7 +458,8 @@ pub struct Function {
 }
 
 impl Function {
-    pub fn parse(pair: Pair<Rule>) -> Result<Self> {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
         let mut doc = vec![];
         let mut is_static = false;
         let mut ident = None;
@@ -136,7 +492,9 @@ impl Function {
                                     _ => {}
                                 }
                             }
-                            args.push((ident.unwrap(), ty.unwrap()));
+                            let ident = required(ident, span, "an argument name")?;
+                            let ty = required(ty, span, "an argument type")?;
+                            args.push((ident, ty));
                         }
                     }
                 }
@@ -149,7 +507,7 @@ impl Function {
         Ok(Self {
             doc,
             is_static,
-            ident: ident.unwrap(),
+            ident: required(ident, span, "an identifier")?,
             args,
             ret,
         })
@@ -176,18 +534,23 @@ pub enum Type {
     Ident(String),
     Slice(Box<Type>),
     Vec(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    Set(Box<Type>),
     Option(Box<Type>),
-    Result(Box<Type>),
-    Iter(Box<Type>),
+    Result(Box<Type>, Box<Type>),
+    Iter(Box<Type>, Option<usize>),
     Future(Box<Type>),
-    Stream(Box<Type>),
+    Stream(Box<Type>, Option<usize>),
     Tuple(Vec<Type>),
+    Callback(Vec<Type>, Option<Box<Type>>),
 }
 
 impl Type {
-    pub fn parse(pair: Pair<Rule>) -> Result<Self> {
-        let pair = pair.into_inner().next().unwrap();
-        Ok(match pair.as_rule() {
+    pub fn parse(pair: Pair<Rule>) -> Result<Self, ParseError> {
+        let span = pair.as_span();
+        let pair = next_pair(&mut pair.into_inner(), span)?;
+        let span = pair.as_span();
+        let ty = match pair.as_rule() {
             Rule::primitive => match pair.as_str() {
                 "u8" => Type::U8,
                 "u16" => Type::U16,
@@ -203,35 +566,54 @@ impl Type {
                 "f32" => Type::F32,
                 "f64" => Type::F64,
                 "string" => Type::String,
-                _ => unreachable!(),
+                ty => return Err(ParseError::new(span, format!("unknown primitive `{}`", ty))),
             },
             Rule::ident => Type::Ident(pair.as_str().to_string()),
+            Rule::res => {
+                let mut inner = pair.into_inner();
+                let ok = Type::parse(next_pair(&mut inner, span)?)?;
+                let err = match inner.next() {
+                    Some(pair) => Type::parse(pair)?,
+                    None => Type::String,
+                };
+                Type::Result(Box::new(ok), Box::new(err))
+            }
+            Rule::map => {
+                let mut inner = pair.into_inner();
+                let key = Type::parse(next_pair(&mut inner, span)?)?;
+                let value = Type::parse(next_pair(&mut inner, span)?)?;
+                Type::Map(Box::new(key), Box::new(value))
+            }
+            Rule::set => {
+                let inner = Type::parse(next_pair(&mut pair.into_inner(), span)?)?;
+                Type::Set(Box::new(inner))
+            }
             Rule::slice
             | Rule::vec
             | Rule::opt
-            | Rule::res
             | Rule::ref_
             | Rule::iter
             | Rule::fut
             | Rule::stream => {
-                let first = pair.as_str().chars().next().unwrap();
+                let rule = pair.as_rule();
                 let mut inner = None;
+                let mut batch = None;
                 for pair in pair.into_inner() {
-                    if pair.as_rule() == Rule::type_ {
-                        inner = Some(Box::new(Type::parse(pair)?));
+                    match pair.as_rule() {
+                        Rule::type_ => inner = Some(Box::new(Type::parse(pair)?)),
+                        Rule::batch => batch = pair.as_str().parse().ok(),
+                        _ => {}
                     }
                 }
-                let inner = inner.unwrap();
-                match first {
-                    '[' => Type::Slice(inner),
-                    'V' => Type::Vec(inner),
-                    'O' => Type::Option(inner),
-                    'R' => Type::Result(inner),
-                    '&' => Type::Ref(inner),
-                    'I' => Type::Iter(inner),
-                    'F' => Type::Future(inner),
-                    'S' => Type::Stream(inner),
-                    _ => unreachable!(),
+                let inner = required(inner, span, "a type")?;
+                match rule {
+                    Rule::slice => Type::Slice(inner),
+                    Rule::vec => Type::Vec(inner),
+                    Rule::opt => Type::Option(inner),
+                    Rule::ref_ => Type::Ref(inner),
+                    Rule::iter => Type::Iter(inner, batch),
+                    Rule::fut => Type::Future(inner),
+                    _ => Type::Stream(inner, batch),
                 }
             }
             Rule::tuple => {
@@ -241,33 +623,41 @@ impl Type {
                 }
                 Type::Tuple(tuple)
             }
-            r => unreachable!("{:?}", r),
-        })
+            Rule::callback => {
+                let mut args = vec![];
+                let mut ret = None;
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::type_ => args.push(Self::parse(pair)?),
+                        Rule::callback_ret => {
+                            let span = pair.as_span();
+                            let pair = next_pair(&mut pair.into_inner(), span)?;
+                            ret = Some(Box::new(Self::parse(pair)?));
+                        }
+                        _ => {}
+                    }
+                }
+                Type::Callback(args, ret)
+            }
+            rule => return Err(ParseError::new(span, format!("unexpected {:?}", rule))),
+        };
+        Ok(ty)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
 
     #[test]
     fn test_parse() -> Result<()> {
         let res = Interface::parse("")?;
-        assert_eq!(
-            res,
-            Interface {
-                doc: Default::default(),
-                objects: vec![],
-                functions: vec![],
-                idents: Default::default(),
-            }
-        );
+        assert_eq!(res, Interface::default());
         let res = Interface::parse("fn hello();")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -275,15 +665,13 @@ mod tests {
                     args: vec![],
                     ret: None,
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
         let res = Interface::parse("fn hello(a: u8);")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -291,15 +679,13 @@ mod tests {
                     args: vec![("a".to_string(), Type::U8)],
                     ret: None,
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
         let res = Interface::parse("fn hello() -> u8;")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -307,15 +693,13 @@ mod tests {
                     args: vec![],
                     ret: Some(Type::U8),
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
         let res = Interface::parse("fn hello(a: &string);")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -323,15 +707,13 @@ mod tests {
                     args: vec![("a".to_string(), Type::Ref(Box::new(Type::String)))],
                     ret: None,
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
         let res = Interface::parse("fn hello(a: &[u8]) -> Vec<i64>;")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -342,15 +724,13 @@ mod tests {
                     )],
                     ret: Some(Type::Vec(Box::new(Type::I64))),
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
         let res = Interface::parse("fn hello() -> Future<u8>;")?;
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
-                objects: vec![],
                 functions: vec![Function {
                     doc: Default::default(),
                     is_static: false,
@@ -358,9 +738,16 @@ mod tests {
                     args: vec![],
                     ret: Some(Type::Future(Box::new(Type::U8))),
                 }],
-                idents: Default::default(),
+                ..Default::default()
             }
         );
+        let res = Interface::parse("fn hello() -> Stream<u8, 16>;")?;
+        assert_eq!(
+            res.functions[0].ret,
+            Some(Type::Stream(Box::new(Type::U8), Some(16)))
+        );
+        let res = Interface::parse("shared object Client {}")?;
+        assert!(res.objects[0].is_shared);
         let res = Interface::parse(
             r#"
             //! A greeter
@@ -385,9 +772,9 @@ mod tests {
                     "read our beautiful docs".to_string(),
                     "here".to_string(),
                 ],
-                functions: vec![],
                 objects: vec![Object {
                     doc: vec!["The main entry point of this example.".to_string(),],
+                    is_shared: false,
                     ident: "Greeter".to_string(),
                     methods: vec![
                         Function {
@@ -407,6 +794,7 @@ mod tests {
                     ]
                 }],
                 idents: vec!["Greeter".to_string()].into_iter().collect(),
+                ..Default::default()
             }
         );
         let res = Interface::parse(
@@ -420,7 +808,6 @@ mod tests {
         assert_eq!(
             res,
             Interface {
-                doc: Default::default(),
                 functions: vec![
                     Function {
                         doc: Default::default(),
@@ -451,10 +838,264 @@ mod tests {
                         ret: Some(Type::Tuple(vec![Type::U8, Type::U8, Type::U8])),
                     }
                 ],
-                objects: Default::default(),
-                idents: Default::default(),
+                ..Default::default()
+            }
+        );
+        let res = Interface::parse(
+            r#"
+            /// Errors returned by the file system.
+            error FsError {
+                NotFound,
+                /// An io error occured.
+                Io { code: i32, msg: string },
+            }
+            fn open(path: &string) -> Result<u32, FsError>;
+            "#,
+        )?;
+        assert_eq!(
+            res,
+            Interface {
+                functions: vec![Function {
+                    doc: Default::default(),
+                    is_static: false,
+                    ident: "open".to_string(),
+                    args: vec![("path".to_string(), Type::Ref(Box::new(Type::String)))],
+                    ret: Some(Type::Result(
+                        Box::new(Type::U32),
+                        Box::new(Type::Ident("FsError".to_string()))
+                    )),
+                }],
+                errors: vec![ErrorType {
+                    doc: vec!["Errors returned by the file system.".to_string()],
+                    ident: "FsError".to_string(),
+                    variants: vec![
+                        Variant {
+                            doc: vec![],
+                            ident: "NotFound".to_string(),
+                            fields: vec![],
+                        },
+                        Variant {
+                            doc: vec!["An io error occured.".to_string()],
+                            ident: "Io".to_string(),
+                            fields: vec![
+                                Field {
+                                    doc: vec![],
+                                    ident: "code".to_string(),
+                                    ty: Type::I32,
+                                },
+                                Field {
+                                    doc: vec![],
+                                    ident: "msg".to_string(),
+                                    ty: Type::String,
+                                },
+                            ],
+                        },
+                    ],
+                }],
+                error_idents: vec!["FsError".to_string()].into_iter().collect(),
+                ..Default::default()
+            }
+        );
+        let res = Interface::parse(
+            r#"
+            /// A point in 2d space.
+            struct Point {
+                x: f64,
+                /// The y coordinate.
+                y: f64,
+            }
+            fn origin() -> Point;
+            "#,
+        )?;
+        assert_eq!(
+            res,
+            Interface {
+                functions: vec![Function {
+                    doc: Default::default(),
+                    is_static: false,
+                    ident: "origin".to_string(),
+                    args: vec![],
+                    ret: Some(Type::Ident("Point".to_string())),
+                }],
+                structs: vec![Struct {
+                    doc: vec!["A point in 2d space.".to_string()],
+                    ident: "Point".to_string(),
+                    fields: vec![
+                        Field {
+                            doc: vec![],
+                            ident: "x".to_string(),
+                            ty: Type::F64,
+                        },
+                        Field {
+                            doc: vec!["The y coordinate.".to_string()],
+                            ident: "y".to_string(),
+                            ty: Type::F64,
+                        },
+                    ],
+                }],
+                struct_idents: vec!["Point".to_string()].into_iter().collect(),
+                ..Default::default()
+            }
+        );
+        let res = Interface::parse("fn hello(cb: Fn(u32, string) -> bool);")?;
+        assert_eq!(
+            res,
+            Interface {
+                functions: vec![Function {
+                    doc: Default::default(),
+                    is_static: false,
+                    ident: "hello".to_string(),
+                    args: vec![(
+                        "cb".to_string(),
+                        Type::Callback(vec![Type::U32, Type::String], Some(Box::new(Type::Bool)))
+                    )],
+                    ret: None,
+                }],
+                ..Default::default()
+            }
+        );
+        let res = Interface::parse(
+            r#"
+            enum Shape {
+                Empty,
+                Circle { radius: f64 },
+            }
+            "#,
+        )?;
+        assert_eq!(
+            res,
+            Interface {
+                enums: vec![Enum {
+                    doc: vec![],
+                    ident: "Shape".to_string(),
+                    variants: vec![
+                        Variant {
+                            doc: vec![],
+                            ident: "Empty".to_string(),
+                            fields: vec![],
+                        },
+                        Variant {
+                            doc: vec![],
+                            ident: "Circle".to_string(),
+                            fields: vec![Field {
+                                doc: vec![],
+                                ident: "radius".to_string(),
+                                ty: Type::F64,
+                            }],
+                        },
+                    ],
+                }],
+                enum_idents: vec!["Shape".to_string()].into_iter().collect(),
+                ..Default::default()
+            }
+        );
+        let res = Interface::parse(
+            r#"
+            /// Receives log messages.
+            interface Logger {
+                fn log(level: u8, msg: &string);
+            }
+            "#,
+        )?;
+        assert_eq!(
+            res,
+            Interface {
+                traits: vec![Trait {
+                    doc: vec!["Receives log messages.".to_string()],
+                    ident: "Logger".to_string(),
+                    methods: vec![Function {
+                        doc: vec![],
+                        is_static: false,
+                        ident: "log".to_string(),
+                        args: vec![
+                            ("level".to_string(), Type::U8),
+                            ("msg".to_string(), Type::Ref(Box::new(Type::String))),
+                        ],
+                        ret: None,
+                    }],
+                }],
+                trait_idents: vec!["Logger".to_string()].into_iter().collect(),
+                ..Default::default()
+            }
+        );
+        assert!(Interface::parse("interface Logger { static fn new() -> Logger; }").is_err());
+        let res = Interface::parse("fn hello(a: Set<u8>) -> Map<string, Vec<u8>>;")?;
+        assert_eq!(
+            res,
+            Interface {
+                functions: vec![Function {
+                    doc: Default::default(),
+                    is_static: false,
+                    ident: "hello".to_string(),
+                    args: vec![("a".to_string(), Type::Set(Box::new(Type::U8)))],
+                    ret: Some(Type::Map(
+                        Box::new(Type::String),
+                        Box::new(Type::Vec(Box::new(Type::U8)))
+                    )),
+                }],
+                ..Default::default()
             }
         );
         Ok(())
     }
+
+    #[test]
+    fn test_validate() {
+        let err = Interface::parse(
+            r#"struct Point { x: u32 }
+fn first(a: Foo) -> Option<Option<u8>>;
+fn second(a: [u8], b: &Point);
+struct Point { y: Future<u8> }
+fn third(a: Iterator<u8, 4>) -> Stream<Result<u8>, 0>;
+object Local {} shared object Client { fn fetch(a: &Local) -> Future<u8>; }
+struct A { b: Option<B> } enum B { C { a: Vec<A> } }
+fn results(a: Result<u8>, b: Option<Result<u8>>) -> Option<Result<u8>>;
+object C {} struct Empty {} fn units(a: Vec<()>, b: Vec<Empty>, c: Option<()>) -> Option<&C>;
+fn callbacks(a: Fn(&[string]), b: Fn() -> u64);
+"#,
+        )
+        .unwrap_err()
+        .with_path("api.rsh")
+        .to_string();
+        let expected = [
+            ("api.rsh:4:8", "duplicate struct identifier `Point`"),
+            ("api.rsh:2:13", "unknown identifier Foo"),
+            ("api.rsh:2:21", "nested options are not supported"),
+            ("api.rsh:3:14", "slice needs to be passed by reference"),
+            ("api.rsh:3:23", "Point needs to be passed by value"),
+            ("api.rsh:4:19", "`Future<u8>` can't be stored in a field"),
+            (
+                "api.rsh:5:26",
+                "only returned iterators and streams can be batched",
+            ),
+            (
+                "api.rsh:5:40",
+                "`Result<u8>` can't be stored in a collection",
+            ),
+            ("api.rsh:5:52", "batch size needs to be at least 1"),
+            (
+                "api.rsh:6:52",
+                "only shared objects can be borrowed by async methods",
+            ),
+            ("api.rsh:7:43", "recursive type `A` has infinite size"),
+            ("api.rsh:8:15", "results can only be returned"),
+            ("api.rsh:8:37", "results can only be returned"),
+            ("api.rsh:9:45", "`()` can't be stored in a collection"),
+            ("api.rsh:9:57", "`Empty` can't be stored in a collection"),
+            ("api.rsh:9:75", "`()` can only be returned"),
+            ("api.rsh:9:90", "objects can only be borrowed as arguments"),
+            (
+                "api.rsh:10:20",
+                "slices of non primitive types can't be passed to a callback",
+            ),
+            ("api.rsh:10:43", "callbacks can't return 64 bit integers"),
+        ];
+        for (location, message) in expected {
+            assert!(err.contains(location), "{} missing in:\n{}", location, err);
+            assert!(err.contains(message), "{} missing in:\n{}", message, err);
+        }
+        assert!(Interface::parse("fn hello(a: u8").is_err());
+        let streams = "object Local {} shared object Client { fn lines(a: &Local) -> Stream<u8>; }";
+        assert!(Interface::parse(streams).is_ok());
+    }
 }
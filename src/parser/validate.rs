@@ -0,0 +1,483 @@
+use super::{ParseError, Rule};
+use pest::error::{Error, ErrorVariant};
+use pest::iterators::{Pair, Pairs};
+use pest::Span;
+use std::collections::{HashMap, HashSet};
+
+/// Where a type is used, which restricts the kinds of types that are allowed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Position {
+    Arg,
+    Ret,
+    InnerArg,
+    Inner,
+    Field,
+    Element,
+    CallbackArg,
+    CallbackRet,
+}
+
+/// Checks that an interface description only uses types that can be passed
+/// across the ffi boundary, collecting all errors instead of stopping at the
+/// first one.
+#[derive(Default)]
+pub struct Validator {
+    objects: HashSet<String>,
+    shared: HashSet<String>,
+    traits: HashSet<String>,
+    errors: HashSet<String>,
+    structs: HashSet<String>,
+    /// Structs without fields, which have no ffi values like `()`.
+    empty_structs: HashSet<String>,
+    enums: HashSet<String>,
+    diagnostics: Vec<Error<Rule>>,
+}
+
+impl Validator {
+    pub fn validate(mut self, pairs: Pairs<Rule>) -> Result<(), ParseError> {
+        let items: Vec<_> = pairs.flat_map(|pair| pair.into_inner()).collect();
+        self.declare(&items);
+        self.cycles(&items);
+        for item in items {
+            match item.as_rule() {
+                Rule::function => self.function(item, false),
+                Rule::object | Rule::interface => {
+                    let is_trait = item.as_rule() == Rule::interface;
+                    let is_shared = is_shared(&item);
+                    for pair in item.into_inner() {
+                        if pair.as_rule() == Rule::function {
+                            if is_shared {
+                                self.async_method(pair.clone());
+                            }
+                            self.function(pair, is_trait);
+                        }
+                    }
+                }
+                Rule::struct_ | Rule::enum_ | Rule::error => {
+                    for pair in item.into_inner().flatten() {
+                        if pair.as_rule() == Rule::field {
+                            let ty = pair.into_inner().next_back().unwrap();
+                            self.ty(ty, Position::Field);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(ParseError {
+                errors: self.diagnostics,
+            })
+        }
+    }
+
+    fn declare(&mut self, items: &[Pair<Rule>]) {
+        let mut declared = HashSet::new();
+        for item in items {
+            let kind = match item.as_rule() {
+                Rule::object => "object",
+                Rule::interface => "interface",
+                Rule::error => "error",
+                Rule::struct_ => "struct",
+                Rule::enum_ => "enum",
+                _ => continue,
+            };
+            let ident = item
+                .clone()
+                .into_inner()
+                .find(|pair| pair.as_rule() == Rule::ident)
+                .unwrap();
+            let name = ident.as_str().to_string();
+            if !declared.insert(name.clone()) {
+                self.error(
+                    ident.as_span(),
+                    format!("duplicate {} identifier `{}`", kind, name),
+                );
+                continue;
+            }
+            if is_shared(item) {
+                self.shared.insert(name.clone());
+            }
+            let has_fields = item
+                .clone()
+                .into_inner()
+                .flatten()
+                .any(|pair| pair.as_rule() == Rule::field);
+            if item.as_rule() == Rule::struct_ && !has_fields {
+                self.empty_structs.insert(name.clone());
+            }
+            match item.as_rule() {
+                Rule::object => self.objects.insert(name),
+                Rule::interface => self.traits.insert(name),
+                Rule::error => self.errors.insert(name),
+                Rule::struct_ => self.structs.insert(name),
+                _ => self.enums.insert(name),
+            };
+        }
+    }
+
+    /// Structs and enums are expanded into their fields, so they can't contain
+    /// themselves, not even through a collection.
+    fn cycles(&mut self, items: &[Pair<Rule>]) {
+        let mut names = vec![];
+        let mut fields = HashMap::new();
+        for item in items {
+            if !matches!(item.as_rule(), Rule::struct_ | Rule::enum_) {
+                continue;
+            }
+            let mut pairs = item.clone().into_inner();
+            let name = pairs.find(|pair| pair.as_rule() == Rule::ident).unwrap();
+            let mut types = vec![];
+            for pair in pairs.flatten() {
+                if pair.as_rule() != Rule::field {
+                    continue;
+                }
+                let ty = pair.into_inner().next_back().unwrap();
+                for ident in ty.clone().into_inner().flatten() {
+                    let is_expanded = self.structs.contains(ident.as_str())
+                        || self.enums.contains(ident.as_str());
+                    if ident.as_rule() == Rule::ident && is_expanded {
+                        types.push((ident.as_str(), ty.as_span()));
+                    }
+                }
+            }
+            names.push(name.as_str());
+            fields.insert(name.as_str(), types);
+        }
+        let mut visited = HashSet::new();
+        for name in names {
+            self.visit(name, &fields, &mut vec![], &mut visited);
+        }
+    }
+
+    fn visit<'i>(
+        &mut self,
+        name: &'i str,
+        fields: &HashMap<&'i str, Vec<(&'i str, Span<'i>)>>,
+        path: &mut Vec<&'i str>,
+        visited: &mut HashSet<&'i str>,
+    ) {
+        if !visited.insert(name) {
+            return;
+        }
+        path.push(name);
+        for (ty, span) in fields.get(name).into_iter().flatten() {
+            if path.contains(ty) {
+                self.error(*span, format!("recursive type `{}` has infinite size", ty));
+            } else {
+                self.visit(ty, fields, path, visited);
+            }
+        }
+        path.pop();
+    }
+
+    fn function(&mut self, pair: Pair<Rule>, is_trait: bool) {
+        let (arg, ret) = if is_trait {
+            (Position::CallbackArg, Position::CallbackRet)
+        } else {
+            (Position::Arg, Position::Ret)
+        };
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::static_ if is_trait => {
+                    self.error(pair.as_span(), "interface methods can't be static");
+                }
+                Rule::args => {
+                    for pair in pair.into_inner() {
+                        let ty = pair.into_inner().next_back().unwrap();
+                        for batch in ty.clone().into_inner().flatten() {
+                            if batch.as_rule() == Rule::batch {
+                                self.error(
+                                    batch.as_span(),
+                                    "only returned iterators and streams can be batched",
+                                );
+                            }
+                        }
+                        self.ty(ty, arg);
+                    }
+                }
+                Rule::type_ => self.ty(pair, ret),
+                _ => {}
+            }
+        }
+    }
+
+    /// Futures returned by methods of shared objects own their arguments, which
+    /// only works for borrowed objects if they are shared too. Streams don't own
+    /// their arguments, so they are checked like streams of other objects.
+    fn async_method(&mut self, pair: Pair<Rule>) {
+        let mut pairs = pair.into_inner();
+        let is_static = pairs.clone().any(|pair| pair.as_rule() == Rule::static_);
+        let returns_future = pairs.clone().any(|pair| {
+            pair.as_rule() == Rule::type_
+                && pair.into_inner().next().unwrap().as_rule() == Rule::fut
+        });
+        if is_static || !returns_future {
+            return;
+        }
+        let args = pairs.find(|pair| pair.as_rule() == Rule::args).unwrap();
+        for arg in args.into_inner() {
+            let ty = arg.into_inner().next_back().unwrap();
+            let span = ty.as_span();
+            let inner = ty.into_inner().next().unwrap();
+            if inner.as_rule() != Rule::ref_ {
+                continue;
+            }
+            let name = inner.into_inner().next().unwrap().as_str();
+            if self.objects.contains(name) && !self.shared.contains(name) {
+                self.error(span, "only shared objects can be borrowed by async methods");
+            }
+        }
+    }
+
+    fn ty(&mut self, pair: Pair<Rule>, pos: Position) {
+        let span = pair.as_span();
+        let pair = pair.into_inner().next().unwrap();
+        let rule = pair.as_rule();
+        if pos == Position::CallbackRet && (rule != Rule::primitive || pair.as_str() == "string") {
+            self.error(span, "callbacks can only return primitive types");
+            return;
+        }
+        // Wasm32 splits 64 bit integers into two ffi values, callbacks return one.
+        if pos == Position::CallbackRet && matches!(pair.as_str(), "u64" | "i64") {
+            self.error(span, "callbacks can't return 64 bit integers");
+            return;
+        }
+        let unsupported = match pos {
+            Position::Field | Position::Element => matches!(
+                rule,
+                Rule::ref_ | Rule::res | Rule::iter | Rule::fut | Rule::stream | Rule::callback
+            ),
+            Position::CallbackArg => matches!(
+                rule,
+                Rule::opt
+                    | Rule::res
+                    | Rule::iter
+                    | Rule::fut
+                    | Rule::stream
+                    | Rule::tuple
+                    | Rule::callback
+            ),
+            _ => false,
+        };
+        if unsupported {
+            self.unsupported(span, pos);
+            return;
+        }
+        let inner = match pos {
+            Position::Arg => Position::InnerArg,
+            Position::Ret => Position::Inner,
+            pos => pos,
+        };
+        match rule {
+            Rule::primitive => {}
+            Rule::ident => self.ident(pair, pos),
+            Rule::ref_ => {
+                let ty = pair.into_inner().next().unwrap();
+                let borrowed = ty.clone().into_inner().next().unwrap();
+                match borrowed.as_rule() {
+                    Rule::primitive if borrowed.as_str() == "string" => {}
+                    Rule::slice => {
+                        let elem = borrowed.into_inner().next().unwrap();
+                        let is_num = elem.clone().into_inner().next().unwrap().as_rule()
+                            == Rule::primitive
+                            && !matches!(elem.as_str(), "bool" | "string" | "usize" | "isize");
+                        if !is_num {
+                            match pos {
+                                Position::Ret | Position::Inner => self
+                                    .error(span, "slices of non primitive types can't be returned"),
+                                Position::CallbackArg => self.error(
+                                    span,
+                                    "slices of non primitive types can't be passed to a callback",
+                                ),
+                                _ => {}
+                            }
+                        }
+                        self.ty(elem, Position::Element);
+                    }
+                    Rule::ident => {
+                        let name = borrowed.as_str();
+                        if self.objects.contains(name) {
+                            match pos {
+                                Position::CallbackArg => self.unsupported(span, pos),
+                                Position::Ret | Position::Inner => {
+                                    self.error(span, "objects can only be borrowed as arguments");
+                                }
+                                _ => {}
+                            }
+                        } else if self.structs.contains(name)
+                            || self.enums.contains(name)
+                            || self.traits.contains(name)
+                        {
+                            self.error(span, format!("{} needs to be passed by value", name));
+                        } else {
+                            self.ident(borrowed, pos);
+                        }
+                    }
+                    _ => self.error(span, "only strings, slices and objects can be borrowed"),
+                }
+            }
+            Rule::slice => self.error(span, "slice needs to be passed by reference"),
+            Rule::vec => {
+                let elem = pair.into_inner().next().unwrap();
+                self.ty(elem, Position::Element);
+            }
+            Rule::map | Rule::set => {
+                let mut inner = pair.into_inner();
+                self.key(inner.next().unwrap());
+                if let Some(value) = inner.next() {
+                    self.ty(value, Position::Element);
+                }
+            }
+            Rule::opt => {
+                let ty = pair.into_inner().next().unwrap();
+                if ty.clone().into_inner().next().unwrap().as_rule() == Rule::opt {
+                    self.error(span, "nested options are not supported");
+                } else {
+                    self.ty(ty, inner);
+                }
+            }
+            Rule::res if matches!(pos, Position::Arg | Position::InnerArg) => {
+                self.error(span, "results can only be returned");
+            }
+            Rule::res => {
+                let mut types = pair.into_inner();
+                self.ty(types.next().unwrap(), inner);
+                if let Some(err) = types.next() {
+                    let name = err.as_str();
+                    if name != "string" && !self.errors.contains(name) {
+                        self.error(err.as_span(), format!("`{}` is not an error type", name));
+                    }
+                }
+            }
+            Rule::iter | Rule::fut | Rule::stream => {
+                let mut inner_pairs = pair.into_inner();
+                let ty = inner_pairs.next().unwrap();
+                match inner_pairs.next() {
+                    // The items of a batched iterator or stream are passed as a list.
+                    Some(batch) => {
+                        if !matches!(batch.as_str().parse::<usize>(), Ok(n) if n > 0) {
+                            self.error(batch.as_span(), "batch size needs to be at least 1");
+                        }
+                        self.ty(ty, Position::Element);
+                    }
+                    None => self.ty(ty, inner),
+                }
+            }
+            // Empty tuples have no ffi values, so they can't be told apart in a list.
+            Rule::tuple if pair.clone().into_inner().next().is_none() => match pos {
+                Position::Field | Position::Element => self.unsupported(span, pos),
+                Position::InnerArg => self.error(span, "`()` can only be returned"),
+                _ => {}
+            },
+            Rule::tuple => {
+                for ty in pair.into_inner() {
+                    self.ty(ty, inner);
+                }
+            }
+            Rule::callback => {
+                if pos != Position::Arg {
+                    self.error(span, "callbacks can only be passed as function arguments");
+                    return;
+                }
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::type_ => self.ty(pair, Position::CallbackArg),
+                        Rule::callback_ret => {
+                            let ty = pair.into_inner().next().unwrap();
+                            self.ty(ty, Position::CallbackRet);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            r => unreachable!("{:?}", r),
+        }
+    }
+
+    fn ident(&mut self, pair: Pair<Rule>, pos: Position) {
+        let span = pair.as_span();
+        let name = pair.as_str();
+        if self.traits.contains(name) {
+            match pos {
+                Position::Arg => {}
+                Position::CallbackArg => {
+                    self.error(
+                        span,
+                        format!("interface {} can't be passed to a callback", name),
+                    );
+                }
+                Position::Field | Position::Element => self.unsupported(span, pos),
+                _ => self.error(span, "interfaces can only be passed as function arguments"),
+            }
+        } else if self.errors.contains(name) {
+            self.error(
+                span,
+                format!(
+                    "error {} can only be returned as `Result<_, {}>`",
+                    name, name
+                ),
+            );
+        } else if self.empty_structs.contains(name)
+            && matches!(pos, Position::Field | Position::Element)
+        {
+            self.unsupported(span, pos);
+        } else if !self.objects.contains(name)
+            && !self.structs.contains(name)
+            && !self.enums.contains(name)
+        {
+            self.error(span, format!("unknown identifier {}", name));
+        }
+    }
+
+    fn key(&mut self, pair: Pair<Rule>) {
+        let span = pair.as_span();
+        let ty = pair.clone().into_inner().next().unwrap();
+        let is_key = match ty.as_rule() {
+            Rule::primitive => !matches!(ty.as_str(), "f32" | "f64"),
+            Rule::ident => self.enums.contains(ty.as_str()),
+            _ => false,
+        };
+        if is_key {
+            return;
+        }
+        if ty.as_rule() == Rule::ident && !self.is_declared(ty.as_str()) {
+            self.ident(ty, Position::Element);
+        } else {
+            self.error(span, format!("`{}` can't be used as a key", span.as_str()));
+        }
+    }
+
+    fn is_declared(&self, name: &str) -> bool {
+        self.objects.contains(name)
+            || self.traits.contains(name)
+            || self.errors.contains(name)
+            || self.structs.contains(name)
+            || self.enums.contains(name)
+    }
+
+    fn unsupported(&mut self, span: Span, pos: Position) {
+        let message = match pos {
+            Position::Field => "can't be stored in a field",
+            Position::Element => "can't be stored in a collection",
+            Position::CallbackArg => "can't be passed to a callback",
+            _ => unreachable!(),
+        };
+        self.error(span, format!("`{}` {}", span.as_str(), message));
+    }
+
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        let variant = ErrorVariant::CustomError {
+            message: message.into(),
+        };
+        self.diagnostics.push(Error::new_from_span(variant, span));
+    }
+}
+
+fn is_shared(item: &Pair<Rule>) -> bool {
+    item.clone()
+        .into_inner()
+        .any(|pair| pair.as_rule() == Rule::shared)
+}
@@ -0,0 +1,978 @@
+use crate::import::{Callback, Import, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Return, Var,
+};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+use heck::*;
+
+pub struct PythonGenerator {
+    abi: Abi,
+    cdylib_name: String,
+}
+
+impl PythonGenerator {
+    pub fn new(cdylib_name: String) -> Self {
+        Self {
+            abi: Abi::native(),
+            cdylib_name,
+        }
+    }
+
+    pub fn generate(&self, iface: Interface) -> python::Tokens {
+        quote! {
+            #(static_literal("#")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("#"))
+            #(static_literal("#")) Generated by "ffi-gen".
+            #(self.generate_doc(&iface.doc))
+            from __future__ import annotations
+
+            import abc
+            import asyncio
+            import ctypes
+            import dataclasses
+            import enum
+            import itertools
+            import sys
+            import typing
+            import weakref
+
+            _T = typing.TypeVar("_T")
+
+            _NULL = object()
+
+            _ports: dict[int, typing.Callable[[], None]] = {}
+            _port_ids = itertools.count(1)
+
+
+            @ctypes.CFUNCTYPE(None, ctypes.c_int64, ctypes.c_void_p)
+            def _post_cobject(port: int, _message: int) -> None:
+                callback = _ports.get(port)
+                if callback is not None:
+                    callback()
+
+
+            _POST_COBJECT = ctypes.cast(_post_cobject, ctypes.c_void_p).value
+
+
+            def _open_port(callback: typing.Callable[[], None]) -> int:
+                """Registers a callback that rust notifies through the returned port."""
+                port = next(_port_ids)
+                _ports[port] = callback
+                return port
+
+
+            def _close_port(port: int) -> None:
+                _ports.pop(port, None)
+
+
+            def _keep_alive(api: Api, *objects: typing.Any) -> int:
+                """Keeps the callbacks in `objects` alive until rust drops them.
+
+                Notifying the returned port runs the calls rust queued for them.
+                """
+                port = next(_port_ids)
+
+                def dispatch(objects: tuple[typing.Any, ...] = objects) -> None:
+                    if api._dispatch(port):
+                        _close_port(port)
+
+                _ports[port] = dispatch
+                return port
+
+
+            def _function(lib: ctypes.CDLL, symbol: str, restype: typing.Any, argtypes: list[typing.Any]) -> typing.Any:
+                func = getattr(lib, symbol)
+                func.restype = restype
+                func.argtypes = argtypes
+                return func
+
+
+            class _Box:
+                def __init__(self, api: Api, ptr: int, drop_symbol: str) -> None:
+                    drop = _function(api._lib, drop_symbol, None, [ctypes.c_ssize_t, ctypes.c_ssize_t])
+                    self._ptr = ptr
+                    self._moved = False
+                    self._finalizer = weakref.finalize(self, drop, 0, ptr)
+
+                def borrow(self) -> int:
+                    if self._moved:
+                        raise RuntimeError("use after move")
+                    if not self._finalizer.alive:
+                        raise RuntimeError("use after free")
+                    return self._ptr
+
+                def move(self) -> int:
+                    if self._moved:
+                        raise RuntimeError("can't move value twice")
+                    if not self._finalizer.alive:
+                        raise RuntimeError("use after free")
+                    self._moved = True
+                    self._finalizer.detach()
+                    return self._ptr
+
+                def drop(self) -> None:
+                    if self._moved:
+                        raise RuntimeError("can't drop moved value")
+                    if not self._finalizer.alive:
+                        raise RuntimeError("double free")
+                    self._finalizer()
+
+
+            class Iter(typing.Generic[_T]):
+                """Implements the iterator protocol for a rust iterator."""
+
+                def __init__(self, box: _Box, next: typing.Callable[[int], typing.Any], batched: bool = False) -> None:
+                    self._box = box
+                    self._next = next
+                    self._batched = batched
+                    self._items: typing.Iterator[_T] = iter(())
+
+                def __iter__(self) -> Iter[_T]:
+                    return self
+
+                def __next__(self) -> _T:
+                    #(static_literal("#")) A batched iterator returns a list of items, which are yielded
+                    #(static_literal("#")) before calling into rust again.
+                    if self._batched:
+                        item = next(self._items, _NULL)
+                        if item is not _NULL:
+                            return item
+                    ret = self._next(self._box.borrow())
+                    if ret is _NULL:
+                        raise StopIteration
+                    if self._batched:
+                        self._items = iter(ret)
+                        return next(self._items)
+                    return ret
+
+                def drop(self) -> None:
+                    self._box.drop()
+
+
+            async def _native_future(box: _Box, poll: typing.Callable[[int, int, int], typing.Any]) -> typing.Any:
+                loop = asyncio.get_running_loop()
+                woken = asyncio.Event()
+                port = _open_port(lambda: loop.call_soon_threadsafe(woken.set))
+                try:
+                    while True:
+                        woken.clear()
+                        ret = poll(box.borrow(), _POST_COBJECT, port)
+                        if ret is not _NULL:
+                            return ret
+                        await woken.wait()
+                finally:
+                    _close_port(port)
+                    box.drop()
+
+
+            class _Future:
+                """Polls a rust future once awaited, so dropping it unawaited leaves no coroutine behind."""
+
+                def __init__(self, box: _Box, poll: typing.Callable[[int, int, int], typing.Any]) -> None:
+                    self._box = box
+                    self._poll = poll
+
+                def __await__(self) -> typing.Generator[typing.Any, None, typing.Any]:
+                    return _native_future(self._box, self._poll).__await__()
+
+
+            async def _native_stream(box: _Box, poll: typing.Callable[[int, int, int, int], typing.Any], batched: bool = False) -> typing.AsyncIterator[typing.Any]:
+                loop = asyncio.get_running_loop()
+                woken = asyncio.Event()
+                finished = False
+
+                def finish() -> None:
+                    nonlocal finished
+                    finished = True
+                    loop.call_soon_threadsafe(woken.set)
+
+                port = _open_port(lambda: loop.call_soon_threadsafe(woken.set))
+                done = _open_port(finish)
+                try:
+                    while not finished:
+                        woken.clear()
+                        ret = poll(box.borrow(), _POST_COBJECT, port, done)
+                        if ret is _NULL:
+                            if not finished:
+                                await woken.wait()
+                        elif batched:
+                            for item in ret:
+                                yield item
+                        else:
+                            yield ret
+                finally:
+                    _close_port(port)
+                    _close_port(done)
+                    box.drop()
+
+
+            class Api:
+                """Main entry point to library."""
+
+                def __init__(self, lib: ctypes.CDLL) -> None:
+                    """The symbols are looked up in `lib`."""
+                    self._lib = lib
+                    self._allocate = _function(lib, "allocate", ctypes.c_ssize_t, [ctypes.c_size_t, ctypes.c_size_t])
+                    self._deallocate = _function(lib, "deallocate", None, [ctypes.c_ssize_t, ctypes.c_size_t, ctypes.c_size_t])
+                    self._dispatch = _function(lib, "__ffi_dispatch", ctypes.c_uint8, [ctypes.c_int64])
+                    #(for func in iface.imports(&self.abi) join (#<push>) => #(self.generate_wrapper(func)))
+
+                @staticmethod
+                def load_static() -> Api:
+                    """The library is loaded from the executable."""
+                    return Api(ctypes.CDLL(None))
+
+                @staticmethod
+                def load_dynamic(name: str) -> Api:
+                    """The library is dynamically loaded."""
+                    return Api(ctypes.CDLL(name))
+
+                @staticmethod
+                def load() -> Api:
+                    """The library is loaded based on platform conventions."""
+                    if sys.platform == "darwin":
+                        return Api.load_dynamic(#_(#("lib")#(&self.cdylib_name)#(".dylib")))
+                    if sys.platform == "win32":
+                        return Api.load_dynamic(#_(#(&self.cdylib_name)#(".dll")))
+                    return Api.load_dynamic(#_(#("lib")#(&self.cdylib_name)#(".so")))
+
+                #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+
+                #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+
+                #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+
+                #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+
+            #(for obj in iface.objects() join (#<line>#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>#<line>) => #(self.generate_error(err)))
+
+            #(for func in iface.imports(&self.abi) => #(self.generate_return_struct(&func.ffi_ret)))
+        }
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> python::Tokens {
+        quote! {
+            class #(self.type_ident(&obj.name)):
+                #(self.generate_doc(&obj.doc))
+                def __init__(self, api: Api, box: _Box) -> None:
+                    self._api = api
+                    self._box = box
+
+                #(for func in &obj.methods join (#<line>) => #(self.generate_function(func)))
+
+                def drop(self) -> None:
+                    """Manually drops the object."""
+                    self._box.drop()
+        }
+    }
+
+    fn generate_trait(&self, tr: AbiTrait) -> python::Tokens {
+        quote! {
+            class #(self.type_ident(&tr.name))(abc.ABC):
+                #(self.generate_doc(&tr.doc))
+                #(for method in &tr.methods join (#<line>) =>
+                    @abc.abstractmethod
+                    def #(self.ident(&method.name))(#("self")#(for (name, ty) in &method.args => , #(self.ident(name)): #(self.generate_type(ty)))) -> #(match &method.ret {
+                        Some(ret) => #(self.generate_type(ret)),
+                        None => None,
+                    }):
+                        #(self.generate_doc(&method.doc))
+                        ...
+                )
+                #(if tr.methods.is_empty() => pass)
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> python::Tokens {
+        quote! {
+            @dataclasses.dataclass
+            class #(self.type_ident(&st.name)):
+                #(self.generate_doc(&st.doc))
+                #(for (name, ty) in &st.fields join (#<push>) => #(self.ident(name)): #(self.generate_type(ty)))
+                #(if st.fields.is_empty() => pass)
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> python::Tokens {
+        if en.is_c_like() {
+            return quote! {
+                class #(self.type_ident(&en.name))(enum.Enum):
+                    #(self.generate_doc(&en.doc))
+                    #(for (i, variant) in en.variants.iter().enumerate() join (#<push>) =>
+                        #(self.generate_comment(&variant.doc))
+                        #(self.variant_const(&variant.name)) = #i
+                    )
+                    #(if en.variants.is_empty() => pass)
+            };
+        }
+        quote! {
+            class #(self.type_ident(&en.name)):
+                #(self.generate_doc(&en.doc))
+                pass
+
+            #(for variant in &en.variants =>
+                #<line>
+                @dataclasses.dataclass
+                class #(self.variant_ident(&en.name, &variant.name))(#(self.type_ident(&en.name))):
+                    #(self.generate_doc(&variant.doc))
+                    #(for (name, ty) in &variant.fields join (#<push>) => #(self.ident(name)): #(self.generate_type(ty)))
+                    #(if variant.fields.is_empty() => pass)
+            )
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> python::Tokens {
+        quote! {
+            class #(self.type_ident(&err.name))(Exception):
+                #(self.generate_doc(&err.doc))
+                def __str__(self) -> str:
+                    return repr(self)
+
+            #(for variant in &err.variants =>
+                #<line>
+                @dataclasses.dataclass
+                class #(self.variant_ident(&err.name, &variant.name))(#(self.type_ident(&err.name))):
+                    #(self.generate_doc(&variant.doc))
+                    #(for (name, ty) in &variant.fields join (#<push>) => #(self.ident(name)): #(self.generate_type(ty)))
+                    #(if variant.fields.is_empty() => pass)
+            )
+        }
+    }
+
+    fn generate_function(&self, func: &AbiFunction) -> python::Tokens {
+        let ffi = self.abi.import(func);
+        let api = match &func.ty {
+            FunctionType::Constructor(_) => "api",
+            FunctionType::Method(_) => "self._api",
+            FunctionType::Function
+            | FunctionType::NextIter(_, _)
+            | FunctionType::PollFuture(_, _)
+            | FunctionType::PollStream(_, _) => "self",
+        };
+        // Polling returns `_NULL` instead of `None` when no value is ready,
+        // so that `None` can be used for the value `()`.
+        let is_poll = matches!(
+            &func.ty,
+            FunctionType::NextIter(_, _)
+                | FunctionType::PollFuture(_, _)
+                | FunctionType::PollStream(_, _)
+        );
+        let name = if is_poll {
+            self.poll_ident(&ffi.symbol)
+        } else {
+            self.ident(&func.name)
+        };
+        let args = quote!(#(for (name, ty) in &ffi.abi_args => , #(self.ident(name)): #(self.generate_type(ty))));
+        let body = ffi.instr.iter().map(|instr| match instr {
+            Instr::HandleNull(var) if is_poll => self.generate_handle_null(var, quote!(_NULL)),
+            _ => self.generate_instr(api, instr),
+        });
+        let body = quote!(#(for instr in body join (#<push>) => #instr));
+        let ret = match ffi.abi_ret.as_ref() {
+            Some(ret) if !is_poll => self.generate_type(ret),
+            Some(_) => quote!(typing.Any),
+            None => quote!(None),
+        };
+        let doc = self.generate_doc(&func.doc);
+        match &func.ty {
+            FunctionType::Constructor(_object) => quote! {
+                @staticmethod
+                def #name(api: #("Api")#args) -> #ret:
+                    #doc
+                    #body
+            },
+            _ => quote! {
+                def #name(#("self")#args) -> #ret:
+                    #doc
+                    #body
+            },
+        }
+    }
+
+    fn generate_handle_null(&self, var: &Var, null: python::Tokens) -> python::Tokens {
+        quote! {
+            if #(self.var(var)) == 0:
+                return #null
+        }
+    }
+
+    fn generate_instr(&self, api: &str, instr: &Instr) -> python::Tokens {
+        match instr {
+            Instr::BorrowSelf(out) => quote!(#(self.var(out)) = self._box.borrow()),
+            Instr::BorrowObject(in_, out)
+            | Instr::BorrowIter(in_, out)
+            | Instr::BorrowFuture(in_, out)
+            | Instr::BorrowStream(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_))._box.borrow())
+            }
+            Instr::MoveObject(in_, out)
+            | Instr::MoveIter(in_, out)
+            | Instr::MoveFuture(in_, out)
+            | Instr::MoveStream(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_))._box.move())
+            }
+            Instr::LiftObject(obj, box_, drop, out) => quote! {
+                #(self.var(box_))_0 = _Box(#api, #(self.var(box_)), #_(#drop))
+                #(self.var(out)) = #(self.type_ident(obj))(#api, #(self.var(box_))_0)
+            },
+            Instr::BindArg(arg, out) => quote!(#(self.var(out)) = #(self.ident(arg))),
+            Instr::BindRets(ret, vars) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(#(self.var(&vars[0])) = #(self.var(ret))),
+                _ => quote! {
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
+                        #(self.var(var)) = #(self.var(ret)).#(format!("arg{}", idx)))
+                },
+            },
+            Instr::LowerNum(in_, out, _num) | Instr::LiftNum(in_, out, _num) => {
+                quote!(#(self.var(out)) = #(self.var(in_)))
+            }
+            Instr::LowerBool(in_, out) => {
+                quote!(#(self.var(out)) = 1 if #(self.var(in_)) else 0)
+            }
+            Instr::LiftBool(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)) > 0)
+            }
+            Instr::Deallocate(ptr, len, size, align) => quote! {
+                if #(self.var(len)) > 0:
+                    #api._deallocate(#(self.var(ptr)), #(self.var(len)) * #(*size), #(*align))
+            },
+            Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
+                #(self.var(in_))_0 = #(self.var(in_)).encode()
+                #(self.var(len)) = len(#(self.var(in_))_0)
+                #(self.var(ptr)) = #api._allocate(#(self.var(len)) * #(*size), #(*align))
+                ctypes.memmove(#(self.var(ptr)), #(self.var(in_))_0, #(self.var(len)))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftString(ptr, len, out) => quote! {
+                #(self.var(out)) = ctypes.string_at(#(self.var(ptr)), #(self.var(len))).decode()
+            },
+            Instr::LowerVec(in_, ptr, len, cap, ty, size, align) => quote! {
+                #(self.var(len)) = len(#(self.var(in_)))
+                #(self.var(ptr)) = #api._allocate(#(self.var(len)) * #(*size), #(*align))
+                (#(self.generate_native_num_type(*ty)) * #(self.var(len))).from_address(#(self.var(ptr)))[:] = #(self.var(in_))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftVec(ptr, len, out, ty) => quote! {
+                #(self.var(out)) = list((#(self.generate_native_num_type(*ty)) * #(self.var(len))).from_address(#(self.var(ptr))))
+            },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match &out.ty {
+                    AbiType::Map(_, _) => (
+                        quote!({}),
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1]))),
+                    ),
+                    AbiType::Set(_) => (
+                        quote!(set()),
+                        quote!(#(self.var(out)).add(#(self.var(&elems[0])))),
+                    ),
+                    _ => (
+                        quote!([]),
+                        quote!(#(self.var(out)).append(#(self.var(&elems[0])))),
+                    ),
+                };
+                quote! {
+                    #(self.var(out)) = #collection
+                    for #(self.var(out))_0 in range(#(self.var(len))):
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            #(self.var(var)) = #(self.generate_native_num_type(var.ty.num())).from_address(#(self.var(ptr)) + (#(self.var(out))_0 * #n + #i) * 8).value
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #add
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (iter, bind) = if let AbiType::Map(_, _) = &in_.ty {
+                    (
+                        quote!(#(self.var(in_)).items()),
+                        quote!((#(self.var(&elems[0])), #(self.var(&elems[1])))),
+                    )
+                } else {
+                    (quote!(#(self.var(in_))), quote!(#(self.var(&elems[0]))))
+                };
+                quote! {
+                    #(self.var(len)) = len(#(self.var(in_)))
+                    if #(self.var(len)) > 0:
+                        #(self.var(ptr)) = #api._allocate(#(self.var(len)) * #(n * 8), 8)
+                    for #(self.var(in_))_0, #bind in enumerate(#iter):
+                        #(self.generate_instr(api, &Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            #(self.generate_native_num_type(var.ty.num())).from_address(#(self.var(ptr)) + (#(self.var(in_))_0 * #n + #i) * 8).value = #(self.var(var))
+                        )
+                }
+            }
+            Instr::Call(symbol, ret, args) => {
+                let invoke = quote!(#api.#(self.symbol_ident(symbol))(#(for arg in args join (, ) => #(self.var(arg)))));
+                if let Some(ret) = ret {
+                    quote!(#(self.var(ret)) = #invoke)
+                } else {
+                    invoke
+                }
+            }
+            Instr::DefineArgs(vars) => quote! {
+                #(for var in vars join (#<push>) => #(self.var(var)) = #(self.literal(var.ty.num())))
+            },
+            Instr::ReturnValue(ret) => quote!(return #(self.var(ret))),
+            Instr::ReturnVoid => quote!(return),
+            Instr::HandleNull(var) => self.generate_handle_null(var, quote!(None)),
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                if #(self.var(var)) == 0:
+                    #(self.var(out)) = None
+                else:
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+                    #(self.var(out)) = #(self.var(some))
+            },
+            Instr::LowerOption(arg, var, some, some_instr) => quote! {
+                if #(self.var(arg)) is None:
+                    #(self.var(var)) = 0
+                else:
+                    #(self.var(var)) = 1
+                    #(self.var(some)) = #(self.var(arg))
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(api, inst)))
+            },
+            Instr::HandleError(var, ptr, len, cap) => quote! {
+                if #(self.var(var)) == 0:
+                    #(self.var(var))_0 = ctypes.string_at(#(self.var(ptr)), #(self.var(len))).decode()
+                    if #(self.var(len)) > 0:
+                        #api._deallocate(#(self.var(ptr)), #(self.var(cap)), 1)
+                    raise Exception(#(self.var(var))_0)
+            },
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if #(self.var(var)) == 0:
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if #(self.var(tag)) == #i:
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            raise #(self.variant_ident(err, variant))(#(for (_, field) in fields join (, ) => #(self.var(field))))
+                    )
+                    raise RuntimeError(#_(unknown variant of #err))
+            },
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => quote! {
+                #(self.generate_native_callable(api, cb, self.var(in_), quote!(#(self.var(in_))_0)))
+                #(self.var(ptr)) = ctypes.cast(#(self.var(in_))_0, ctypes.c_void_p).value
+                #(self.var(post_cobject)) = _POST_COBJECT
+                #(self.var(port)) = _keep_alive(#api, #(self.var(in_))_0)
+            },
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => quote! {
+                #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                    #(self.generate_native_callable(
+                        api,
+                        cb,
+                        quote!(#(self.var(in_)).#(self.ident(name))),
+                        quote!(#(self.var(in_))#(format!("_{}", i))),
+                    ))
+                )
+                #(for (i, ptr) in vtable.iter().enumerate() join (#<push>) =>
+                    #(self.var(ptr)) = ctypes.cast(#(self.var(in_))#(format!("_{}", i)), ctypes.c_void_p).value)
+                #(self.var(post_cobject)) = _POST_COBJECT
+                #(self.var(port)) = _keep_alive(#api, #(for i in 0..methods.len() join (, ) => #(self.var(in_))#(format!("_{}", i))))
+            },
+            Instr::LowerEnum(in_, tag, payload, name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = #(self.var(in_)).value);
+                }
+                quote! {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        #(if i == 0 { if } else { elif }) isinstance(#(self.var(in_)), #(self.variant_ident(name, variant))):
+                            #(self.var(tag)) = #i
+                            #(for (field, var) in fields join (#<push>) => #(self.var(var)) = #(self.var(in_)).#(self.ident(field)))
+                            #(self.generate_instr(api, &Instr::DefineArgs(ffi.clone())))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(self.write_payload(api, payload, ffi))
+                    )
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(out)) = #(self.type_ident(name))(#(self.var(tag))));
+                }
+                quote! {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        #(if i == 0 { if } else { elif }) #(self.var(tag)) == #i:
+                            #(self.read_payload(api, payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(api, inst)))
+                            #(self.var(out)) = #(self.variant_ident(name, variant))(#(for (_, field) in fields join (, ) => #(self.var(field))))
+                    )
+                    else:
+                        raise RuntimeError(#_(unknown variant of #name))
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let batched = if *batched { quote!(, True) } else { quote!() };
+                quote! {
+                    #(self.var(box_))_0 = _Box(#api, #(self.var(box_)), #_(#drop))
+                    #(self.var(out)) = Iter(#(self.var(box_))_0, #api.#(self.poll_ident(next))#batched)
+                }
+            }
+            Instr::LiftFuture(box_, poll, drop, out) => quote! {
+                #(self.var(box_))_0 = _Box(#api, #(self.var(box_)), #_(#drop))
+                #(self.var(out)) = _Future(#(self.var(box_))_0, #api.#(self.poll_ident(poll)))
+            },
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let batched = if *batched { quote!(, True) } else { quote!() };
+                quote! {
+                    #(self.var(box_))_0 = _Box(#api, #(self.var(box_)), #_(#drop))
+                    #(self.var(out)) = _native_stream(#(self.var(box_))_0, #api.#(self.poll_ident(poll))#batched)
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(#(self.var(&vars[0])) = #(self.var(in_))),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(self.var(var)) = #(self.var(in_))[#(i)])
+                },
+            },
+            Instr::LiftTuple(vars, out) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(#(self.var(out)) = #(self.var(&vars[0]))),
+                _ => quote!(#(self.var(out)) = (#(for var in vars => #(self.var(var)),))),
+            },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => #(self.var(var)) = #(self.var(in_)).#(self.ident(field)))
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                #(self.var(out)) = #(self.type_ident(name))(#(for (_, var) in vars join (, ) => #(self.var(var))))
+            },
+            Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
+        }
+    }
+
+    /// Defines a ctypes function pointer `name` that calls `func`.
+    fn generate_native_callable(
+        &self,
+        api: &str,
+        cb: &Callback,
+        func: python::Tokens,
+        name: python::Tokens,
+    ) -> python::Tokens {
+        let native_args =
+            quote!(#(for var in &cb.ffi_args => , #(self.generate_native_num_type(var.ty.num()))));
+        let args = quote!(#(for var in &cb.args join (, ) => #(self.var(var))));
+        let native_ret = match &cb.ffi_ret {
+            Some(var) => self.generate_native_num_type(var.ty.num()),
+            None => quote!(None),
+        };
+        quote! {
+            def #(&name)_fn(#(for var in &cb.ffi_args join (, ) => #(self.var(var)))):
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(api, inst)))
+                #(match &cb.ret {
+                    Some(ret) => #(self.var(ret)) = #(func)(#args),
+                    None => #(func)(#args),
+                })
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(api, inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret)))
+            #(&name) = ctypes.CFUNCTYPE(#(native_ret)#(native_args))(#(&name)_fn)
+        }
+    }
+
+    fn var(&self, var: &Var) -> python::Tokens {
+        quote!(#(format!("tmp{}", var.binding)))
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, api: &str, payload: &Option<Var>, ffi: &[Var]) -> python::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                #(self.var(var)) = #(self.generate_native_num_type(var.ty.num())).from_address(#(self.var(payload)) + #(i * 8)).value
+            )
+            #api._deallocate(#(self.var(payload)), #(ffi.len() * 8), 8)
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, api: &str, payload: &Option<Var>, ffi: &[Var]) -> python::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = #api._allocate(#(ffi.len() * 8), 8)
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                #(self.generate_native_num_type(var.ty.num())).from_address(#(self.var(payload)) + #(i * 8)).value = #(self.var(var))
+            )
+        }
+    }
+
+    fn literal(&self, ty: NumType) -> python::Tokens {
+        match ty {
+            NumType::F32 | NumType::F64 => quote!(0.0),
+            _ => quote!(0),
+        }
+    }
+
+    fn generate_wrapper(&self, func: Import) -> python::Tokens {
+        quote! {
+            self.#(self.symbol_ident(&func.symbol)) = _function(
+                lib,
+                #_(#(&func.symbol)),
+                #(self.generate_native_return_type(&func.ffi_ret)),
+                [#(for var in &func.ffi_args join (, ) => #(self.generate_native_num_type(var.ty.num())))],
+            )
+        }
+    }
+
+    fn generate_type(&self, ty: &AbiType) -> python::Tokens {
+        match ty {
+            AbiType::Num(ty) => self.generate_wrapped_num_type(*ty),
+            AbiType::Isize | AbiType::Usize => quote!(int),
+            AbiType::Bool => quote!(bool),
+            AbiType::RefStr | AbiType::String => quote!(str),
+            AbiType::RefSlice(ty) | AbiType::Vec(ty) => {
+                quote!(list[#(self.generate_wrapped_num_type(*ty))])
+            }
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!(list[#(self.generate_type(ty))]),
+            AbiType::Map(key, value) => {
+                quote!(dict[#(self.generate_type(key)), #(self.generate_type(value))])
+            }
+            AbiType::Set(ty) => quote!(set[#(self.generate_type(ty))]),
+            AbiType::Option(ty) => quote!(#(self.generate_type(ty)) | None),
+            AbiType::Result(ty, _) => self.generate_type(ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
+            AbiType::Tuple(tuple) => match tuple.len() {
+                0 => quote!(None),
+                1 => self.generate_type(&tuple[0]),
+                _ => quote!(tuple[#(for ty in tuple join (, ) => #(self.generate_type(ty)))]),
+            },
+            AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(#(self.type_ident(ty))),
+            AbiType::Callback(args, ret) => {
+                let ret = match ret {
+                    Some(ret) => self.generate_type(ret),
+                    None => quote!(None),
+                };
+                quote!(typing.Callable[[#(for arg in args join (, ) => #(self.generate_type(arg)))], #ret])
+            }
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => quote!(Iter[#(self.generate_type(ty))]),
+            AbiType::RefFuture(ty) | AbiType::Future(ty) => {
+                quote!(typing.Awaitable[#(self.generate_type(ty))])
+            }
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
+                quote!(typing.AsyncIterator[#(self.generate_type(ty))])
+            }
+        }
+    }
+
+    fn generate_wrapped_num_type(&self, ty: NumType) -> python::Tokens {
+        match ty {
+            NumType::F32 | NumType::F64 => quote!(float),
+            _ => quote!(int),
+        }
+    }
+
+    fn generate_native_num_type(&self, ty: NumType) -> python::Tokens {
+        match ty {
+            NumType::I8 => quote!(ctypes.c_int8),
+            NumType::I16 => quote!(ctypes.c_int16),
+            NumType::I32 => quote!(ctypes.c_int32),
+            NumType::I64 => quote!(ctypes.c_int64),
+            NumType::U8 => quote!(ctypes.c_uint8),
+            NumType::U16 => quote!(ctypes.c_uint16),
+            NumType::U32 => quote!(ctypes.c_uint32),
+            NumType::U64 => quote!(ctypes.c_uint64),
+            NumType::F32 => quote!(ctypes.c_float),
+            NumType::F64 => quote!(ctypes.c_double),
+        }
+    }
+
+    fn generate_native_return_type(&self, ret: &Return) -> python::Tokens {
+        match ret {
+            Return::Void => quote!(None),
+            Return::Num(var) => self.generate_native_num_type(var.ty.num()),
+            Return::Struct(_, s) => quote!(#(format!("_{}", self.type_ident(s)))),
+        }
+    }
+
+    fn generate_return_struct(&self, ret: &Return) -> python::Tokens {
+        if let Return::Struct(vars, name) = ret {
+            quote! {
+                #<line>
+                #<line>
+                class #(format!("_{}", self.type_ident(name)))(ctypes.Structure):
+                    _fields_ = [
+                        #(for (i, var) in vars.iter().enumerate() join (#<push>) =>
+                            (#_(#(format!("arg{}", i))), #(self.generate_native_num_type(var.ty.num()))),
+                        )
+                    ]
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    fn generate_doc(&self, doc: &[String]) -> python::Tokens {
+        let mut lines = doc.iter().map(|line| {
+            line.trim()
+                .replace('\\', "\\\\")
+                .replace("\"\"\"", "\\\"\\\"\\\"")
+        });
+        match doc.len() {
+            0 => quote!(),
+            1 => {
+                quote!(#(static_literal("\"\"\""))#(lines.next().unwrap())#(static_literal("\"\"\"")))
+            }
+            _ => quote! {
+                #(static_literal("\"\"\""))#(lines.next().unwrap())
+                #(for line in lines join (#<push>) => #line)
+                #(static_literal("\"\"\""))
+            },
+        }
+    }
+
+    fn generate_comment(&self, doc: &[String]) -> python::Tokens {
+        quote!(#(for line in doc => #(static_literal("#")) #(line.trim()) #<push>))
+    }
+
+    fn type_ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_upper_camel_case())
+    }
+
+    fn ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_snake_case())
+    }
+
+    fn variant_ident(&self, err: &str, variant: &str) -> String {
+        self.type_ident(&format!("{}_{}", err, variant))
+    }
+
+    fn variant_const(&self, variant: &str) -> String {
+        sanitize_identifier(&variant.to_shouty_snake_case())
+    }
+
+    /// Attribute of `Api` holding the ctypes function for `symbol`.
+    fn symbol_ident(&self, symbol: &str) -> String {
+        format!("_ffi{}", symbol)
+    }
+
+    /// Method of `Api` polling the iterator, future or stream of `symbol`.
+    fn poll_ident(&self, symbol: &str) -> String {
+        format!("_{}", symbol.trim_start_matches('_'))
+    }
+}
+
+fn sanitize_identifier(id: &str) -> String {
+    if RESERVED_IDENTIFIERS.contains(&id) {
+        format!("{}_", id)
+    } else {
+        id.to_string()
+    }
+}
+
+// https://docs.python.org/3/reference/lexical_analysis.html#keywords
+static RESERVED_IDENTIFIERS: [&str; 35] = [
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, python: python::Tokens) -> Result<()> {
+        run(iface, rust, python, None)
+    }
+
+    pub fn compile_pass_executor(
+        iface: &str,
+        executor: &str,
+        rust: rust::Tokens,
+        python: python::Tokens,
+    ) -> Result<()> {
+        run(iface, rust, python, Some(executor.to_string()))
+    }
+
+    fn run(
+        iface: &str,
+        rust: rust::Tokens,
+        python: python::Tokens,
+        executor: Option<String>,
+    ) -> Result<()> {
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native()).executor(executor);
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let python_gen = PythonGenerator::new("compile_pass".to_string());
+        let python_tokens = python_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let bin_tokens = quote! {
+            #python_tokens
+
+
+            async def main() -> None:
+                api = Api.load()
+                #python
+
+
+            #(static_literal("#")) Warnings raised in finalizers, like coroutines that were never awaited,
+            #(static_literal("#")) are only printed even when warnings are errors.
+            ignored = []
+
+
+            def unraisablehook(unraisable: typing.Any) -> None:
+                sys.__unraisablehook__(unraisable)
+                ignored.append(unraisable.exc_type)
+
+
+            sys.unraisablehook = unraisablehook
+            asyncio.run(main())
+            assert not ignored, ignored
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        let python_file = dir.as_ref().join("compile_pass.py");
+        std::fs::write(&python_file, bin_tokens.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let ret = Command::new("python3")
+            .env("LD_LIBRARY_PATH", dir.as_ref())
+            .arg("-W")
+            .arg("error")
+            .arg(&python_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "python test failed");
+        Ok(())
+    }
+}
@@ -1,29 +1,65 @@
-use crate::export::Instr;
+use crate::export::{Callback, Instr};
 use crate::{
-    Abi, AbiFunction, AbiFuture, AbiIter, AbiObject, AbiStream, AbiType, FunctionType, Interface,
-    NumType, Return, Var,
+    Abi, AbiFunction, AbiFuture, AbiIter, AbiObject, AbiStream, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Return, Var,
 };
 use genco::prelude::*;
+use std::collections::HashSet;
 
 pub struct RustGenerator {
     abi: Abi,
+    return_pointer: bool,
+    executor: Option<String>,
+    shared: HashSet<String>,
 }
 
 impl RustGenerator {
     pub fn new(abi: Abi) -> Self {
-        Self { abi }
+        Self {
+            abi,
+            return_pointer: false,
+            executor: None,
+            shared: HashSet::new(),
+        }
+    }
+
+    /// Functions returning a struct write it to a pointer passed as first
+    /// argument instead of returning it, so the wasm blob doesn't need to be
+    /// patched with `WasmMultiValueShim`.
+    pub fn with_return_pointer(abi: Abi) -> Self {
+        Self {
+            abi,
+            return_pointer: true,
+            executor: None,
+            shared: HashSet::new(),
+        }
+    }
+
+    /// Spawns the returned futures onto an executor instead of polling them
+    /// from the host, see `FfiGen::executor`.
+    pub fn executor(mut self, executor: Option<String>) -> Self {
+        self.executor = executor;
+        self
     }
 
     pub fn generate(&self, iface: Interface) -> rust::Tokens {
-        let wasm_bindgen: rust::Tokens = if cfg!(feature = "wasm-bindgen") {
-            quote! {
-                // Workaround for combined use with `wasm-bindgen`, so we don't have to
-                // patch the `importObject` while loading the WASM module.
-                #[cfg_attr(target_family = "wasm", wasm_bindgen::prelude::wasm_bindgen(js_namespace = window, js_name = __notifier_callback))]
-            }
-        } else {
-            quote!()
+        let shared = iface
+            .objects()
+            .into_iter()
+            .filter(|obj| obj.shared)
+            .map(|obj| obj.name)
+            .collect();
+        let gen = Self {
+            abi: self.abi,
+            return_pointer: self.return_pointer,
+            executor: self.executor.clone(),
+            shared,
         };
+        gen.generate_api(iface)
+    }
+
+    fn generate_api(&self, iface: Interface) -> rust::Tokens {
+        let wasm_bindgen = self.wasm_bindgen("__notifier_callback");
         quote! {
         #[allow(unused)]
         mod api {
@@ -31,7 +67,8 @@ impl RustGenerator {
             use core::mem::ManuallyDrop;
             use core::pin::Pin;
             use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
-            use std::sync::Arc;
+            use std::collections::{HashMap, HashSet};
+            use std::sync::{Arc, Mutex};
             use super::*;
 
             /// Try to execute some function, catching any panics and aborting to make sure Rust
@@ -45,6 +82,13 @@ impl RustGenerator {
                 }
             }
 
+            /// Returns a new `Arc` of a shared object, which is stored in an `Arc` behind the
+            /// pointer handed out to the host.
+            pub unsafe fn clone_arc<T>(object: &T) -> Arc<T> {
+                Arc::increment_strong_count(object);
+                Arc::from_raw(object)
+            }
+
             #[inline(always)]
             pub fn assert_send_static<T: Send + 'static>(t: T) -> T {
                 t
@@ -80,6 +124,23 @@ impl RustGenerator {
                     Self(Box::new(iter.into_iter()))
                 }
 
+                /// Yields the items of `iter` in vectors of up to `batch` items.
+                pub fn batched<I>(iter: I, batch: usize) -> FfiIter<Vec<T>>
+                where
+                    I: IntoIterator<Item = T>,
+                    I::IntoIter: Send + 'static,
+                {
+                    let mut iter = iter.into_iter();
+                    FfiIter::new(core::iter::from_fn(move || {
+                        let items: Vec<T> = iter.by_ref().take(batch).collect();
+                        if items.is_empty() {
+                            None
+                        } else {
+                            Some(items)
+                        }
+                    }))
+                }
+
                 pub fn next(&mut self) -> Option<T> {
                     self.0.next()
                 }
@@ -125,6 +186,14 @@ impl RustGenerator {
                 }
             }
 
+            /// Returns a waker notifying the host through `port`.
+            ///
+            /// On native targets the host passes a function pointer as `post_cobject`,
+            /// which is called with `port` and a message the host ignores. Dart passes
+            /// `Dart_PostCObject`, the other hosts pass a function looking up what they
+            /// registered for the port: polling a future or stream again, or releasing a
+            /// callback once rust dropped it. On wasm the port is passed to the imported
+            /// `__notifier_callback`.
             fn ffi_waker(_post_cobject: isize, port: i64) -> Waker {
                 waker_fn(move || unsafe {
                     if cfg!(target_family = "wasm") {
@@ -142,12 +211,82 @@ impl RustGenerator {
                 })
             }
 
+            /// Calls of host callbacks queued by port, `None` once the callback was dropped.
+            static FFI_CALLS: Mutex<Vec<(i64, Option<Box<dyn FnOnce() + Send>>)>> = Mutex::new(Vec::new());
+
+            /// Runs the calls queued for the callback or interface passed with `port` on the
+            /// calling thread. Returns 1 once rust dropped it, so the host can release it.
+            #[no_mangle]
+            pub extern "C" fn __ffi_dispatch(port: i64) -> u8 {
+                let calls = {
+                    let mut queue = FFI_CALLS.lock().unwrap();
+                    let (calls, rest) = queue.drain(..).partition(|(call_port, _)| *call_port == port);
+                    *queue = rest;
+                    calls
+                };
+                let mut dropped = 0;
+                for (_, call) in calls {
+                    match call {
+                        Some(call) => call(),
+                        None => dropped = 1,
+                    }
+                }
+                dropped
+            }
+
+            /// Owns the host side of a callback or interface.
+            ///
+            /// Host callbacks are called on the thread that passed them to rust. Calls from
+            /// other threads are queued and the host is notified through the waker port, it
+            /// runs them with `__ffi_dispatch` on its thread while the caller waits for the
+            /// result. The host is notified the same way when the callback is dropped. On
+            /// native targets the port is also passed as first argument to the callback.
+            pub struct FfiCallback {
+                post_cobject: isize,
+                pub port: i64,
+                thread: std::thread::ThreadId,
+            }
+
+            impl FfiCallback {
+                pub fn new(post_cobject: isize, port: i64) -> Self {
+                    Self {
+                        post_cobject,
+                        port,
+                        thread: std::thread::current().id(),
+                    }
+                }
+
+                /// Runs `call` on the thread owning the callback and returns its result.
+                pub fn call<R: Send>(&self, call: impl FnOnce() -> R + Send) -> R {
+                    if cfg!(target_family = "wasm") || std::thread::current().id() == self.thread {
+                        return call();
+                    }
+                    let (tx, rx) = std::sync::mpsc::channel();
+                    let call: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+                        tx.send(call()).ok();
+                    });
+                    // Safety: the call doesn't outlive its borrows, this thread waits until it
+                    // ran or was dropped.
+                    let call: Box<dyn FnOnce() + Send> = unsafe { core::mem::transmute(call) };
+                    FFI_CALLS.lock().unwrap().push((self.port, Some(call)));
+                    ffi_waker(self.post_cobject, self.port).wake();
+                    rx.recv().expect("the host dropped the callback")
+                }
+            }
+
+            impl Drop for FfiCallback {
+                fn drop(&mut self) {
+                    FFI_CALLS.lock().unwrap().push((self.port, None));
+                    ffi_waker(self.post_cobject, self.port).wake();
+                }
+            }
+
             #[repr(transparent)]
             pub struct FfiFuture<T: Send + 'static>(Pin<Box<dyn Future<Output = T> + Send + 'static>>);
 
             impl<T: Send + 'static> FfiFuture<T> {
                 pub fn new(f: impl Future<Output = T> + Send + 'static) -> Self {
-                    Self(Box::pin(f))
+                    #(self.generate_future_new())
                 }
 
                 pub fn poll(&mut self, post_cobject: isize, port: i64) -> Option<T> {
@@ -160,6 +299,8 @@ impl RustGenerator {
                 }
             }
 
+            #(self.generate_executor())
+
             #[cfg(feature = "test_runner")]
             pub trait Stream {
                 type Item;
@@ -188,14 +329,22 @@ impl RustGenerator {
                     Self(Box::pin(f))
                 }
 
+                /// Yields the ready items of `f` in vectors of up to `batch` items.
+                pub fn batched(f: impl Stream<Item = T> + Send + 'static, batch: usize) -> FfiStream<Vec<T>> {
+                    FfiStream::new(FfiBatch {
+                        stream: Box::pin(f),
+                        batch,
+                        done: false,
+                    })
+                }
+
+                /// Returns the next item if it is ready. The host polls again when it wants the
+                /// next item, `port` is only woken when a pending stream makes progress.
                 pub fn poll(&mut self, post_cobject: isize, port: i64, done: i64) -> Option<T> {
                     let waker = ffi_waker(post_cobject, port);
                     let mut ctx = Context::from_waker(&waker);
                     match Pin::new(&mut self.0).poll_next(&mut ctx) {
-                        Poll::Ready(Some(res)) => {
-                            ffi_waker(post_cobject, port).wake();
-                            Some(res)
-                        }
+                        Poll::Ready(Some(res)) => Some(res),
                         Poll::Ready(None) => {
                             ffi_waker(post_cobject, done).wake();
                             None
@@ -205,18 +354,279 @@ impl RustGenerator {
                 }
             }
 
-            #(for func in iface.functions() => #(self.generate_function(&func)))
-            #(for obj in iface.objects() => #(self.generate_object(&obj)))
-            #(for iter in iface.iterators() => #(self.generate_iterator(&iter)))
-            #(for fut in iface.futures() => #(self.generate_future(&fut)))
-            #(for stream in iface.streams() => #(self.generate_stream(&stream)))
+            struct FfiBatch<T> {
+                stream: Pin<Box<dyn Stream<Item = T> + Send + 'static>>,
+                batch: usize,
+                done: bool,
+            }
+
+            impl<T> Stream for FfiBatch<T> {
+                type Item = Vec<T>;
+
+                fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    let this = &mut *self;
+                    let mut items = Vec::new();
+                    while !this.done && items.len() < this.batch {
+                        match this.stream.as_mut().poll_next(cx) {
+                            Poll::Ready(Some(item)) => items.push(item),
+                            Poll::Ready(None) => this.done = true,
+                            Poll::Pending => break,
+                        }
+                    }
+                    if !items.is_empty() {
+                        Poll::Ready(Some(items))
+                    } else if this.done {
+                        Poll::Ready(None)
+                    } else {
+                        Poll::Pending
+                    }
+                }
+            }
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(&tr)))
+            #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(&obj)))
+            #(for iter in iface.iterators() join (#<line>) => #(self.generate_iterator(&iter)))
+            #(for fut in iface.futures() join (#<line>) => #(self.generate_future(&fut)))
+            #(for stream in iface.streams() join (#<line>) => #(self.generate_stream(&stream)))
+        }
+        }
+    }
+
+    fn generate_future_new(&self) -> rust::Tokens {
+        if self.executor.is_none() {
+            return quote!(Self(Box::pin(f)));
+        }
+        quote! {
+            let state = Arc::new(Mutex::new(FfiTaskState {
+                output: None,
+                waker: None,
+                task: None,
+                cancelled: false,
+            }));
+            ffi_spawn(FfiTask {
+                future: Box::pin(f),
+                state: state.clone(),
+            });
+            Self(Box::pin(FfiSpawned { state }))
         }
+    }
+
+    fn generate_executor(&self) -> rust::Tokens {
+        let spawn = match self.executor.as_deref() {
+            None => return quote!(),
+            Some("tokio") => quote! {
+                static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+                let runtime = RUNTIME.get_or_init(|| {
+                    tokio::runtime::Builder::new_multi_thread()
+                        .enable_all()
+                        .build()
+                        .expect("failed to create the tokio runtime")
+                });
+                let _handle = runtime.spawn(task);
+            },
+            Some(path) => quote!(#path(task);),
+        };
+        quote! {
+            /// Spawns a future returned to the host onto the executor.
+            fn ffi_spawn(task: impl Future<Output = ()> + Send + 'static) {
+                #spawn
+            }
+
+            struct FfiTaskState<T> {
+                output: Option<T>,
+                /// Woken when the output is ready.
+                waker: Option<Waker>,
+                /// Woken when the host dropped the future.
+                task: Option<Waker>,
+                cancelled: bool,
+            }
+
+            /// Runs a future on the executor and stores its output for `FfiSpawned`.
+            struct FfiTask<T> {
+                future: Pin<Box<dyn Future<Output = T> + Send + 'static>>,
+                state: Arc<Mutex<FfiTaskState<T>>>,
+            }
+
+            impl<T> Future for FfiTask<T> {
+                type Output = ();
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    let this = &mut *self;
+                    {
+                        let mut state = this.state.lock().unwrap();
+                        if state.cancelled {
+                            return Poll::Ready(());
+                        }
+                        state.task = Some(cx.waker().clone());
+                    }
+                    let future = &mut this.future;
+                    let output = match panic_abort(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                        Poll::Ready(output) => output,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    let waker = {
+                        let mut state = this.state.lock().unwrap();
+                        state.output = Some(output);
+                        state.waker.take()
+                    };
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                    Poll::Ready(())
+                }
+            }
+
+            /// Completes with the output of a `FfiTask`, cancelling it when dropped.
+            struct FfiSpawned<T> {
+                state: Arc<Mutex<FfiTaskState<T>>>,
+            }
+
+            impl<T> Future for FfiSpawned<T> {
+                type Output = T;
+
+                fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    let mut state = self.state.lock().unwrap();
+                    match state.output.take() {
+                        Some(output) => Poll::Ready(output),
+                        None => {
+                            state.waker = Some(cx.waker().clone());
+                            Poll::Pending
+                        }
+                    }
+                }
+            }
+
+            impl<T> Drop for FfiSpawned<T> {
+                fn drop(&mut self) {
+                    let task = {
+                        let mut state = self.state.lock().unwrap();
+                        state.cancelled = true;
+                        state.task.take()
+                    };
+                    if let Some(task) = task {
+                        task.wake();
+                    }
+                }
+            }
+        }
+    }
+
+    fn wasm_bindgen(&self, js_name: &str) -> rust::Tokens {
+        if cfg!(feature = "wasm-bindgen") {
+            quote! {
+                // Workaround for combined use with `wasm-bindgen`, so we don't have to
+                // patch the `importObject` while loading the WASM module.
+                #[cfg_attr(target_family = "wasm", wasm_bindgen::prelude::wasm_bindgen(js_namespace = window, js_name = #js_name))]
+            }
+        } else {
+            quote!()
+        }
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> rust::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                let #(self.var(var)): #(self.ty(&var.ty)) = unsafe {
+                    (#(self.var(payload)) as *const u64).add(#i).cast::<#(self.ty(&var.ty))>().read()
+                };
+            )
+            unsafe { deallocate(#(self.var(payload)) as _, #(ffi.len() * 8), 8) };
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// the host.
+    fn write_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> rust::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            let mut #(self.var(payload))_0 = vec![0u64; #(ffi.len())].into_boxed_slice();
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                unsafe {
+                    #(self.var(payload))_0.as_mut_ptr().add(#i).cast::<#(self.ty(&var.ty))>().write(#(self.var(var)))
+                };
+            )
+            #(self.var(payload)) = Box::into_raw(#(self.var(payload))_0) as *mut u64 as _;
+        }
+    }
+
+    fn generate_callback_call(
+        &self,
+        cb: &Callback,
+        ptr: rust::Tokens,
+        callback: rust::Tokens,
+    ) -> rust::Tokens {
+        let args =
+            quote!(#(for var in &cb.ffi_args join (, ) => #(self.var(var)): #(self.ty(&var.ty))));
+        let ret = match &cb.ffi_ret {
+            Some(var) => quote!(-> #(self.ty(&var.ty))),
+            None => quote!(),
+        };
+        let bind = match &cb.ffi_ret {
+            Some(var) => quote!(let #(self.var(var)) =),
+            None => quote!(),
+        };
+        let call_args = quote!(#(for var in &cb.ffi_args join (, ) => #(self.var(var))));
+        match self.abi {
+            Abi::Wasm32 | Abi::Wasm64 => quote! {
+                #(self.wasm_bindgen(&cb.symbol))
+                extern "C" {
+                    fn #(&cb.symbol)(idx: #(self.ffi_num_type(self.abi.iptr())), #args) #ret;
+                }
+                let ptr: isize = #ptr;
+                #bind #callback.call(move || unsafe { #(&cb.symbol)(ptr as _, #call_args) });
+            },
+            Abi::Native32 | Abi::Native64 => quote! {
+                let ptr: isize = #ptr;
+                let port = #(&callback).port;
+                #bind #callback.call(move || {
+                    let func: extern "C" fn(port: i64, #args) #ret = unsafe { core::mem::transmute(ptr) };
+                    func(port, #call_args)
+                });
+            },
+        }
+    }
+
+    fn generate_trait(&self, tr: &AbiTrait) -> rust::Tokens {
+        let proxy = format!("{}Proxy", tr.name);
+        let methods = self.abi.export_trait_methods(tr);
+        quote! {
+            pub trait #(&tr.name): Send + Sync {
+                #(for method in &tr.methods join (#<push>) =>
+                    fn #(&method.name)(&self, #(for (name, ty) in &method.args join (, ) => #name: #(self.api_ty(ty)))) #(if let Some(ret) = &method.ret => -> #(self.api_ty(ret)));
+                )
+            }
+
+            pub struct #(&proxy) {
+                vtable: [isize; #(tr.methods.len())],
+                callback: FfiCallback,
+            }
+
+            impl #(&tr.name) for #(&proxy) {
+                #(for (i, (name, cb)) in methods.iter().enumerate() join (#<push>) =>
+                    fn #name(&self, #(for arg in &cb.args join (, ) => #(self.var(arg)): #(self.api_ty(&arg.ty)))) #(if let Some(ret) = &cb.ret => -> #(self.api_ty(&ret.ty))) {
+                        #(for instr in &cb.lower join (#<push>) => #(self.instr(instr)))
+                        #(self.generate_callback_call(cb, quote!(self.vtable[#i]), quote!(self.callback)))
+                        #(for instr in &cb.lift join (#<push>) => #(self.instr(instr)))
+                        #(if let Some(ret) = &cb.ret => #(self.var(ret)))
+                    }
+                )
+            }
         }
     }
 
     fn generate_function(&self, func: &AbiFunction) -> rust::Tokens {
         let ffi = self.abi.export(func);
-        let args = quote!(#(for var in &ffi.ffi_args => #(self.var(var)): #(self.ty(&var.ty)),));
+        let args =
+            quote!(#(for var in &ffi.ffi_args join (, ) => #(self.var(var)): #(self.ty(&var.ty))));
         let ret = match &ffi.ffi_ret {
             Return::Void => quote!(),
             Return::Num(var) => quote!(-> #(self.ty(&var.ty))),
@@ -227,7 +637,7 @@ impl RustGenerator {
             Return::Num(var) => self.var(var),
             Return::Struct(vars, name) => quote! {
                 #name {
-                    #(for (i, var) in vars.iter().enumerate() => #(format!("ret{}", i)): #(self.var(var)),)
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(format!("ret{}", i)): #(self.var(var)),)
                 }
             },
         };
@@ -236,11 +646,25 @@ impl RustGenerator {
         } else {
             quote!()
         };
+        if let (Return::Struct(_, name), true) = (&ffi.ffi_ret, self.return_pointer) {
+            let iptr = self.ffi_num_type(self.abi.iptr());
+            return quote! {
+                #[no_mangle]
+                pub extern "C" fn #(&ffi.symbol)(ret_ptr: #iptr, #args) {
+                    let ret = panic_abort(move || {
+                        #(for instr in &ffi.instr join (#<push>) => #(self.instr(instr)))
+                        #return_
+                    });
+                    unsafe { (ret_ptr as *mut #name).write(ret) };
+                }
+                #return_struct
+            };
+        }
         quote! {
             #[no_mangle]
             pub extern "C" fn #(&ffi.symbol)(#args) #ret {
                 panic_abort(move || {
-                    #(for instr in &ffi.instr => #(self.instr(instr)))
+                    #(for instr in &ffi.instr join (#<push>) => #(self.instr(instr)))
                     #return_
                 })
             }
@@ -250,9 +674,13 @@ impl RustGenerator {
 
     fn generate_object(&self, obj: &AbiObject) -> rust::Tokens {
         let destructor_name = format!("drop_box_{}", &obj.name);
-        let destructor_type = quote!(#(&obj.name));
+        let destructor_type = if obj.shared {
+            quote!(Arc<#(&obj.name)>)
+        } else {
+            quote!(Box<#(&obj.name)>)
+        };
         quote! {
-            #(for method in &obj.methods => #(self.generate_function(method)))
+            #(for method in &obj.methods join (#<line>) => #(self.generate_function(method)))
             #(self.generate_destructor(&destructor_name, destructor_type))
         }
     }
@@ -264,7 +692,7 @@ impl RustGenerator {
             #[no_mangle]
             pub extern "C" fn #name(_: #(self.ffi_num_type(self.abi.iptr())), boxed: #(self.ffi_num_type(self.abi.iptr()))) {
                 panic_abort(move || {
-                    unsafe { Box::<#ty>::from_raw(boxed as *mut _) };
+                    unsafe { <#ty>::from_raw(boxed as _) };
                 });
             }
         }
@@ -272,7 +700,7 @@ impl RustGenerator {
 
     fn generate_iterator(&self, iter: &AbiIter) -> rust::Tokens {
         let destructor_name = format!("{}_iter_drop", &iter.symbol);
-        let destructor_type = quote!(FfiIter<#(self.ty(&iter.ty))>);
+        let destructor_type = quote!(Box<FfiIter<#(self.ty(&iter.ty))>>);
         quote! {
             #(self.generate_function(&iter.next()))
             #(self.generate_destructor(&destructor_name, destructor_type))
@@ -281,7 +709,7 @@ impl RustGenerator {
 
     fn generate_future(&self, fut: &AbiFuture) -> rust::Tokens {
         let destructor_name = format!("{}_future_drop", &fut.symbol);
-        let destructor_type = quote!(FfiFuture<#(self.ty(&fut.ty))>);
+        let destructor_type = quote!(Box<FfiFuture<#(self.ty(&fut.ty))>>);
         quote! {
             #(self.generate_function(&fut.poll()))
             #(self.generate_destructor(&destructor_name, destructor_type))
@@ -290,7 +718,7 @@ impl RustGenerator {
 
     fn generate_stream(&self, stream: &AbiStream) -> rust::Tokens {
         let destructor_name = format!("{}_stream_drop", &stream.symbol);
-        let destructor_type = quote!(FfiStream<#(self.ty(&stream.ty))>);
+        let destructor_type = quote!(Box<FfiStream<#(self.ty(&stream.ty))>>);
         quote! {
             #(self.generate_function(&stream.poll()))
             #(self.generate_destructor(&destructor_name, destructor_type))
@@ -303,7 +731,7 @@ impl RustGenerator {
             quote! {
                 #[repr(C)]
                 pub struct #name {
-                    #(for (i, var) in vars.iter().enumerate() => #(format!("pub ret{}", i)): #(self.ty(&var.ty)),)
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => #(format!("pub ret{}", i)): #(self.ty(&var.ty)),)
                 }
             }
         } else {
@@ -378,15 +806,79 @@ impl RustGenerator {
                     )
                 };
             },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let (collection, add) = match &out.ty {
+                    AbiType::Map(_, _) => (
+                        quote!(HashMap),
+                        quote!(insert(#(self.var(&elems[0])), #(self.var(&elems[1])))),
+                    ),
+                    AbiType::Set(_) => (quote!(HashSet), quote!(insert(#(self.var(&elems[0]))))),
+                    _ => (quote!(Vec), quote!(push(#(self.var(&elems[0]))))),
+                };
+                let value = if let AbiType::RefList(_) = &out.ty {
+                    quote!(&#(self.var(out))_0)
+                } else {
+                    quote!(#(self.var(out))_0)
+                };
+                quote! {
+                    let mut #(self.var(out))_0 = #collection::with_capacity(#(self.var(len)) as _);
+                    for #(self.var(out))_1 in 0..#(self.var(len)) as usize {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            let #(self.var(var)): #(self.ty(&var.ty)) = unsafe {
+                                (#(self.var(ptr)) as *const u64).add(#(self.var(out))_1 * #n + #i).cast::<#(self.ty(&var.ty))>().read()
+                            };
+                        )
+                        #(for instr in elem_instr join (#<push>) => #(self.instr(instr)))
+                        #(self.var(out))_0.#add;
+                    }
+                    if #(self.var(len)) > 0 {
+                        unsafe { deallocate(#(self.var(ptr)) as _, #(self.var(len)) as usize * #(n * 8), 8) };
+                    }
+                    let #(self.var(out)) = #value;
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let elem = if elems.len() == 1 {
+                    self.var(&elems[0])
+                } else {
+                    quote!((#(for elem in elems join (, ) => #(self.var(elem)))))
+                };
+                quote! {
+                    #(self.var(len)) = #(self.var(in_)).len() as _;
+                    let mut #(self.var(in_))_0 = vec![0u64; #(self.var(in_)).len() * #n].into_boxed_slice();
+                    for (#(self.var(in_))_1, #elem) in #(self.var(in_)).into_iter().enumerate() {
+                        #(self.instr(&Instr::DefineRets(elem_ffi.clone())))
+                        #(for instr in elem_instr join (#<push>) => #(self.instr(instr)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            unsafe {
+                                #(self.var(in_))_0.as_mut_ptr().add(#(self.var(in_))_1 * #n + #i).cast::<#(self.ty(&var.ty))>().write(#(self.var(var)))
+                            };
+                        )
+                    }
+                    #(self.var(ptr)) = Box::into_raw(#(self.var(in_))_0) as *mut u64 as _;
+                }
+            }
+            Instr::LiftRefObject(in_, out, object) if self.shared.contains(object) => quote! {
+                let #(self.var(out)) = unsafe { &*(#(self.var(in_)) as *const #object) };
+            },
             Instr::LiftRefObject(in_, out, object) => quote! {
                 let #(self.var(out)) = unsafe { &mut *(#(self.var(in_)) as *mut #object) };
             },
             Instr::LowerRefObject(in_, out) => quote! {
                 #(self.var(out)) = #(self.var(in_)) as *const _ as _;
             },
+            Instr::LiftObject(in_, out, object) if self.shared.contains(object) => quote! {
+                let #(self.var(out)) = unsafe { Arc::from_raw(#(self.var(in_)) as *const #object) };
+            },
             Instr::LiftObject(in_, out, object) => quote! {
                 let #(self.var(out)) = unsafe { Box::from_raw(#(self.var(in_)) as *mut #object) };
             },
+            Instr::LowerObject(in_, out) if self.is_shared(&in_.ty) => quote! {
+                let #(self.var(in_))_0 = assert_send_static(<#(self.ty(&in_.ty))>::from(#(self.var(in_))));
+                #(self.var(out)) = Arc::into_raw(#(self.var(in_))_0) as _;
+            },
             Instr::LowerObject(in_, out) => quote! {
                 let #(self.var(in_))_0 = assert_send_static(#(self.var(in_)));
                 #(self.var(out)) = Box::into_raw(Box::new(#(self.var(in_))_0)) as _;
@@ -401,14 +893,21 @@ impl RustGenerator {
                 let #(self.var(out)) = unsafe { Box::from_raw(#(self.var(in_)) as *mut FfiIter<#(self.ty(ty))>) };
             },
             Instr::LowerIter(in_, out, ty) => {
-                let iter = if let AbiType::Result(_) = ty {
+                let iter = if matches!(ty, AbiType::Result(_, err) if matches!(**err, AbiType::String))
+                {
                     quote!(#(self.var(in_)).map_err(|err| err.to_string()))
                 } else {
                     quote!(#(self.var(in_)))
                 };
+                let new = match &in_.ty {
+                    AbiType::Iter(_, Some(batch)) => {
+                        quote!(FfiIter::batched(#(self.var(out))_0, #(*batch)))
+                    }
+                    _ => quote!(FfiIter::new(#(self.var(out))_0)),
+                };
                 quote! {
                     let #(self.var(out))_0 = #iter;
-                    let #(self.var(out))_1: FfiIter<#(self.ty(ty))> = FfiIter::new(#(self.var(out))_0);
+                    let #(self.var(out))_1: FfiIter<#(self.ty(ty))> = #new;
                     #(self.var(out)) = Box::into_raw(Box::new(#(self.var(out))_1)) as _;
                 }
             }
@@ -422,7 +921,8 @@ impl RustGenerator {
                 let #(self.var(out)) = unsafe { Box::from_raw(#(self.var(in_)) as *mut FfiFuture<#(self.ty(ty))>) };
             },
             Instr::LowerFuture(in_, out, ty) => {
-                let future = if let AbiType::Result(_) = ty {
+                let future = if matches!(ty, AbiType::Result(_, err) if matches!(**err, AbiType::String))
+                {
                     quote!(async move { #(self.var(in_)).await.map_err(|err| err.to_string()) })
                 } else {
                     quote!(#(self.var(in_)))
@@ -443,13 +943,20 @@ impl RustGenerator {
                 let #(self.var(out)) = unsafe { Box::from_raw(#(self.var(in_)) as *mut FfiStream<#(self.ty(ty))>) };
             },
             Instr::LowerStream(in_, out, ty) => {
-                let map_err = if let AbiType::Result(_) = ty {
+                let map_err = if matches!(ty, AbiType::Result(_, err) if matches!(**err, AbiType::String))
+                {
                     quote!(.map_err(|err| err.to_string()))
                 } else {
                     quote!()
                 };
+                let new = match &in_.ty {
+                    AbiType::Stream(_, Some(batch)) => {
+                        quote!(FfiStream::batched(#(self.var(in_))#map_err, #(*batch)))
+                    }
+                    _ => quote!(FfiStream::new(#(self.var(in_))#map_err)),
+                };
                 quote! {
-                    let #(self.var(out))_0: FfiStream<#(self.ty(ty))> = FfiStream::new(#(self.var(in_))#map_err);
+                    let #(self.var(out))_0: FfiStream<#(self.ty(ty))> = #new;
                     #(self.var(out)) = Box::into_raw(Box::new(#(self.var(out))_0)) as _;
                 }
             }
@@ -457,36 +964,101 @@ impl RustGenerator {
                 let #(self.var(out)) = if #(self.var(var)) == 0 {
                     None
                 } else {
-                    #(for instr in inner_instr => #(self.instr(instr)))
+                    #(for instr in inner_instr join (#<push>) => #(self.instr(instr)))
                     Some(#(self.var(inner)))
                 };
             },
             Instr::LowerOption(in_, var, some, some_instr) => quote! {
                 if let Some(#(self.var(some))) = #(self.var(in_)) {
                     #(self.var(var)) = 1;
-                    #(for instr in some_instr => #(self.instr(instr)))
+                    #(for instr in some_instr join (#<push>) => #(self.instr(instr)))
                 } else {
                     #(self.var(var)) = 0;
                 }
             },
-            Instr::LowerResult(in_, var, ok, ok_instr, err, err_instr) => quote! {
-                match #(self.var(in_)) {
-                    Ok(#(self.var(ok))) => {
-                        #(self.var(var)) = 1;
-                        #(for instr in ok_instr => #(self.instr(instr)))
-                    }
-                    Err(#(self.var(err))_0) => {
-                        #(self.var(var)) = 0;
-                        let #(self.var(err)) = #(self.var(err))_0.to_string();
-                        #(for instr in err_instr => #(self.instr(instr)))
-                    }
+            Instr::LowerResult(in_, var, ok, ok_instr, err, err_instr) => {
+                let err_value = if let AbiType::String = &err.ty {
+                    quote!(#(self.var(err))_0.to_string())
+                } else {
+                    quote!(#(self.var(err))_0)
+                };
+                quote! {
+                    match #(self.var(in_)) {
+                        Ok(#(self.var(ok))) => {
+                            #(self.var(var)) = 1;
+                            #(for instr in ok_instr join (#<push>) => #(self.instr(instr)))
+                        }
+                        Err(#(self.var(err))_0) => {
+                            #(self.var(var)) = 0;
+                            let #(self.var(err)) = #err_value;
+                            #(for instr in err_instr join (#<push>) => #(self.instr(instr)))
+                        }
+                    };
+                }
+            }
+            Instr::LiftEnum(tag, payload, out, name, variants) => quote! {
+                let #(self.var(out)) = match #(self.var(tag)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        #(i as u32) => {
+                            #(self.read_payload(payload, ffi))
+                            #(for instr in instr join (#<push>) => #(self.instr(instr)))
+                            #name::#variant #(if !fields.is_empty() {
+                                { #(for (field, var) in fields join (, ) => #field: #(self.var(var))) }
+                            })
+                        }
+                    )
+                    // The bindings only pass the discriminants of declared variants.
+                    _ => unreachable!(#(quoted(format!("invalid discriminant for {}", name)))),
                 };
             },
+            Instr::LiftCallback(ptr, post_cobject, port, out, cb) => quote! {
+                let #(self.var(out)): #(self.ty(&out.ty)) = {
+                    let ptr = #(self.var(ptr)) as isize;
+                    let callback = FfiCallback::new(#(self.var(post_cobject)) as _, #(self.var(port)));
+                    Box::new(move |#(for arg in &cb.args join (, ) => #(self.var(arg)): #(self.api_ty(&arg.ty)))| {
+                        // Borrow the whole callback so the closure owns it and the host
+                        // isn't notified before the closure is dropped.
+                        let callback = &callback;
+                        #(for instr in &cb.lower join (#<push>) => #(self.instr(instr)))
+                        #(self.generate_callback_call(cb, quote!(ptr), quote!(callback)))
+                        #(for instr in &cb.lift join (#<push>) => #(self.instr(instr)))
+                        #(if let Some(ret) = &cb.ret => #(self.var(ret)))
+                    })
+                };
+            },
+            Instr::LiftTrait(vtable, post_cobject, port, out, name) => quote! {
+                let #(self.var(out)): Box<dyn #name> = Box::new(#(format!("{}Proxy", name)) {
+                    vtable: [#(for ptr in vtable join (, ) => #(self.var(ptr)) as _)],
+                    callback: FfiCallback::new(#(self.var(post_cobject)) as _, #(self.var(port))),
+                });
+            },
+            Instr::LowerEnum(in_, tag, payload, name, variants) => quote! {
+                match #(self.var(in_)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        #name::#variant #(if !fields.is_empty() {
+                            { #(for (field, var) in fields join (, ) => #field: #(self.var(var))) }
+                        }) => {
+                            #(self.var(tag)) = #(i as u32);
+                            #(self.instr(&Instr::DefineRets(ffi.clone())))
+                            #(for instr in instr join (#<push>) => #(self.instr(instr)))
+                            #(self.write_payload(payload, ffi))
+                        }
+                    )
+                }
+            },
             Instr::LiftTuple(vars, out) => quote! {
                 let #(self.var(out)) = (#(for var in vars => #(self.var(var)),));
             },
             Instr::LowerTuple(ret, vars) => quote! {
-                #(for (i, var) in vars.iter().enumerate() => let #(self.var(var)) = #(self.var(ret)).#i;)
+                #(for (i, var) in vars.iter().enumerate() join (#<push>) => let #(self.var(var)) = #(self.var(ret)).#i;)
+            },
+            Instr::LiftStruct(vars, out, name) => quote! {
+                let #(self.var(out)) = #name {
+                    #(for (field, var) in vars join (#<push>) => #field: #(self.var(var)),)
+                };
+            },
+            Instr::LowerStruct(ret, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => let #(self.var(var)) = #(self.var(ret)).#field;)
             },
             Instr::CallAbi(ty, self_, name, ret, args) => {
                 let invoke = match ty {
@@ -503,7 +1075,13 @@ impl RustGenerator {
                         quote!(#name)
                     }
                 };
-                let args = quote!(#(for arg in args => #(self.var(arg)),));
+                if let (FunctionType::Method(object), Some(ret)) = (ty, ret) {
+                    if self.shared.contains(object) && matches!(ret.ty, AbiType::Future(_)) {
+                        let future = self.shared_future(self_.as_ref().unwrap(), name, args);
+                        return quote!(let #(self.var(ret)) = #future;);
+                    }
+                }
+                let args = quote!(#(for arg in args join (, ) => #(self.var(arg))));
                 if let Some(ret) = ret {
                     quote!(let #(self.var(ret)) = #invoke(#args);)
                 } else {
@@ -511,11 +1089,47 @@ impl RustGenerator {
                 }
             }
             Instr::DefineRets(vars) => quote! {
-                #(for var in vars => #[allow(unused_assignments)] let mut #(self.var(var)) = Default::default();)
+                #(for var in vars join (#<push>) => #[allow(unused_assignments)] let mut #(self.var(var)) = Default::default();)
             },
         }
     }
 
+    /// Calls an async method of a shared object. The returned future holds an `Arc` of the
+    /// object and owned copies of the borrowed arguments, so it can outlive the ffi call.
+    fn shared_future(&self, self_: &Var, name: &str, args: &[Var]) -> rust::Tokens {
+        let owned = args.iter().map(|arg| {
+            let var = self.var(arg);
+            match &arg.ty {
+                AbiType::RefStr => quote!(let #(&var) = #(&var).to_owned();),
+                AbiType::RefSlice(_) => quote!(let #(&var) = #(&var).to_vec();),
+                AbiType::RefList(_) => quote!(let #(&var) = #(&var)_0;),
+                AbiType::RefObject(_) => quote!(let #(&var) = unsafe { clone_arc(#(&var)) };),
+                _ => quote!(),
+            }
+        });
+        let args = args.iter().map(|arg| match &arg.ty {
+            AbiType::RefStr
+            | AbiType::RefSlice(_)
+            | AbiType::RefList(_)
+            | AbiType::RefObject(_) => {
+                quote!(&#(self.var(arg)))
+            }
+            _ => self.var(arg),
+        });
+        let this = self.var(self_);
+        quote! {
+            {
+                let #(&this) = unsafe { clone_arc(#(&this)) };
+                #(for owned in owned join (#<push>) => #owned)
+                async move { #(&this).#name(#(for arg in args join (, ) => #arg)).await }
+            }
+        }
+    }
+
+    fn is_shared(&self, ty: &AbiType) -> bool {
+        matches!(ty, AbiType::Object(ident) if self.shared.contains(ident))
+    }
+
     fn var(&self, var: &Var) -> rust::Tokens {
         quote!(#(format!("tmp{}", var.binding)))
     }
@@ -530,17 +1144,44 @@ impl RustGenerator {
             AbiType::String => quote!(String),
             AbiType::RefSlice(ty) => quote!(&[#(self.num_type(*ty))]),
             AbiType::Vec(ty) => quote!(Vec<#(self.num_type(*ty))>),
+            AbiType::RefList(ty) => quote!(&[#(self.api_ty(ty))]),
+            AbiType::List(ty) => quote!(Vec<#(self.api_ty(ty))>),
+            AbiType::Map(key, value) => {
+                quote!(HashMap<#(self.api_ty(key)), #(self.api_ty(value))>)
+            }
+            AbiType::Set(ty) => quote!(HashSet<#(self.api_ty(ty))>),
             AbiType::Option(ty) => quote!(Option<#(self.ty(ty))>),
-            AbiType::Result(ty) => quote!(Result<#(self.ty(ty))>),
+            AbiType::Result(ty, err) => quote!(Result<#(self.ty(ty)), #(self.ty(err))>),
+            AbiType::Error(err) => quote!(#(&err.name)),
+            AbiType::Object(ident) if self.shared.contains(ident) => quote!(Arc<#ident>),
             AbiType::Object(ident) => quote!(#ident),
             AbiType::RefObject(ident) => quote!(&#ident),
             AbiType::Tuple(ty) => quote!((#(for ty in ty => #(self.ty(ty)),))),
+            AbiType::Struct(st) => quote!(#(&st.name)),
+            AbiType::Enum(en) => quote!(#(&en.name)),
+            AbiType::Callback(args, ret) => {
+                let ret = match ret {
+                    Some(ret) => quote!(-> #(self.api_ty(ret))),
+                    None => quote!(),
+                };
+                quote!(Box<dyn Fn(#(for arg in args join (, ) => #(self.api_ty(arg)))) #ret + Send + Sync + 'static>)
+            }
+            AbiType::Trait(tr) => quote!(Box<dyn #(&tr.name)>),
             AbiType::RefIter(ty) => quote!(&Vec<#(self.ty(ty))>),
-            AbiType::Iter(ty) => quote!(Vec<#(self.ty(ty))>),
+            AbiType::Iter(ty, _) => quote!(Vec<#(self.ty(ty))>),
             AbiType::RefFuture(ty) => quote!(&impl Future<Output = #(self.ty(ty))>),
             AbiType::Future(ty) => quote!(impl Future<Output = #(self.ty(ty))>),
             AbiType::RefStream(ty) => quote!(&impl Stream<Item = #(self.ty(ty))>),
-            AbiType::Stream(ty) => quote!(impl Stream<Item = #(self.ty(ty))>),
+            AbiType::Stream(ty, _) => quote!(impl Stream<Item = #(self.ty(ty))>),
+        }
+    }
+
+    /// Returns the type used in the rust api, which unlike the ffi type
+    /// isn't widened on wasm.
+    fn api_ty(&self, ty: &AbiType) -> rust::Tokens {
+        match ty {
+            AbiType::Num(num) => self.num_type(*num),
+            ty => self.ty(ty),
         }
     }
 
@@ -0,0 +1,1111 @@
+use crate::import::{Callback, Instr};
+use crate::{
+    Abi, AbiEnum, AbiError, AbiFunction, AbiObject, AbiStruct, AbiTrait, AbiType, FunctionType,
+    Interface, NumType, Var,
+};
+use genco::prelude::*;
+use genco::tokens::static_literal;
+use heck::*;
+
+/// Generates swift bindings calling the symbols declared by the c header.
+pub struct SwiftGenerator {
+    abi: Abi,
+    module_name: String,
+}
+
+impl SwiftGenerator {
+    /// `module_name` is the name of the clang module exposing the c header.
+    pub fn new(module_name: String) -> Self {
+        Self {
+            abi: Abi::native(),
+            module_name,
+        }
+    }
+
+    pub fn generate(&self, iface: Interface) -> swift::Tokens {
+        quote! {
+            #(static_literal("//")) AUTO GENERATED FILE, DO NOT EDIT.
+            #(static_literal("//"))
+            #(static_literal("//")) Generated by "ffi-gen".
+            #(self.generate_comment(&iface.doc))
+
+            import Foundation
+            import #(&self.module_name)
+
+            #(self.generate_doc(&["Owns a value boxed by rust, which is dropped when the box is released."]))
+            final class FfiBox {
+                private let ptr: Int64
+                private let dropFn: (Int64, Int64) -> Void
+                private var moved = false
+                private var dropped = false
+
+                init(_ ptr: Int64, _ dropFn: @escaping (Int64, Int64) -> Void) {
+                    self.ptr = ptr
+                    self.dropFn = dropFn
+                }
+
+                deinit {
+                    if !moved && !dropped {
+                        dropFn(0, ptr)
+                    }
+                }
+
+                func borrow() -> Int64 {
+                    precondition(!moved, "use after move")
+                    precondition(!dropped, "use after free")
+                    return ptr
+                }
+
+                func move() -> Int64 {
+                    precondition(!moved, "can't move value twice")
+                    precondition(!dropped, "use after free")
+                    moved = true
+                    return ptr
+                }
+
+                func drop() {
+                    precondition(!moved, "can't drop moved value")
+                    precondition(!dropped, "double free")
+                    dropped = true
+                    dropFn(0, ptr)
+                }
+            }
+
+            #(self.generate_doc(&[
+                "Maps the ports passed to rust to the closures run when they are notified.",
+                "",
+                "Closures passed to rust are kept alive by port until rust drops them, c function",
+                "pointers can't capture them so the port is passed to the function instead.",
+            ]))
+            final class FfiPorts {
+                private let lock = NSLock()
+                private var nextPort: Int64 = 1
+                private var callbacks: [Int64: () -> Void] = [:]
+                private var values: [Int64: Any] = [:]
+
+                func open(_ callback: @escaping () -> Void) -> Int64 {
+                    lock.lock()
+                    defer { lock.unlock() }
+                    let port = nextPort
+                    nextPort += 1
+                    callbacks[port] = callback
+                    return port
+                }
+
+                func close(_ port: Int64) {
+                    lock.lock()
+                    defer { lock.unlock() }
+                    callbacks[port] = nil
+                    values[port] = nil
+                }
+
+                func keepAlive(_ value: Any) -> Int64 {
+                    lock.lock()
+                    defer { lock.unlock() }
+                    let port = nextPort
+                    nextPort += 1
+                    callbacks[port] = { [unowned self] in
+                        if __ffi_dispatch(port) != 0 {
+                            self.close(port)
+                        }
+                    }
+                    values[port] = value
+                    return port
+                }
+
+                func value<T>(_ port: Int64, as: T.Type) -> T {
+                    lock.lock()
+                    defer { lock.unlock() }
+                    return values[port] as! T
+                }
+
+                func post(_ port: Int64) {
+                    lock.lock()
+                    let callback = callbacks[port]
+                    lock.unlock()
+                    callback?()
+                }
+            }
+
+            let ffiPorts = FfiPorts()
+
+            private let ffiPostCObject: @convention(c) (Int64, UnsafeRawPointer?) -> Void = { port, _ in
+                ffiPorts.post(port)
+            }
+
+            let ffiPostCObjectAddress = unsafeBitCast(ffiPostCObject, to: Int.self)
+
+            #(self.generate_doc(&["A flag that is set from another thread."]))
+            final class FfiFlag {
+                private let lock = NSLock()
+                private var value = false
+
+                var isSet: Bool {
+                    lock.lock()
+                    defer { lock.unlock() }
+                    return value
+                }
+
+                func set() {
+                    lock.lock()
+                    value = true
+                    lock.unlock()
+                }
+            }
+
+            #(self.generate_doc(&["Returns a stream of wake ups, which coalesces wake ups that are not awaited."]))
+            func ffiWakes() -> (AsyncStream<Void>, AsyncStream<Void>.Continuation) {
+                var continuation: AsyncStream<Void>.Continuation?
+                let stream = AsyncStream<Void>(bufferingPolicy: .bufferingNewest(1)) { continuation = $0 }
+                return (stream, continuation!)
+            }
+
+            func ffiAllocate(_ size: Int, _ align: Int) -> Int64 {
+                return Int64(Int(bitPattern: allocate(size, align)))
+            }
+
+            func ffiDeallocate(_ ptr: Int64, _ size: Int, _ align: Int) {
+                deallocate(UnsafeMutablePointer<UInt8>(bitPattern: Int(ptr)), size, align)
+            }
+
+            func ffiWrite<T>(_ values: [T], to ptr: Int64) {
+                values.withUnsafeBytes { bytes in
+                    if bytes.count > 0 {
+                        UnsafeMutableRawPointer(bitPattern: Int(ptr))!.copyMemory(from: bytes.baseAddress!, byteCount: bytes.count)
+                    }
+                }
+            }
+
+            func ffiRead<T>(_ ptr: Int64, _ len: Int, as: T.Type) -> [T] {
+                return Array(UnsafeBufferPointer(start: UnsafePointer<T>(bitPattern: Int(ptr)), count: len))
+            }
+
+            func ffiString(_ ptr: Int64, _ len: Int) -> String {
+                return String(decoding: UnsafeRawBufferPointer(start: UnsafeRawPointer(bitPattern: Int(ptr)), count: len), as: UTF8.self)
+            }
+
+            func ffiLoad<T>(_ ptr: Int64, _ offset: Int, as: T.Type) -> T {
+                return UnsafeRawPointer(bitPattern: Int(ptr) + offset)!.load(as: T.self)
+            }
+
+            func ffiStore<T>(_ value: T, _ ptr: Int64, _ offset: Int) {
+                UnsafeMutableRawPointer(bitPattern: Int(ptr) + offset)!.storeBytes(of: value, as: T.self)
+            }
+
+            #(self.generate_doc(&["Error returned by a rust function as a string."]))
+            public struct FfiError: Error, CustomStringConvertible {
+                public let message: String
+
+                public var description: String {
+                    return message
+                }
+            }
+
+            #(self.generate_doc(&[
+                "Iterates over the items of a rust iterator.",
+                "",
+                "Errors returned by the rust iterator are fatal.",
+            ]))
+            public final class Iter<T>: Sequence, IteratorProtocol {
+                let box: FfiBox
+                private let nextFn: (Int) throws -> T?
+
+                init(_ box: FfiBox, _ nextFn: @escaping (Int) throws -> T?) {
+                    self.box = box
+                    self.nextFn = nextFn
+                }
+
+                #(self.generate_doc(&["Iterates over a batched rust iterator, which returns an array of items at a time."]))
+                convenience init(_ box: FfiBox, batched nextFn: @escaping (Int) throws -> [T]?) {
+                    var items: [T] = []
+                    var index = 0
+                    self.init(box) { ptr in
+                        while index == items.count {
+                            guard let batch = try nextFn(ptr) else {
+                                return nil
+                            }
+                            items = batch
+                            index = 0
+                        }
+                        index += 1
+                        return items[index - 1]
+                    }
+                }
+
+                public func next() -> T? {
+                    return try! nextFn(Int(box.borrow()))
+                }
+
+                #(self.generate_doc(&["Manually drops the iterator."]))
+                public func drop() {
+                    box.drop()
+                }
+            }
+
+            #(self.generate_doc(&["Resolves to the output of a rust future."]))
+            public final class FfiFuture<T> {
+                let box: FfiBox
+                private let pollFn: (Int, Int, Int64) throws -> T?
+
+                init(_ box: FfiBox, _ pollFn: @escaping (Int, Int, Int64) throws -> T?) {
+                    self.box = box
+                    self.pollFn = pollFn
+                }
+
+                #(self.generate_doc(&["Polls the future until it is ready, it can only be awaited once."]))
+                public func value() async throws -> T {
+                    let (wakes, wake) = ffiWakes()
+                    let port = ffiPorts.open { wake.yield(()) }
+                    defer {
+                        ffiPorts.close(port)
+                        box.drop()
+                    }
+                    var iterator = wakes.makeAsyncIterator()
+                    while true {
+                        if let value = try pollFn(Int(box.borrow()), ffiPostCObjectAddress, port) {
+                            return value
+                        }
+                        _ = await iterator.next()
+                        try Task.checkCancellation()
+                    }
+                }
+            }
+
+            #(self.generate_doc(&["Yields the items of a rust stream, which is dropped once the stream terminates."]))
+            func ffiStream<T>(_ box: FfiBox, _ pollFn: @escaping (Int, Int, Int64, Int64) throws -> T?) -> AsyncThrowingStream<T, Error> {
+                return AsyncThrowingStream { continuation in
+                    let task = Task {
+                        let (wakes, wake) = ffiWakes()
+                        let finished = FfiFlag()
+                        let port = ffiPorts.open { wake.yield(()) }
+                        let done = ffiPorts.open {
+                            finished.set()
+                            wake.yield(())
+                        }
+                        defer {
+                            ffiPorts.close(port)
+                            ffiPorts.close(done)
+                            box.drop()
+                        }
+                        var iterator = wakes.makeAsyncIterator()
+                        do {
+                            while !finished.isSet {
+                                if let value = try pollFn(Int(box.borrow()), ffiPostCObjectAddress, port, done) {
+                                    continuation.yield(value)
+                                } else if !finished.isSet {
+                                    _ = await iterator.next()
+                                    try Task.checkCancellation()
+                                }
+                            }
+                            continuation.finish()
+                        } catch {
+                            continuation.finish(throwing: error)
+                        }
+                    }
+                    continuation.onTermination = { _ in task.cancel() }
+                }
+            }
+
+            #(self.generate_doc(&["Yields the items of a batched rust stream, which returns an array of items at a time."]))
+            func ffiStream<T>(_ box: FfiBox, batched pollFn: @escaping (Int, Int, Int64, Int64) throws -> [T]?) -> AsyncThrowingStream<T, Error> {
+                let batches = ffiStream(box, pollFn)
+                return AsyncThrowingStream { continuation in
+                    let task = Task {
+                        do {
+                            for try await items in batches {
+                                for item in items {
+                                    continuation.yield(item)
+                                }
+                            }
+                            continuation.finish()
+                        } catch {
+                            continuation.finish(throwing: error)
+                        }
+                    }
+                    continuation.onTermination = { _ in task.cancel() }
+                }
+            }
+
+            #(for func in iface.functions() join (#<line>) => #(self.generate_function(&func)))
+
+            #(for iter in iface.iterators() join (#<line>) => #(self.generate_function(&iter.next())))
+
+            #(for fut in iface.futures() join (#<line>) => #(self.generate_function(&fut.poll())))
+
+            #(for stream in iface.streams() join (#<line>) => #(self.generate_function(&stream.poll())))
+
+            #(for obj in iface.objects() join (#<line>) => #(self.generate_object(obj)))
+
+            #(for tr in iface.traits() join (#<line>) => #(self.generate_trait(tr)))
+
+            #(for st in iface.structs() join (#<line>) => #(self.generate_struct(st)))
+
+            #(for en in iface.enums() join (#<line>) => #(self.generate_enum(en)))
+
+            #(for err in iface.errors() join (#<line>) => #(self.generate_error(err)))
+        }
+    }
+
+    fn generate_object(&self, obj: AbiObject) -> swift::Tokens {
+        quote! {
+            #(self.generate_doc(&obj.doc))
+            public final class #(self.type_ident(&obj.name)) {
+                let box: FfiBox
+
+                init(_ box: FfiBox) {
+                    self.box = box
+                }
+
+                #(for func in &obj.methods join (#<line>) => #(self.generate_function(func)))
+
+                #(self.generate_doc(&["Manually drops the object."]))
+                public func drop() {
+                    box.drop()
+                }
+            }
+        }
+    }
+
+    fn generate_trait(&self, tr: AbiTrait) -> swift::Tokens {
+        quote! {
+            #(self.generate_doc(&tr.doc))
+            public protocol #(self.type_ident(&tr.name)): AnyObject {
+                #(for method in &tr.methods join (#<line>) =>
+                    #(self.generate_doc(&method.doc))
+                    func #(self.ident(&method.name))(#(for (name, ty) in &method.args join (, ) => #(self.ident(name)): #(self.generate_type(ty))))#(if let Some(ret) = &method.ret => #(" ")-> #(self.generate_type(ret)))
+                )
+            }
+        }
+    }
+
+    fn generate_struct(&self, st: AbiStruct) -> swift::Tokens {
+        quote! {
+            #(self.generate_doc(&st.doc))
+            public struct #(self.type_ident(&st.name)) {
+                #(for (name, ty) in &st.fields join (#<push>) => public var #(self.ident(name)): #(self.generate_type(ty)))
+
+                public init(#(for (name, ty) in &st.fields join (, ) => #(self.ident(name)): #(self.generate_type(ty)))) {
+                    #(for (name, _) in &st.fields join (#<push>) => self.#(self.ident(name)) = #(self.ident(name)))
+                }
+            }
+        }
+    }
+
+    fn generate_enum(&self, en: AbiEnum) -> swift::Tokens {
+        let raw = if en.is_c_like() {
+            quote!(: UInt32)
+        } else {
+            quote!()
+        };
+        quote! {
+            #(self.generate_doc(&en.doc))
+            public enum #(self.type_ident(&en.name))#raw {
+                #(for variant in &en.variants join (#<push>) =>
+                    #(self.generate_doc(&variant.doc))
+                    case #(self.generate_case(&variant.name, &variant.fields))
+                )
+            }
+        }
+    }
+
+    fn generate_error(&self, err: AbiError) -> swift::Tokens {
+        quote! {
+            #(self.generate_doc(&err.doc))
+            public enum #(self.type_ident(&err.name)): Error {
+                #(for variant in &err.variants join (#<push>) =>
+                    #(self.generate_doc(&variant.doc))
+                    case #(self.generate_case(&variant.name, &variant.fields))
+                )
+            }
+        }
+    }
+
+    fn generate_case(&self, name: &str, fields: &[(String, AbiType)]) -> swift::Tokens {
+        if fields.is_empty() {
+            quote!(#(self.ident(name)))
+        } else {
+            quote!(#(self.ident(name))(#(for (name, ty) in fields join (, ) => #(self.ident(name)): #(self.generate_type(ty)))))
+        }
+    }
+
+    fn generate_function(&self, func: &AbiFunction) -> swift::Tokens {
+        let ffi = self.abi.import(func);
+        let is_poll = matches!(
+            &func.ty,
+            FunctionType::NextIter(_, _)
+                | FunctionType::PollFuture(_, _)
+                | FunctionType::PollStream(_, _)
+        );
+        let doc = self.generate_doc(&func.doc);
+        if is_poll {
+            // Polling returns `nil` when no value is ready, an optional value
+            // is wrapped so that a ready `nil` can be told apart.
+            let ret = match &func.ty {
+                FunctionType::NextIter(_, ty)
+                | FunctionType::PollFuture(_, ty)
+                | FunctionType::PollStream(_, ty) => self.generate_type(ty),
+                _ => unreachable!(),
+            };
+            let pending = ffi
+                .instr
+                .iter()
+                .position(|instr| matches!(instr, Instr::HandleNull(_)));
+            let body = ffi.instr.iter().enumerate().map(|(i, instr)| match instr {
+                Instr::HandleNull(var) if Some(i) != pending => quote! {
+                    if #(self.var(var)) == 0 {
+                        return .some(nil)
+                    }
+                },
+                Instr::ReturnVoid => quote!(return ()),
+                _ => self.generate_instr(instr),
+            });
+            return quote! {
+                private func #(self.poll_ident(&ffi.symbol))(#(for (name, ty) in &ffi.abi_args join (, ) => _ #(self.ident(name)): #(self.generate_type(ty)))) throws -> #ret? {
+                    #(for instr in body join (#<push>) => #instr)
+                }
+            };
+        }
+        let args = quote!(#(for (name, ty) in &ffi.abi_args join (, ) => #(self.ident(name)): #(self.generate_arg_type(ty))));
+        let is_async = ffi.abi_ret.as_ref().map(|ret| self.is_async(ret)) == Some(true);
+        let (effects, ret) = match &ffi.abi_ret {
+            Some(ret) => self.generate_return_type(ret),
+            None => (quote!(), quote!()),
+        };
+        let decl = match &func.ty {
+            FunctionType::Constructor(_) => quote!(public static func),
+            _ => quote!(public func),
+        };
+        let signature = quote!(#decl #(self.ident(&func.name))(#args)#(effects)#(ret));
+        if let Some(reason) = self.unsupported(&ffi.abi_args) {
+            return quote! {
+                #doc
+                @available(*, unavailable, message: #_(#reason))
+                #signature {
+                    fatalError(#_(#reason))
+                }
+            };
+        }
+        let body = ffi.instr.iter().map(|instr| match instr {
+            Instr::ReturnValue(ret) if is_async => {
+                quote!(return try await #(self.var(ret)).value())
+            }
+            Instr::ReturnVoid if ffi.abi_ret.is_some() => quote!(return ()),
+            _ => self.generate_instr(instr),
+        });
+        quote! {
+            #doc
+            #signature {
+                #(for instr in body join (#<push>) => #instr)
+            }
+        }
+    }
+
+    /// Functions returning a future await it.
+    fn is_async(&self, ret: &AbiType) -> bool {
+        match ret {
+            AbiType::Result(ty, _) => self.is_async(ty),
+            AbiType::Future(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the effects and the return type of a function.
+    fn generate_return_type(&self, ret: &AbiType) -> (swift::Tokens, swift::Tokens) {
+        match ret {
+            AbiType::Result(ty, _) => {
+                let (_, ret) = self.generate_return_type(ty);
+                match &**ty {
+                    AbiType::Future(_) => (quote!(#(" ")async throws), ret),
+                    _ => (quote!(#(" ")throws), ret),
+                }
+            }
+            AbiType::Future(ty) => (quote!(#(" ")async throws), self.generate_return_type(ty).1),
+            AbiType::Tuple(tys) if tys.is_empty() => (quote!(), quote!()),
+            ty => (quote!(), quote!(#(" ")-> #(self.generate_type(ty)))),
+        }
+    }
+
+    /// Streams can't be passed to rust, because they don't own the rust value.
+    fn unsupported(&self, args: &[(String, AbiType)]) -> Option<&'static str> {
+        args.iter().find_map(|(_, ty)| match ty {
+            AbiType::RefStream(_) | AbiType::Stream(_, _) => {
+                Some("streams can't be passed to rust from swift")
+            }
+            _ => None,
+        })
+    }
+
+    fn generate_instr(&self, instr: &Instr) -> swift::Tokens {
+        match instr {
+            Instr::BorrowSelf(out) => quote!(#(self.var(out)) = self.box.borrow()),
+            Instr::BorrowObject(in_, out)
+            | Instr::BorrowIter(in_, out)
+            | Instr::BorrowFuture(in_, out)
+            | Instr::BorrowStream(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).box.borrow())
+            }
+            Instr::MoveObject(in_, out)
+            | Instr::MoveIter(in_, out)
+            | Instr::MoveFuture(in_, out)
+            | Instr::MoveStream(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)).box.move())
+            }
+            Instr::LiftObject(obj, box_, drop, out) => quote! {
+                let #(self.var(out)) = #(self.type_ident(obj))(FfiBox(#(self.var(box_)), #drop))
+            },
+            Instr::BindArg(arg, out) => quote!(let #(self.var(out)) = #(self.ident(arg))),
+            Instr::BindRets(ret, vars) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(let #(self.var(&vars[0])) = #(self.var(ret))),
+                _ => quote! {
+                    #(for (idx, var) in vars.iter().enumerate() join (#<push>) =>
+                        let #(self.var(var)) = #(self.var(ret)).#(format!("ret{}", idx)))
+                },
+            },
+            Instr::LowerNum(in_, out, num) => {
+                quote!(#(self.var(out)) = #(self.generate_num_type(*num))(#(self.var(in_))))
+            }
+            Instr::LiftNum(in_, out, num) => {
+                let ty = match &out.ty {
+                    AbiType::Isize | AbiType::Usize => self.generate_type(&out.ty),
+                    _ => self.generate_num_type(*num),
+                };
+                quote!(let #(self.var(out)) = #ty(#(self.var(in_))))
+            }
+            Instr::LowerBool(in_, out) => {
+                quote!(#(self.var(out)) = #(self.var(in_)) ? 1 : 0)
+            }
+            Instr::LiftBool(in_, out) => {
+                quote!(let #(self.var(out)) = #(self.var(in_)) > 0)
+            }
+            Instr::Deallocate(ptr, len, size, align) => quote! {
+                if #(self.var(len)) > 0 {
+                    ffiDeallocate(#(self.var(ptr)), Int(#(self.var(len))) * #(*size), #(*align))
+                }
+            },
+            Instr::LowerString(in_, ptr, len, cap, size, align) => quote! {
+                let #(self.var(in_))_0 = Array(#(self.var(in_)).utf8)
+                #(self.var(len)) = #(self.generate_num_type(len.ty.num()))(#(self.var(in_))_0.count)
+                #(self.var(ptr)) = ffiAllocate(Int(#(self.var(len))) * #(*size), #(*align))
+                ffiWrite(#(self.var(in_))_0, to: #(self.var(ptr)))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftString(ptr, len, out) => quote! {
+                let #(self.var(out)) = ffiString(#(self.var(ptr)), Int(#(self.var(len))))
+            },
+            Instr::LowerVec(in_, ptr, len, cap, _ty, size, align) => quote! {
+                #(self.var(len)) = #(self.generate_num_type(len.ty.num()))(#(self.var(in_)).count)
+                #(self.var(ptr)) = ffiAllocate(Int(#(self.var(len))) * #(*size), #(*align))
+                ffiWrite(#(self.var(in_)), to: #(self.var(ptr)))
+                #(self.var(cap)) = #(self.var(len))
+            },
+            Instr::LiftVec(ptr, len, out, ty) => quote! {
+                let #(self.var(out)) = ffiRead(#(self.var(ptr)), Int(#(self.var(len))), as: #(self.generate_num_type(*ty)).self)
+            },
+            Instr::LiftList(ptr, len, out, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let add = match &out.ty {
+                    AbiType::Map(_, _) => {
+                        quote!(#(self.var(out))[#(self.var(&elems[0]))] = #(self.var(&elems[1])))
+                    }
+                    AbiType::Set(_) => quote!(#(self.var(out)).insert(#(self.var(&elems[0])))),
+                    _ => quote!(#(self.var(out)).append(#(self.var(&elems[0])))),
+                };
+                quote! {
+                    var #(self.var(out)) = #(self.generate_type(&out.ty))()
+                    for #(self.var(out))_0 in 0..<Int(#(self.var(len))) {
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            let #(self.var(var)) = ffiLoad(#(self.var(ptr)), (#(self.var(out))_0 * #n + #i) * 8, as: #(self.generate_num_type(var.ty.num())).self)
+                        )
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(inst)))
+                        #add
+                    }
+                }
+            }
+            Instr::LowerList(in_, ptr, len, elems, elem_ffi, elem_instr) => {
+                let n = elem_ffi.len();
+                let bind = if let AbiType::Map(_, _) = &in_.ty {
+                    quote!((#(self.var(&elems[0])), #(self.var(&elems[1]))))
+                } else {
+                    self.var(&elems[0])
+                };
+                quote! {
+                    #(self.var(len)) = #(self.generate_num_type(len.ty.num()))(#(self.var(in_)).count)
+                    if #(self.var(len)) > 0 {
+                        #(self.var(ptr)) = ffiAllocate(Int(#(self.var(len))) * #(n * 8), 8)
+                    }
+                    for (#(self.var(in_))_0, #bind) in #(self.var(in_)).enumerated() {
+                        #(self.generate_instr(&Instr::DefineArgs(elem_ffi.clone())))
+                        #(for inst in elem_instr join (#<push>) => #(self.generate_instr(inst)))
+                        #(for (i, var) in elem_ffi.iter().enumerate() join (#<push>) =>
+                            ffiStore(#(self.var(var)), #(self.var(ptr)), (#(self.var(in_))_0 * #n + #i) * 8)
+                        )
+                    }
+                }
+            }
+            Instr::Call(symbol, ret, args) => {
+                let invoke = quote!(#(symbol)(#(for arg in args join (, ) => #(self.var(arg)))));
+                if let Some(ret) = ret {
+                    quote!(let #(self.var(ret)) = #invoke)
+                } else {
+                    invoke
+                }
+            }
+            Instr::DefineArgs(vars) => quote! {
+                #(for var in vars join (#<push>) => var #(self.var(var)): #(self.generate_num_type(var.ty.num())) = 0)
+            },
+            Instr::ReturnValue(ret) => quote!(return #(self.var(ret))),
+            Instr::ReturnVoid => quote!(return),
+            Instr::HandleNull(var) => quote! {
+                if #(self.var(var)) == 0 {
+                    return nil
+                }
+            },
+            Instr::LiftOption(var, some, some_instr, out) => quote! {
+                let #(self.var(out)): #(self.generate_type(&out.ty))
+                if #(self.var(var)) == 0 {
+                    #(self.var(out)) = nil
+                } else {
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(inst)))
+                    #(self.var(out)) = #(self.var(some))
+                }
+            },
+            Instr::LowerOption(arg, var, some, some_instr) => quote! {
+                if let #(self.var(some)) = #(self.var(arg)) {
+                    #(self.var(var)) = 1
+                    #(for inst in some_instr join (#<push>) => #(self.generate_instr(inst)))
+                } else {
+                    #(self.var(var)) = 0
+                }
+            },
+            Instr::HandleError(var, ptr, len, cap) => quote! {
+                if #(self.var(var)) == 0 {
+                    let #(self.var(var))_0 = ffiString(#(self.var(ptr)), Int(#(self.var(len))))
+                    if #(self.var(len)) > 0 {
+                        ffiDeallocate(#(self.var(ptr)), Int(#(self.var(cap))), 1)
+                    }
+                    throw FfiError(message: #(self.var(var))_0)
+                }
+            },
+            Instr::HandleTypedError(var, tag, payload, err, variants) => quote! {
+                if #(self.var(var)) == 0 {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        if #(self.var(tag)) == #i {
+                            #(self.read_payload(payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                            throw #(self.type_ident(err)).#(self.generate_variant(variant, fields))
+                        }
+                    )
+                    fatalError(#_(unknown variant of #err))
+                }
+            },
+            Instr::LowerEnum(in_, tag, payload, _name, variants) => {
+                if payload.is_none() {
+                    return quote!(#(self.var(tag)) = #(self.var(in_)).rawValue);
+                }
+                quote! {
+                    switch #(self.var(in_)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        #(if fields.is_empty() {
+                            case .#(self.ident(variant)):
+                        } else {
+                            case let .#(self.ident(variant))(#(for (_, var) in fields join (, ) => #(self.var(var)))):
+                        })
+                            #(self.var(tag)) = #i
+                            #(self.generate_instr(&Instr::DefineArgs(ffi.clone())))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                            #(self.write_payload(payload, ffi))
+                    )
+                    }
+                }
+            }
+            Instr::LiftEnum(tag, payload, name, variants, out) => {
+                if payload.is_none() {
+                    return quote!(let #(self.var(out)) = #(self.type_ident(name))(rawValue: #(self.var(tag)))!);
+                }
+                quote! {
+                    let #(self.var(out)): #(self.type_ident(name))
+                    switch #(self.var(tag)) {
+                    #(for (i, (variant, fields, ffi, instr)) in variants.iter().enumerate() join (#<push>) =>
+                        case #i:
+                            #(self.read_payload(payload, ffi))
+                            #(for inst in instr join (#<push>) => #(self.generate_instr(inst)))
+                            #(self.var(out)) = .#(self.generate_variant(variant, fields))
+                    )
+                    default:
+                        fatalError(#_(unknown variant of #name))
+                    }
+                }
+            }
+            Instr::LiftIter(box_, next, drop, out, batched) => {
+                let batched = if *batched { "batched: " } else { "" };
+                quote! {
+                    let #(self.var(out)) = Iter(FfiBox(#(self.var(box_)), #drop), #(batched)#(self.poll_ident(next)))
+                }
+            }
+            Instr::LiftFuture(box_, poll, drop, out) => quote! {
+                let #(self.var(out)) = FfiFuture(FfiBox(#(self.var(box_)), #drop), #(self.poll_ident(poll)))
+            },
+            Instr::LiftStream(box_, poll, drop, out, batched) => {
+                let batched = if *batched { "batched: " } else { "" };
+                quote! {
+                    let #(self.var(out)) = ffiStream(FfiBox(#(self.var(box_)), #drop), #(batched)#(self.poll_ident(poll)))
+                }
+            }
+            Instr::LowerTuple(in_, vars) => match vars.len() {
+                1 => quote!(let #(self.var(&vars[0])) = #(self.var(in_))),
+                _ => quote! {
+                    #(for (i, var) in vars.iter().enumerate() join (#<push>) => let #(self.var(var)) = #(self.var(in_)).#(i))
+                },
+            },
+            Instr::LiftTuple(vars, out) => match vars.len() {
+                0 => quote!(),
+                1 => quote!(let #(self.var(out)) = #(self.var(&vars[0]))),
+                _ => {
+                    quote!(let #(self.var(out)) = (#(for var in vars join (, ) => #(self.var(var)))))
+                }
+            },
+            Instr::LowerStruct(in_, vars) => quote! {
+                #(for (field, var) in vars join (#<push>) => let #(self.var(var)) = #(self.var(in_)).#(self.ident(field)))
+            },
+            Instr::LiftStruct(name, vars, out) => quote! {
+                let #(self.var(out)) = #(self.type_ident(name))(#(for (field, var) in vars join (, ) => #(self.ident(field)): #(self.var(var))))
+            },
+            Instr::LowerCallback(in_, ptr, post_cobject, port, cb) => {
+                let func = quote!(ffiPorts.value(#(self.var(&cb.ffi_args[0])), as: (#(self.generate_type(&in_.ty))).self));
+                quote! {
+                    #(self.var(ptr)) = #(self.generate_num_type(ptr.ty.num()))(unsafeBitCast(#(self.generate_callback_fn(cb, func, &[])), to: Int.self))
+                    #(self.var(post_cobject)) = #(self.generate_num_type(post_cobject.ty.num()))(ffiPostCObjectAddress)
+                    #(self.var(port)) = ffiPorts.keepAlive(#(self.var(in_)))
+                }
+            }
+            Instr::LowerTrait(in_, vtable, post_cobject, port, methods) => {
+                let tr = match &in_.ty {
+                    AbiType::Trait(tr) => tr,
+                    _ => unreachable!(),
+                };
+                let ptrs = vtable.iter().zip(methods).zip(&tr.methods).map(|((ptr, (name, cb)), method)| {
+                    let func = quote!(ffiPorts.value(#(self.var(&cb.ffi_args[0])), as: #(self.type_ident(&tr.name)).self).#(self.ident(name)));
+                    let labels: Vec<_> = method.args.iter().map(|(arg, _)| arg.clone()).collect();
+                    quote! {
+                        #(self.var(ptr)) = #(self.generate_num_type(ptr.ty.num()))(unsafeBitCast(#(self.generate_callback_fn(cb, func, &labels)), to: Int.self))
+                    }
+                });
+                quote! {
+                    #(for ptr in ptrs join (#<push>) => #ptr)
+                    #(self.var(post_cobject)) = #(self.generate_num_type(post_cobject.ty.num()))(ffiPostCObjectAddress)
+                    #(self.var(port)) = ffiPorts.keepAlive(#(self.var(in_)))
+                }
+            }
+            Instr::LiftNumFromU32Tuple(..) | Instr::LowerNumFromU32Tuple(..) => unreachable!(),
+        }
+    }
+
+    /// A c function calling `func` with the lifted arguments of a callback.
+    ///
+    /// `func` looks up the closure or interface passed to rust by the port, which is the
+    /// first argument of the callback.
+    fn generate_callback_fn(
+        &self,
+        cb: &Callback,
+        func: swift::Tokens,
+        labels: &[String],
+    ) -> swift::Tokens {
+        let native_args =
+            quote!(#(for var in &cb.ffi_args join (, ) => #(self.generate_num_type(var.ty.num()))));
+        let native_ret = match &cb.ffi_ret {
+            Some(ret) => self.generate_num_type(ret.ty.num()),
+            None => quote!(Void),
+        };
+        let args = cb
+            .args
+            .iter()
+            .enumerate()
+            .map(|(i, var)| match labels.get(i) {
+                Some(label) => quote!(#(self.ident(label)): #(self.var(var))),
+                None => self.var(var),
+            });
+        let call = quote!(#(func)(#(for arg in args join (, ) => #arg)));
+        let call = match &cb.ret {
+            Some(ret) => quote!(let #(self.var(ret)) = #call),
+            None => call,
+        };
+        quote! {
+            { (#(for var in &cb.ffi_args join (, ) => #(self.var(var)))) in
+                #(for inst in &cb.lift join (#<push>) => #(self.generate_instr(inst)))
+                #call
+                #(for inst in &cb.lower join (#<push>) => #(self.generate_instr(inst)))
+                #(if let Some(ret) = &cb.ffi_ret => return #(self.var(ret)))
+            } as @convention(c) (#native_args) -> #native_ret
+        }
+    }
+
+    /// Constructs the enum case `variant` from the variables holding its fields.
+    fn generate_variant(&self, variant: &str, fields: &[(String, Var)]) -> swift::Tokens {
+        if fields.is_empty() {
+            quote!(#(self.ident(variant)))
+        } else {
+            quote!(#(self.ident(variant))(#(for (field, var) in fields join (, ) => #(self.ident(field)): #(self.var(var)))))
+        }
+    }
+
+    /// Reads the ffi values of a variant from its payload and frees it.
+    fn read_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> swift::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                let #(self.var(var)) = ffiLoad(#(self.var(payload)), #(i * 8), as: #(self.generate_num_type(var.ty.num())).self)
+            )
+            ffiDeallocate(#(self.var(payload)), #(ffi.len() * 8), 8)
+        }
+    }
+
+    /// Writes the ffi values of a variant to a new payload, which is freed by
+    /// rust.
+    fn write_payload(&self, payload: &Option<Var>, ffi: &[Var]) -> swift::Tokens {
+        let payload = match payload {
+            Some(payload) if !ffi.is_empty() => payload,
+            _ => return quote!(),
+        };
+        quote! {
+            #(self.var(payload)) = ffiAllocate(#(ffi.len() * 8), 8)
+            #(for (i, var) in ffi.iter().enumerate() join (#<push>) =>
+                ffiStore(#(self.var(var)), #(self.var(payload)), #(i * 8))
+            )
+        }
+    }
+
+    fn var(&self, var: &Var) -> swift::Tokens {
+        quote!(#(format!("tmp{}", var.binding)))
+    }
+
+    /// Callbacks escape, they are kept alive until rust drops them.
+    fn generate_arg_type(&self, ty: &AbiType) -> swift::Tokens {
+        match ty {
+            AbiType::Callback(_, _) => quote!(@escaping #(self.generate_type(ty))),
+            ty => self.generate_type(ty),
+        }
+    }
+
+    fn generate_type(&self, ty: &AbiType) -> swift::Tokens {
+        match ty {
+            AbiType::Num(ty) => self.generate_num_type(*ty),
+            AbiType::Isize => quote!(Int),
+            AbiType::Usize => quote!(UInt),
+            AbiType::Bool => quote!(Bool),
+            AbiType::RefStr | AbiType::String => quote!(String),
+            AbiType::RefSlice(ty) | AbiType::Vec(ty) => {
+                quote!([#(self.generate_num_type(*ty))])
+            }
+            AbiType::RefList(ty) | AbiType::List(ty) => quote!([#(self.generate_type(ty))]),
+            AbiType::Map(key, value) => {
+                quote!([#(self.generate_type(key)): #(self.generate_type(value))])
+            }
+            AbiType::Set(ty) => quote!(Set<#(self.generate_type(ty))>),
+            AbiType::Option(ty) => quote!(#(self.generate_type(ty))?),
+            AbiType::Result(ty, _) => self.generate_type(ty),
+            AbiType::Error(err) => quote!(#(self.type_ident(&err.name))),
+            AbiType::Struct(st) => quote!(#(self.type_ident(&st.name))),
+            AbiType::Enum(en) => quote!(#(self.type_ident(&en.name))),
+            AbiType::Trait(tr) => quote!(#(self.type_ident(&tr.name))),
+            AbiType::Tuple(tuple) => match tuple.len() {
+                0 => quote!(Void),
+                1 => self.generate_type(&tuple[0]),
+                _ => quote!((#(for ty in tuple join (, ) => #(self.generate_type(ty))))),
+            },
+            AbiType::RefObject(ty) | AbiType::Object(ty) => quote!(#(self.type_ident(ty))),
+            AbiType::Callback(args, ret) => {
+                let ret = match ret {
+                    Some(ret) => self.generate_type(ret),
+                    None => quote!(Void),
+                };
+                quote!((#(for arg in args join (, ) => #(self.generate_type(arg)))) -> #ret)
+            }
+            AbiType::RefIter(ty) | AbiType::Iter(ty, _) => quote!(Iter<#(self.generate_type(ty))>),
+            AbiType::RefFuture(ty) | AbiType::Future(ty) => {
+                quote!(FfiFuture<#(self.generate_type(ty))>)
+            }
+            AbiType::RefStream(ty) | AbiType::Stream(ty, _) => {
+                quote!(AsyncThrowingStream<#(self.generate_type(ty)), Error>)
+            }
+        }
+    }
+
+    fn generate_num_type(&self, ty: NumType) -> swift::Tokens {
+        match ty {
+            NumType::I8 => quote!(Int8),
+            NumType::I16 => quote!(Int16),
+            NumType::I32 => quote!(Int32),
+            NumType::I64 => quote!(Int64),
+            NumType::U8 => quote!(UInt8),
+            NumType::U16 => quote!(UInt16),
+            NumType::U32 => quote!(UInt32),
+            NumType::U64 => quote!(UInt64),
+            NumType::F32 => quote!(Float),
+            NumType::F64 => quote!(Double),
+        }
+    }
+
+    fn generate_doc<S: AsRef<str>>(&self, doc: &[S]) -> swift::Tokens {
+        quote!(#(for line in doc => #(static_literal("///")) #(line.as_ref().trim()) #<push>))
+    }
+
+    fn generate_comment(&self, doc: &[String]) -> swift::Tokens {
+        quote!(#(for line in doc => #(static_literal("//")) #(line.trim()) #<push>))
+    }
+
+    fn type_ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_upper_camel_case())
+    }
+
+    fn ident(&self, s: &str) -> String {
+        sanitize_identifier(&s.to_lower_camel_case())
+    }
+
+    /// Function polling the iterator, future or stream of `symbol`.
+    fn poll_ident(&self, symbol: &str) -> String {
+        format!("ffi{}", symbol.to_upper_camel_case())
+    }
+}
+
+fn sanitize_identifier(id: &str) -> String {
+    if RESERVED_IDENTIFIERS.contains(&id) {
+        format!("`{}`", id)
+    } else {
+        id.to_string()
+    }
+}
+
+// https://docs.swift.org/swift-book/documentation/the-swift-programming-language/lexicalstructure/#Keywords-and-Punctuation
+static RESERVED_IDENTIFIERS: [&str; 53] = [
+    "Any",
+    "Self",
+    "as",
+    "associatedtype",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "continue",
+    "default",
+    "defer",
+    "deinit",
+    "do",
+    "else",
+    "enum",
+    "extension",
+    "fallthrough",
+    "false",
+    "fileprivate",
+    "for",
+    "func",
+    "guard",
+    "if",
+    "import",
+    "in",
+    "init",
+    "inout",
+    "internal",
+    "is",
+    "let",
+    "nil",
+    "open",
+    "operator",
+    "precedencegroup",
+    "private",
+    "protocol",
+    "public",
+    "repeat",
+    "rethrows",
+    "return",
+    "self",
+    "static",
+    "struct",
+    "subscript",
+    "super",
+    "switch",
+    "throw",
+    "throws",
+    "true",
+    "try",
+    "typealias",
+    "var",
+];
+
+#[cfg(feature = "test_runner")]
+#[doc(hidden)]
+pub mod test_runner {
+    use super::*;
+    use crate::{Abi, CHeaderGenerator, RustGenerator};
+    use anyhow::Result;
+    use std::process::Command;
+
+    pub fn compile_pass(iface: &str, rust: rust::Tokens, swift: swift::Tokens) -> Result<()> {
+        if crate::test_runner::skip_without("swiftc") {
+            return Ok(());
+        }
+        let iface = Interface::parse(iface)?;
+        let rust_gen = RustGenerator::new(Abi::native());
+        let rust_tokens = rust_gen.generate(iface.clone());
+        let header_tokens = CHeaderGenerator::default().generate(iface.clone());
+        let swift_gen = SwiftGenerator::new("CompilePass".to_string());
+        let swift_tokens = swift_gen.generate(iface);
+
+        let library_tokens = quote! {
+            #rust_tokens
+            #rust
+        };
+
+        let dir = tempfile::tempdir()?;
+        let rust_file = dir.as_ref().join("compile_pass.rs");
+        std::fs::write(&rust_file, library_tokens.to_file_string()?)?;
+        let header_file = dir.as_ref().join("compile_pass.h");
+        std::fs::write(&header_file, header_tokens.to_file_string()?)?;
+        let module_map = dir.as_ref().join("module.modulemap");
+        std::fs::write(
+            &module_map,
+            "module CompilePass {\n    header \"compile_pass.h\"\n    export *\n}\n",
+        )?;
+        let bindings_file = dir.as_ref().join("bindings.swift");
+        std::fs::write(&bindings_file, swift_tokens.to_file_string()?)?;
+        let main_file = dir.as_ref().join("main.swift");
+        std::fs::write(&main_file, swift.to_file_string()?)?;
+
+        let ret = Command::new("rustc")
+            .arg("--edition")
+            .arg("2021")
+            .arg("--crate-name")
+            .arg("compile_pass")
+            .arg("--crate-type")
+            .arg("cdylib")
+            .arg("--cfg")
+            .arg("feature=\"test_runner\"")
+            .arg("--out-dir")
+            .arg(dir.as_ref())
+            .arg(&rust_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile rust library");
+        let bin_file = dir.as_ref().join("main");
+        let ret = Command::new("swiftc")
+            .arg("-o")
+            .arg(&bin_file)
+            .arg("-I")
+            .arg(dir.as_ref())
+            .arg("-L")
+            .arg(dir.as_ref())
+            .arg("-lcompile_pass")
+            .arg(&main_file)
+            .arg(&bindings_file)
+            .status()?;
+        anyhow::ensure!(ret.success(), "failed to compile swift program");
+        let ret = Command::new(&bin_file)
+            .env("LD_LIBRARY_PATH", dir.as_ref())
+            .status()?;
+        anyhow::ensure!(ret.success(), "swift test failed");
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use ffi_gen::test_runner::compile_pass_c;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_c(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn is_even(value: i32) -> bool;
+        "#,
+        quote! {
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+        },
+        quote! {
+            assert(__add(40, 2) == 42);
+            assert(__is_even(2) == 1);
+            assert(__is_even(3) == 0);
+
+            const char *name = "world";
+            __greetReturn ret = __greet((int64_t)name, strlen(name));
+            assert(ret.ret1 == 11);
+            assert(memcmp((const char *)ret.ret0, "hello world", 11) == 0);
+            deallocate((uint8_t *)ret.ret0, ret.ret2, 1);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_c(
+        r#"
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        "#,
+        quote! {
+            pub struct Counter(std::sync::atomic::AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(value.into())
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, std::sync::atomic::Ordering::SeqCst) + by
+                }
+            }
+        },
+        quote! {
+            int64_t counter = __Counter_new(40);
+            assert(__Counter_increment(counter, 2) == 42);
+            drop_box_Counter(0, counter);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures() {
+    compile_pass_c(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn answer() -> Future<u32>;
+        "#,
+        quote! {
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub async fn answer() -> u32 {
+                42
+            }
+        },
+        quote! {
+            int64_t iter = __numbers(3);
+            for (uint32_t i = 0; i < 3; i++) {
+                __numbers_iter_nextReturn next = __numbers_iter_next(iter);
+                assert(next.ret0 == 1);
+                assert(next.ret1 == i);
+            }
+            assert(__numbers_iter_next(iter).ret0 == 0);
+            __numbers_iter_drop(0, iter);
+
+            int64_t fut = __answer();
+            __answer_future_pollReturn poll = __answer_future_poll(fut, 0, 0);
+            assert(poll.ret0 == 1);
+            assert(poll.ret1 == 42);
+            __answer_future_drop(0, fut);
+        },
+    )
+    .unwrap();
+}
@@ -0,0 +1,63 @@
+use ffi_gen::{Abi, FfiGen};
+use std::path::Path;
+use std::process::{Command, Output};
+
+const API: &str = r#"
+fn add(a: u32, b: u32) -> u32;
+object Counter {
+    static fn new() -> Counter;
+    fn increment(by: u32) -> u32;
+}
+"#;
+
+fn ffi_gen(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ffi-gen"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("api.rsh"), API).unwrap();
+    let output = ffi_gen(dir.path(), &["check", "api.rsh"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn check_error() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("api.rsh"), "struct A { a: A }\n").unwrap();
+    let output = ffi_gen(dir.path(), &["check", "api.rsh"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("api.rsh:1:15"), "{}", stderr);
+    assert!(
+        stderr.contains("recursive type `A` has infinite size"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn generate() {
+    let dir = tempfile::tempdir().unwrap();
+    let iface = dir.path().join("api.rsh");
+    std::fs::write(&iface, API).unwrap();
+    let output = ffi_gen(
+        dir.path(),
+        &["generate", "rust", "api.rsh", "--out-dir", "src"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let generated = std::fs::read_to_string(dir.path().join("src/bindings.rs")).unwrap();
+    let expected = FfiGen::new(&iface)
+        .unwrap()
+        .generate_rust("native".parse::<Abi>().unwrap())
+        .unwrap();
+    assert_eq!(generated, expected);
+    assert!(generated.contains("pub extern \"C\" fn __add("));
+    assert!(generated.contains("pub extern \"C\" fn __Counter_increment("));
+}
@@ -0,0 +1,195 @@
+use ffi_gen::test_runner::compile_pass_csharp;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_csharp(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        "#,
+        quote! {
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+        },
+        quote! {
+            Check(Api.Add(40, 2) == 42);
+            Check(Api.Greet("world") == "hello world");
+            Check(Api.Reverse(new List<byte> { 1, 2, 3 }).SequenceEqual(new List<byte> { 3, 2, 1 }));
+            Check(Api.Find(new List<string> { "a", "b" }, "b") == 1);
+            Check(Api.Find(new List<string> { "a", "b" }, "c") == null);
+            Check(Api.Parse("-42") == -42);
+            try
+            {
+                Api.Parse("nan");
+                throw new Exception("expected an exception");
+            }
+            catch (FfiException err)
+            {
+                Check(err.Message == "invalid number nan");
+            }
+            Check(Api.IsEven(2));
+            Check(!Api.IsEven(3));
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_csharp(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            var counter = Counter.New(40);
+            Check(counter.Increment(2) == 42);
+            counter.Dispose();
+            Check(Api.WasDropped());
+
+            using (Counter.New(42)) { }
+            Check(Api.WasDropped());
+
+            var consumed = Counter.New(42);
+            Check(Api.Consume(consumed) == 42);
+            Check(Api.WasDropped());
+            consumed.Dispose();
+            Check(!Api.WasDropped());
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_csharp(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            Check(Api.Numbers(3).SequenceEqual(new List<uint> { 0, 1, 2 }));
+            Check(await Api.Delayed(42) == 42);
+            var values = new List<uint>();
+            await foreach (var value in Api.Values(new List<uint> { 1, 2, 3 }))
+            {
+                values.Add(value);
+            }
+            Check(values.SequenceEqual(new List<uint> { 1, 2, 3 }));
+        },
+    )
+    .unwrap();
+}
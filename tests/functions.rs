@@ -291,6 +291,170 @@ compile_pass! {
     })
 }
 
+compile_pass! {
+    args_list_ret_list,
+    r#"
+    struct Entry {
+        name: string,
+        value: Option<u32>,
+    }
+    fn names(n: u32) -> Vec<string>;
+    fn join(names: &[string]) -> string;
+    fn reverse(entries: Vec<Entry>) -> Vec<Entry>;
+    fn chunks(data: Vec<Vec<u8>>) -> Vec<Vec<u8>>;
+    "#,
+    (
+        pub struct Entry {
+            name: String,
+            value: Option<u32>,
+        }
+
+        pub fn names(n: u32) -> Vec<String> {
+            (0..n).map(|i| i.to_string()).collect()
+        }
+
+        pub fn join(names: &[String]) -> String {
+            names.join("+")
+        }
+
+        pub fn reverse(mut entries: Vec<Entry>) -> Vec<Entry> {
+            entries.reverse();
+            entries
+        }
+
+        pub fn chunks(data: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+            data.into_iter().filter(|chunk| !chunk.is_empty()).collect()
+        }
+    ),
+    (
+        let ret = __names(3);
+        assert_eq!(ret.ret1, 3);
+        let words = unsafe { core::slice::from_raw_parts(ret.ret0 as *const u64, 9) };
+        let name = unsafe { String::from_raw_parts(words[6] as _, words[7] as _, words[8] as _) };
+        assert_eq!(name, "2");
+
+        let a = core::mem::ManuallyDrop::new(String::from("a"));
+        let b = core::mem::ManuallyDrop::new(String::from("b"));
+        let words: Box<[u64]> = Box::new([
+            a.as_ptr() as _,
+            a.len() as _,
+            a.capacity() as _,
+            b.as_ptr() as _,
+            b.len() as _,
+            b.capacity() as _,
+        ]);
+        let ret = __join(Box::into_raw(words) as *mut u64 as _, 2);
+        let joined = unsafe { String::from_raw_parts(ret.ret0 as _, ret.ret1 as _, ret.ret2 as _) };
+        assert_eq!(joined, "a+b");
+    ),
+    (
+        assert(api.names(0).isEmpty);
+        assert(api.names(3).join(",") == "0,1,2");
+        assert(api.join(["a", "b"]) == "a+b");
+        final entries = api.reverse([Entry("a", 1), Entry("b", null)]);
+        assert(entries[0].name == "b");
+        assert(entries[0].value == null);
+        assert(entries[1].name == "a");
+        assert(entries[1].value == 1);
+        final chunks = api.chunks([[1, 2], [], [3]]);
+        assert(chunks.length == 2);
+        assert(chunks[0].equals([1, 2]));
+        assert(chunks[1].equals([3]));
+    ),
+    (
+        assert.deepEqual(api.names(0), []);
+        assert.deepEqual(api.names(3), ["0", "1", "2"]);
+        assert.equal(api.join(["a", "b"]), "a+b");
+        assert.deepEqual(api.reverse([{ name: "a", value: 1 }, { name: "b", value: null }]), [
+            { name: "b", value: null },
+            { name: "a", value: 1 },
+        ]);
+        assert.deepEqual(api.chunks([[1, 2], [], [3]]), [[1, 2], [3]]);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        names(n: number): Array<string>;
+
+        join(names: Array<string>): string;
+
+        reverse(entries: Array<Entry>): Array<Entry>;
+
+        chunks(data: Array<Array<number>>): Array<Array<number>>;
+    }
+
+    export interface Entry {
+        name: string;
+        value: number?;
+    })
+}
+
+compile_pass! {
+    args_map_ret_map,
+    r#"
+    fn config(n: u32) -> Map<string, u32>;
+    fn lookup(config: Map<string, u32>, key: &string) -> Option<u32>;
+    fn tags(tags: Set<u32>) -> Set<u32>;
+    "#,
+    (
+        use std::collections::{HashMap, HashSet};
+
+        pub fn config(n: u32) -> HashMap<String, u32> {
+            (0..n).map(|i| (i.to_string(), i)).collect()
+        }
+
+        pub fn lookup(config: HashMap<String, u32>, key: &str) -> Option<u32> {
+            config.get(key).copied()
+        }
+
+        pub fn tags(tags: HashSet<u32>) -> HashSet<u32> {
+            tags.into_iter().filter(|tag| tag % 2 == 0).collect()
+        }
+    ),
+    (
+        let ret = __config(2);
+        assert_eq!(ret.ret1, 2);
+
+        let words: Box<[u64]> = Box::new([1, 2, 3]);
+        let ret = __tags(Box::into_raw(words) as *mut u64 as _, 3);
+        assert_eq!(ret.ret1, 1);
+        let words = unsafe { core::slice::from_raw_parts(ret.ret0 as *const u64, 1) };
+        assert_eq!(words[0] as u32, 2);
+    ),
+    (
+        assert(api.config(0).isEmpty);
+        final config = api.config(3);
+        assert(config.length == 3);
+        assert(config["2"] == 2);
+        assert(api.lookup(config, "1") == 1);
+        assert(api.lookup(config, "3") == null);
+        assert(api.tags({1, 2, 3, 4}).containsAll([2, 4]));
+    ),
+    (
+        assert.deepEqual(api.config(0), new Map());
+        const config = api.config(3);
+        assert.deepEqual(config, new Map([["0", 0], ["1", 1], ["2", 2]]));
+        assert.equal(api.lookup(config, "1"), 1);
+        assert.equal(api.lookup(config, "3"), null);
+        assert.deepEqual(api.tags(new Set([1, 2, 3, 4])), new Set([2, 4]));
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        config(n: number): Map<string, number>;
+
+        lookup(config: Map<string, number>, key: string): number?;
+
+        tags(tags: Set<number>): Set<number>;
+    })
+}
+
 compile_pass! {
     args_i64_ret_opt_i64,
     "fn non_zero(num: i64) -> Option<i64>;",
@@ -369,6 +533,446 @@ compile_pass! {
     })
 }
 
+compile_pass! {
+    args_i64_ret_res_i64_typed_error,
+    r#"
+    error ZeroError {
+        Zero,
+        Negative { value: i64 },
+    }
+    fn non_zero(num: i64) -> Result<i64, ZeroError>;
+    "#,
+    (
+        pub enum ZeroError {
+            Zero,
+            Negative { value: i64 },
+        }
+
+        pub fn non_zero(num: i64) -> Result<i64, ZeroError> {
+            if num > 0 {
+                Ok(num)
+            } else if num == 0 {
+                Err(ZeroError::Zero)
+            } else {
+                Err(ZeroError::Negative { value: num })
+            }
+        }
+    ),
+    (
+        let ret = __non_zero(42);
+        assert_eq!(ret.ret0, 1);
+        assert_eq!(ret.ret3, 42);
+        let ret = __non_zero(0);
+        assert_eq!(ret.ret0, 0);
+        assert_eq!(ret.ret1, 0);
+        let ret = __non_zero(-1);
+        assert_eq!(ret.ret0, 0);
+        assert_eq!(ret.ret1, 1);
+        let negative = ret.ret2 as *mut i64;
+        assert_eq!(unsafe { negative.read() }, -1);
+        unsafe { deallocate(negative as _, 8, 8) };
+    ),
+    (
+        assert(api.nonZero(42) == 42);
+
+        var err = false;
+        try {
+            api.nonZero(0);
+        } on ZeroErrorZero catch(e) {
+            err = true;
+        }
+        assert(err);
+
+        err = false;
+        try {
+            api.nonZero(-1);
+        } on ZeroErrorNegative catch(e) {
+            err = true;
+            assert(e.value == -1);
+        }
+        assert(err);
+    ),
+    (
+        assert.equal(api.nonZero(42n), 42);
+
+        let err = false;
+        try {
+            api.nonZero(0n);
+        } catch(e) {
+            err = true;
+            assert(e instanceof ZeroError);
+            assert.equal(e.kind, "Zero");
+        }
+        assert.equal(err, true);
+
+        err = false;
+        try {
+            api.nonZero(-1n);
+        } catch(e) {
+            err = true;
+            assert(e instanceof ZeroError);
+            assert.equal(e.kind, "Negative");
+            assert.equal(e.value, -1n);
+        }
+        assert.equal(err, true);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        nonZero(num: BigInt): BigInt;
+    }
+
+    export interface ZeroErrorZero extends Error {
+        kind: "Zero";
+    }
+
+    export interface ZeroErrorNegative extends Error {
+        kind: "Negative";
+        value: BigInt;
+    }
+
+    export type ZeroError = ZeroErrorZero | ZeroErrorNegative;
+
+    export const ZeroError: {
+        new (kind: ZeroError["kind"], fields?: object): ZeroError;
+        prototype: ZeroError;
+    };)
+}
+
+compile_pass! {
+    args_struct_ret_struct,
+    r#"
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    struct Label {
+        text: string,
+        pos: Point,
+    }
+    fn mirror(point: Point) -> Point;
+    fn label(text: &string, x: f64) -> Label;
+    "#,
+    (
+        pub struct Point {
+            x: f64,
+            y: f64,
+        }
+
+        pub struct Label {
+            text: String,
+            pos: Point,
+        }
+
+        pub fn mirror(point: Point) -> Point {
+            Point { x: point.y, y: point.x }
+        }
+
+        pub fn label(text: &str, x: f64) -> Label {
+            Label {
+                text: text.to_string(),
+                pos: Point { x, y: 0.0 },
+            }
+        }
+    ),
+    (
+        let ret = __mirror(1.0, 2.0);
+        assert_eq!(ret.ret0, 2.0);
+        assert_eq!(ret.ret1, 1.0);
+    ),
+    (
+        final p = api.mirror(Point(1.0, 2.0));
+        assert(p.x == 2.0);
+        assert(p.y == 1.0);
+        final l = api.label("hello", 3.0);
+        assert(l.text == "hello");
+        assert(l.pos.x == 3.0);
+        assert(l == api.label("hello", 3.0));
+        assert(l.hashCode == api.label("hello", 3.0).hashCode);
+        assert(l != api.label("hello", 4.0));
+    ),
+    (
+        const p = api.mirror({ x: 1.0, y: 2.0 });
+        assert.equal(p.x, 2.0);
+        assert.equal(p.y, 1.0);
+        const l = api.label("hello", 3.0);
+        assert.equal(l.text, "hello");
+        assert.equal(l.pos.x, 3.0);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        mirror(point: Point): Point;
+
+        label(text: string, x: number): Label;
+    }
+
+    export interface Point {
+        x: number;
+        y: number;
+    }
+
+    export interface Label {
+        text: string;
+        pos: Point;
+    })
+}
+
+compile_pass! {
+    args_enum_ret_enum,
+    r#"
+    enum Color {
+        Red,
+        Green,
+        Blue,
+    }
+    enum Shape {
+        Empty,
+        Circle { radius: f64 },
+        Rect { w: f64, h: f64 },
+    }
+    fn next_color(color: Color) -> Color;
+    fn area(shape: Shape) -> f64;
+    fn square(side: f64) -> Shape;
+    "#,
+    (
+        pub enum Color {
+            Red,
+            Green,
+            Blue,
+        }
+
+        pub enum Shape {
+            Empty,
+            Circle { radius: f64 },
+            Rect { w: f64, h: f64 },
+        }
+
+        pub fn next_color(color: Color) -> Color {
+            match color {
+                Color::Red => Color::Green,
+                Color::Green => Color::Blue,
+                Color::Blue => Color::Red,
+            }
+        }
+
+        pub fn area(shape: Shape) -> f64 {
+            match shape {
+                Shape::Empty => 0.0,
+                Shape::Circle { radius } => radius * radius * 3.0,
+                Shape::Rect { w, h } => w * h,
+            }
+        }
+
+        pub fn square(side: f64) -> Shape {
+            if side > 0.0 {
+                Shape::Rect { w: side, h: side }
+            } else {
+                Shape::Empty
+            }
+        }
+    ),
+    (
+        assert_eq!(__next_color(0), 1);
+        assert_eq!(__next_color(2), 0);
+        assert_eq!(__area(0, 0), 0.0);
+        let circle = unsafe { allocate(8, 8) } as *mut f64;
+        unsafe { circle.write(2.0) };
+        assert_eq!(__area(1, circle as _), 12.0);
+        let rect = unsafe { allocate(16, 8) } as *mut f64;
+        unsafe {
+            rect.write(2.0);
+            rect.add(1).write(3.0);
+        }
+        assert_eq!(__area(2, rect as _), 6.0);
+        let ret = __square(2.0);
+        assert_eq!(ret.ret0, 2);
+        let rect = ret.ret1 as *mut f64;
+        assert_eq!(unsafe { rect.read() }, 2.0);
+        assert_eq!(unsafe { rect.add(1).read() }, 2.0);
+        unsafe { deallocate(rect as _, 16, 8) };
+        let ret = __square(0.0);
+        assert_eq!(ret.ret0, 0);
+    ),
+    (
+        assert(api.nextColor(Color.red) == Color.green);
+        assert(api.nextColor(Color.blue) == Color.red);
+        assert(api.area(ShapeEmpty()) == 0.0);
+        assert(api.area(ShapeCircle(2.0)) == 12.0);
+        assert(api.area(ShapeRect(2.0, 3.0)) == 6.0);
+        final shape = api.square(2.0);
+        assert(shape is ShapeRect);
+        assert((shape as ShapeRect).w == 2.0);
+        assert(api.square(0.0) is ShapeEmpty);
+    ),
+    (
+        assert.equal(api.nextColor("Red"), "Green");
+        assert.equal(api.nextColor("Blue"), "Red");
+        assert.equal(api.area({ kind: "Empty" }), 0.0);
+        assert.equal(api.area({ kind: "Circle", radius: 2.0 }), 12.0);
+        assert.equal(api.area({ kind: "Rect", w: 2.0, h: 3.0 }), 6.0);
+        const shape = api.square(2.0);
+        assert.equal(shape.kind, "Rect");
+        assert.equal(shape.w, 2.0);
+        assert.equal(api.square(0.0).kind, "Empty");
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        nextColor(color: Color): Color;
+
+        area(shape: Shape): number;
+
+        square(side: number): Shape;
+    }
+
+    export type Color = "Red" | "Green" | "Blue";
+
+    export type Shape = { kind: "Empty"; } | { kind: "Circle"; radius: number; } | { kind: "Rect"; w: number; h: number; };)
+}
+
+compile_pass! {
+    args_callback_ret_u32,
+    "fn count(n: u32, cb: Fn(u32, string) -> bool) -> u32;",
+    (
+        pub fn count(n: u32, cb: Box<dyn Fn(u32, String) -> bool + Send + Sync>) -> u32 {
+            (0..n).filter(|i| cb(*i, i.to_string())).count() as u32
+        }
+    ),
+    (
+        extern "C" fn cb(port: i64, value: u32, ptr: i64, len: u64, cap: u64) -> u8 {
+            assert_eq!(port, 7);
+            let s = unsafe { String::from_raw_parts(ptr as _, len as _, cap as _) };
+            assert_eq!(s, value.to_string());
+            (value % 2 == 0) as u8
+        }
+
+        extern "C" fn post_cobject(port: i64, _obj: &i32) {
+            assert_eq!(port, 7);
+        }
+
+        let ret = __count(
+            4,
+            cb as *const core::ffi::c_void as _,
+            post_cobject as *const core::ffi::c_void as _,
+            7,
+        );
+        assert_eq!(ret, 2);
+    ),
+    (
+        assert(api.count(4, (i, s) => i.toString() == s && i % 2 == 0) == 2);
+    ),
+    (
+        assert.equal(api.count(4, (i, s) => i.toString() === s && i % 2 === 0), 2);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        count(n: number, cb: (arg0: number, arg1: string) => boolean): number;
+    })
+}
+
+compile_pass! {
+    args_interface_ret_u32,
+    r#"
+    interface Logger {
+        fn enabled(level: u8) -> bool;
+        fn log(level: u8, msg: &string);
+    }
+    fn run(logger: Logger, n: u32) -> u32;
+    "#,
+    (
+        pub fn run(logger: Box<dyn api::Logger>, n: u32) -> u32 {
+            let mut count = 0;
+            for i in 0..n {
+                if logger.enabled(i as u8) {
+                    logger.log(i as u8, &i.to_string());
+                    count += 1;
+                }
+            }
+            count
+        }
+    ),
+    (
+        extern "C" fn enabled(port: i64, level: u8) -> u8 {
+            assert_eq!(port, 7);
+            (level % 2 == 0) as u8
+        }
+
+        extern "C" fn log(port: i64, level: u8, ptr: i64, len: u64) {
+            assert_eq!(port, 7);
+            let msg = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+            assert_eq!(msg, level.to_string().as_bytes());
+        }
+
+        extern "C" fn post_cobject(port: i64, _obj: &i32) {
+            assert_eq!(port, 7);
+        }
+
+        let ret = __run(
+            enabled as *const core::ffi::c_void as _,
+            log as *const core::ffi::c_void as _,
+            post_cobject as *const core::ffi::c_void as _,
+            7,
+            4,
+        );
+        assert_eq!(ret, 2);
+    ),
+    ({
+        class ListLogger implements Logger {
+            final List<int> logged = [];
+
+            @override
+            bool enabled(int level) => level % 2 == 0;
+
+            @override
+            void log(int level, String msg) {
+                assert(msg == level.toString());
+                logged.add(level);
+            }
+        }
+    }
+        final logger = ListLogger();
+        assert(api.run(logger, 4) == 2);
+        assert(logger.logged.equals([0, 2]));
+    ),
+    (
+        const logged = [];
+        const logger = {
+            enabled: (level) => level % 2 === 0,
+            log: (level, msg) => logged.push([level, msg]),
+        };
+        assert.equal(api.run(logger, 4), 2);
+        assert.deepEqual(logged, [[0, "0"], [2, "2"]]);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        run(logger: Logger, n: number): number;
+    }
+
+    export interface Logger {
+        enabled(level: number): boolean;
+
+        log(level: number, msg: string): void;
+    })
+}
+
 compile_pass! {
     num_min_max,
     "
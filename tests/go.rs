@@ -0,0 +1,237 @@
+use ffi_gen::test_runner::compile_pass_go;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_go(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        "#,
+        quote! {
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+        },
+        quote! {
+            if Add(40, 2) != 42 {
+                panic("add")
+            }
+            if Greet("world") != "hello world" {
+                panic("greet")
+            }
+            if reversed := Reverse([]uint8{1, 2, 3}); len(reversed) != 3 || reversed[0] != 3 || reversed[2] != 1 {
+                panic("reverse")
+            }
+            if found := Find([]string{"a", "b"}, "b"); found == nil || *found != 1 {
+                panic("find")
+            }
+            if Find([]string{"a", "b"}, "c") != nil {
+                panic("find")
+            }
+            if value, err := Parse("-42"); err != nil || value != -42 {
+                panic("parse")
+            }
+            if _, err := Parse("nan"); err == nil || err.Error() != "invalid number nan" {
+                panic("parse error")
+            }
+            if !IsEven(2) || IsEven(3) {
+                panic("is even")
+            }
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_go(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            counter := NewCounter(40)
+            if counter.Increment(2) != 42 {
+                panic("increment")
+            }
+            counter.Close()
+            if !WasDropped() {
+                panic("close")
+            }
+
+            counter = NewCounter(42)
+            if Consume(counter) != 42 || !WasDropped() {
+                panic("consume")
+            }
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn callbacks() {
+    compile_pass_go(
+        r#"
+        fn apply(value: u32, f: Fn(u32, &string) -> u32) -> u32;
+        "#,
+        quote! {
+            pub fn apply(value: u32, f: impl Fn(u32, &str) -> u32) -> u32 {
+                f(value, "twice")
+            }
+        },
+        quote! {
+            applied := Apply(21, func(value uint32, op string) uint32 {
+                if op != "twice" {
+                    panic("op")
+                }
+                return value * 2
+            })
+            if applied != 42 {
+                panic("apply")
+            }
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_go(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            var numbers []uint32
+            for number := range Numbers(3).All() {
+                numbers = append(numbers, number)
+            }
+            if len(numbers) != 3 || numbers[0] != 0 || numbers[2] != 2 {
+                panic("numbers")
+            }
+            if <-Delayed(42) != 42 {
+                panic("delayed")
+            }
+            var values []uint32
+            for value := range Values([]uint32{1, 2, 3}) {
+                values = append(values, value)
+            }
+            if len(values) != 3 || values[0] != 1 || values[2] != 3 {
+                panic("values")
+            }
+        },
+    )
+    .unwrap();
+}
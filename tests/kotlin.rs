@@ -0,0 +1,186 @@
+use ffi_gen::test_runner::compile_pass_kotlin;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_kotlin(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        "#,
+        quote! {
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+        },
+        quote! {
+            check(api.add(40u, 2u) == 42u)
+            check(api.greet("world") == "hello world")
+            check(api.reverse(listOf(1u, 2u, 3u)) == listOf<UByte>(3u, 2u, 1u))
+            check(api.find(listOf("a", "b"), "b") == 1uL)
+            check(api.find(listOf("a", "b"), "c") == null)
+            check(api.parse("-42") == -42L)
+            try {
+                api.parse("nan")
+                error("expected an exception")
+            } catch (err: FfiException) {
+                check(err.message == "invalid number nan")
+            }
+            check(api.isEven(2))
+            check(!api.isEven(3))
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_kotlin(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            val counter = Counter.new(api, 40u)
+            check(counter.increment(2u) == 42u)
+            counter.close()
+            check(api.wasDropped())
+
+            Counter.new(api, 42u).use {}
+            check(api.wasDropped())
+
+            val consumed = Counter.new(api, 42u)
+            check(api.consume(consumed) == 42u)
+            check(api.wasDropped())
+            check(!api.wasDropped())
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_kotlin(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            check(api.numbers(3u).asSequence().toList() == listOf(0u, 1u, 2u))
+            check(api.delayed(42uL) == 42uL)
+            check(api.values(listOf(1u, 2u, 3u)).toList() == listOf(1u, 2u, 3u))
+        },
+    )
+    .unwrap();
+}
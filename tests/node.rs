@@ -0,0 +1,470 @@
+use ffi_gen::test_runner::compile_pass_node;
+use ffi_gen::FfiGen;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_node(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        fn count(values: Map<string, u32>) -> Set<u32>;
+        enum Shape {
+            Empty,
+            Rect { w: f64, name: string },
+        }
+        fn rename(shape: Shape, name: &string) -> Shape;
+        "#,
+        quote! {
+            use std::collections::{HashMap, HashSet};
+
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+
+            pub fn count(values: HashMap<String, u32>) -> HashSet<u32> {
+                values.into_values().collect()
+            }
+
+            pub enum Shape {
+                Empty,
+                Rect { w: f64, name: String },
+            }
+
+            pub fn rename(shape: Shape, name: &str) -> Shape {
+                match shape {
+                    Shape::Empty => Shape::Empty,
+                    Shape::Rect { w, .. } => Shape::Rect { w, name: name.to_string() },
+                }
+            }
+        },
+        quote! {
+            assert.equal(api.add(40, 2), 42);
+            assert.equal(api.greet("world"), "hello world");
+            assert.deepEqual(api.reverse([1, 2, 3]), [3, 2, 1]);
+            assert.equal(api.find(["a", "b"], "b"), 1n);
+            assert.equal(api.find(["a", "b"], "c"), null);
+            assert.equal(api.parse("-42"), -42n);
+            assert.throws(() => api.parse("nan"), (err) => err === "invalid number nan");
+            assert.equal(api.isEven(2), true);
+            assert.equal(api.isEven(3), false);
+            assert.deepEqual(api.count(new Map([["a", 1], ["b", 2], ["c", 1]])), new Set([1, 2]));
+            assert.deepEqual(api.rename({ kind: "Empty" }, "b"), { kind: "Empty" });
+            assert.deepEqual(
+                api.rename({ kind: "Rect", w: 2.5, name: "a" }, "b"),
+                { kind: "Rect", w: 2.5, name: "b" },
+            );
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_node(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            const counter = Counter._new(api, 40);
+            assert.equal(counter.increment(2), 42);
+            counter.drop();
+            assert.equal(api.wasDropped(), true);
+
+            const consumed = Counter._new(api, 42);
+            assert.equal(api.consume(consumed), 42);
+            assert.equal(api.wasDropped(), true);
+            assert.throws(() => consumed.drop());
+            assert.equal(api.wasDropped(), false);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn shared_objects() {
+    compile_pass_node(
+        r#"
+        fn was_dropped() -> bool;
+        shared object Client {
+            static fn new(prefix: &string) -> Client;
+            fn fetch(path: &string, ids: &[u8]) -> Future<string>;
+        }
+        "#,
+        quote! {
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::sync::atomic::{AtomicBool, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            // Returns pending once, so the borrows are held across an await point.
+            struct YieldNow(bool);
+
+            impl Future for YieldNow {
+                type Output = ();
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0 {
+                        return Poll::Ready(());
+                    }
+                    self.0 = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+
+            pub struct Client {
+                prefix: String,
+            }
+
+            impl Client {
+                pub fn new(prefix: &str) -> Self {
+                    Self {
+                        prefix: prefix.to_string(),
+                    }
+                }
+
+                pub async fn fetch(&self, path: &str, ids: &[u8]) -> String {
+                    YieldNow(false).await;
+                    format!("{}{}{:?}", self.prefix, path, ids)
+                }
+            }
+
+            impl Drop for Client {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+        },
+        quote! {
+            const client = Client._new(api, "a");
+            const fut = client.fetch("/b", [1, 2]);
+            client.drop();
+            assert.equal(api.wasDropped(), false);
+            assert.equal(await fut, "a/b[1, 2]");
+            assert.equal(api.wasDropped(), true);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_node(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            assert.deepEqual(Array.from(api.numbers(3)), [0, 1, 2]);
+            assert.equal(await api.delayed(42n), 42n);
+            const values = [];
+            for await (const value of api.values([1, 2, 3])) {
+                values.push(value);
+            }
+            assert.deepEqual(values, [1, 2, 3]);
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn cancelled_future() {
+    compile_pass_node(
+        r#"
+        fn pending() -> Future<u64>;
+        fn was_dropped() -> bool;
+        "#,
+        quote! {
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::sync::atomic::{AtomicBool, Ordering};
+            use std::time::Duration;
+
+            static DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Pending;
+
+            impl Future for Pending {
+                type Output = u64;
+
+                fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(Duration::from_millis(20));
+                        waker.wake();
+                    });
+                    Poll::Pending
+                }
+            }
+
+            impl Drop for Pending {
+                fn drop(&mut self) {
+                    DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn pending() -> Pending {
+                Pending
+            }
+        },
+        quote! {
+            const controller = new AbortController();
+            const fut = api.pending(controller.signal);
+            controller.abort();
+            await assert.rejects(fut, (err) => err.name === "AbortError");
+            assert(api.wasDropped());
+            // the waker outlives the future and wakes it after it was dropped
+            await new Promise((resolve) => setTimeout(resolve, 50));
+
+            await assert.rejects(api.pending(AbortSignal.abort()), (err) => err.name === "AbortError");
+            assert(api.wasDropped());
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn stream_backpressure() {
+    compile_pass_node(
+        r#"
+        fn counter() -> Stream<u32>;
+        fn polled() -> u32;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::sync::atomic::{AtomicU32, Ordering};
+
+            static POLLED: AtomicU32 = AtomicU32::new(0);
+
+            pub fn polled() -> u32 {
+                POLLED.load(Ordering::SeqCst)
+            }
+
+            struct Counter(u32);
+
+            impl Stream for Counter {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    POLLED.fetch_add(1, Ordering::SeqCst);
+                    self.0 += 1;
+                    Poll::Ready(Some(self.0))
+                }
+            }
+
+            pub fn counter() -> impl Stream<Item = u32> {
+                Counter(0)
+            }
+        },
+        quote! {
+            const reader = api.counter().getReader();
+            assert.equal((await reader.read()).value, 1);
+            assert.equal((await reader.read()).value, 2);
+            await new Promise((resolve) => setTimeout(resolve, 50));
+            // polled for the items read and the one queued by the high water mark
+            assert(api.polled() <= 3);
+            await reader.cancel();
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn batched_iterators_streams() {
+    compile_pass_node(
+        r#"
+        fn names(n: u32) -> Iterator<string, 4>;
+        fn chunks(n: u32) -> Stream<string, 4>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+
+            pub fn names(n: u32) -> impl Iterator<Item = String> {
+                (0..n).map(|i| format!("name{}", i))
+            }
+
+            // Returns pending after every third item, so some batches are not full.
+            struct Chunks {
+                next: u32,
+                n: u32,
+                pending: bool,
+            }
+
+            impl Stream for Chunks {
+                type Item = String;
+
+                fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    if self.pending {
+                        self.pending = false;
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    if self.next == self.n {
+                        return Poll::Ready(None);
+                    }
+                    self.next += 1;
+                    self.pending = self.next % 3 == 0;
+                    Poll::Ready(Some(self.next.to_string()))
+                }
+            }
+
+            pub fn chunks(n: u32) -> impl Stream<Item = String> {
+                Chunks { next: 0, n, pending: false }
+            }
+        },
+        quote! {
+            assert.deepEqual(Array.from(api.names(0)), []);
+            assert.deepEqual(Array.from(api.names(9)), [...Array(9).keys()].map((i) => "name" + i));
+            const values = [];
+            for await (const value of api.chunks(10)) {
+                values.push(value);
+            }
+            assert.deepEqual(values, [...Array(10).keys()].map((i) => String(i + 1)));
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn callbacks_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("api.rsh");
+    std::fs::write(&path, "fn apply(value: u32, f: Fn(u32) -> u32) -> u32;").unwrap();
+    let ffigen = FfiGen::new(&path).unwrap().format(false);
+    let err = ffigen.generate_napi().unwrap_err().to_string();
+    assert!(err.contains("argument `f` of `apply`"), "{}", err);
+    assert!(ffigen
+        .generate_node_js(dir.path().join("bindings.mjs"))
+        .is_err());
+    assert!(ffigen
+        .generate_node_ts(dir.path().join("bindings.d.ts"))
+        .is_err());
+}
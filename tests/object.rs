@@ -181,7 +181,7 @@ compile_pass! {
 
         fetch(url, imports): Promise<void>;
 
-        create(value: number): Promise<number>;
+        create(value: number, signal?: AbortSignal): Promise<number>;
     })
 }
 
@@ -255,12 +255,110 @@ compile_pass! {
 
         fetch(url, imports): Promise<void>;
 
-        create(): Promise<BigInt>;
+        create(signal?: AbortSignal): Promise<BigInt>;
 
         wake(): void;
     })
 }
 
+compile_pass! {
+    cancelled_future,
+    "fn create() -> Future<u64>; fn wake(); fn was_dropped() -> bool;",
+    (
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Poll, Waker};
+
+        static mut WAKER: Option<Waker> = None;
+        static mut DROPPED: bool = false;
+
+        pub struct Pending;
+
+        impl Future for Pending {
+            type Output = u64;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                unsafe { WAKER = Some(cx.waker().clone()) };
+                Poll::Pending
+            }
+        }
+
+        impl Drop for Pending {
+            fn drop(&mut self) {
+                unsafe { DROPPED = true };
+            }
+        }
+
+        pub fn create() -> Pending {
+            Pending
+        }
+
+        pub fn wake() {
+            unsafe {
+                if let Some(waker) = WAKER.take() {
+                    waker.wake();
+                }
+            }
+        }
+
+        pub fn was_dropped() -> bool {
+            unsafe { core::mem::replace(&mut DROPPED, false) }
+        }
+    ),
+    (
+        let fut = __create();
+        let _poll = __create_future_poll;
+        __create_future_drop(0, fut);
+        assert!(__was_dropped() != 0);
+    ),
+    (
+        final token = CancellationToken();
+        final fut = api.create(token);
+        token.cancel();
+        try {
+            await fut;
+            throw StateError("expected a CancelledException");
+        } on CancelledException {
+            // cancelled
+        }
+        assert(api.wasDropped());
+        api.wake();
+
+        final cancelled = CancellationToken();
+        cancelled.cancel();
+        try {
+            await api.create(cancelled);
+            throw StateError("expected a CancelledException");
+        } on CancelledException {
+            // cancelled
+        }
+        assert(api.wasDropped());
+    ),
+    (
+        const controller = new AbortController();
+        const fut = api.create(controller.signal);
+        controller.abort();
+        await assert.rejects(fut, (err) => err.name === "AbortError");
+        assert(api.wasDropped());
+        api.wake();
+
+        await assert.rejects(api.create(AbortSignal.abort()), (err) => err.name === "AbortError");
+        assert(api.wasDropped());
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        create(signal?: AbortSignal): Promise<BigInt>;
+
+        wake(): void;
+
+        wasDropped(): boolean;
+    })
+}
+
 compile_pass! {
     nodelay_stream,
     "fn create(values: &[u32]) -> Stream<u32>;",
@@ -335,6 +433,87 @@ compile_pass! {
     })
 }
 
+compile_pass! {
+    batched_iterator_stream,
+    "fn numbers(n: u32) -> Iterator<u32, 2>;\nfn create(values: &[u32]) -> Stream<u32, 2>;",
+    (
+        use crate::api::Stream;
+        use core::pin::Pin;
+        use core::task::{Context, Poll};
+
+        pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+            0..n
+        }
+
+        struct TestStream(Vec<u32>);
+
+        impl Stream for TestStream {
+            type Item = u32;
+
+            fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                Poll::Ready(self.0.pop())
+            }
+        }
+
+        pub fn create(values: &[u32]) -> impl Stream<Item = u32> {
+            TestStream(values.into_iter().rev().copied().collect())
+        }
+    ),
+    (
+        let iter = __numbers(3);
+        let next = __numbers_iter_next(iter);
+        assert_eq!(next.ret0, 1);
+        assert_eq!(next.ret2, 2);
+        let next = __numbers_iter_next(iter);
+        assert_eq!(next.ret0, 1);
+        assert_eq!(next.ret2, 1);
+        let next = __numbers_iter_next(iter);
+        assert_eq!(next.ret0, 0);
+        __numbers_iter_drop(0, iter);
+
+        let values = [42, 99, 7];
+        let stream = __create(values.as_ptr() as _, values.len() as _);
+
+        extern "C" fn callback(port: i64, _obj: &i32) {
+            assert_eq!(port, 1);
+        }
+
+        let poll = __create_stream_poll(stream, callback as *const core::ffi::c_void as _, 0, 1);
+        assert_eq!(poll.ret0, 1);
+        assert_eq!(poll.ret2, 2);
+        let poll = __create_stream_poll(stream, callback as *const core::ffi::c_void as _, 0, 1);
+        assert_eq!(poll.ret0, 1);
+        assert_eq!(poll.ret2, 1);
+        let poll = __create_stream_poll(stream, callback as *const core::ffi::c_void as _, 0, 1);
+        assert_eq!(poll.ret0, 0);
+
+        __create_stream_drop(0, stream);
+    ),
+    (
+        assert(api.numbers(5).toList().toString() == [0, 1, 2, 3, 4].toString());
+        final values = await api.create([42, 99, 7]).toList();
+        assert(values.toString() == [42, 99, 7].toString());
+    ),
+    (
+        assert.deepEqual(Array.from(api.numbers(5)), [0, 1, 2, 3, 4]);
+        const values = [];
+        for await (const value of api.create([42, 99, 7])) {
+            values.push(value);
+        }
+        assert.deepEqual(values, [42, 99, 7]);
+    ),
+    (
+    export class Api {
+        constructor();
+
+        fetch(url, imports): Promise<void>;
+
+        numbers(n: number): Iterable<number>;
+
+        create(values: Array<number>): ReadableStream<number>;
+    })
+}
+
 compile_pass! {
     result_future,
     "fn create(value: u32) -> Future<Result<u32>>;",
@@ -386,7 +565,7 @@ compile_pass! {
 
         fetch(url, imports): Promise<void>;
 
-        create(value: number): Promise<number>;
+        create(value: number, signal?: AbortSignal): Promise<number>;
     })
 }
 
@@ -425,6 +604,6 @@ compile_pass! {
 
         fetch(url, imports): Promise<void>;
 
-        futureIterator(): Promise<Iterable<string>>;
+        futureIterator(signal?: AbortSignal): Promise<Iterable<string>>;
     })
 }
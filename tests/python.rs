@@ -0,0 +1,621 @@
+use ffi_gen::test_runner::{compile_pass_python, compile_pass_python_executor};
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_python(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        fn count(values: Map<string, u32>) -> Set<u32>;
+        fn swap(pairs: Vec<(u8, string)>) -> Vec<(string, u8)>;
+        "#,
+        quote! {
+            use std::collections::{HashMap, HashSet};
+
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+
+            pub fn count(values: HashMap<String, u32>) -> HashSet<u32> {
+                values.into_values().collect()
+            }
+
+            pub fn swap(pairs: Vec<(u8, String)>) -> Vec<(String, u8)> {
+                pairs.into_iter().map(|(n, s)| (s, n)).collect()
+            }
+        },
+        quote! {
+            assert api.add(40, 2) == 42
+            assert api.greet("world") == "hello world"
+            assert api.reverse([1, 2, 3]) == [3, 2, 1]
+            assert api.find(["a", "b"], "b") == 1
+            assert api.find(["a", "b"], "c") is None
+            assert api.parse("-42") == -42
+            try:
+                api.parse("nan")
+                assert False
+            except Exception as err:
+                assert str(err) == "invalid number nan"
+            assert api.is_even(2)
+            assert not api.is_even(3)
+            assert api.count({"a": 1, "b": 2, "c": 1}) == {1, 2}
+            assert api.swap([(1, "a"), (2, "b")]) == [("a", 1), ("b", 2)]
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn types() {
+    compile_pass_python(
+        r#"
+        struct Point {
+            x: f64,
+            y: f64,
+        }
+        enum Color { Red, Green }
+        enum Shape {
+            Circle { center: Point, radius: f64 },
+            Empty,
+        }
+        error ShapeError {
+            Negative { radius: f64 },
+        }
+        fn mirror(point: Point) -> Point;
+        fn invert(color: Color) -> Color;
+        fn grow(shape: Shape) -> Result<Shape, ShapeError>;
+        "#,
+        quote! {
+            pub struct Point {
+                pub x: f64,
+                pub y: f64,
+            }
+
+            pub enum Color {
+                Red,
+                Green,
+            }
+
+            pub enum Shape {
+                Circle { center: Point, radius: f64 },
+                Empty,
+            }
+
+            #[derive(Debug)]
+            pub enum ShapeError {
+                Negative { radius: f64 },
+            }
+
+            pub fn mirror(point: Point) -> Point {
+                Point { x: point.y, y: point.x }
+            }
+
+            pub fn invert(color: Color) -> Color {
+                match color {
+                    Color::Red => Color::Green,
+                    Color::Green => Color::Red,
+                }
+            }
+
+            pub fn grow(shape: Shape) -> Result<Shape, ShapeError> {
+                match shape {
+                    Shape::Circle { radius, .. } if radius < 0.0 => {
+                        Err(ShapeError::Negative { radius })
+                    }
+                    Shape::Circle { center, radius } => Ok(Shape::Circle {
+                        center,
+                        radius: radius * 2.0,
+                    }),
+                    Shape::Empty => Ok(Shape::Empty),
+                }
+            }
+        },
+        quote! {
+            assert api.mirror(Point(1.0, 2.0)) == Point(2.0, 1.0)
+            assert api.invert(Color.RED) == Color.GREEN
+            assert api.grow(ShapeCircle(Point(0.0, 0.0), 1.0)) == ShapeCircle(Point(0.0, 0.0), 2.0)
+            assert api.grow(ShapeEmpty()) == ShapeEmpty()
+            try:
+                api.grow(ShapeCircle(Point(0.0, 0.0), -1.0))
+                assert False
+            except ShapeError as err:
+                assert err == ShapeErrorNegative(-1.0)
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_python(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            counter = Counter.new(api, 40)
+            assert counter.increment(2) == 42
+            counter.drop()
+            assert api.was_dropped()
+            try:
+                counter.increment(1)
+                assert False
+            except RuntimeError:
+                pass
+
+            counter = Counter.new(api, 42)
+            del counter
+            assert api.was_dropped()
+
+            counter = Counter.new(api, 42)
+            assert api.consume(counter) == 42
+            assert api.was_dropped()
+            del counter
+            assert not api.was_dropped()
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn shared_objects() {
+    compile_pass_python(
+        r#"
+        fn was_dropped() -> bool;
+        shared object Client {
+            static fn new(prefix: &string) -> Client;
+            fn fetch(path: &string, ids: &[u8], names: &[string]) -> Future<string>;
+            fn join(other: &Client) -> Future<string>;
+            fn lines(count: u32) -> Stream<string>;
+        }
+        fn consume(client: Client) -> string;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::sync::atomic::{AtomicBool, Ordering};
+            use std::sync::Arc;
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            // Returns pending once, so the borrows are held across an await point.
+            struct YieldNow(bool);
+
+            impl Future for YieldNow {
+                type Output = ();
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0 {
+                        return Poll::Ready(());
+                    }
+                    self.0 = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+
+            pub struct Client {
+                prefix: String,
+            }
+
+            impl Client {
+                pub fn new(prefix: &str) -> Self {
+                    Self {
+                        prefix: prefix.to_string(),
+                    }
+                }
+
+                pub async fn fetch(&self, path: &str, ids: &[u8], names: &[String]) -> String {
+                    YieldNow(false).await;
+                    format!("{}{}{:?}{:?}", self.prefix, path, ids, names)
+                }
+
+                pub async fn join(&self, other: &Client) -> String {
+                    YieldNow(false).await;
+                    format!("{}{}", self.prefix, other.prefix)
+                }
+
+                // Streams don't hold the `Arc`, so they own what they need.
+                pub fn lines(&self, count: u32) -> impl Stream<Item = String> {
+                    Lines(self.prefix.clone(), 0, count)
+                }
+            }
+
+            struct Lines(String, u32, u32);
+
+            impl Stream for Lines {
+                type Item = String;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    if self.1 == self.2 {
+                        return Poll::Ready(None);
+                    }
+                    self.1 += 1;
+                    Poll::Ready(Some(format!("{}{}", self.0, self.1)))
+                }
+            }
+
+            impl Drop for Client {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(client: Arc<Client>) -> String {
+                client.prefix.clone()
+            }
+        },
+        quote! {
+            client = Client.new(api, "a")
+            fut = client.fetch("/b", [1, 2], ["c"])
+            client.drop()
+            assert not api.was_dropped()
+            assert await fut == "a/b[1, 2][\"c\"]"
+            assert api.was_dropped()
+
+            client = Client.new(api, "a")
+            other = Client.new(api, "b")
+            fut = client.join(other)
+            lines = other.lines(2)
+            other.drop()
+            assert await fut == "ab"
+            assert api.was_dropped()
+            assert [line async for line in lines] == ["b1", "b2"]
+            assert api.consume(client) == "a"
+            assert api.was_dropped()
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn callbacks() {
+    compile_pass_python(
+        r#"
+        fn apply(value: u32, f: Fn(u32) -> u32) -> u32;
+        interface Logger {
+            fn log(level: u8, message: string);
+        }
+        fn run(logger: Logger);
+        fn run_in_thread(logger: Logger);
+        fn store(f: Fn(u32) -> u32);
+        fn fire(value: u32) -> u32;
+        "#,
+        quote! {
+            use std::sync::Mutex;
+
+            static STORED: Mutex<Option<Box<dyn Fn(u32) -> u32 + Send + Sync>>> = Mutex::new(None);
+
+            pub fn apply(value: u32, f: impl Fn(u32) -> u32) -> u32 {
+                f(value)
+            }
+
+            pub fn store(f: Box<dyn Fn(u32) -> u32 + Send + Sync>) {
+                *STORED.lock().unwrap() = Some(f);
+            }
+
+            // Calls and drops the callback on another thread.
+            pub fn fire(value: u32) -> u32 {
+                let f = STORED.lock().unwrap().take().unwrap();
+                std::thread::spawn(move || f(value)).join().unwrap()
+            }
+
+            pub fn run(logger: Box<dyn api::Logger>) {
+                logger.log(1, "started".to_string());
+                logger.log(2, "stopped".to_string());
+            }
+
+            pub fn run_in_thread(logger: Box<dyn api::Logger>) {
+                std::thread::spawn(move || run(logger)).join().unwrap();
+            }
+        },
+        quote! {
+            assert api.apply(21, lambda x: x * 2) == 42
+
+            ports = len(_ports)
+            api.store(lambda x: x + 1)
+            assert len(_ports) == ports + 1
+            assert api.fire(1) == 2
+            assert len(_ports) == ports
+
+            class ListLogger(Logger):
+                def __init__(self) -> None:
+                    self.messages = []
+
+                def log(self, level: int, message: str) -> None:
+                    self.messages.append((level, message))
+
+            logger = ListLogger()
+            api.run(logger)
+            assert logger.messages == [(1, "started"), (2, "stopped")]
+
+            logger = ListLogger()
+            api.run_in_thread(logger)
+            assert logger.messages == [(1, "started"), (2, "stopped")]
+            assert len(_ports) == ports
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_python(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn unit() -> Future<()>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            pub async fn unit() {}
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            assert list(api.numbers(3)) == [0, 1, 2]
+            assert await api.delayed(42) == 42
+            assert await api.unit() is None
+            assert [value async for value in api.values([1, 2, 3])] == [1, 2, 3]
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn executor() {
+    compile_pass_python_executor(
+        r#"
+        fn thread_name() -> Future<string>;
+        fn pending() -> Future<u32>;
+        fn was_dropped() -> bool;
+        "#,
+        "crate::spawn",
+        quote! {
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll, Waker};
+            use std::sync::atomic::{AtomicBool, Ordering};
+            use std::sync::Arc;
+            use std::task::Wake;
+            use std::thread::Thread;
+
+            struct ThreadWaker(Thread);
+
+            impl Wake for ThreadWaker {
+                fn wake(self: Arc<Self>) {
+                    self.0.unpark();
+                }
+            }
+
+            // Runs each task on its own thread until it completes.
+            pub fn spawn(task: impl Future<Output = ()> + Send + 'static) {
+                std::thread::Builder::new()
+                    .name("executor".to_string())
+                    .spawn(move || {
+                        let mut task = Box::pin(task);
+                        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+                        let mut cx = Context::from_waker(&waker);
+                        while task.as_mut().poll(&mut cx).is_pending() {
+                            std::thread::park();
+                        }
+                    })
+                    .unwrap();
+            }
+
+            pub async fn thread_name() -> String {
+                std::thread::current().name().unwrap().to_string()
+            }
+
+            static DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Pending;
+
+            impl Future for Pending {
+                type Output = u32;
+
+                fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+                    Poll::Pending
+                }
+            }
+
+            impl Drop for Pending {
+                fn drop(&mut self) {
+                    DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn pending() -> Pending {
+                Pending
+            }
+        },
+        quote! {
+            assert await api.thread_name() == "executor"
+
+            fut = api.pending()
+            del fut
+            await asyncio.sleep(0.05)
+            assert api.was_dropped()
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn batched_iterators_streams() {
+    compile_pass_python(
+        r#"
+        fn names(n: u32) -> Iterator<string, 4>;
+        fn maybe(n: u32) -> Iterator<Option<u32>, 3>;
+        fn chunks(n: u32) -> Stream<string, 4>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+
+            pub fn names(n: u32) -> impl Iterator<Item = String> {
+                (0..n).map(|i| format!("name{}", i))
+            }
+
+            pub fn maybe(n: u32) -> impl Iterator<Item = Option<u32>> {
+                (0..n).map(|i| if i % 2 == 0 { Some(i) } else { None })
+            }
+
+            // Returns pending after every third item, so some batches are not full.
+            struct Chunks {
+                next: u32,
+                n: u32,
+                pending: bool,
+            }
+
+            impl Stream for Chunks {
+                type Item = String;
+
+                fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    if self.pending {
+                        self.pending = false;
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                    if self.next == self.n {
+                        return Poll::Ready(None);
+                    }
+                    self.next += 1;
+                    self.pending = self.next % 3 == 0;
+                    Poll::Ready(Some(self.next.to_string()))
+                }
+            }
+
+            pub fn chunks(n: u32) -> impl Stream<Item = String> {
+                Chunks { next: 0, n, pending: false }
+            }
+        },
+        quote! {
+            assert list(api.names(0)) == []
+            assert list(api.names(9)) == ["name" + str(i) for i in range(9)]
+            assert list(api.maybe(5)) == [0, None, 2, None, 4]
+            assert [value async for value in api.chunks(0)] == []
+            assert [value async for value in api.chunks(10)] == [str(i) for i in range(1, 11)]
+        },
+    )
+    .unwrap();
+}
@@ -0,0 +1,235 @@
+use ffi_gen::test_runner::compile_pass_swift;
+use genco::quote;
+
+#[test]
+fn functions() {
+    compile_pass_swift(
+        r#"
+        fn add(a: u32, b: u32) -> u32;
+        fn greet(name: &string) -> string;
+        fn reverse(values: &[u8]) -> Vec<u8>;
+        fn find(names: Vec<string>, name: &string) -> Option<u64>;
+        fn parse(value: &string) -> Result<i64>;
+        fn is_even(value: i32) -> bool;
+        "#,
+        quote! {
+            pub fn add(a: u32, b: u32) -> u32 {
+                a + b
+            }
+
+            pub fn greet(name: &str) -> String {
+                format!("hello {}", name)
+            }
+
+            pub fn reverse(values: &[u8]) -> Vec<u8> {
+                values.iter().rev().copied().collect()
+            }
+
+            pub fn find(names: Vec<String>, name: &str) -> Option<u64> {
+                names.iter().position(|n| n == name).map(|i| i as u64)
+            }
+
+            pub fn parse(value: &str) -> Result<i64, String> {
+                value.parse().map_err(|_| format!("invalid number {}", value))
+            }
+
+            pub fn is_even(value: i32) -> bool {
+                value % 2 == 0
+            }
+        },
+        quote! {
+            precondition(add(a: 40, b: 2) == 42)
+            precondition(greet(name: "world") == "hello world")
+            precondition(reverse(values: [1, 2, 3]) == [3, 2, 1])
+            precondition(find(names: ["a", "b"], name: "b") == 1)
+            precondition(find(names: ["a", "b"], name: "c") == nil)
+            precondition(try! parse(value: "-42") == -42)
+            do {
+                _ = try parse(value: "nan")
+                preconditionFailure()
+            } catch let err as FfiError {
+                precondition(err.message == "invalid number nan")
+            }
+            precondition(isEven(value: 2))
+            precondition(!isEven(value: 3))
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn objects() {
+    compile_pass_swift(
+        r#"
+        fn was_dropped() -> bool;
+        object Counter {
+            static fn new(value: u32) -> Counter;
+            fn increment(by: u32) -> u32;
+        }
+        fn consume(counter: Counter) -> u32;
+        "#,
+        quote! {
+            use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+            static WAS_DROPPED: AtomicBool = AtomicBool::new(false);
+
+            pub fn was_dropped() -> bool {
+                WAS_DROPPED.swap(false, Ordering::SeqCst)
+            }
+
+            pub struct Counter(AtomicU32);
+
+            impl Counter {
+                pub fn new(value: u32) -> Self {
+                    Self(AtomicU32::new(value))
+                }
+
+                pub fn increment(&self, by: u32) -> u32 {
+                    self.0.fetch_add(by, Ordering::SeqCst) + by
+                }
+            }
+
+            impl Drop for Counter {
+                fn drop(&mut self) {
+                    WAS_DROPPED.store(true, Ordering::SeqCst);
+                }
+            }
+
+            pub fn consume(counter: Box<Counter>) -> u32 {
+                counter.0.load(Ordering::SeqCst)
+            }
+        },
+        quote! {
+            var counter: Counter? = Counter.new(value: 40)
+            precondition(counter!.increment(by: 2) == 42)
+            counter!.drop()
+            precondition(wasDropped())
+
+            counter = Counter.new(value: 42)
+            counter = nil
+            precondition(wasDropped())
+
+            counter = Counter.new(value: 42)
+            precondition(consume(counter: counter!) == 42)
+            precondition(wasDropped())
+            counter = nil
+            precondition(!wasDropped())
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn callbacks() {
+    compile_pass_swift(
+        r#"
+        fn apply(value: u32, f: Fn(u32) -> u32) -> u32;
+        interface Logger {
+            fn log(level: u8, message: &string);
+        }
+        fn run(logger: Logger);
+        "#,
+        quote! {
+            pub fn apply(value: u32, f: impl Fn(u32) -> u32) -> u32 {
+                f(value)
+            }
+
+            pub fn run(logger: Box<dyn api::Logger>) {
+                logger.log(1, "started");
+                logger.log(2, "stopped");
+            }
+        },
+        quote! {
+            precondition(apply(value: 21, f: { x in x * 2 }) == 42)
+
+            final class ListLogger: Logger {
+                var levels: [UInt8] = []
+                var messages: [String] = []
+
+                func log(level: UInt8, message: String) {
+                    levels.append(level)
+                    messages.append(message)
+                }
+            }
+
+            let logger = ListLogger()
+            run(logger: logger)
+            precondition(logger.levels == [1, 2])
+            precondition(logger.messages == ["started", "stopped"])
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn iterators_futures_streams() {
+    compile_pass_swift(
+        r#"
+        fn numbers(n: u32) -> Iterator<u32>;
+        fn delayed(value: u64) -> Future<u64>;
+        fn values(values: &[u32]) -> Stream<u32>;
+        "#,
+        quote! {
+            use crate::api::Stream;
+            use core::future::Future;
+            use core::pin::Pin;
+            use core::task::{Context, Poll};
+            use std::time::Duration;
+
+            pub fn numbers(n: u32) -> impl Iterator<Item = u32> {
+                0..n
+            }
+
+            pub struct Delayed(Option<std::thread::JoinHandle<u64>>);
+
+            impl Future for Delayed {
+                type Output = u64;
+
+                fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                    if self.0.as_ref().unwrap().is_finished() {
+                        Poll::Ready(self.0.take().unwrap().join().unwrap())
+                    } else {
+                        let waker = cx.waker().clone();
+                        std::thread::spawn(move || {
+                            std::thread::sleep(Duration::from_millis(10));
+                            waker.wake();
+                        });
+                        Poll::Pending
+                    }
+                }
+            }
+
+            pub fn delayed(value: u64) -> Delayed {
+                Delayed(Some(std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    value
+                })))
+            }
+
+            struct TestStream(Vec<u32>);
+
+            impl Stream for TestStream {
+                type Item = u32;
+
+                fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
+                    Poll::Ready(self.0.pop())
+                }
+            }
+
+            pub fn values(values: &[u32]) -> impl Stream<Item = u32> {
+                TestStream(values.iter().rev().copied().collect())
+            }
+        },
+        quote! {
+            precondition(Array(numbers(n: 3)) == [0, 1, 2])
+            let answer = try await delayed(value: 42)
+            precondition(answer == 42)
+            var items: [UInt32] = []
+            for try await value in values(values: [1, 2, 3]) {
+                items.append(value)
+            }
+            precondition(items == [1, 2, 3])
+        },
+    )
+    .unwrap();
+}